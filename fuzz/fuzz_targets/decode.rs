@@ -0,0 +1,9 @@
+#![no_main]
+
+// Single 16 or 32-bit instruction words
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|inst: u32| {
+    rvlator::rvlator::fuzz(&inst.to_le_bytes());
+    rvlator::rvlator::fuzz(&(inst as u16).to_le_bytes());
+});
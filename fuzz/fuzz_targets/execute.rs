@@ -0,0 +1,8 @@
+#![no_main]
+
+// Arbitrary byte streams as straight-line programs
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|code: &[u8]| {
+    rvlator::rvlator::fuzz(code);
+});
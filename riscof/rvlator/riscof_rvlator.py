@@ -0,0 +1,86 @@
+# RISCOF plugin for rvlator.
+#
+# Each test is built at address 0x0 (rvlator's reset vector) and flattened
+# to a raw binary. The begin/end_signature addresses are looked up with nm
+# and passed to `rvlator run --signature`, which dumps the region on exit.
+
+import os
+import subprocess
+import logging
+
+import riscof.utils as utils
+from riscof.pluginTemplate import pluginTemplate
+
+logger = logging.getLogger()
+
+
+class rvlator(pluginTemplate):
+    __model__ = "rvlator"
+    __version__ = "0.1.0"
+
+    def __init__(self, *args, **kwargs):
+        super().__init__(*args, **kwargs)
+        config = kwargs.get("config")
+        if config is None:
+            raise SystemExit("rvlator: missing plugin config")
+        self.dut_exe = os.path.join(config.get("PATH", ""), "rvlator")
+        self.pluginpath = os.path.abspath(config["pluginpath"])
+        self.isa_spec = os.path.abspath(config["ispec"])
+        self.platform_spec = os.path.abspath(config["pspec"])
+
+    def initialise(self, suite, work_dir, archtest_env):
+        self.work_dir = work_dir
+        self.suite_dir = suite
+        self.archtest_env = archtest_env
+        self.compile_cmd = (
+            "riscv64-unknown-elf-gcc -march={0} -mabi=lp64 -static -mcmodel=medany"
+            " -fvisibility=hidden -nostdlib -nostartfiles"
+            " -T " + self.pluginpath + "/env/link.ld"
+            " -I " + self.pluginpath + "/env/"
+            " -I " + archtest_env + " {1} -o {2} {3}"
+        )
+
+    def build(self, isa_yaml, platform_yaml):
+        ispec = utils.load_yaml(isa_yaml)["hart0"]
+        self.isa = "rv64i"
+        for ext in "MAFDC":
+            if ext in ispec["ISA"]:
+                self.isa += ext.lower()
+
+    def symbol(self, elf, name):
+        out = subprocess.run(
+            ["riscv64-unknown-elf-nm", elf], capture_output=True, text=True, check=True
+        ).stdout
+        for line in out.splitlines():
+            fields = line.split()
+            if len(fields) == 3 and fields[2] == name:
+                return "0x" + fields[0]
+        raise SystemExit("rvlator: {0} has no symbol {1}".format(elf, name))
+
+    def runTests(self, testList):
+        for testname in testList:
+            entry = testList[testname]
+            test_dir = entry["work_dir"]
+            elf = os.path.join(test_dir, "my.elf")
+            binary = os.path.join(test_dir, "my.bin")
+            sig_file = os.path.join(test_dir, self.name[:-1] + ".signature")
+            macros = " ".join("-D" + m for m in entry["macros"])
+
+            cmds = [
+                self.compile_cmd.format(entry["isa"].lower(), entry["test_path"], elf, macros),
+                "riscv64-unknown-elf-objcopy -O binary {0} {1}".format(elf, binary),
+            ]
+            for cmd in cmds:
+                logger.debug(cmd)
+                utils.shellCommand(cmd).run(cwd=test_dir)
+
+            begin = self.symbol(elf, "begin_signature")
+            end = self.symbol(elf, "end_signature")
+            tohost = self.symbol(elf, "tohost")
+            subprocess.run(
+                [self.dut_exe, "run", binary, "--tohost", tohost,
+                 "--signature", sig_file, begin, end],
+                cwd=test_dir,
+                stdout=subprocess.DEVNULL,
+                check=False,
+            )
@@ -0,0 +1,3 @@
+// Library target so external harnesses (cargo-fuzz) can drive the cpu.
+
+pub mod rvlator;
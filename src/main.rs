@@ -1,7 +1,7 @@
 // Read binary file.
 // Decode the instructions
 
-mod rvlator;
+use rvlator::rvlator;
 
 fn print_rvlator() {
     println!("
@@ -13,6 +13,5 @@ fn print_rvlator() {
 }
 
 fn main() {
-    print_rvlator();
-    rvlator::rvlator();
+    rvlator::rvlator(print_rvlator);
 }
@@ -1,8 +1,9 @@
 #![allow(dead_code)]
-use std::env;
+use clap::Parser;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::io::ErrorKind;
-use std::io::Read;
+use std::io::Write;
 //use std::println as debug;
 
 /// bitmask32(width, position)
@@ -34,6 +35,68 @@ macro_rules! sanitizereg {
     }};
 }
 
+// Declared first so encode! is visible in the other modules' tests
+#[cfg(test)]
+#[macro_use]
+mod progbuilder;
+mod analyze;
+mod assertion;
+mod batch;
+mod bitmanip;
+mod bpred;
+mod callstack;
+mod cache;
+mod checkpoint;
+mod cli;
+mod compressed;
+mod crypto;
+mod csr;
+mod debugger;
+mod disasm;
+mod explain;
+mod fpu;
+mod fusion;
+mod heatmap;
+mod isa;
+mod latency;
+mod memcheck;
+mod packed;
+mod pipeline;
+mod protect;
+mod rng;
+mod script;
+mod shadowstack;
+mod softfloat;
+mod spinwait;
+mod syscall;
+mod taint;
+mod timebase;
+mod torture;
+mod watch;
+mod xthead;
+#[cfg(test)]
+mod reftests;
+use assertion::Assertion;
+use bpred::{BpredConfig, BranchPredictor};
+use cache::{Cache, CacheConfig};
+use callstack::CallStack;
+use checkpoint::Checkpointer;
+use disasm::disasm;
+use explain::explain;
+use fusion::fuse;
+use heatmap::Heatmap;
+use isa::Isa;
+use latency::LatencyTable;
+use memcheck::MemCheck;
+use pipeline::{PipeClass, PipeInst, Pipeline};
+use protect::{Protect, Region};
+use rng::Rng;
+use script::Script;
+use spinwait::SpinDetector;
+use taint::Taint;
+use timebase::{Timebase, TimebaseMode};
+use watch::Watch;
+
 //LATER: Check for the corner cases which may break it
 #[inline]
 fn signext12to64(val:u32) -> u64 {
@@ -66,6 +129,53 @@ fn signext_nto64(val:u64, bits: u64) -> u64 {
     }
 }
 
+/// Name of the HINT a 32-bit instruction is, None if it isn't one. HINTs
+/// are the encodings that would only write x0, and fences ordering nothing,
+/// set aside for performance hints: Zihintpause, Zihintntl and the Zicbop
+/// prefetches, and plain "hint" for the ones not given a meaning yet. The
+/// canonical nop is not a hint.
+fn hint(inst: u32) -> Option<&'static str> {
+    let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+    let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS);
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS);
+    match opcode {
+        _ if inst == INST_PAUSE => Some("pause"),
+        _ if rd != 0 || inst == 0x00000013 => None,
+        OPCODE_MISC_MEM if funct3 == 0 && (inst >> 24 & 0xf == 0 || inst >> 20 & 0xf == 0) => Some("hint"),
+        // add x0,x0,x2 to x5
+        OPCODE_OP if inst & !(0x1f << INST_RS2_POS) == OPCODE_OP && (2..=5).contains(&rs2) => {
+            Some(["ntl.p1", "ntl.pall", "ntl.s1", "ntl.all"][rs2 as usize - 2])
+        }
+        // ori x0 with imm[4:0] 0, 1 or 3, the offset in imm[11:5]
+        OPCODE_OP_IMM if funct3 == 0b110 => match inst >> INST_IMM11_0_POS & 0x1f {
+            0 => Some("prefetch.i"),
+            1 => Some("prefetch.r"),
+            3 => Some("prefetch.w"),
+            _ => Some("hint"),
+        },
+        OPCODE_OP_IMM | OPCODE_OP_IMM_32 | OPCODE_OP | OPCODE_OP_32 | OPCODE_LUI | OPCODE_AUIPC => Some("hint"),
+        _ => None,
+    }
+}
+
+/// J-type offset, imm[20|10:1|11|19:12] in inst[31:12]
+#[inline]
+fn jump_offset(inst: u32) -> u64 {
+    let imm20 = getfield32!(inst, INST_IMM31_12_WID, INST_IMM31_12_POS);
+    let imm = (imm20 >> 19) << 20 | (imm20 & 0xff) << 12 | ((imm20 >> 8) & 1) << 11 | ((imm20 >> 9) & 0x3ff) << 1;
+    signext_nto64(imm as u64, 21)
+}
+
+/// B-type offset, imm[12|10:5] in inst[31:25] and imm[4:1|11] in inst[11:7]
+#[inline]
+fn branch_offset(inst: u32) -> u64 {
+    let imm11_5 = getfield32!(inst, INST_IMM11_5_WID, INST_IMM11_5_POS);
+    let imm4_0 = getfield32!(inst, INST_IMM4_0_WID, INST_IMM4_0_POS);
+    let imm = (imm11_5 >> 6) << 12 | (imm4_0 & 1) << 11 | (imm11_5 & 0x3f) << 5 | (imm4_0 & 0x1e);
+    signext_nto64(imm as u64, 13)
+}
+
 // Color Codes for terminal
 const COLOR_RESET:&str = "\x1b[0m";
 const COLOR_GREY:&str = "\x1b[1;30m";
@@ -78,8 +188,14 @@ const COLOR_AQUA:&str = "\x1b[1;36m";
 
 const RESET_VECTOR: u64 = 0x0;
 const ISIZE: u8 = 32;
-const IALIGN: u8 = 32;
+// 16 with the C extension, so only odd targets are misaligned
+const IALIGN: u8 = 16;
+// LATER: RV32 (ELFCLASS32) user binaries, which needs XLEN per hart rather
+// than a constant, 32-bit results sign-extended in ixu and the rv32
+// syscall numbering.
 const XLEN: u8 = 64;
+// Zicbom/Zicboz cache block, the usual riscv,cbom-block-size
+const CBO_BLOCK: u64 = 64;
 const HALFWORD: u8 = 16;
 const WORD: u8 = 32;
 const DOUBLEWORD: u8 = 64;
@@ -132,6 +248,8 @@ const INST_RS2_POS: u8 = 20;
 const INST_RS2_WID: u8 = 5;
 const INST_FUNCT7_POS: u8 = 25;
 const INST_FUNCT7_WID: u8 = 7;
+const INST_FUNCT6_POS: u8 = 26;
+const INST_FUNCT6_WID: u8 = 6;
 const INST_SHAMT_POS:u8 = 20;
 const INST_SHAMT_WID:u8 = 6;
 const INST_IMM4_0_POS: u8 = INST_RD_POS;
@@ -143,12 +261,85 @@ const INST_IMM11_5_WID: u8 = INST_FUNCT7_WID;
 const INST_IMM31_12_POS: u8 = INST_FUNCT3_POS;
 const INST_IMM31_12_WID: u8 = INST_FUNCT3_WID + INST_RS1_WID + INST_IMM11_0_WID;
 
+// Major opcodes, inst[6:0], named as in the base ISA opcode map
+const OPCODE_LOAD: u32 = 0b0000011;
+const OPCODE_LOAD_FP: u32 = 0b0000111;
+const OPCODE_CUSTOM0: u32 = 0b0001011;
+const OPCODE_MISC_MEM: u32 = 0b0001111;
+const OPCODE_OP_IMM: u32 = 0b0010011;
+const OPCODE_AUIPC: u32 = 0b0010111;
+const OPCODE_OP_IMM_32: u32 = 0b0011011;
+const OPCODE_STORE: u32 = 0b0100011;
+const OPCODE_STORE_FP: u32 = 0b0100111;
+const OPCODE_AMO: u32 = 0b0101111;
+const OPCODE_OP: u32 = 0b0110011;
+const OPCODE_LUI: u32 = 0b0110111;
+const OPCODE_OP_32: u32 = 0b0111011;
+const OPCODE_MADD: u32 = 0b1000011;
+const OPCODE_MSUB: u32 = 0b1000111;
+const OPCODE_NMSUB: u32 = 0b1001011;
+const OPCODE_NMADD: u32 = 0b1001111;
+const OPCODE_OP_FP: u32 = 0b1010011;
+const OPCODE_OP_V: u32 = 0b1010111;
+const OPCODE_BRANCH: u32 = 0b1100011;
+const OPCODE_JALR: u32 = 0b1100111;
+const OPCODE_JAL: u32 = 0b1101111;
+const OPCODE_SYSTEM: u32 = 0b1110011;
+const OPCODE_OP_P: u32 = 0b1110111;
+
+const INST_ECALL: u32 = 0x00000073;
+const INST_EBREAK: u32 = 0x00100073;
+const INST_WRS_NTO: u32 = 0x00d00073;
+const INST_WRS_STO: u32 = 0x01d00073;
+// fence w,0
+const INST_PAUSE: u32 = 0x0100000f;
+
 const REGNAME: [&str; 32] = [
     "z0", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1",
     "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
     "s8", "s9", "sA", "sB", "t3", "t4", "t5", "t6",
 ];
 
+// ABI register names, REGNAME is the short form used in traces
+const ABINAME: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1",
+    "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// ABI names of the FP registers f0-f31
+const FREGNAME: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1",
+    "fa2", "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+    "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// Register number for an x-name (x10), ABI name (a0, fp, s10) or REGNAME entry
+fn regnum(name: &str) -> Option<usize> {
+    if let Some(num) = name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+        return if num < 32 { Some(num) } else { None };
+    }
+    if name == "fp" {
+        return Some(REG_S0);
+    }
+    ABINAME
+        .iter()
+        .position(|&abi| abi == name)
+        .or_else(|| REGNAME.iter().position(|&short| short == name))
+}
+
+/// FP register number for an f-name (f10) or ABI name (fa0)
+fn fregnum(name: &str) -> Option<usize> {
+    if let Some(num) = name.strip_prefix('f').and_then(|n| n.parse::<usize>().ok()) {
+        return if num < 32 { Some(num) } else { None };
+    }
+    FREGNAME.iter().position(|&abi| abi == name)
+}
+
+// LATER: In run-user mode, faulting accesses and host SIGINT become guest
+// signals: sigaction/sigprocmask/kill, a signal frame pushed on the guest
+// stack and rt_sigreturn to unwind it.
+#[derive(Debug, PartialEq, Clone, Copy)]
 enum RiscvException {
     InstructionAddressMisaligned,
     InstructionAccessFault,
@@ -164,6 +355,7 @@ enum RiscvException {
     InstructionPageFault,
     LoadPageFault,
     StoreAmoPageFault,
+    SoftwareCheck,
 }
 
 enum RiscvMemType {
@@ -172,26 +364,194 @@ enum RiscvMemType {
     IoMemory,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum RiscvAccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 enum RiscvInstType {
     Bit16,
     Bit32,
     Illegal,
 }
 
+/// Length encoding of the instruction in the low bits: xx (xx != 11) is
+/// 16-bit, bbb11 (bbb != 111) 32-bit. Longer ones aren't supported.
+fn inst_type(inst: u32) -> RiscvInstType {
+    match (getfield32!(inst, 2, 0), getfield32!(inst, 3, 2)) {
+        (0b11, 0b111) => RiscvInstType::Illegal,
+        (0b11, _) => RiscvInstType::Bit32,
+        _ => RiscvInstType::Bit16,
+    }
+}
+
+/// Bytes the pc steps over `inst`
+fn inst_len(inst: u32) -> u64 {
+    match inst_type(inst) {
+        RiscvInstType::Bit16 => 2,
+        _ => 4,
+    }
+}
+
+/// The 32-bit form of `inst`: compressed instructions are expanded, any
+/// other comes back unchanged
+fn expanded(inst: u32) -> u32 {
+    match inst_type(inst) {
+        RiscvInstType::Bit16 => compressed::expand(inst).map_or(inst, |(_, expansion)| expansion),
+        _ => inst,
+    }
+}
+
+// How much a run prints, each level includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Verbosity {
+    // Only errors and failed assertions
+    Quiet,
+    // Retired count, timing statistics and exit status at the end
+    Summary,
+    // Disassembly of every instruction
+    Trace,
+}
+
+// What x1-x31 and the memory past the image hold at reset
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResetFill {
+    Zero,
+    // The value in every register, repeated little-endian through memory
+    Pattern(u64),
+    // Drawn from --seed
+    Random,
+}
+
+// When to print the registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RegDump {
+    Never,
+    // Once, when the run is over
+    End,
+    // After every N retired instructions
+    Every(u64),
+}
+
 #[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 enum RiscvCpuError {
     FetchError,
     DecodeError,
     ExecuteError,
+    AccessFault,
+    StackOverflow,
+    // An exception with no guest handler to take it
+    Exception(RiscvException),
 }
 
 struct RiscvCpu {
     // 64-bit 32 registers integer register unit
     ixu: [u64; 32],
+    // 32 floating point registers of the F and D extensions, singles NaN-boxed
+    fpr: [u64; 32],
+    // fcsr, frm in [7:5] and the accrued fflags in [4:0]
+    fcsr: u32,
+    // CSRs that are plain storage, by address. fcsr and the counters are
+    // read and written through their own fields
+    csrs: BTreeMap<u32, u64>,
+    // Polls of the seed CSR since reset, which step its OPST states
+    seed_polls: u64,
     // program counter
     pc: u64,
-    // Byte addressable memory
+    // Where the pc goes after the instruction being executed, pc + 4 (or
+    // + 2 for a compressed one) unless it branches or jumps
+    next_pc: u64,
+    // Byte addressable memory, mem[0] is at guest address mem_base
+    // LATER: User mode needs a mapping list on top of this (brk, anonymous
+    // and file-backed mmap, munmap, mprotect with per-mapping permissions)
+    // rather than one flat region
     mem: Vec<u8>,
+    mem_base: u64,
+    // Reservation set of the last LR as (address, size), dropped by any SC
+    // and by a store that overlaps it
+    reservation: Option<(u64, u64)>,
+    // The last instruction would have waited (WRS.NTO, WRS.STO, PAUSE with
+    // pause_yield), a hint for the run loop to give the host CPU away
+    idle: bool,
+    pause_yield: bool,
+    // Flags polling loops idle too, when set
+    spin: Option<SpinDetector>,
+    // HINTs executed, by name
+    hint_counts: BTreeMap<&'static str, u64>,
+    // Retired instruction count
+    instret: u64,
+    // Cycle count, 1 per instruction unless a latency table is set
+    cycle: u64,
+    latency: Option<LatencyTable>,
+    // Optional L1 cache timing models
+    icache: Option<Cache>,
+    dcache: Option<Cache>,
+    // Optional branch predictor model
+    bpred: Option<BranchPredictor>,
+    // Optional 5-stage pipeline timing model
+    pipe: Option<Pipeline>,
+    // Macro-op fusion of adjacent instruction pairs
+    fusion: bool,
+    // Optional commit log, one line per retired instruction
+    commit_log: Option<Box<dyn Write>>,
+    // Guest state assertions and the ones that failed
+    assertions: Vec<Assertion>,
+    assert_failures: Vec<String>,
+    // Watch expressions that stop execution
+    watches: Vec<Watch>,
+    // Stop the run when the pc gets here, before executing it
+    run_until: Option<u64>,
+    // Guest stdout/stderr not yet passed to the script, when one runs
+    output: Option<Vec<u8>>,
+    // The script's breakpoints, kept here so a fused pair doesn't step over one
+    script_breaks: BTreeSet<u64>,
+    // HTIF tohost word, polled after every step
+    tohost: Option<u64>,
+    // Set once the guest asks to exit, the run stops there
+    exit_code: Option<u64>,
+    // Program break for the brk syscall
+    brk: u64,
+    verbosity: Verbosity,
+    dump_regs: RegDump,
+    // Break every instruction down into its encoding fields and effects
+    explain: bool,
+    // T-Head vendor extensions in custom-0
+    xthead: bool,
+    // Optional standard extensions from --isa
+    isa: Isa,
+    // Optional dynamic taint tracking
+    taint: Option<Taint>,
+    // Optional uninitialized read detection
+    memcheck: Option<MemCheck>,
+    // Optional return address checking
+    callstack: Option<CallStack>,
+    // Print every syscall, strace style
+    strace: bool,
+    // Print every CSR access
+    csr_trace: bool,
+    // Timestamped copy of the guest's console output
+    console_log: Option<syscall::ConsoleLog>,
+    // ecalls serviced, by syscall number
+    syscall_counts: BTreeMap<u64, u64>,
+    // CSR instructions executed, by CSR address
+    csr_counts: BTreeMap<u32, u64>,
+    // Optional periodic checkpoints
+    checkpointer: Option<Checkpointer>,
+    // Source of all emulator randomness, from --seed
+    rng: Rng,
+    // Guest time, read by gettimeofday
+    timebase: Timebase,
+    // Optional execution count per pc and the file to list them in
+    exec_counts: Option<BTreeMap<u64, u64>>,
+    listing: Option<std::path::PathBuf>,
+    // Optional per-block load/store counts
+    heatmap: Option<Heatmap>,
+    // Optional region permissions
+    protect: Option<Protect>,
 }
 
 impl RiscvCpu {
@@ -199,35 +559,269 @@ impl RiscvCpu {
     fn new(code: Vec<u8>) -> RiscvCpu {
         RiscvCpu {
             ixu: [0; 32],
+            fpr: [0; 32],
+            fcsr: 0,
+            csrs: csr::reset(),
+            seed_polls: 0,
             pc: RESET_VECTOR,
+            next_pc: RESET_VECTOR + 4,
             mem: code.clone(),
+            mem_base: 0,
+            reservation: None,
+            idle: false,
+            spin: None,
+            pause_yield: false,
+            hint_counts: BTreeMap::new(),
+            instret: 0,
+            cycle: 0,
+            latency: None,
+            icache: None,
+            dcache: None,
+            bpred: None,
+            pipe: None,
+            fusion: true,
+            commit_log: None,
+            assertions: Vec::new(),
+            assert_failures: Vec::new(),
+            watches: Vec::new(),
+            run_until: None,
+            output: None,
+            script_breaks: BTreeSet::new(),
+            tohost: None,
+            exit_code: None,
+            brk: code.len() as u64,
+            verbosity: Verbosity::Summary,
+            dump_regs: RegDump::Never,
+            explain: false,
+            xthead: false,
+            isa: Isa::default(),
+            taint: None,
+            memcheck: None,
+            callstack: None,
+            protect: None,
+            heatmap: None,
+            exec_counts: None,
+            listing: None,
+            strace: false,
+            csr_trace: false,
+            syscall_counts: BTreeMap::new(),
+            csr_counts: BTreeMap::new(),
+            console_log: None,
+            checkpointer: None,
+            rng: Rng::new(0),
+            timebase: Timebase::new(TimebaseMode::Host, 10_000_000),
         }
     }
 
     fn fetch(&self) -> Result<u32, RiscvCpuError> {
-        if self.pc < self.mem.len().try_into().unwrap() {
-            let idx = self.pc as usize; // LATER: Using `as` is lossy conversion
-                                        // Instructions are stored in memory in 16-bit parcels which
-                                        // follow little-endian order. ILEN encoding on the LSB side.
-                                        // Fetching 32-bit instruction
-            let inst = self.mem[idx] as u32
-                | (self.mem[idx + 1] as u32) << 8
-                | (self.mem[idx + 2] as u32) << 16
-                | (self.mem[idx + 3] as u32) << 24;
-            Ok(inst)
-        } else {
-            Err(RiscvCpuError::FetchError)
+        self.fetch_at(self.pc)
+    }
+
+    /// Index into `mem` of the guest address, None unless all `size`
+    /// bytes are in range
+    fn mem_index(&self, addr: u64, size: u64) -> Option<usize> {
+        let idx = usize::try_from(addr.checked_sub(self.mem_base)?).ok()?;
+        match idx.checked_add(size as usize)? <= self.mem.len() {
+            true => Some(idx),
+            false => None,
+        }
+    }
+
+    /// Read `size` bytes (1, 2, 4 or 8) little-endian, None if out of range
+    fn read_mem(&self, addr: u64, size: u64) -> Option<u64> {
+        let idx = self.mem_index(addr, size)?;
+        let bytes = &self.mem[idx..idx + size as usize];
+        Some(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u64))
+    }
+
+    /// Write the low `size` bytes of value little-endian, None if out of range
+    fn write_mem(&mut self, addr: u64, size: u64, value: u64) -> Option<()> {
+        let idx = self.mem_index(addr, size)?;
+        self.mem[idx..idx + size as usize].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        Some(())
+    }
+
+    /// mstatus.MBE, machine mode data accesses are big-endian. Only
+    /// machine mode exists, so SBE and UBE are read-only 0.
+    fn big_endian(&self) -> bool {
+        self.csrs[&csr::CSR_MSTATUS] & csr::MSTATUS_MBE != 0
+    }
+
+    /// Read `size` bytes of guest data in the byte order mstatus selects,
+    /// None if out of range. Fetches are always little-endian.
+    fn load_data(&self, addr: u64, size: u64) -> Option<u64> {
+        let value = self.read_mem(addr, size)?;
+        match self.big_endian() {
+            true => Some(value.swap_bytes() >> (64 - 8 * size)),
+            false => Some(value),
+        }
+    }
+
+    /// Write the low `size` bytes of value as guest data in the byte order
+    /// mstatus selects, None if out of range
+    fn store_data(&mut self, addr: u64, size: u64, value: u64) -> Option<()> {
+        match self.big_endian() {
+            true => self.write_mem(addr, size, value.swap_bytes() >> (64 - 8 * size)),
+            false => self.write_mem(addr, size, value),
+        }
+    }
+
+    /// The instruction at addr, a 16-bit one in the low half
+    fn fetch_at(&self, addr: u64) -> Result<u32, RiscvCpuError> {
+        // Instructions are stored in memory in 16-bit parcels which
+        // follow little-endian order. ILEN encoding on the LSB side,
+        // so the first parcel says whether a second one follows.
+        let parcel = |addr: u64| match self.mem_index(addr, 2) {
+            Some(idx) => Ok(self.mem[idx] as u32 | (self.mem[idx + 1] as u32) << 8),
+            None => Err(RiscvCpuError::FetchError),
+        };
+        let low = parcel(addr)?;
+        match inst_type(low) {
+            RiscvInstType::Bit16 => Ok(low),
+            _ => Ok(low | parcel(addr.wrapping_add(2))? << 16),
         }
     }
     
+    /// Memory access hook. Every fetch, load and store reports here so
+    /// timing models can observe the access stream, checked first against
+    /// the --protect regions and --stack-guard ranges, then against the
+    /// bounds of guest memory.
+    fn mem_access(&mut self, kind: RiscvAccessType, addr: u64, size: u64) -> Result<(), RiscvCpuError> {
+        if let Some(protect) = &self.protect {
+            if let Err((fault, reason)) = protect.check(kind, addr, size) {
+                println!("{COLOR_RED}{}{COLOR_RESET}", reason);
+                return Err(fault);
+            }
+        }
+        self.mem_bounds(kind, addr, size)?;
+        self.mem_observe(kind, addr, size);
+        Ok(())
+    }
+
+    /// Shadow stack access hook, mem_access for the Zicfiss instructions,
+    /// which --protect holds to the shadow stack regions instead.
+    fn shadow_access(&mut self, kind: RiscvAccessType, addr: u64, size: u64) -> Result<(), RiscvCpuError> {
+        if let Some(protect) = &self.protect {
+            if let Err((fault, reason)) = protect.check_shadow(addr, size) {
+                println!("{COLOR_RED}{}{COLOR_RESET}", reason);
+                return Err(fault);
+            }
+        }
+        self.mem_bounds(kind, addr, size)?;
+        self.mem_observe(kind, addr, size);
+        Ok(())
+    }
+
+    /// Accesses outside guest memory fault before any observer sees them
+    fn mem_bounds(&self, kind: RiscvAccessType, addr: u64, size: u64) -> Result<(), RiscvCpuError> {
+        match (self.mem_index(addr, size), kind) {
+            (Some(_), _) => Ok(()),
+            (None, RiscvAccessType::Fetch) => Err(RiscvCpuError::FetchError),
+            (None, _) => Err(RiscvCpuError::AccessFault),
+        }
+    }
+
+    /// An access that passed its checks, for the models and checkers
+    /// watching the access stream
+    fn mem_observe(&mut self, kind: RiscvAccessType, addr: u64, size: u64) {
+        let cache = match kind {
+            RiscvAccessType::Fetch => &mut self.icache,
+            RiscvAccessType::Load | RiscvAccessType::Store => &mut self.dcache,
+        };
+        if let Some(cache) = cache {
+            cache.access_range(addr, size);
+        }
+        if let (RiscvAccessType::Store, Some((start, len))) = (kind, self.reservation) {
+            if addr < start + len && start < addr + size {
+                self.reservation = None;
+            }
+        }
+        if let Some(taint) = &mut self.taint {
+            taint.access(kind, addr, size);
+        }
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.access(self.pc, kind, addr, size);
+        }
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.access(kind, addr, size);
+        }
+    }
+
+    /// Branch hook. Conditional branches report their resolved outcome
+    /// here so the predictor model can account for mispredictions.
+    fn branch_observe(&mut self, target: u64, taken: bool) {
+        if let Some(bpred) = &mut self.bpred {
+            bpred.observe(self.pc, target, taken);
+        }
+    }
+
+    /// Fence hook. FENCE and FENCE.I (`fetch`) change nothing architectural
+    /// here; FENCE.I empties the icache model, like a core whose
+    /// instruction cache doesn't snoop stores.
+    fn fence_observe(&mut self, fetch: bool) {
+        if let (true, Some(icache)) = (fetch, &mut self.icache) {
+            icache.invalidate();
+        }
+    }
+
+    // LATER: senvcfg, and cbo.* raising illegal (or virtual) instruction in
+    // S and U mode when menvcfg.CBIE/CBCFE/CBZE and senvcfg say so. The
+    // hart only runs in machine mode, which they never gate.
+    /// Cache block hook. cbo.clean, cbo.flush and cbo.inval change nothing
+    /// architectural, memory is never stale; flush and inval (`drop`) take
+    /// the block out of the dcache model.
+    fn cbo_observe(&mut self, addr: u64, drop: bool) {
+        if let (true, Some(dcache)) = (drop, &mut self.dcache) {
+            dcache.invalidate_range(addr, CBO_BLOCK);
+        }
+    }
+
+    /// Idle hook. WRS.NTO and WRS.STO stall while the reservation set is
+    /// valid, until another hart or device stores to it. Nothing else
+    /// stores here, so they retire as a timed out wait would and only
+    /// flag the hart idle; with no reservation they don't wait at all.
+    fn idle_observe(&mut self) {
+        self.idle = self.reservation.is_some();
+    }
+
+    /// Hint hook. HINTs are no-ops architecturally and only counted here;
+    /// PAUSE flags the hart idle when asked to, so spin-wait loops built on
+    /// it yield the host CPU.
+    fn hint_observe(&mut self, name: &'static str) {
+        *self.hint_counts.entry(name).or_default() += 1;
+        if name == "pause" && self.pause_yield {
+            self.idle = true;
+        }
+    }
+
+    /// Take `exception` at pc. With no trap vector to go to the emulator
+    /// stands in for the handler: environment calls are serviced as
+    /// syscalls and anything else stops the run. On error the pc stays at
+    /// the instruction that raised it, as mepc would.
+    /// LATER: Set mepc, mcause and mtval and jump to mtvec, proxying ecalls
+    /// only while no handler is installed
+    fn trap(&mut self, exception: RiscvException) -> Result<(), RiscvCpuError> {
+        match exception {
+            RiscvException::EcallUmode | RiscvException::EcallSmode | RiscvException::EcallMmode => self.syscall(),
+            _ => Err(RiscvCpuError::Exception(exception)),
+        }
+    }
+
+    // LATER: Vector crypto (Zvkned AES, Zvknh SHA-2, Zvbb, Zvbc) in OP-V,
+    // on top of a vector unit with vtype/vl and element groups. There is
+    // no RVV yet.
     fn execute(&mut self, inst: u32) -> Result<(), RiscvCpuError> {
+        //16-bit instructions run as their 32-bit expansion, stepping the pc by 2
+        //and linking pc + 2
+        self.next_pc = self.pc.wrapping_add(inst_len(inst));
+        let inst = expanded(inst);
         //32-bit Valid Instruction => xxxxxxxxxbbb11 (bbb != 111)
         //inst[1:0] field
         let enc: u32 = getfield32!(inst, 2, 0);
         //inst[4:2](bbb) field
         let bbb: u32 = getfield32!(inst, 3, 2);
 
-        //Check if valid 32-bit instruction
+        //Check if valid 32-bit instruction, reserved 16-bit ones don't expand
         if enc != 0x3 || bbb == 0x7 {
             println!(
                 "Error: Inval Inst: 0x{:08x}, enc: 0b{:02b}, bbb: 0b{:03b}",
@@ -244,25 +838,29 @@ impl RiscvCpu {
 
         let opcode: u32 = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
         match opcode {
-            0b0010111 => {
+            // Zba, Zbb, Zbc, Zbs, Zicond, ahead of the base ISA rows sharing these opcodes
+            OPCODE_OP | OPCODE_OP_32 | OPCODE_OP_IMM | OPCODE_OP_IMM_32 if bitmanip::decode(inst).is_some() => {
+                self.execute_bitmanip(inst)?
+            }
+            // Zkne, Zknd, Zknh
+            OPCODE_OP | OPCODE_OP_IMM if crypto::decode(inst).is_some() => self.execute_crypto(inst)?,
+            OPCODE_AUIPC => {
                 let rd:usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
                 sanitizereg!(rd);
-                let imm20:u32 = getfield32!(inst, INST_IMM31_12_WID, INST_IMM31_12_POS).try_into().unwrap();
+                let imm20:u32 = getfield32!(inst, INST_IMM31_12_WID, INST_IMM31_12_POS);
                 let simm20:u64 = signext20to64(imm20);
-                println!("auipc {},{}", REGNAME[rd], simm20 as i64);
-                self.ixu[rd] = self.pc + (simm20 << 12);
+                self.ixu[rd] = self.pc.wrapping_add(simm20 << 12);
             }
             // Base ISA
-            0b0110111 => { // lui
+            OPCODE_LUI => { // lui
                 let rd:usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
                 sanitizereg!(rd);
-                let imm20:u32 = getfield32!(inst, INST_IMM31_12_WID, INST_IMM31_12_POS).try_into().unwrap();
+                let imm20:u32 = getfield32!(inst, INST_IMM31_12_WID, INST_IMM31_12_POS);
                 let simm20:u64 = signext20to64(imm20);
-                println!("lui {},{}", REGNAME[rd], simm20 as i64);
                 self.ixu[rd] = simm20 << 12;
             }
             // Base ISA
-            0b0010011 => { // addi, slti, sltiu, andi, ori, xori, slli, srli, srai
+            OPCODE_OP_IMM => { // addi, slti, sltiu, andi, ori, xori, slli, srli, srai
                 //Integer Register Immediate Instructions
                 // Both rd and rs are usize instead of u32 to index into the ixu array
                 let rd: usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
@@ -276,7 +874,6 @@ impl RiscvCpu {
 
                 match funct3 {
                     0b000 => { //ADDI: x[rd] = x[rs1] + sext(immediate)
-                        println!("addi {},{},{}", REGNAME[rd], REGNAME[rs1], simm12 as i64);
                         // Why wrapping_add? 0xfffffffffffffffc + 0xffffffffffffffff = 1fffffffffffffffb
                         // We need to discard 1 since this instruction ignores the Arithmetic Overflows
                         self.ixu[rd] = self.ixu[rs1].wrapping_add(simm12);
@@ -284,11 +881,12 @@ impl RiscvCpu {
                     0b001 => { //SLLI: x[rd] = x[rs1] << shamt
                         // 0 <= shamt <= 63, imm12[5:0] or inst[25:20] are used as shift value
                         let shamt = getfield32!(inst, INST_SHAMT_WID, INST_SHAMT_POS);
-                        println!("slli {},{},{}", REGNAME[rd], REGNAME[rs1], shamt);
+                        if getfield32!(inst, INST_FUNCT6_WID, INST_FUNCT6_POS) != 0 {
+                            return Err(RiscvCpuError::DecodeError);
+                        }
                         self.ixu[rd] = self.ixu[rs1] << shamt;
                     }
                     0b010 => { //SLTI: x[rd] = 1 if x[rs1] <s sext(immediate) else x[rd] = 0
-                        println!("slti {},{},{}", REGNAME[rd], REGNAME[rs1], simm12 as i64);
                         if (self.ixu[rs1] as i64) < (simm12 as i64) {
                             self.ixu[rd] = 1;
                         }
@@ -297,7 +895,6 @@ impl RiscvCpu {
                         }
                     }
                     0b011 => { //SLTIU: x[rd] = 1 if x[rs1] <u sext(immediate) else x[rd] = 0
-                        println!("sltiu {},{},{}", REGNAME[rd], REGNAME[rs1], simm12 as i64);
                         if self.ixu[rs1] < simm12 {
                             self.ixu[rd] = 1;
                         }
@@ -306,42 +903,426 @@ impl RiscvCpu {
                         }
                     }
                     0b100 => { //XORI: x[rd] = x[rs1] ^ sext(immediate)
-                        println!("xori {},{},{}", REGNAME[rd], REGNAME[rs1], simm12 as i64);
                         self.ixu[rd] = self.ixu[rs1] ^ simm12;
                     }
                     0b101 => {
                         //SRLI or SRAI
-                        let funct7: u32 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+                        //funct6 not funct7, inst[25] is shamt[5] on RV64
+                        let funct6: u32 = getfield32!(inst, INST_FUNCT6_WID, INST_FUNCT6_POS);
                         //0 <= shamt <= 63, imm12[5:0] or inst[25:20] are used as shift value
                         let shamt = getfield32!(inst, INST_SHAMT_WID, INST_SHAMT_POS);
-                        match funct7 {
-                            0b0000000 => { //SRLI: x[rd] = x[rs1] >> shamt
+                        match funct6 {
+                            0b000000 => { //SRLI: x[rd] = x[rs1] >> shamt
                                 //Inserts 0's in the vacant bits on left side
-                                println!("srli {},{},{}", REGNAME[rd], REGNAME[rs1], shamt);
                                 self.ixu[rd] = self.ixu[rs1] >> shamt;
                             }
-                            0b0100000 => { //SRAI: x[rd] = sext(x[rs1] >> shamt)
+                            0b010000 => { //SRAI: x[rd] = sext(x[rs1] >> shamt)
                                 //Inserts sign-bit(msb) in the vacant  bits on the left side to preserve the sign
-                                println!("srai {},{},{}", REGNAME[rd], REGNAME[rs1], shamt);
-                                self.ixu[rd] = signext_nto64(self.ixu[rs1] >> shamt, 64 - shamt as u64);
+                                self.ixu[rd] = ((self.ixu[rs1] as i64) >> shamt) as u64;
                             }
-                            _ => panic!("Not handling this FUNCT7"),
+                            _ => return Err(RiscvCpuError::DecodeError),
                         }
                     }
                     0b110 => {
-                        println!("ori {},{},{}", REGNAME[rd], REGNAME[rs1], simm12 as i64);
                         self.ixu[rd] = self.ixu[rs1] | simm12;
                     }
                     0b111 => {
-                        println!("andi {},{},{}", REGNAME[rd], REGNAME[rs1], simm12 as i64);
                         self.ixu[rd] = self.ixu[rs1] & simm12;
                     }
-                    _ => panic!("Not handling this Funct3"),
+                    _ => return Err(RiscvCpuError::DecodeError),
+                };
+            }
+            // Base ISA
+            OPCODE_LOAD => { // lb, lh, lw, ld, lbu, lhu, lwu
+                //Load: x[rd] = sext/zext(M[x[rs1] + sext(offset)])
+                let rd: usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
+                sanitizereg!(rd);
+                let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                sanitizereg!(rs1);
+                let imm12:u32 = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+                let simm12:u64 = signext12to64(imm12);
+                let funct3:u32 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+
+                //funct3[1:0] is log2 of the access size, funct3[2] zero extends
+                //There is no ldu on RV64
+                if funct3 == 0b111 {
+                    return Err(RiscvCpuError::DecodeError);
+                }
+                let size: u64 = 1 << (funct3 & 0b11);
+                let addr = self.ixu[rs1].wrapping_add(simm12);
+                self.mem_access(RiscvAccessType::Load, addr, size)?;
+                //Outside guest memory is an access fault, not a host panic
+                let value = self.load_data(addr, size).ok_or(RiscvCpuError::AccessFault)?;
+                self.ixu[rd] = match funct3 {
+                    0b000..=0b010 => signext_nto64(value, size * 8),
+                    _ => value,
                 };
             }
-            _ => panic!("Illegal Instruction: 0b{:07b}", opcode),
+            // Base ISA
+            OPCODE_STORE => { // sb, sh, sw, sd
+                //Store: M[x[rs1] + sext(offset)] = x[rs2][size*8-1:0]
+                let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                sanitizereg!(rs1);
+                let rs2: usize = getfield32!(inst, INST_RS2_WID, INST_RS2_POS).try_into().unwrap();
+                sanitizereg!(rs2);
+                //S-type splits the offset in imm[11:5] and imm[4:0]
+                let imm12:u32 = getfield32!(inst, INST_IMM11_5_WID, INST_IMM11_5_POS) << 5
+                    | getfield32!(inst, INST_IMM4_0_WID, INST_IMM4_0_POS);
+                let simm12:u64 = signext12to64(imm12);
+                let funct3:u32 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+
+                if funct3 > 0b011 {
+                    return Err(RiscvCpuError::DecodeError);
+                }
+                let size: u64 = 1 << funct3;
+                let addr = self.ixu[rs1].wrapping_add(simm12);
+                self.mem_access(RiscvAccessType::Store, addr, size)?;
+                self.store_data(addr, size, self.ixu[rs2]).ok_or(RiscvCpuError::AccessFault)?;
+            }
+            // A extension, Zacas
+            OPCODE_AMO => { // lr, sc, amoswap, amoadd, amoxor, amoand, amoor, amomin, amomax, amominu, amomaxu, amocas
+                //aq and rl order this hart's accesses, which are in program order already
+                let rd: usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
+                sanitizereg!(rd);
+                let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                sanitizereg!(rs1);
+                let rs2: usize = getfield32!(inst, INST_RS2_WID, INST_RS2_POS).try_into().unwrap();
+                sanitizereg!(rs2);
+                let funct3:u32 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+                let funct5:u32 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS) >> 2;
+
+                //funct3 010 is .w, 011 is .d, 100 is amocas.q
+                let size: u64 = match funct3 {
+                    0b010 => 4,
+                    0b011 => 8,
+                    0b100 if funct5 == 0b00101 => 16,
+                    _ => return Err(RiscvCpuError::DecodeError),
+                };
+                //funct5 picks LR, SC or the AMO, the rest is reserved
+                if !matches!(funct5, 0b00000..=0b00101 | 0b01000 | 0b01100 | 0b10000 | 0b10100 | 0b11000 | 0b11100) {
+                    return Err(RiscvCpuError::DecodeError);
+                }
+                let (addr, y) = (self.ixu[rs1], self.ixu[rs2]);
+                //Words are sign extended, so the min/max compares work on them as on 64 bits
+                let sext = |value: u64| match size {
+                    4 => value as i32 as u64,
+                    _ => value,
+                };
+                if !addr.is_multiple_of(size) {
+                    return self.trap(match funct5 {
+                        0b00010 => RiscvException::LoadAddressMisaligned,
+                        _ => RiscvException::StoreAmoAddressMisaligned,
+                    });
+                }
+                match funct5 {
+                    0b00010 => { //LR: x[rd] = M[x[rs1]], reserve it
+                        if rs2 != REG_ZERO {
+                            return Err(RiscvCpuError::DecodeError);
+                        }
+                        self.mem_access(RiscvAccessType::Load, addr, size)?;
+                        let value = self.load_data(addr, size).ok_or(RiscvCpuError::AccessFault)?;
+                        self.reservation = Some((addr, size));
+                        self.ixu[rd] = sext(value);
+                    }
+                    0b00011 => { //SC: M[x[rs1]] = x[rs2] if still reserved, x[rd] = 0 on success
+                        //A range that wraps the address space is never reserved
+                        let reserved = self.reservation.take().is_some_and(|(start, len)| {
+                            addr >= start && addr.checked_add(size).is_some_and(|end| Some(end) <= start.checked_add(len))
+                        });
+                        if reserved {
+                            self.mem_access(RiscvAccessType::Store, addr, size)?;
+                            self.store_data(addr, size, y).ok_or(RiscvCpuError::AccessFault)?;
+                        }
+                        self.ixu[rd] = (!reserved) as u64;
+                    }
+                    //AMOCAS needs store permission whether or not the compare succeeds
+                    0b00101 if size == 16 => { //AMOCAS.Q: as AMOCAS on the even-odd pairs x[rd+1]:x[rd] and x[rs2+1]:x[rs2]
+                        if rd % 2 == 1 || rs2 % 2 == 1 {
+                            return Err(RiscvCpuError::DecodeError);
+                        }
+                        //The x0 pair reads as zero and discards writes
+                        let pair = |reg: usize| match reg {
+                            REG_ZERO => (0, 0),
+                            _ => (self.ixu[reg], self.ixu[reg + 1]),
+                        };
+                        let (compare, swap) = (pair(rd), pair(rs2));
+                        self.mem_access(RiscvAccessType::Load, addr, size)?;
+                        //Big-endian, the high doubleword comes first
+                        let (at_low, at_high) = match self.big_endian() {
+                            true => (addr + 8, addr),
+                            false => (addr, addr + 8),
+                        };
+                        let low = self.load_data(at_low, 8).ok_or(RiscvCpuError::AccessFault)?;
+                        let high = self.load_data(at_high, 8).ok_or(RiscvCpuError::AccessFault)?;
+                        self.mem_access(RiscvAccessType::Store, addr, size)?;
+                        if (low, high) == compare {
+                            self.store_data(at_low, 8, swap.0).ok_or(RiscvCpuError::AccessFault)?;
+                            self.store_data(at_high, 8, swap.1).ok_or(RiscvCpuError::AccessFault)?;
+                        }
+                        if rd != REG_ZERO {
+                            (self.ixu[rd], self.ixu[rd + 1]) = (low, high);
+                        }
+                    }
+                    0b00101 => { //AMOCAS: M[x[rs1]] = x[rs2] if it holds x[rd], x[rd] = M[x[rs1]]
+                        self.mem_access(RiscvAccessType::Load, addr, size)?;
+                        let x = sext(self.load_data(addr, size).ok_or(RiscvCpuError::AccessFault)?);
+                        self.mem_access(RiscvAccessType::Store, addr, size)?;
+                        if x == sext(self.ixu[rd]) {
+                            self.store_data(addr, size, y).ok_or(RiscvCpuError::AccessFault)?;
+                        }
+                        self.ixu[rd] = x;
+                    }
+                    _ => { //AMO: x[rd] = M[x[rs1]], M[x[rs1]] = M[x[rs1]] op x[rs2]
+                        let y = sext(y);
+                        self.mem_access(RiscvAccessType::Load, addr, size)?;
+                        let x = sext(self.load_data(addr, size).ok_or(RiscvCpuError::AccessFault)?);
+                        let value = match funct5 {
+                            0b00001 => y, //AMOSWAP
+                            0b00000 => x.wrapping_add(y), //AMOADD
+                            0b00100 => x ^ y, //AMOXOR
+                            0b01100 => x & y, //AMOAND
+                            0b01000 => x | y, //AMOOR
+                            0b10000 => (x as i64).min(y as i64) as u64, //AMOMIN
+                            0b10100 => (x as i64).max(y as i64) as u64, //AMOMAX
+                            0b11000 => x.min(y), //AMOMINU
+                            _ => x.max(y), //AMOMAXU
+                        };
+                        self.mem_access(RiscvAccessType::Store, addr, size)?;
+                        self.store_data(addr, size, value).ok_or(RiscvCpuError::AccessFault)?;
+                        self.ixu[rd] = x;
+                    }
+                }
+            }
+            // Base ISA, M extension
+            OPCODE_OP => { // add, sub, sll, slt, sltu, xor, srl, sra, or, and, mul*, div*, rem*
+                //Integer Register-Register Instructions
+                let rd: usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
+                sanitizereg!(rd);
+                let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                sanitizereg!(rs1);
+                let rs2: usize = getfield32!(inst, INST_RS2_WID, INST_RS2_POS).try_into().unwrap();
+                sanitizereg!(rs2);
+                let funct3:u32 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+                let funct7:u32 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+                let (x, y) = (self.ixu[rs1], self.ixu[rs2]);
+                //Register shifts use x[rs2][5:0] on RV64
+                let shamt = (y & 0x3f) as u32;
+
+                self.ixu[rd] = match (funct7, funct3) {
+                    (0b0000000, 0b000) => x.wrapping_add(y), //ADD
+                    (0b0100000, 0b000) => x.wrapping_sub(y), //SUB
+                    (0b0000000, 0b001) => x << shamt, //SLL
+                    (0b0000000, 0b010) => ((x as i64) < (y as i64)) as u64, //SLT
+                    (0b0000000, 0b011) => (x < y) as u64, //SLTU
+                    (0b0000000, 0b100) => x ^ y, //XOR
+                    (0b0000000, 0b101) => x >> shamt, //SRL
+                    (0b0100000, 0b101) => ((x as i64) >> shamt) as u64, //SRA
+                    (0b0000000, 0b110) => x | y, //OR
+                    (0b0000000, 0b111) => x & y, //AND
+                    (0b0000001, 0b000) => x.wrapping_mul(y), //MUL
+                    //MULH*: upper 64 bits of the 128-bit product
+                    (0b0000001, 0b001) => ((x as i64 as i128 * y as i64 as i128) >> 64) as u64, //MULH
+                    (0b0000001, 0b010) => ((x as i64 as i128 * y as i128) >> 64) as u64, //MULHSU
+                    (0b0000001, 0b011) => ((x as u128 * y as u128) >> 64) as u64, //MULHU
+                    //Division by zero doesn't trap: the quotient is all ones and the
+                    //remainder the dividend. MIN / -1 overflows to MIN, remainder 0.
+                    (0b0000001, 0b100 | 0b101) if y == 0 => u64::MAX, //DIV, DIVU
+                    (0b0000001, 0b110 | 0b111) if y == 0 => x, //REM, REMU
+                    (0b0000001, 0b100) => (x as i64).wrapping_div(y as i64) as u64, //DIV
+                    (0b0000001, 0b101) => x / y, //DIVU
+                    (0b0000001, 0b110) => (x as i64).wrapping_rem(y as i64) as u64, //REM
+                    (0b0000001, 0b111) => x % y, //REMU
+                    _ => return Err(RiscvCpuError::DecodeError),
+                };
+            }
+            // Base ISA (RV64)
+            OPCODE_OP_IMM_32 => { // addiw, slliw, srliw, sraiw
+                //32-bit results sign extended to 64 bits
+                let rd: usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
+                sanitizereg!(rd);
+                let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                sanitizereg!(rs1);
+                let imm12:u32 = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+                let funct3:u32 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+                let funct7:u32 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+                //Word shifts take shamt[4:0], imm[5] set is reserved
+                let shamt = getfield32!(inst, 5, INST_SHAMT_POS);
+                let x = self.ixu[rs1] as u32;
+
+                let word = match (funct3, funct7) {
+                    (0b000, _) => x.wrapping_add(signext12to64(imm12) as u32), //ADDIW
+                    (0b001, 0b0000000) => x << shamt, //SLLIW
+                    (0b101, 0b0000000) => x >> shamt, //SRLIW
+                    (0b101, 0b0100000) => ((x as i32) >> shamt) as u32, //SRAIW
+                    _ => return Err(RiscvCpuError::DecodeError),
+                };
+                self.ixu[rd] = word as i32 as u64;
+            }
+            // Base ISA (RV64), M extension (RV64)
+            OPCODE_OP_32 => { // addw, subw, sllw, srlw, sraw, mulw, divw, divuw, remw, remuw
+                let rd: usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
+                sanitizereg!(rd);
+                let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                sanitizereg!(rs1);
+                let rs2: usize = getfield32!(inst, INST_RS2_WID, INST_RS2_POS).try_into().unwrap();
+                sanitizereg!(rs2);
+                let funct3:u32 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+                let funct7:u32 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+                let (x, y) = (self.ixu[rs1] as u32, self.ixu[rs2] as u32);
+                //Word shifts use x[rs2][4:0]
+                let shamt = y & 0x1f;
+
+                let word = match (funct7, funct3) {
+                    (0b0000000, 0b000) => x.wrapping_add(y), //ADDW
+                    (0b0100000, 0b000) => x.wrapping_sub(y), //SUBW
+                    (0b0000000, 0b001) => x << shamt, //SLLW
+                    (0b0000000, 0b101) => x >> shamt, //SRLW
+                    (0b0100000, 0b101) => ((x as i32) >> shamt) as u32, //SRAW
+                    (0b0000001, 0b000) => x.wrapping_mul(y), //MULW
+                    (0b0000001, 0b100 | 0b101) if y == 0 => u32::MAX, //DIVW, DIVUW
+                    (0b0000001, 0b110 | 0b111) if y == 0 => x, //REMW, REMUW
+                    (0b0000001, 0b100) => (x as i32).wrapping_div(y as i32) as u32, //DIVW
+                    (0b0000001, 0b101) => x / y, //DIVUW
+                    (0b0000001, 0b110) => (x as i32).wrapping_rem(y as i32) as u32, //REMW
+                    (0b0000001, 0b111) => x % y, //REMUW
+                    _ => return Err(RiscvCpuError::DecodeError),
+                };
+                self.ixu[rd] = word as i32 as u64;
+            }
+            // Base ISA
+            OPCODE_JAL => { // jal
+                //JAL: x[rd] = pc + 4 (pc + 2 for c.j), pc += sext(offset)
+                let rd: usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
+                sanitizereg!(rd);
+                let target = self.pc.wrapping_add(jump_offset(inst));
+                //LATER: RiscvException::InstructionAddressMisaligned
+                if !target.is_multiple_of(IALIGN as u64 / 8) {
+                    return Err(RiscvCpuError::ExecuteError);
+                }
+                self.ixu[rd] = self.next_pc;
+                self.next_pc = target;
+            }
+            // Base ISA
+            OPCODE_JALR => { // jalr
+                //JALR: x[rd] = pc + 4 (pc + 2 for c.jalr), pc = (x[rs1] + sext(offset)) & ~1
+                let rd: usize = getfield32!(inst, INST_RD_WID, INST_RD_POS).try_into().unwrap();
+                sanitizereg!(rd);
+                let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                sanitizereg!(rs1);
+                let imm12:u32 = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+                if getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS) != 0 {
+                    return Err(RiscvCpuError::DecodeError);
+                }
+                //The target is computed before rd is written, rd may be rs1
+                let target = self.ixu[rs1].wrapping_add(signext12to64(imm12)) & !1;
+                if !target.is_multiple_of(IALIGN as u64 / 8) {
+                    return Err(RiscvCpuError::ExecuteError);
+                }
+                self.ixu[rd] = self.next_pc;
+                self.next_pc = target;
+            }
+            // Base ISA
+            OPCODE_BRANCH => { // beq, bne, blt, bge, bltu, bgeu
+                //Branch: pc += sext(offset) if x[rs1] <cond> x[rs2]
+                let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                sanitizereg!(rs1);
+                let rs2: usize = getfield32!(inst, INST_RS2_WID, INST_RS2_POS).try_into().unwrap();
+                sanitizereg!(rs2);
+                let funct3:u32 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+                let (x, y) = (self.ixu[rs1], self.ixu[rs2]);
+
+                let taken = match funct3 {
+                    0b000 => x == y,
+                    0b001 => x != y,
+                    0b100 => (x as i64) < (y as i64),
+                    0b101 => (x as i64) >= (y as i64),
+                    0b110 => x < y,
+                    0b111 => x >= y,
+                    _ => return Err(RiscvCpuError::DecodeError),
+                };
+                let target = self.pc.wrapping_add(branch_offset(inst));
+                //Only a taken branch to a misaligned target faults
+                //LATER: RiscvException::InstructionAddressMisaligned
+                if taken && !target.is_multiple_of(IALIGN as u64 / 8) {
+                    return Err(RiscvCpuError::ExecuteError);
+                }
+                self.branch_observe(target, taken);
+                if taken {
+                    self.next_pc = target;
+                }
+            }
+            OPCODE_MISC_MEM => { // fence, fence.i, cbo.*
+                //One in-order hart sees its own loads, stores and fetches in
+                //program order, so neither fence has anything to wait for
+                let funct3:u32 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+                match funct3 {
+                    //FENCE: fm, pred and succ are ignored, FENCE.TSO is a FENCE
+                    0b000 => self.fence_observe(false),
+                    //FENCE.I: rd, rs1 and imm are reserved and ignored
+                    0b001 => self.fence_observe(true),
+                    //Zicbom, Zicboz: imm[11:0] picks the operation on the
+                    //block holding x[rs1], rd is reserved and ignored
+                    0b010 => {
+                        let rs1: usize = getfield32!(inst, INST_RS1_WID, INST_RS1_POS).try_into().unwrap();
+                        sanitizereg!(rs1);
+                        let addr = self.ixu[rs1] & !(CBO_BLOCK - 1);
+                        match getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS) {
+                            //cbo.inval (as a flush, nothing is dirty), cbo.clean, cbo.flush
+                            0b000 => self.cbo_observe(addr, true),
+                            0b001 => self.cbo_observe(addr, false),
+                            0b010 => self.cbo_observe(addr, true),
+                            //cbo.zero: a block-sized store of zeros
+                            0b100 => {
+                                self.mem_access(RiscvAccessType::Store, addr, CBO_BLOCK)?;
+                                let idx = self.mem_index(addr, CBO_BLOCK).ok_or(RiscvCpuError::AccessFault)?;
+                                self.mem[idx..idx + CBO_BLOCK as usize].fill(0);
+                            }
+                            _ => return Err(RiscvCpuError::DecodeError),
+                        }
+                    }
+                    _ => return Err(RiscvCpuError::DecodeError),
+                }
+            }
+            // Zicfiss, in Zimop's space beside the CSR instructions
+            OPCODE_SYSTEM if shadowstack::decode(inst).is_some() => self.execute_shadow_stack(inst)?,
+            // Zicsr extension
+            OPCODE_SYSTEM if getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS) != 0 => { // csrr*
+                self.execute_csr(inst)?
+            }
+            OPCODE_SYSTEM => { // ecall, ebreak, wrs.nto, wrs.sto
+                //funct3, rd and rs1 are all zero, imm[11:0] picks the instruction
+                if inst & !(0xfff << INST_IMM11_0_POS) != OPCODE_SYSTEM {
+                    return Err(RiscvCpuError::DecodeError);
+                }
+                match getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS) {
+                    //Only machine mode exists
+                    0 => self.trap(RiscvException::EcallMmode)?,
+                    1 => self.trap(RiscvException::Breakpoint)?,
+                    //Zawrs: the wait ends on a store to the reservation set or
+                    //after an implementation-defined time, short for .sto
+                    0x00d | 0x01d => self.idle_observe(),
+                    _ => return Err(RiscvCpuError::DecodeError),
+                }
+            }
+            // F extension
+            OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD | OPCODE_OP_FP => {
+                self.execute_fp(inst)?
+            }
+            OPCODE_CUSTOM0 if self.xthead => self.execute_xthead(inst)?,
+            #[cfg(feature = "packed")]
+            OPCODE_OP_P => self.execute_packed(inst)?,
+            _ => {
+                println!("Error: Illegal Instruction: 0x{:08x}, opcode: 0b{:07b}", inst, opcode);
+                //LATER: Generate RiscvException::IllegalInstruction
+                return Err(RiscvCpuError::DecodeError);
+            }
         }
 
+        if let Some(name) = hint(inst) {
+            self.hint_observe(name);
+        }
+        // x0 is hardwired to zero, discard whatever was written to it
+        self.ixu[REG_ZERO] = 0;
         Ok(())
     }
 
@@ -350,22 +1331,20 @@ impl RiscvCpu {
         let mut output = String::from("");
         for i in (0..32).step_by(4) {
             output = format!(
-                "{}\n{}",
+                "{}\n\
+                 {COLOR_GREEN}[{}]{COLOR_RESET} = {:#018x} \
+                 {COLOR_GREEN}[{}]{COLOR_RESET} = {:#018x} \
+                 {COLOR_GREEN}[{}]{COLOR_RESET} = {:#018x} \
+                 {COLOR_GREEN}[{}]{COLOR_RESET} = {:#018x}",
                 output,
-                format!(
-                    "{COLOR_GREEN}[{}]{COLOR_RESET} = {:#018x} \
-                     {COLOR_GREEN}[{}]{COLOR_RESET} = {:#018x} \
-                     {COLOR_GREEN}[{}]{COLOR_RESET} = {:#018x} \
-                     {COLOR_GREEN}[{}]{COLOR_RESET} = {:#018x}",
-                    REGNAME[i],
-                    self.ixu[i],
-                    REGNAME[i + 1],
-                    self.ixu[i + 1],
-                    REGNAME[i + 2],
-                    self.ixu[i + 2],
-                    REGNAME[i + 3],
-                    self.ixu[i + 3],
-                )
+                REGNAME[i],
+                self.ixu[i],
+                REGNAME[i + 1],
+                self.ixu[i + 1],
+                REGNAME[i + 2],
+                self.ixu[i + 2],
+                REGNAME[i + 3],
+                self.ixu[i + 3],
             );
         }
 
@@ -375,48 +1354,463 @@ impl RiscvCpu {
         ---------------------------------------------------------")
     }
 
-    fn pipeline(&self) -> Result<(), RiscvCpuError> {
+    /// Write memory [begin, end) as one 32-bit little-endian word per line,
+    /// the begin_signature/end_signature format expected by RISCOF.
+    fn dump_signature(&self, path: &str, begin: u64, end: u64) -> std::io::Result<()> {
+        let mut output = String::new();
+        for addr in (begin..end).step_by(4) {
+            let word = self.read_mem(addr, 4).ok_or(ErrorKind::UnexpectedEof)?;
+            output.push_str(&format!("{:08x}\n", word));
+        }
+        fs::write(path, output)
+    }
+
+    /// Print cache and branch predictor statistics and the cycle
+    /// estimate they imply.
+    pub fn print_timing_stats(&self) {
+        let mut cycles = self.cycle;
+        for (name, cache) in [("icache", &self.icache), ("dcache", &self.dcache)] {
+            if let Some(cache) = cache {
+                println!(
+                    "{COLOR_AQUA}[{}]{COLOR_RESET} accesses = {} misses = {} miss rate = {:.2}%",
+                    name,
+                    cache.accesses,
+                    cache.misses,
+                    cache.miss_rate() * 100.0
+                );
+                cycles += cache.penalty_cycles();
+            }
+        }
+        if let Some(bpred) = &self.bpred {
+            println!(
+                "{COLOR_AQUA}[bpred]{COLOR_RESET} branches = {} mispredicts = {}",
+                bpred.branches, bpred.mispredicts
+            );
+            for (pc, site) in &bpred.sites {
+                println!(
+                    "    {:#018x} executed = {} taken = {} mispredicted = {}",
+                    pc, site.executed, site.taken, site.mispredicted
+                );
+            }
+            cycles += bpred.penalty_cycles();
+        }
+        if let Some(pipe) = &self.pipe {
+            println!(
+                "{COLOR_AQUA}[pipeline]{COLOR_RESET} cycles = {} CPI = {:.2} stalls = {} flushes = {}",
+                pipe.cycles(),
+                pipe.cycles() as f64 / pipe.insts.max(1) as f64,
+                pipe.stalls,
+                pipe.flushes
+            );
+        }
+        println!("{COLOR_AQUA}[cycles]{COLOR_RESET} = {} (estimated, {} retired)", cycles, self.instret);
+    }
+
+    /// Print the HINTs executed by name, if there were any
+    pub fn print_hint_stats(&self) {
+        if self.hint_counts.is_empty() {
+            return;
+        }
+        let hints: Vec<_> = self.hint_counts.iter().map(|(name, count)| format!("{} = {}", name, count)).collect();
+        println!("{COLOR_AQUA}[hints]{COLOR_RESET} {}", hints.join(", "));
+    }
+
+    /// Fetch and execute the next instruction, or a fused pair of them,
+    /// and move the pc past it or to the branch target.
+    fn step(&mut self) -> Result<(), RiscvCpuError> {
+        let inst = self.fetch()?;
+        let before = self.ixu;
+        let retired = self.instret;
+        self.check_assertions_at_pc();
+        // Pre-decode the following instruction to look for a fusable pair.
+        // Don't fuse over an instruction that has to be observed on its own.
+        // Explain mode shows every instruction on its own too, watches are
+        // checked after every instruction and only 32-bit pairs fuse.
+        let observed = self.explain
+            || inst_len(inst) != 4
+            || !self.watches.is_empty()
+            || self.run_until == Some(self.pc + 4)
+            || self.script_breaks.contains(&(self.pc + 4))
+            || self.assertions.iter().any(|a| a.at == Some(self.pc + 4));
+        let fused = match self.fusion && !observed {
+            true => self.fetch_at(self.pc + 4).ok().and_then(|next| fuse(inst, next)),
+            false => None,
+        };
+        match fused {
+            Some(op) => {
+                let next = self.fetch_at(self.pc + 4)?;
+                self.mem_access(RiscvAccessType::Fetch, self.pc, 8)?;
+                self.trace(&op.disasm());
+                let first = self.execute_fused(op);
+                self.retire(inst, self.pc, self.pc + 4);
+                self.retire(next, self.pc + 4, self.pc + 8);
+                self.log_commit(self.pc, inst, &before, &first);
+                self.log_commit(self.pc + 4, next, &first, &self.ixu.clone());
+                self.trace_registers(retired);
+                self.pc += 8;
+            }
+            None => {
+                self.mem_access(RiscvAccessType::Fetch, self.pc, inst_len(inst))?;
+                if !self.explain {
+                    self.trace(&disasm(inst));
+                }
+                self.execute(inst)?;
+                self.retire(inst, self.pc, self.next_pc);
+                self.log_commit(self.pc, inst, &before, &self.ixu.clone());
+                if self.explain {
+                    print!("{}", explain(self.pc, inst, &before, &self.ixu));
+                }
+                self.trace_registers(retired);
+                self.pc = self.next_pc;
+            }
+        }
+        self.poll_tohost();
         Ok(())
     }
+
+    fn trace(&self, text: &str) {
+        if self.verbosity >= Verbosity::Trace {
+            println!("{}", text);
+        }
+    }
+
+    /// Print the registers if the step that started at `retired` crossed
+    /// a --dump-regs every-N boundary. A fused pair retires two at once.
+    fn trace_registers(&self, retired: u64) {
+        if let RegDump::Every(n) = self.dump_regs {
+            if self.instret / n != retired / n {
+                self.print_registers();
+            }
+        }
+    }
+
+    /// HTIF: a word with bit 0 set written to tohost is an exit request
+    /// with the status in the upper bits.
+    /// LATER: Other tohost values are syscall proxy requests, ignored today
+    fn poll_tohost(&mut self) {
+        if let Some(value) = self.tohost.and_then(|addr| self.read_mem(addr, 8)) {
+            if value & 1 == 1 {
+                self.exit_code = Some(value >> 1);
+            }
+        }
+    }
+
+    /// Append "pc (inst) [reg value]" to the commit log, naming the
+    /// register that changed from `before` to `after`.
+    fn log_commit(&mut self, pc: u64, inst: u32, before: &[u64; 32], after: &[u64; 32]) {
+        let Some(log) = &mut self.commit_log else { return };
+        let mut line = format!("{:#018x} ({:#010x})", pc, inst);
+        for (reg, value) in after.iter().enumerate() {
+            if *value != before[reg] {
+                line.push_str(&format!(" {} {:#018x}", REGNAME[reg], value));
+            }
+        }
+        writeln!(log, "{}", line).expect("unable to write the commit log");
+    }
+
+    /// Retire the instruction at pc: advance the counters and feed the
+    /// timing models, which see compressed instructions expanded.
+    fn retire(&mut self, inst: u32, pc: u64, next_pc: u64) {
+        let fallthrough = pc + inst_len(inst);
+        let sequential = next_pc == fallthrough;
+        let inst = expanded(inst);
+        self.instret += 1;
+        self.cycle += match &self.latency {
+            Some(table) => table.latency(PipeInst::decode(inst).class),
+            None => 1,
+        };
+        self.pipeline(inst, !sequential);
+        if let Some(counts) = &mut self.exec_counts {
+            *counts.entry(pc).or_default() += 1;
+        }
+        if let Some(taint) = &mut self.taint {
+            taint.retire(inst, pc);
+        }
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.retire(inst, pc);
+        }
+        if let Some(callstack) = &mut self.callstack {
+            callstack.retire(inst, pc, fallthrough, next_pc);
+        }
+        if let Some(spin) = &mut self.spin {
+            self.idle |= spin.retire(inst, pc, next_pc, &self.ixu);
+        }
+    }
+
+    fn timing_enabled(&self) -> bool {
+        self.icache.is_some()
+            || self.dcache.is_some()
+            || self.bpred.is_some()
+            || self.pipe.is_some()
+            || self.latency.is_some()
+    }
+
+    /// Feed the retired instruction to the pipeline timing model,
+    /// `redirect` when it went anywhere but the next instruction.
+    fn pipeline(&mut self, inst: u32, redirect: bool) {
+        if let Some(pipe) = &mut self.pipe {
+            pipe.issue(PipeInst::decode(inst), redirect);
+        }
+    }
 }
 
-fn read_bin(f: &String) -> Result<Vec<u8>, ErrorKind> {
-    let mut content: Vec<u8> = Vec::new();
-    let metadata = fs::metadata(f).expect("unable to get the metadata");
-    if metadata.is_file() {
-        // LATER: Better error handling whild reading the binary file
-        let mut fbin = fs::File::open(f).unwrap();
-        fbin.read_to_end(&mut content).unwrap();
-        Ok(content)
-    } else {
-        println!("binary file invalid\n");
-        // LATER: Right error type for metadata of not file
-        Err(ErrorKind::Other)
+/// Parse a hex (0x prefixed) or decimal address
+fn parse_addr(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
     }
 }
 
-pub fn rvlator() {
-    let args: Vec<String> = env::args().collect();
-    let binfilepath = &args[1];
-    let inststream = read_bin(binfilepath).expect("input binary missing");
+// Upper bound on steps per fuzz input, branches make loops possible
+const FUZZ_MAX_STEPS: usize = 4096;
 
-    let mut cpu = RiscvCpu::new(inststream);
+/// Fuzzing entry point. Runs `code` from the reset vector on a fresh cpu
+/// until it falls off the end or an instruction fails to decode. Any panic
+/// is a bug: illegal encodings must come back as RiscvCpuError.
+pub fn fuzz(code: &[u8]) {
+    let mut cpu = RiscvCpu::new(code.to_vec());
+    for _ in 0..FUZZ_MAX_STEPS {
+        if cpu.step().is_err() || cpu.exit_code.is_some() {
+            break;
+        }
+        assert_eq!(cpu.ixu[REG_ZERO], 0);
+    }
+}
 
-    for _ in 0..cpu.mem.len()/4 {
-        let inst = cpu.fetch().unwrap();
-        cpu.execute(inst).unwrap();
+const TORTURE_PROG_LEN: usize = 256;
+
+/// Run `count` torture programs starting at `seed`, each checked in
+/// lockstep with and without fusion. A failing program is saved as
+/// torture-SEED.bin for replay.
+fn run_torture(seed: u64, count: u64) {
+    for seed in seed..seed + count {
+        let prog = torture::generate(seed, TORTURE_PROG_LEN);
+        if let Err(pc) = torture::check(&prog) {
+            let path = format!("torture-{}.bin", seed);
+            fs::write(&path, torture::assemble(&prog)).expect("unable to save the torture program");
+            println!("{COLOR_RED}torture seed {} diverged at pc {:#018x}, saved {}{COLOR_RESET}", seed, pc, path);
+            std::process::exit(1);
+        }
+    }
+    println!("{COLOR_GREEN}torture: {} programs from seed {} passed{COLOR_RESET}", count, seed);
+}
+
+// LATER: When stdin is a terminal, switch it to raw mode for the guest
+// console (restored on exit, panic included) and forward every byte to
+// the UART receive FIFO, keeping an escape sequence such as Ctrl-a x for
+// emulator control. There is no UART to forward to yet.
+fn script_failed<T>(e: String) -> T {
+    println!("{COLOR_RED}script: {}{COLOR_RESET}", e);
+    std::process::exit(1);
+}
+
+/// Run until the pc leaves the image, then report timing, the signature
+/// and assertion results
+fn run(cpu: &mut RiscvCpu, end: u64, signature: Option<Vec<String>>, mut script: Option<Script>) {
+    let signature = signature.map(|sig| {
+        let addr = |a: &String| parse_addr(a).unwrap_or_else(|| cli::usage_error(format!("bad signature address {}", a)));
+        (sig[0].clone(), addr(&sig[1]), addr(&sig[2]))
+    });
+
+    if let Some(script) = &mut script {
+        script.start(cpu).unwrap_or_else(script_failed);
+    }
+    while cpu.exit_code.is_none() && cpu.pc >= cpu.mem_base && cpu.pc < end {
+        if let Some(script) = &mut script {
+            if script.at_pc(cpu).unwrap_or_else(script_failed) {
+                break;
+            }
+        }
+        if cpu.run_until == Some(cpu.pc) {
+            if cpu.verbosity >= Verbosity::Summary {
+                println!("stopped at pc {:#018x}", cpu.pc);
+            }
+            break;
+        }
+        let retired = cpu.instret;
+        if let Err(e) = cpu.step() {
+            println!("{COLOR_RED}{:?} at pc {:#018x}{COLOR_RESET}", e, cpu.pc);
+            if let Some(script) = &mut script {
+                script.trap(cpu, &e).unwrap_or_else(script_failed);
+            }
+            std::process::exit(1);
+        }
+        // A spin-wait on wrs.* or a polling loop gets nothing done until
+        // something else runs
+        if std::mem::take(&mut cpu.idle) {
+            std::thread::yield_now();
+        }
+        cpu.checkpoint(retired);
+        if cpu.check_watches() {
+            break;
+        }
+    }
+
+    if cpu.dump_regs == RegDump::End {
         cpu.print_registers();
-        cpu.pc += 4;
+    }
+    if cpu.verbosity >= Verbosity::Summary {
+        println!("{COLOR_AQUA}[retired]{COLOR_RESET} = {} pc = {:#018x}", cpu.instret, cpu.pc);
+        if cpu.timing_enabled() {
+            cpu.print_timing_stats();
+        }
+        cpu.print_trap_stats();
+        cpu.print_hint_stats();
+    }
+
+    if let Some(taint) = &cpu.taint {
+        for report in &taint.reports {
+            println!("{COLOR_BROWN}taint: {}{COLOR_RESET}", report);
+        }
+    }
+    if let Some(memcheck) = &cpu.memcheck {
+        for report in &memcheck.reports {
+            println!("{COLOR_BROWN}memcheck: {}{COLOR_RESET}", report);
+        }
+    }
+    if let Some(callstack) = &cpu.callstack {
+        for report in &callstack.reports {
+            println!("{COLOR_BROWN}callcheck: {}{COLOR_RESET}", report);
+        }
+    }
+    if let Some(heatmap) = &cpu.heatmap {
+        print!("{}", heatmap.report());
+    }
+    if let (Some(path), Some(counts)) = (&cpu.listing, &cpu.exec_counts) {
+        let code = &cpu.mem[..(end - cpu.mem_base) as usize];
+        fs::write(path, disasm::listing(code, cpu.mem_base, Some(counts))).expect("unable to write the listing");
+    }
+
+    if let Some((path, begin, end)) = signature {
+        cpu.dump_signature(&path, begin, end).expect("unable to write the signature");
+    }
+
+    if let Some(script) = &mut script {
+        script.exit(cpu).unwrap_or_else(script_failed);
+    }
+    cpu.check_assertions_at_exit();
+    if !cpu.assert_failures.is_empty() {
+        for failure in &cpu.assert_failures {
+            println!("{COLOR_RED}assertion failed: {}{COLOR_RESET}", failure);
+        }
+        std::process::exit(1);
+    }
+
+    // The host only keeps the low 8 bits, like any other process
+    if let Some(code) = cpu.exit_code {
+        if cpu.verbosity >= Verbosity::Summary {
+            println!("guest exited with code {}", code);
+        }
+        std::process::exit(code as i32);
+    }
+}
+
+/// List every 32-bit word of the image as an instruction
+fn disasm_image(image: &std::path::Path, base: u64) {
+    let code = fs::read(image)
+        .unwrap_or_else(|e| cli::usage_error(format!("unable to read {}: {}", image.display(), e)));
+    print!("{}", disasm::listing(&code, base, None));
+}
+
+/// Print the instruction set usage of an ELF or flat binary, failing if
+/// it needs anything this build doesn't execute
+fn analyze_image(image: &std::path::Path) {
+    let file = fs::read(image)
+        .unwrap_or_else(|e| cli::usage_error(format!("unable to read {}: {}", image.display(), e)));
+    let sections = analyze::text_sections(&file).unwrap_or_else(|e| cli::usage_error(e));
+    let usage = analyze::analyze(&sections);
+    usage.print();
+    if !usage.unsupported.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Run `images` as separate processes and print the summary table, exit
+/// status 1 unless every one passed
+fn run_batch(
+    images: &[std::path::PathBuf],
+    jobs: Option<usize>,
+    timeout: std::time::Duration,
+    junit: Option<std::path::PathBuf>,
+    run_args: &[String],
+) {
+    let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let results = batch::run_batch(images, jobs, timeout, run_args);
+    print!("{}", batch::summary(&results));
+    if let Some(path) = junit {
+        fs::write(&path, batch::junit(&results))
+            .unwrap_or_else(|e| cli::usage_error(format!("unable to write {}: {}", path.display(), e)));
+    }
+    if results.iter().any(|r| r.outcome != batch::Outcome::Pass) {
+        std::process::exit(1);
+    }
+}
+
+// LATER: A weak-memory stress mode for SMP runs: random hart
+// interleavings and store buffers that delay visibility as far as RVWMO
+// allows, with a litmus test runner on top. Needs the harts to exist.
+/// Command line entry point. `banner` is printed unless the output is
+/// meant for another tool (-q, disasm).
+pub fn rvlator(banner: fn()) {
+    // LATER: SMP configuration with one hart per host thread. Needs `mem`
+    // moved out of RiscvCpu into a shared (sharded) bus, where a store from
+    // any hart drops the other harts' LR reservations.
+    match cli::Cli::parse().command {
+        cli::Command::Run { machine, signature, script } => {
+            let (mut cpu, end) = machine.build().unwrap_or_else(|e| cli::usage_error(e));
+            let script = script.map(|path| Script::load(&path).unwrap_or_else(|e| cli::usage_error(e)));
+            if cpu.verbosity > Verbosity::Quiet {
+                banner();
+            }
+            run(&mut cpu, end, signature, script);
+        }
+        cli::Command::Disasm { image, base } => disasm_image(&image, base),
+        cli::Command::Analyze { image } => analyze_image(&image),
+        cli::Command::Debug { machine, narrate } => {
+            let (mut cpu, end) = machine.build().unwrap_or_else(|e| cli::usage_error(e));
+            banner();
+            // A reload must not truncate the logs the session writes
+            let reload = || cli::MachineArgs { commit_log: None, console_log: None, ..machine.clone() }.build();
+            cpu.debug(end, narrate, reload, std::io::stdin().lock());
+        }
+        cli::Command::Torture { seed, count } => {
+            banner();
+            run_torture(seed, count);
+        }
+        cli::Command::Batch { images, jobs, timeout, junit, run_args } => {
+            run_batch(&images, jobs, timeout, junit, &run_args);
+        }
     }
 }
 
+// LATER: Run the official riscv-tests (rv64ui-p-*, rv64um-p-*, ...) here,
+// loading each ELF and checking the value written to tohost. Blocked on an
+// ELF loader and the trap vector: the `-p-` env installs a handler in
+// mtvec and enters each test with mret, and traps don't go there yet.
 #[cfg(test)]
 mod tests {
     use super::*;
+    use progbuilder::*;
+    use std::env;
 
+    // Same program as test/baseinst.s
     fn prelog() -> RiscvCpu {
-        let bin = read_bin(&String::from("test/bin/rvlatortest.bin")).unwrap();
-        RiscvCpu::new(bin)
+        ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, -4))
+            .inst(encode!(addi a1, zero, -5))
+            .inst(encode!(slti a2, a1, -4))
+            .inst(encode!(slli a2, a0, 60))
+            .inst(encode!(srli a3, a2, 1))
+            .inst(encode!(srai a4, a1, 1))
+            .inst(encode!(sltiu a5, a1, -4))
+            .inst(encode!(andi a6, a0, 4))
+            .inst(encode!(ori a7, a0, 4))
+            .inst(encode!(xori s2, a0, -1))
+            .inst(encode!(auipc s3, 0xdead))
+            .inst(encode!(lui s4, 0xdead))
+            .inst(encode!(addi a0, a0, -1))
+            .cpu()
     }
 
     #[test]
@@ -488,6 +1882,41 @@ mod tests {
         assert_eq!(cpu.ixu[REG_A2], 0xc000000000000000);
     }
 
+    #[test]
+    fn test_inst_srli_srai_wide() {
+        let mut cpu = prelog();
+        // addi a0, zero, -4  (ffc00513)
+        cpu.execute(0xffc00513).unwrap();
+        // srli a1, a0, 32 (02055593)
+        cpu.execute(0x02055593).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 0x00000000ffffffff);
+        // srai a2, a0, 0 (40055613)
+        cpu.execute(0x40055613).unwrap();
+        assert_eq!(cpu.ixu[REG_A2], 0xfffffffffffffffc);
+        // srai a3, a0, 63 (43f55693)
+        cpu.execute(0x43f55693).unwrap();
+        assert_eq!(cpu.ixu[REG_A3], 0xffffffffffffffff);
+    }
+
+    #[test]
+    fn test_inst_illegal() {
+        let mut cpu = prelog();
+        // slli with imm[11:6] != 0 (08051613)
+        assert_eq!(Err(RiscvCpuError::DecodeError), cpu.execute(0x08051613));
+        // srli with funct6 0b000010 (08055593)
+        assert_eq!(Err(RiscvCpuError::DecodeError), cpu.execute(0x08055593));
+        // custom-0 opcode (0000000b)
+        assert_eq!(Err(RiscvCpuError::DecodeError), cpu.execute(0x0000000b));
+    }
+
+    #[test]
+    fn test_inst_write_zero() {
+        let mut cpu = prelog();
+        // addi zero, zero, 1 (00100013)
+        cpu.execute(0x00100013).unwrap();
+        assert_eq!(cpu.ixu[REG_ZERO], 0);
+    }
+
     #[test]
     fn test_inst_lui() {
         let mut cpu = prelog();
@@ -505,4 +1934,844 @@ mod tests {
         cpu.execute(0x0dead997).unwrap();
         assert_eq!(cpu.ixu[REG_S3], 0x000000000dead004);
     }
+
+    #[test]
+    fn test_inst_load_store() {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, -2))
+            .inst(encode!(addi sp, zero, 0x40))
+            .inst(encode!(sd a0, -16(sp)))
+            .inst(encode!(sb zero, -9(sp)))
+            .inst(encode!(lb a1, -16(sp)))
+            .inst(encode!(lbu a2, -16(sp)))
+            .inst(encode!(lh a3, -10(sp)))
+            .inst(encode!(lwu a4, -12(sp)))
+            .inst(encode!(ld a5, -16(sp)))
+            .cpu();
+        cpu.mem.resize(0x40, 0);
+        cpu.fusion = false;
+        for _ in 0..9 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.read_mem(0x30, 8), Some(0x00ff_ffff_ffff_fffe));
+        assert_eq!(cpu.ixu[REG_A1], 0xffff_ffff_ffff_fffe);
+        assert_eq!(cpu.ixu[REG_A2], 0xfe);
+        assert_eq!(cpu.ixu[REG_A3], 0x0000_0000_0000_00ff);
+        assert_eq!(cpu.ixu[REG_A4], 0x00ff_ffff);
+        assert_eq!(cpu.ixu[REG_A5], 0x00ff_ffff_ffff_fffe);
+
+        // Past the end of memory is a fault, not a panic, and nothing is written
+        assert_eq!(cpu.execute(encode!(ld a0, -4(sp))), Err(RiscvCpuError::AccessFault));
+        assert_eq!(cpu.execute(encode!(sw a0, 0(sp))), Err(RiscvCpuError::AccessFault));
+        assert_eq!(cpu.mem.len(), 0x40);
+        // Also with a cache watching, for an access that wraps the address space
+        cpu.dcache = Some(Cache::new(CacheConfig::parse("1k:2:64:10").unwrap()));
+        cpu.ixu[REG_A1] = u64::MAX;
+        assert_eq!(cpu.execute(encode!(sd a0, 0(a1))), Err(RiscvCpuError::AccessFault));
+        assert_eq!(cpu.dcache.as_ref().unwrap().accesses, 0);
+        // ldu doesn't exist
+        assert_eq!(cpu.execute(0x00016503 | 0b111 << 12), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_big_endian() {
+        // mstatus.MBE swaps data, the instructions still fetch little-endian
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi t0, zero, 1))
+            .inst(encode!(slli t0, t0, 37))
+            .inst(encode!(csrrs zero, mstatus, t0))
+            .inst(encode!(lui a0, 0x12345))
+            .inst(encode!(addi a0, a0, 0x678))
+            .inst(encode!(sw a0, 0x40(zero)))
+            .inst(encode!(lh a1, 0x40(zero)))
+            .inst(encode!(lbu a2, 0x43(zero)))
+            .inst(encode!(addi a3, zero, -2))
+            .inst(encode!(sh a3, 0x44(zero)))
+            .inst(encode!(lh a4, 0x44(zero)))
+            .cpu();
+        cpu.mem.resize(0x48, 0);
+        while cpu.pc < 0x2c {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.read_mem(0x40, 4), Some(0x78563412));
+        assert_eq!((cpu.ixu[REG_A1], cpu.ixu[REG_A2]), (0x1234, 0x78));
+        assert_eq!(cpu.read_mem(0x44, 2), Some(0xfeff));
+        assert_eq!(cpu.ixu[REG_A4], (-2i64) as u64);
+    }
+
+    #[test]
+    fn test_inst_branch() {
+        // Count a0 down from 3, then skip the addi a2 with a taken beq
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 3))
+            .inst(encode!(addi a1, a1, 1))
+            .inst(encode!(addi a0, a0, -1))
+            .inst(encode!(bne a0, zero, -8))
+            .inst(encode!(beq a0, zero, 8))
+            .inst(encode!(addi a2, zero, 1))
+            .inst(encode!(blt a0, a1, 8))
+            .cpu();
+        cpu.fusion = false;
+        cpu.bpred = BpredConfig::parse("static").map(BranchPredictor::new);
+        while cpu.fetch().is_ok() {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.ixu[REG_A0], cpu.ixu[REG_A1], cpu.ixu[REG_A2]), (0, 3, 0));
+        assert_eq!(cpu.instret, 1 + 3 * 3 + 2);
+        assert_eq!(cpu.pc, 32);
+        let bpred = cpu.bpred.as_ref().unwrap();
+        assert_eq!((bpred.branches, bpred.mispredicts), (5, 3));
+
+        // Signed and unsigned compares differ on negative values
+        cpu.ixu[REG_A0] = -1i64 as u64;
+        cpu.pc = 0x100;
+        cpu.execute(encode!(blt a0, a1, 16)).unwrap();
+        assert_eq!(cpu.next_pc, 0x110);
+        cpu.execute(encode!(bltu a0, a1, 16)).unwrap();
+        assert_eq!(cpu.next_pc, 0x104);
+        cpu.execute(encode!(bgeu a0, a1, -0x100)).unwrap();
+        assert_eq!(cpu.next_pc, 0);
+        // With the C extension any even target is an instruction boundary
+        cpu.execute(encode!(bge a0, a0, 6)).unwrap();
+        assert_eq!(cpu.next_pc, 0x106);
+    }
+
+    #[test]
+    fn test_inst_jal_jalr() {
+        // Call a function that doubles a0 twice, jalr returning through ra
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 5))
+            .inst(encode!(jal ra, 16))
+            .inst(encode!(jal ra, 12))
+            .inst(encode!(jal zero, 16))
+            .inst(encode!(addi zero, zero, 0))
+            .inst(encode!(slli a0, a0, 1))
+            .inst(encode!(jalr zero, 0(ra)))
+            .cpu();
+        cpu.fusion = false;
+        while cpu.fetch().is_ok() {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.ixu[REG_A0], 20);
+        assert_eq!(cpu.ixu[REG_RA], 12);
+        assert_eq!(cpu.pc, 28);
+
+        // jalr clears bit 0 and reads rs1 before writing rd
+        cpu.pc = 0x40;
+        cpu.ixu[REG_T0] = 0x101;
+        cpu.execute(encode!(jalr t0, 3(t0))).unwrap();
+        assert_eq!((cpu.next_pc, cpu.ixu[REG_T0]), (0x104, 0x44));
+        cpu.ixu[REG_T0] = 0x106;
+        cpu.execute(encode!(jalr t0, 0(t0))).unwrap();
+        assert_eq!((cpu.next_pc, cpu.ixu[REG_T0]), (0x106, 0x44));
+        // jal reaches back 1 MiB
+        cpu.execute(encode!(jal zero, -0x100000)).unwrap();
+        assert_eq!(cpu.next_pc, 0x40u64.wrapping_sub(0x100000));
+    }
+
+    #[test]
+    fn test_inst_op() {
+        let mut cpu = prelog();
+        cpu.ixu[REG_A1] = 0x8000_0000_0000_0010;
+        cpu.ixu[REG_A2] = 0x44;
+        cpu.execute(encode!(add a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_0054);
+        cpu.execute(encode!(sub a0, a2, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_0034);
+        // Shift amounts come from the low 6 bits of rs2, 0x44 shifts by 4
+        cpu.execute(encode!(sll a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0000_0000_0000_0100);
+        cpu.execute(encode!(srl a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0800_0000_0000_0001);
+        cpu.execute(encode!(sra a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xf800_0000_0000_0001);
+        cpu.execute(encode!(slt a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 1);
+        cpu.execute(encode!(sltu a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        cpu.execute(encode!(xor a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_0054);
+        cpu.execute(encode!(or a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_0054);
+        cpu.execute(encode!(and a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        // sub's funct7 with funct3 other than add and srl is reserved
+        assert_eq!(cpu.execute(encode!(sub a0, a1, a2) | 0b001 << 12), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_word() {
+        let mut cpu = prelog();
+        cpu.ixu[REG_A1] = 0x1234_5678_7fff_ffff;
+        cpu.ixu[REG_A2] = 0x21;
+        // addiw wraps at 32 bits and sign extends
+        cpu.execute(encode!(addiw a0, a1, 1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0000);
+        // sext.w
+        cpu.execute(encode!(addiw a0, a0, 0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0000);
+        cpu.execute(encode!(slliw a0, a1, 31)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0000);
+        cpu.execute(encode!(srliw a0, a0, 4)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0800_0000);
+        cpu.execute(encode!(sraiw a0, a1, 0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x7fff_ffff);
+        cpu.execute(encode!(addw a0, a1, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_ffff_fffe);
+        cpu.execute(encode!(subw a0, a2, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0022);
+        // x[rs2][4:0] is 1
+        cpu.execute(encode!(sllw a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_ffff_fffe);
+        cpu.execute(encode!(sraw a0, a0, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_ffff_ffff);
+        cpu.execute(encode!(srlw a0, a0, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x7fff_ffff);
+        // slliw with shamt[5] set is reserved
+        assert_eq!(cpu.execute(encode!(slliw a0, a1, 0) | 1 << 25), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_muldiv() {
+        let mut cpu = RiscvCpu::new(vec![0; 16]);
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (i64::MIN as u64, u64::MAX);
+        cpu.execute(encode!(mul a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], i64::MIN as u64);
+        cpu.execute(encode!(mulh a0, a2, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        cpu.execute(encode!(mulhu a0, a2, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], u64::MAX - 1);
+        cpu.execute(encode!(mulhsu a0, a2, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], u64::MAX);
+        // Signed overflow: MIN / -1 = MIN remainder 0
+        cpu.execute(encode!(div a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], i64::MIN as u64);
+        cpu.execute(encode!(rem a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        // Division by zero
+        cpu.execute(encode!(divu a0, a1, zero)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], u64::MAX);
+        cpu.execute(encode!(rem a0, a1, zero)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], i64::MIN as u64);
+        cpu.execute(encode!(divw a0, a1, zero)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], u64::MAX);
+        // The word forms only see the low 32 bits, 0 here
+        cpu.execute(encode!(remuw a0, a1, zero)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        cpu.ixu[REG_A1] = 0x8000_0000;
+        cpu.execute(encode!(divw a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0000);
+        cpu.execute(encode!(mulw a0, a1, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        // There is no mulhw
+        assert_eq!(cpu.execute(encode!(mulw a0, a1, a1) | 1 << 12), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_float() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        let boxed = |x: f32| 0xffff_ffff_0000_0000 | x.to_bits() as u64;
+        cpu.write_mem(32, 4, 1.5f32.to_bits() as u64).unwrap();
+        cpu.ixu[REG_A0] = 32;
+        cpu.execute(encode!(flw fa0, 0(a0))).unwrap();
+        cpu.execute(encode!(fadd.s fa1, fa0, fa0)).unwrap();
+        assert_eq!(cpu.fpr[11], boxed(3.0));
+        cpu.execute(encode!(fmadd.s fa2, fa1, fa1, fa0)).unwrap();
+        cpu.execute(encode!(fsw fa2, 4(a0))).unwrap();
+        assert_eq!(cpu.read_mem(36, 4), Some(10.5f32.to_bits() as u64));
+        assert_eq!(cpu.fcsr, 0);
+
+        // 10.5 converts to 10 rounding to nearest-even, 11 with frm = rup
+        cpu.execute(encode!(fcvt.w.s a1, fa2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 10);
+        assert_eq!(cpu.fcsr, softfloat::FLAG_NX);
+        cpu.fcsr = 0b011 << 5;
+        cpu.execute(encode!(fcvt.l.s a1, fa2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 11);
+        // A static rm overrides frm
+        cpu.execute(encode!(fcvt.w.s a1, fa2) & !(0b111 << 12) | 0b001 << 12).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 10);
+        // Reserved frm is illegal for dynamic rounding only
+        cpu.fcsr = 0b101 << 5;
+        assert_eq!(cpu.execute(encode!(fcvt.w.s a1, fa2)), Err(RiscvCpuError::DecodeError));
+        cpu.execute(encode!(fsgnjn.s fa3, fa2, fa2)).unwrap();
+        assert_eq!(cpu.fpr[13], boxed(-10.5));
+
+        // Words are sign extended in x registers, NaN results are canonical
+        cpu.execute(encode!(fmv.x.w a1, fa3)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], (-10.5f32).to_bits() as i32 as u64);
+        cpu.fcsr = 0;
+        cpu.ixu[REG_A2] = (-1.0f32).to_bits() as u64;
+        cpu.execute(encode!(fmv.w.x fa4, a2)).unwrap();
+        cpu.execute(encode!(fsqrt.s fa5, fa4)).unwrap();
+        assert_eq!(cpu.fpr[15], boxed(f32::from_bits(0x7fc0_0000)));
+        assert_eq!(cpu.fcsr, softfloat::FLAG_NV);
+        cpu.execute(encode!(fcvt.wu.s a1, fa5)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], u64::MAX);
+        cpu.execute(encode!(feq.s a1, fa5, fa5)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 0);
+        cpu.execute(encode!(fle.s a1, fa4, fa3)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 0);
+        cpu.execute(encode!(flt.s a1, fa3, fa4)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 1);
+        cpu.execute(encode!(fclass.s a1, fa5)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 1 << 9);
+        cpu.ixu[REG_A2] = -3i64 as u64;
+        cpu.execute(encode!(fcvt.s.w fa6, a2)).unwrap();
+        assert_eq!(cpu.fpr[16], boxed(-3.0));
+        cpu.execute(encode!(fcvt.s.wu fa6, a2)).unwrap();
+        assert_eq!(cpu.fpr[16], boxed(u32::MAX as f32));
+        // There is no fmt 11 quad here
+        assert_eq!(cpu.execute(encode!(fadd.s fa0, fa1, fa2) | 0b11 << 25), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_double() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        cpu.write_mem(32, 8, 0.1f64.to_bits()).unwrap();
+        cpu.ixu[REG_A0] = 32;
+        cpu.execute(encode!(fld fa0, 0(a0))).unwrap();
+        cpu.execute(encode!(fadd.d fa1, fa0, fa0)).unwrap();
+        assert_eq!(cpu.fpr[11], 0.2f64.to_bits());
+        cpu.execute(encode!(fsd fa1, 8(a0))).unwrap();
+        assert_eq!(cpu.read_mem(40, 8), Some(0.2f64.to_bits()));
+        assert_eq!(cpu.fcsr, 0);
+
+        // Narrowing rounds, widening is exact
+        cpu.execute(encode!(fcvt.s.d fa2, fa1)).unwrap();
+        assert_eq!(cpu.fpr[12], 0xffff_ffff_0000_0000 | 0.2f32.to_bits() as u64);
+        assert_eq!(cpu.fcsr, softfloat::FLAG_NX);
+        cpu.execute(encode!(fcvt.d.s fa3, fa2)).unwrap();
+        assert_eq!(cpu.fpr[13], (0.2f32 as f64).to_bits());
+        cpu.ixu[REG_A1] = -(1i64 << 60) as u64;
+        cpu.execute(encode!(fcvt.d.l fa4, a1)).unwrap();
+        assert_eq!(cpu.fpr[14], (-(1i64 << 60) as f64).to_bits());
+        cpu.execute(encode!(fcvt.w.d a2, fa4)).unwrap();
+        assert_eq!(cpu.ixu[REG_A2], i32::MIN as u64);
+        cpu.execute(encode!(fcvt.l.d a2, fa4)).unwrap();
+        assert_eq!(cpu.ixu[REG_A2], -(1i64 << 60) as u64);
+        cpu.execute(encode!(fmv.x.d a3, fa4)).unwrap();
+        assert_eq!(cpu.ixu[REG_A3], (-(1i64 << 60) as f64).to_bits());
+        cpu.execute(encode!(fmv.d.x fa5, a1)).unwrap();
+        assert_eq!(cpu.fpr[15], -(1i64 << 60) as u64);
+
+        // A double is not a boxed single, so reads as the canonical NaN
+        cpu.fcsr = 0;
+        cpu.execute(encode!(fadd.s fa6, fa1, fa2)).unwrap();
+        assert_eq!(cpu.fpr[16], 0xffff_ffff_7fc0_0000);
+        assert_eq!(cpu.fcsr, 0);
+        cpu.execute(encode!(fclass.s a1, fa1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 1 << 9);
+        // fmv.x.w and fsw move the low bits regardless
+        cpu.execute(encode!(fmv.x.w a1, fa1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A1], 0.2f64.to_bits() as u32 as i32 as u64);
+        cpu.execute(encode!(fsw fa1, 16(a0))).unwrap();
+        assert_eq!(cpu.read_mem(48, 4), Some(0.2f64.to_bits() & 0xffff_ffff));
+        // fcvt.d.d is not a conversion
+        assert_eq!(cpu.execute(encode!(fcvt.d.s fa0, fa1) | 1 << 20), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_half() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        // 1.5, boxed with 48 ones above it
+        cpu.write_mem(32, 2, 0x3e00).unwrap();
+        cpu.ixu[REG_A0] = 32;
+        cpu.execute(encode!(flh fa0, 0(a0))).unwrap();
+        assert_eq!(cpu.fpr[10], 0xffff_ffff_ffff_3e00);
+        cpu.execute(encode!(fmul.h fa1, fa0, fa0)).unwrap();
+        assert_eq!(cpu.fpr[11], 0xffff_ffff_ffff_4080);
+        cpu.execute(encode!(fsh fa1, 2(a0))).unwrap();
+        assert_eq!(cpu.read_mem(32, 8), Some(0x4080_3e00));
+        cpu.execute(encode!(fcvt.s.h fa2, fa1)).unwrap();
+        assert_eq!(cpu.fpr[12], 0xffff_ffff_0000_0000 | 2.25f32.to_bits() as u64);
+        assert_eq!(cpu.fcsr, 0);
+        cpu.fpr[13] = 0xffff_ffff_0000_0000 | 0.1f32.to_bits() as u64;
+        cpu.execute(encode!(fcvt.h.s fa4, fa3)).unwrap();
+        assert_eq!(cpu.fpr[14], 0xffff_ffff_ffff_2e66);
+        assert_eq!(cpu.fcsr, softfloat::FLAG_NX);
+        cpu.ixu[REG_A1] = 0xbc00;
+        cpu.execute(encode!(fmv.h.x fa5, a1)).unwrap();
+        cpu.execute(encode!(fmv.x.h a2, fa5)).unwrap();
+        assert_eq!(cpu.ixu[REG_A2], 0xffff_ffff_ffff_bc00);
+        cpu.execute(encode!(fcvt.w.h a3, fa5)).unwrap();
+        assert_eq!(cpu.ixu[REG_A3], u64::MAX);
+
+        // A boxed single is not a boxed half
+        cpu.execute(encode!(fadd.h fa6, fa2, fa2)).unwrap();
+        assert_eq!(cpu.fpr[16], 0xffff_ffff_ffff_7e00);
+    }
+
+    #[test]
+    fn test_inst_zfa() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        cpu.execute(encode!(fli.d fa0, 21)).unwrap();
+        assert_eq!(cpu.fpr[10], 2.5f64.to_bits());
+        cpu.execute(encode!(fli.h fa1, 0)).unwrap();
+        assert_eq!(cpu.fpr[11], 0xffff_ffff_ffff_bc00);
+        cpu.execute(encode!(fround.d fa2, fa0)).unwrap();
+        assert_eq!(cpu.fpr[12], 2.0f64.to_bits());
+        assert_eq!(cpu.fcsr, 0);
+        cpu.execute(encode!(froundnx.d fa2, fa0)).unwrap();
+        assert_eq!(cpu.fcsr, softfloat::FLAG_NX);
+
+        // fmaxm passes a NaN on, fltq compares it without flagging
+        cpu.fcsr = 0;
+        cpu.execute(encode!(fli.d fa3, 31)).unwrap();
+        cpu.execute(encode!(fmaxm.d fa4, fa0, fa3)).unwrap();
+        assert_eq!(cpu.fpr[14], softfloat::F64.canonical_nan());
+        cpu.execute(encode!(fminm.d fa4, fa0, fa2)).unwrap();
+        assert_eq!(cpu.fpr[14], 2.0f64.to_bits());
+        cpu.execute(encode!(fltq.d a0, fa3, fa0)).unwrap();
+        cpu.execute(encode!(fleq.d a1, fa2, fa0)).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.ixu[REG_A1], cpu.fcsr), (0, 1, 0));
+
+        // 2^32 + 2^31 + 3 wraps to i32::MIN + 3
+        cpu.fpr[15] = 6442450947.0f64.to_bits();
+        cpu.execute(encode!(fcvtmod.w.d a2, fa5)).unwrap();
+        assert_eq!(cpu.ixu[REG_A2], (i32::MIN + 3) as u64);
+        assert_eq!(cpu.fcsr, softfloat::FLAG_NV);
+    }
+
+    #[test]
+    fn test_inst_zfinx() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        cpu.isa = Isa::parse("rv64imac_zfinx").unwrap();
+        // Singles sign-extend into x registers, nothing is NaN-boxed. The f
+        // register fields name the x register with the same number.
+        cpu.ixu[REG_A1] = 1.5f32.to_bits() as u64;
+        cpu.ixu[REG_A2] = 0x1234_5678 << 32 | (-2.0f32).to_bits() as u64;
+        cpu.execute(encode!(fmul.s fa0, fa1, fa2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_0000_0000 | (-3.0f32).to_bits() as u64);
+        cpu.execute(encode!(fcvt.w.s a3, fa0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A3], -3i64 as u64);
+        cpu.execute(encode!(fadd.s ft0, fa1, fa1)).unwrap();
+        assert_eq!(cpu.ixu[REG_ZERO], 0);
+        assert_eq!(cpu.fpr, [0; 32]);
+
+        // No f registers to load, store or move, and no D without Zdinx
+        cpu.ixu[REG_A4] = 32;
+        assert_eq!(cpu.execute(encode!(flw fa0, 0(a4))), Err(RiscvCpuError::DecodeError));
+        assert_eq!(cpu.execute(encode!(fmv.x.w a0, fa1)), Err(RiscvCpuError::DecodeError));
+        assert_eq!(cpu.execute(encode!(fcvt.d.s fa0, fa1)), Err(RiscvCpuError::DecodeError));
+        cpu.isa = Isa::parse("rv64imac_zdinx").unwrap();
+        cpu.execute(encode!(fcvt.d.s fa0, fa1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 1.5f64.to_bits());
+        assert_eq!(cpu.csr_read(csr::CSR_MISA).unwrap() & 0b101000, 0);
+    }
+
+    #[test]
+    fn test_inst_csr() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        // The old value goes to rd even when rd is rs1
+        cpu.ixu[REG_A0] = 0x55;
+        cpu.execute(encode!(csrrw a0, mscratch, a0)).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.csr_read(csr::CSR_MSCRATCH)), (0, Some(0x55)));
+        cpu.ixu[REG_A1] = 0x0f;
+        cpu.execute(encode!(csrrc a0, mscratch, a1)).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.csr_read(csr::CSR_MSCRATCH)), (0x55, Some(0x50)));
+        cpu.execute(encode!(csrrsi a0, mscratch, 3)).unwrap();
+        assert_eq!(cpu.csr_read(csr::CSR_MSCRATCH), Some(0x53));
+
+        // fflags, frm and fcsr are views of the one register
+        cpu.execute(encode!(csrrwi zero, frm, 3)).unwrap();
+        cpu.execute(encode!(csrrsi zero, fflags, 0b10001)).unwrap();
+        assert_eq!(cpu.fcsr, 0b011_10001);
+        cpu.execute(encode!(csrrs a0, fcsr, zero)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0b011_10001);
+        cpu.execute(encode!(csrrci a0, fflags, 1)).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.fcsr), (0b10001, 0b011_10000));
+
+        // Read-only CSRs read with rs1 x0, a write to one is illegal even
+        // when it wouldn't change anything, as is a CSR that doesn't exist
+        cpu.instret = 7;
+        cpu.execute(encode!(csrrs a0, instret, zero)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 7);
+        assert_eq!(cpu.execute(encode!(csrrs a0, instret, a1)), Err(RiscvCpuError::DecodeError));
+        assert_eq!(cpu.execute(encode!(csrrw zero, mhartid, zero)), Err(RiscvCpuError::DecodeError));
+        assert_eq!(cpu.execute(0x7c002573), Err(RiscvCpuError::DecodeError));
+        // funct3 100 is reserved
+        assert_eq!(cpu.execute(encode!(csrrs a0, instret, zero) & !(0b111 << 12) | 0b100 << 12), Err(RiscvCpuError::DecodeError));
+
+        // The instruction after a minstret write reads the written value
+        let mut cpu = ProgramBuilder::new()
+            .insts(&[encode!(csrrwi zero, minstret, 20), encode!(csrrs a0, minstret, zero)])
+            .cpu();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.instret), (20, 21));
+    }
+
+    #[test]
+    fn test_inst_atomic() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        cpu.write_mem(32, 8, 0x1_8000_0000).unwrap();
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (32, 5);
+        // lr/sc pair, the reservation is gone after the sc
+        cpu.execute(encode!(lr.w a0, (a1))).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0000);
+        cpu.execute(encode!(sc.w a0, a2, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.read_mem(32, 8)), (0, Some(0x1_0000_0005)));
+        cpu.execute(encode!(sc.w a0, a1, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.read_mem(32, 4)), (1, Some(5)));
+        // A store into the reserved bytes drops the reservation, one next to them doesn't
+        cpu.execute(encode!(lr.d a0, (a1))).unwrap();
+        cpu.execute(encode!(sw a2, 40(zero))).unwrap();
+        cpu.execute(encode!(sc.w a0, a2, (a1))).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        cpu.execute(encode!(lr.d a0, (a1))).unwrap();
+        cpu.execute(encode!(sb a2, 39(zero))).unwrap();
+        cpu.execute(encode!(sc.d a0, a2, (a1))).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 1);
+        // An sc at the top of the address space fails rather than wrapping
+        cpu.execute(encode!(lr.d a0, (zero))).unwrap();
+        cpu.ixu[REG_A4] = (-8i64) as u64;
+        cpu.execute(encode!(sc.d a0, a2, (a4))).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 1);
+
+        // amo*.w operate on sign extended words and return the old value
+        cpu.write_mem(32, 8, 0x1_ffff_ffff).unwrap();
+        cpu.execute(encode!(amoadd.w a0, a2, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.read_mem(32, 8)), (u64::MAX, Some(0x1_0000_0004)));
+        cpu.execute(encode!(amomin.w a0, a1, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.read_mem(32, 4)), (4, Some(4)));
+        cpu.ixu[REG_A3] = u64::MAX;
+        cpu.execute(encode!(amomaxu.w a0, a3, (a1))).unwrap();
+        assert_eq!(cpu.read_mem(32, 8), Some(0x1_ffff_ffff));
+        cpu.execute(encode!(amomin.d a0, a3, (a1))).unwrap();
+        assert_eq!(cpu.read_mem(32, 8), Some(u64::MAX));
+        // rd == rs2 swaps
+        cpu.execute(encode!(amoswap.d a2, a2, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A2], cpu.read_mem(32, 8)), (u64::MAX, Some(5)));
+
+        cpu.ixu[REG_A1] = 36;
+        assert_eq!(
+            cpu.execute(encode!(amoxor.d a0, a2, (a1))),
+            Err(RiscvCpuError::Exception(RiscvException::StoreAmoAddressMisaligned))
+        );
+        assert_eq!(
+            cpu.execute(encode!(lr.d a0, (a1))),
+            Err(RiscvCpuError::Exception(RiscvException::LoadAddressMisaligned))
+        );
+        cpu.ixu[REG_A1] = 64;
+        assert_eq!(cpu.execute(encode!(amoor.w a0, a2, (a1))), Err(RiscvCpuError::AccessFault));
+    }
+
+    #[test]
+    fn test_inst_zacas() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        cpu.write_mem(32, 8, 0x1_8000_0000).unwrap();
+        (cpu.ixu[REG_A0], cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0xffff_ffff_8000_0000, 32, 5);
+        // Words compare sign extended, rd gets the old value either way
+        cpu.execute(encode!(amocas.w a0, a2, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.read_mem(32, 8)), (0xffff_ffff_8000_0000, Some(0x1_0000_0005)));
+        cpu.execute(encode!(amocas.w a0, a2, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.read_mem(32, 8)), (5, Some(0x1_0000_0005)));
+        cpu.execute(encode!(amocas.d a0, a2, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A0], cpu.read_mem(32, 8)), (0x1_0000_0005, Some(0x1_0000_0005)));
+        cpu.execute(encode!(amocas.d a0, zero, (a1))).unwrap();
+        assert_eq!(cpu.read_mem(32, 8), Some(0));
+
+        // The q form swaps 16 bytes from an even-odd pair, x0 pairs are zero
+        cpu.write_mem(40, 8, 7).unwrap();
+        (cpu.ixu[REG_A4], cpu.ixu[REG_A5]) = (0xaaaa, 0xbbbb);
+        cpu.execute(encode!(amocas.q a2, a4, (a1))).unwrap();
+        assert_eq!((cpu.ixu[REG_A2], cpu.ixu[REG_A3]), (0, 7));
+        assert_eq!((cpu.read_mem(32, 8), cpu.read_mem(40, 8)), (Some(0), Some(7)));
+        cpu.execute(encode!(amocas.q a2, a4, (a1))).unwrap();
+        assert_eq!((cpu.read_mem(32, 8), cpu.read_mem(40, 8)), (Some(0xaaaa), Some(0xbbbb)));
+        cpu.execute(encode!(amocas.q zero, zero, (a1))).unwrap();
+        assert_eq!(cpu.read_mem(40, 8), Some(0xbbbb));
+        assert_eq!(cpu.execute(encode!(amocas.q a1, a4, (a1))), Err(RiscvCpuError::DecodeError));
+        cpu.ixu[REG_A1] = 40;
+        assert_eq!(
+            cpu.execute(encode!(amocas.q a2, a4, (a1))),
+            Err(RiscvCpuError::Exception(RiscvException::StoreAmoAddressMisaligned))
+        );
+        // Only amocas has a q form
+        assert_eq!(cpu.execute(encode!(amoadd.d a0, a2, (a1)) ^ 0b111 << 12), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_compressed() {
+        // c.li a0,5 then an all-zero parcel
+        let mut cpu = RiscvCpu::new(vec![0x15, 0x45, 0, 0]);
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.ixu[REG_A0]), (RESET_VECTOR + 2, 5));
+        // The all-zero parcel is reserved
+        assert_eq!(cpu.step(), Err(RiscvCpuError::DecodeError));
+
+        // jal ra,6 ; c.j 6 ; c.slli a0,1 ; c.jr ra ; addi a1,a0,0
+        let mut code = ProgramBuilder::new().inst(encode!(jal ra, 6)).build();
+        code.extend([0xa019u16, 0x0506, 0x8082].iter().flat_map(|p| p.to_le_bytes()));
+        code.extend(encode!(addi a1, a0, 0).to_le_bytes());
+        let mut cpu = RiscvCpu::new(code);
+        cpu.ixu[REG_A0] = 5;
+        cpu.pipe = Some(Pipeline::new(true));
+        while cpu.fetch().is_ok() {
+            cpu.step().unwrap();
+        }
+        // jal, c.slli, c.jr back to pc 4, c.j over both to the addi
+        assert_eq!(cpu.ixu[REG_A1], 10);
+        assert_eq!(cpu.ixu[REG_RA], 4);
+        assert_eq!((cpu.pc, cpu.instret), (14, 5));
+        // Only the jal, c.jr and c.j redirect the fetch
+        assert_eq!(cpu.pipe.as_ref().unwrap().flushes, 3);
+
+        // c.jalr links pc + 2
+        cpu.pc = 0x20;
+        cpu.ixu[REG_A0] = 0x40;
+        cpu.execute(0x9502).unwrap();
+        assert_eq!((cpu.next_pc, cpu.ixu[REG_RA]), (0x40, 0x22));
+    }
+
+    #[test]
+    fn test_inst_fence() {
+        let mut cpu = ProgramBuilder::new().insts(&[encode!(fence), encode!(fence.i)]).cpu();
+        cpu.icache = Some(Cache::new(CacheConfig::parse("1k:2:16:5").unwrap()));
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, RESET_VECTOR + 4);
+        assert_eq!(cpu.icache.as_ref().unwrap().misses, 1);
+        // FENCE.I drops the line it was fetched from
+        cpu.step().unwrap();
+        cpu.mem_access(RiscvAccessType::Fetch, RESET_VECTOR, 4).unwrap();
+        assert_eq!(cpu.icache.as_ref().unwrap().misses, 2);
+        // funct3 011 is reserved
+        assert_eq!(cpu.execute(0x0000300f), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_cbo() {
+        let mut cpu = ProgramBuilder::new()
+            .insts(&[encode!(cbo.zero (a0)), encode!(cbo.clean (a0)), encode!(cbo.flush (a0)), encode!(cbo.inval (a0))])
+            .cpu();
+        cpu.dcache = Some(Cache::new(CacheConfig::parse("1k:2:64:5").unwrap()));
+        cpu.mem.resize(0x100, 0);
+        cpu.mem.resize(0x180, 0xaa);
+        // Anywhere in the block zeroes all of it
+        cpu.ixu[REG_A0] = 0x13f;
+        cpu.step().unwrap();
+        assert!(cpu.mem[0x100..0x140].iter().all(|&b| b == 0));
+        assert_eq!(cpu.mem[0xff], 0);
+        assert_eq!(cpu.mem[0x140], 0xaa);
+        // clean keeps the line, flush drops it and inval finds it gone
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.mem_access(RiscvAccessType::Load, 0x100, 8).unwrap();
+        assert_eq!(cpu.dcache.as_ref().unwrap().misses, 2);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, RESET_VECTOR + 16);
+        // The unallocated imm[11:0] values are reserved
+        assert_eq!(cpu.execute(encode!(cbo.zero (a0)) | 0b011 << 20), Err(RiscvCpuError::DecodeError));
+        cpu.ixu[REG_A0] = 1 << 40;
+        assert_eq!(cpu.execute(encode!(cbo.zero (a0))), Err(RiscvCpuError::AccessFault));
+        // The last block of the address space flushes without wrapping
+        cpu.ixu[REG_A0] = u64::MAX;
+        cpu.execute(encode!(cbo.flush (a0))).unwrap();
+    }
+
+    #[test]
+    fn test_inst_system() {
+        let mut cpu = ProgramBuilder::new()
+            .insts(&[encode!(addi a0, zero, 3), encode!(ebreak), encode!(addi a7, zero, 93), encode!(ecall)])
+            .cpu();
+        cpu.step().unwrap();
+        // The breakpoint doesn't retire and leaves the pc on the ebreak
+        assert_eq!(cpu.step(), Err(RiscvCpuError::Exception(RiscvException::Breakpoint)));
+        assert_eq!((cpu.pc, cpu.instret), (RESET_VECTOR + 4, 1));
+        cpu.pc += 4;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.exit_code, Some(3));
+        // rd, rs1 and funct3 must be zero
+        assert_eq!(cpu.execute(INST_ECALL | 1 << 7), Err(RiscvCpuError::DecodeError));
+        assert_eq!(cpu.execute(INST_EBREAK | 1 << 15), Err(RiscvCpuError::DecodeError));
+        assert_eq!(cpu.execute(0x00200073), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_zawrs() {
+        let mut cpu = ProgramBuilder::new()
+            .insts(&[encode!(wrs.nto), encode!(lr.w a0, (a1)), encode!(wrs.sto), encode!(sw a0, 0(a1)), encode!(wrs.nto)])
+            .cpu();
+        cpu.ixu[REG_A1] = 16;
+        // Only a held reservation makes the hart idle, and the wait times out
+        cpu.step().unwrap();
+        assert!(!cpu.idle);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.idle);
+        assert_eq!(cpu.pc, RESET_VECTOR + 12);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(!cpu.idle);
+        assert_eq!(cpu.execute(INST_WRS_NTO | 1 << 15), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_inst_hints() {
+        let mut cpu = ProgramBuilder::new()
+            .insts(&[encode!(pause), encode!(prefetch.w 64(a0)), encode!(addi zero, a0, 5), encode!(addi zero, zero, 0), encode!(pause)])
+            .cpu();
+        cpu.ixu[REG_A0] = 0x1000;
+        cpu.step().unwrap();
+        assert!(!cpu.idle);
+        cpu.pause_yield = true;
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.idle);
+        assert_eq!(cpu.ixu[REG_ZERO], 0);
+        let counts: Vec<_> = cpu.hint_counts.into_iter().collect();
+        assert_eq!(counts, [("hint", 1), ("pause", 2), ("prefetch.w", 1)]);
+        assert_eq!(hint(0x00200033), Some("ntl.p1"));
+        assert_eq!(hint(0x0ff0000f), None);
+        assert_eq!(hint(0x0f00000f), Some("hint"));
+    }
+
+    #[test]
+    fn test_signature() {
+        let cpu = prelog();
+        let path = env::temp_dir().join("rvlator_test_signature");
+        let path = path.to_str().unwrap();
+        cpu.dump_signature(path, 0, 8).unwrap();
+        // addi a0,zero,-4 (ffc00513) ; addi a1,zero,-5 (ffb00593)
+        assert_eq!(fs::read_to_string(path).unwrap(), "ffc00513\nffb00593\n");
+        assert!(cpu.dump_signature(path, 0, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_ecall_exit() {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 3))
+            .inst(encode!(addi a7, zero, 93))
+            .inst(encode!(ecall))
+            .cpu();
+        cpu.fusion = false;
+        while cpu.exit_code.is_none() {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.exit_code, Some(3));
+        assert_eq!(cpu.instret, 3);
+
+        // Anything but exit is left for traps
+        let mut cpu = ProgramBuilder::new().inst(encode!(ecall)).cpu();
+        assert_eq!(cpu.step(), Err(RiscvCpuError::ExecuteError));
+    }
+
+    #[test]
+    fn test_tohost_exit() {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 1))
+            .inst(encode!(addi a1, zero, 2))
+            // tohost
+            .insts(&[0, 0])
+            .cpu();
+        cpu.tohost = Some(8);
+        cpu.step().unwrap();
+        assert_eq!(cpu.exit_code, None);
+        // (5 << 1) | 1, as the guest would store it
+        cpu.mem[8] = 0xb;
+        cpu.step().unwrap();
+        assert_eq!(cpu.exit_code, Some(5));
+    }
+
+    #[test]
+    fn test_mem_base() {
+        let mut cpu = prelog();
+        cpu.mem_base = 0x80000000;
+        cpu.pc = 0x80000000;
+        assert_eq!(cpu.fetch(), Ok(0xffc00513));
+        assert_eq!(cpu.read_mem(0x80000004, 4), Some(0xffb00593));
+        assert_eq!(cpu.read_mem(0x0, 4), None);
+        assert_eq!(cpu.read_mem(0x7ffffffe, 4), None);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x80000004);
+        assert_eq!(cpu.ixu[REG_A0], 0xfffffffffffffffc);
+    }
+
+    // Programs in test/golden/*.hex (one instruction word per line, `#`
+    // comments) are run and their commit log compared with the matching
+    // .trace file. Run with RVLATOR_BLESS=1 to regenerate the traces after
+    // an intended behavior change.
+    #[test]
+    fn test_golden_traces() {
+        let bless = env::var_os("RVLATOR_BLESS").is_some();
+        let mut programs: Vec<_> = fs::read_dir("test/golden")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "hex"))
+            .collect();
+        programs.sort();
+        assert!(!programs.is_empty());
+
+        for hex in programs {
+            let mut code: Vec<u8> = Vec::new();
+            for line in fs::read_to_string(&hex).unwrap().lines() {
+                let word = line.split('#').next().unwrap().trim();
+                if !word.is_empty() {
+                    code.extend(u32::from_str_radix(word, 16).unwrap().to_le_bytes());
+                }
+            }
+
+            let tracepath = hex.with_extension("trace");
+            let logpath = env::temp_dir().join(hex.with_extension("log").file_name().unwrap());
+            let mut cpu = RiscvCpu::new(code);
+            cpu.commit_log = Some(Box::new(fs::File::create(&logpath).unwrap()));
+            while cpu.fetch().is_ok() {
+                cpu.step().unwrap();
+            }
+            drop(cpu);
+
+            let trace = fs::read_to_string(&logpath).unwrap();
+            if bless {
+                fs::write(&tracepath, &trace).unwrap();
+            }
+            assert_eq!(
+                trace,
+                fs::read_to_string(&tracepath).unwrap(),
+                "trace mismatch for {}",
+                hex.display()
+            );
+        }
+    }
+
+    #[test]
+    fn test_fuse_lui_addi() {
+        let mut cpu = prelog();
+        // lui a0, 0x12345 (12345537) ; addi a0, a0, -1 (fff50513)
+        let op = fuse(0x12345537, 0xfff50513).unwrap();
+        let first = cpu.execute_fused(op);
+        assert_eq!(cpu.ixu[REG_A0], 0x0000000012344fff);
+        assert_eq!(first[REG_A0], 0x0000000012345000);
+    }
+
+    #[test]
+    fn test_fuse_auipc_addi() {
+        let mut cpu = prelog();
+        cpu.pc = 8;
+        // auipc a1, 0x1 (00001597) ; addi a1, a1, 16 (01058593)
+        let op = fuse(0x00001597, 0x01058593).unwrap();
+        cpu.execute_fused(op);
+        assert_eq!(cpu.ixu[REG_A1], 0x0000000000001018);
+    }
+
+    #[test]
+    fn test_fuse_slli_srli() {
+        let mut cpu = prelog();
+        // addi a0, zero, -4  (ffc00513)
+        cpu.execute(0xffc00513).unwrap();
+        // slli a2, a0, 32 (02051613) ; srli a2, a2, 32 (02065613)
+        let op = fuse(0x02051613, 0x02065613).unwrap();
+        cpu.execute_fused(op);
+        assert_eq!(cpu.ixu[REG_A2], 0x00000000fffffffc);
+    }
+
+    #[test]
+    fn test_fuse_mismatch() {
+        // slli a2, a0, 32 (02051613) ; srli a3, a2, 32 (02065693)
+        assert_eq!(None, fuse(0x02051613, 0x02065693));
+        // addi a0,zero,-4 (ffc00513) ; addi a1,zero,-5 (ffb00593)
+        assert_eq!(None, fuse(0xffc00513, 0xffb00593));
+    }
 }
@@ -0,0 +1,315 @@
+// Static instruction set usage analysis.
+//
+// Decodes the executable sections of an ELF (or a whole flat binary) and
+// reports which extensions and instructions it uses, flagging the ones
+// this build of rvlator can't execute yet.
+
+use super::*;
+use std::collections::BTreeMap;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 0xf3;
+const SHF_EXECINSTR: u64 = 0x4;
+
+fn le(bytes: &[u8], off: usize, size: usize) -> Option<u64> {
+    let field = bytes.get(off..off + size)?;
+    Some(field.iter().rev().fold(0, |value, &b| value << 8 | b as u64))
+}
+
+/// The executable sections as (address, contents). A file without the ELF
+/// magic is a flat binary, all code, loaded at 0.
+pub(super) fn text_sections(file: &[u8]) -> Result<Vec<(u64, &[u8])>, String> {
+    if !file.starts_with(ELF_MAGIC) {
+        return Ok(vec![(0, file)]);
+    }
+    if file.get(4) != Some(&ELFCLASS64) || file.get(5) != Some(&ELFDATA2LSB) {
+        return Err(String::from("only little-endian ELF64 is supported"));
+    }
+    let bad = || String::from("truncated ELF header");
+    if le(file, 0x12, 2).ok_or_else(bad)? != EM_RISCV as u64 {
+        return Err(String::from("not a RISC-V ELF"));
+    }
+    let shoff = le(file, 0x28, 8).ok_or_else(bad)? as usize;
+    let shentsize = le(file, 0x3a, 2).ok_or_else(bad)? as usize;
+    let shnum = le(file, 0x3c, 2).ok_or_else(bad)? as usize;
+
+    let mut sections = Vec::new();
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        let bad = || format!("truncated section header {}", i);
+        let flags = le(file, sh + 0x08, 8).ok_or_else(bad)?;
+        if flags & SHF_EXECINSTR == 0 {
+            continue;
+        }
+        let addr = le(file, sh + 0x10, 8).ok_or_else(bad)?;
+        let offset = le(file, sh + 0x18, 8).ok_or_else(bad)? as usize;
+        let size = le(file, sh + 0x20, 8).ok_or_else(bad)? as usize;
+        let contents = file.get(offset..offset + size).ok_or(format!("section {} is past the end of the file", i))?;
+        sections.push((addr, contents));
+    }
+    Ok(sections)
+}
+
+/// Extension and mnemonic of a 32-bit instruction, None if it isn't a
+/// valid encoding of any extension known here
+pub(super) fn classify(inst: u32) -> Option<(&'static str, &'static str)> {
+    let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS) as usize;
+    let funct7 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+    let pick = |names: [&'static str; 8]| match names[funct3] {
+        "" => None,
+        name => Some(name),
+    };
+    let ext = |ext: &'static str, name: Option<&'static str>| name.map(|name| (ext, name));
+
+    if let Some(op) = bitmanip::decode(inst) {
+        return Some(op.names());
+    }
+    if let Some(op) = crypto::decode(inst) {
+        return Some(op.names());
+    }
+    if let Some(op) = shadowstack::decode(inst) {
+        return Some(op.names());
+    }
+    match hint(inst) {
+        Some(name @ "pause") => return Some(("Zihintpause", name)),
+        Some(name) if name.starts_with("ntl.") => return Some(("Zihintntl", name)),
+        Some(name) if name.starts_with("prefetch.") => return Some(("Zicbop", name)),
+        _ => {}
+    }
+    match opcode {
+        OPCODE_LUI => Some(("I", "lui")),
+        OPCODE_AUIPC => Some(("I", "auipc")),
+        OPCODE_JAL => Some(("I", "jal")),
+        OPCODE_JALR if funct3 == 0 => Some(("I", "jalr")),
+        OPCODE_BRANCH => ext("I", pick(["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"])),
+        OPCODE_LOAD => ext("I", pick(["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu", ""])),
+        OPCODE_STORE => ext("I", pick(["sb", "sh", "sw", "sd", "", "", "", ""])),
+        OPCODE_OP_IMM => match (funct3, funct7 >> 1) {
+            (1, 0) => Some(("I", "slli")),
+            (5, 0) => Some(("I", "srli")),
+            (5, 0b010000) => Some(("I", "srai")),
+            (1 | 5, _) => None,
+            _ => ext("I", pick(["addi", "", "slti", "sltiu", "xori", "", "ori", "andi"])),
+        },
+        OPCODE_OP_IMM_32 => match (funct3, funct7) {
+            (0, _) => Some(("I", "addiw")),
+            (1, 0) => Some(("I", "slliw")),
+            (5, 0) => Some(("I", "srliw")),
+            (5, 0b0100000) => Some(("I", "sraiw")),
+            _ => None,
+        },
+        OPCODE_OP => match funct7 {
+            0b0000000 => ext("I", pick(["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"])),
+            0b0100000 => ext("I", pick(["sub", "", "", "", "", "sra", "", ""])),
+            0b0000001 => ext("M", pick(["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"])),
+            _ => Some(("B", "op")),
+        },
+        OPCODE_OP_32 => match funct7 {
+            0b0000000 => ext("I", pick(["addw", "sllw", "", "", "", "srlw", "", ""])),
+            0b0100000 => ext("I", pick(["subw", "", "", "", "", "sraw", "", ""])),
+            0b0000001 => ext("M", pick(["mulw", "", "", "", "divw", "divuw", "remw", "remuw"])),
+            _ => Some(("B", "op-32")),
+        },
+        OPCODE_MISC_MEM => match funct3 {
+            0 => Some(("I", "fence")),
+            1 => Some(("Zifencei", "fence.i")),
+            2 => match inst >> 20 {
+                0 => Some(("Zicbom", "cbo.inval")),
+                1 => Some(("Zicbom", "cbo.clean")),
+                2 => Some(("Zicbom", "cbo.flush")),
+                4 => Some(("Zicboz", "cbo.zero")),
+                _ => None,
+            },
+            _ => None,
+        },
+        OPCODE_SYSTEM => match (funct3, inst) {
+            (0, 0x00000073) => Some(("I", "ecall")),
+            (0, 0x00100073) => Some(("I", "ebreak")),
+            (0, INST_WRS_NTO) => Some(("Zawrs", "wrs.nto")),
+            (0, INST_WRS_STO) => Some(("Zawrs", "wrs.sto")),
+            (0, 0x30200073) => Some(("Priv", "mret")),
+            (0, 0x10200073) => Some(("Priv", "sret")),
+            (0, 0x10500073) => Some(("Priv", "wfi")),
+            (0, _) => Some(("Priv", "system")),
+            _ => ext("Zicsr", pick(["", "csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci"])),
+        },
+        OPCODE_AMO => {
+            let width = match funct3 {
+                2 => ".w",
+                3 => ".d",
+                4 if funct7 >> 2 == 0b00101 => ".q",
+                _ => return None,
+            };
+            let name = match (funct7 >> 2, width) {
+                (0b00010, ".w") => "lr.w",
+                (0b00010, _) => "lr.d",
+                (0b00011, ".w") => "sc.w",
+                (0b00011, _) => "sc.d",
+                (0b00101, _) => return Some(("Zacas", "amocas")),
+                (0b00000 | 0b00001 | 0b00100 | 0b01000 | 0b01100 | 0b10000 | 0b10100 | 0b11000 | 0b11100, _) => "amo",
+                _ => return None,
+            };
+            Some(("A", name))
+        }
+        OPCODE_LOAD_FP | OPCODE_STORE_FP => {
+            let name = if opcode == OPCODE_LOAD_FP { "load" } else { "store" };
+            match funct3 {
+                1 => Some(("Zfh", name)),
+                2 => Some(("F", name)),
+                3 => Some(("D", name)),
+                4 => Some(("Q", name)),
+                _ => Some(("V", name)),
+            }
+        }
+        OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD | OPCODE_OP_FP => {
+            let name = if opcode == OPCODE_OP_FP { "op-fp" } else { "fma" };
+            if fpu::decode(inst).is_some_and(|fp| fp.op.zfa()) {
+                return Some(("Zfa", name));
+            }
+            // fmt in inst[26:25]
+            match funct7 & 0b11 {
+                0 => Some(("F", name)),
+                1 => Some(("D", name)),
+                2 => Some(("Zfh", name)),
+                _ => Some(("Q", name)),
+            }
+        }
+        OPCODE_OP_V => Some(("V", "op-v")),
+        OPCODE_CUSTOM0 => xthead::disasm(inst).map(|_| ("XThead", "custom-0")),
+        OPCODE_OP_P => packed::decode(inst).map(|op| ("P", packed::mnemonic(op.0))),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Usage {
+    // (extension, mnemonic) -> (count, first address)
+    pub insts: BTreeMap<(&'static str, &'static str), (u64, u64)>,
+    // First address of each instruction this build can't execute
+    pub unsupported: BTreeMap<(&'static str, &'static str), u64>,
+    // Words that don't decode at all, usually data in a text section
+    pub unknown: u64,
+}
+
+/// Walk the sections 16-bit parcel by parcel, as the fetch would
+pub(super) fn analyze(sections: &[(u64, &[u8])]) -> Usage {
+    let mut usage = Usage::default();
+    for &(base, code) in sections {
+        let mut off = 0;
+        while off + 2 <= code.len() {
+            let addr = base + off as u64;
+            let parcel = le(code, off, 2).unwrap() as u32;
+            let (key, inst) = match inst_len(parcel) {
+                2 => (compressed::expand(parcel).map(|(name, _)| ("C", name)), parcel),
+                _ => {
+                    let Some(inst) = le(code, off, 4) else { break };
+                    (classify(inst as u32), inst as u32)
+                }
+            };
+            // A disassembly means the cpu decodes it, compressed ones
+            // included: c.fld needs fld to be there too
+            if let Some(key) = key {
+                if disasm(inst).starts_with('.') {
+                    usage.unsupported.entry(key).or_insert(addr);
+                }
+            }
+            match key {
+                Some(key) => {
+                    let entry = usage.insts.entry(key).or_insert((0, addr));
+                    entry.0 += 1;
+                }
+                None => usage.unknown += 1,
+            }
+            off += inst_len(inst) as usize;
+        }
+    }
+    usage
+}
+
+impl Usage {
+    pub(super) fn print(&self) {
+        let mut ext = "";
+        for (&(e, name), &(count, _)) in &self.insts {
+            if e != ext {
+                ext = e;
+                println!("{COLOR_AQUA}[{}]{COLOR_RESET}", ext);
+            }
+            println!("    {:<12} {}", name, count);
+        }
+        if self.unknown > 0 {
+            println!("{} words don't decode as instructions", self.unknown);
+        }
+        for (&(e, name), addr) in &self.unsupported {
+            println!("{COLOR_RED}unsupported: {} ({}), first at {:#018x}{COLOR_RESET}", name, e, addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(0xffc00513), Some(("I", "addi")));
+        // ld a0,0(sp) ; mul a0,a1,a2 ; amoadd.w a0,a1,(a2) ; csrr a0,mcycle
+        assert_eq!(classify(0x00013503), Some(("I", "ld")));
+        assert_eq!(classify(0x02c58533), Some(("M", "mul")));
+        assert_eq!(classify(0x00b6252f), Some(("A", "amo")));
+        assert_eq!(classify(0xb0002573), Some(("Zicsr", "csrrs")));
+        // fadd.d fa0,fa1,fa2
+        assert_eq!(classify(0x02c58553), Some(("D", "op-fp")));
+        assert_eq!(classify(0xf0140553), Some(("Zfa", "op-fp")));
+        assert_eq!(classify(0x0100000f), Some(("Zihintpause", "pause")));
+        assert_eq!(classify(0xce104073), Some(("Zicfiss", "sspush")));
+        // sh1add a0,a1,a2
+        assert_eq!(classify(0x20c5a533), Some(("Zba", "sh1add")));
+        assert_eq!(classify(0x3005_9513), Some(("Zknd", "aes64im")));
+        assert_eq!(classify(0x00000000), None);
+    }
+
+    #[test]
+    fn test_analyze() {
+        // addi a0,zero,-4 ; c.nop ; vadd.vv v1,v2,v3 ; ld a0,0(sp) ; c.fldsp ft0,0(sp) ; flq ft0,0(sp)
+        let code = [
+            0x13, 0x05, 0xc0, 0xff, 0x01, 0x00, 0xd7, 0x80, 0x21, 0x02, 0x03, 0x35, 0x01, 0x00, 0x02, 0x20, 0x07, 0x40, 0x01, 0x00,
+        ];
+        let usage = analyze(&text_sections(&code).unwrap());
+        assert_eq!(usage.insts[&("I", "addi")], (1, 0));
+        assert_eq!(usage.insts[&("C", "c.nop")], (1, 4));
+        assert_eq!(usage.insts[&("V", "op-v")], (1, 6));
+        assert_eq!(usage.insts[&("I", "ld")], (1, 10));
+        assert_eq!(usage.insts[&("C", "c.fldsp")], (1, 14));
+        assert_eq!(usage.unsupported.len(), 2);
+        assert_eq!(usage.unsupported[&("V", "op-v")], 6);
+        assert_eq!(usage.unsupported[&("Q", "load")], 16);
+        assert!(!usage.unsupported.contains_key(&("C", "c.fldsp")));
+        assert!(!usage.unsupported.contains_key(&("I", "addi")));
+        assert!(!usage.unsupported.contains_key(&("I", "ld")));
+    }
+
+    #[test]
+    fn test_text_sections() {
+        // ELF64 header plus one executable section header
+        let mut elf = vec![0; 0x40 + 0x40];
+        elf[..4].copy_from_slice(ELF_MAGIC);
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+        elf[0x12] = 0xf3;
+        elf[0x28] = 0x40; // e_shoff
+        elf[0x3a] = 0x40; // e_shentsize
+        elf[0x3c] = 1; // e_shnum
+        elf[0x48] = SHF_EXECINSTR as u8;
+        elf[0x50..0x58].copy_from_slice(&0x80000000u64.to_le_bytes());
+        elf[0x58] = 0x10; // sh_offset
+        elf[0x60] = 4; // sh_size
+        let sections = text_sections(&elf).unwrap();
+        assert_eq!(sections, vec![(0x80000000, &elf[0x10..0x14])]);
+
+        elf[0x12] = 0x3e;
+        assert!(text_sections(&elf).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+// Assertions on guest state.
+//
+//     a0==0               at exit
+//     mem[0x1000]!=0      32-bit word at exit (mem8/mem16/mem32/mem64 for other widths)
+//     s1==0xdead@0x40     when the pc reaches 0x40, before it executes
+//
+// A failing assertion makes the run exit with a nonzero status, so CI can
+// use rvlator as a checker without any guest side support.
+
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Operand {
+    Reg(usize),
+    Pc,
+    // Address and access size in bytes
+    Mem(u64, u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Assertion {
+    pub text: String,
+    pub lhs: Operand,
+    pub equal: bool,
+    pub value: u64,
+    // Check when the pc reaches this address instead of at exit
+    pub at: Option<u64>,
+}
+
+impl Operand {
+    pub(super) fn parse(s: &str) -> Option<Operand> {
+        let s = s.trim();
+        if s == "pc" {
+            return Some(Operand::Pc);
+        }
+        if let Some(mem) = s.strip_suffix(']') {
+            let (width, addr) = mem.split_once('[')?;
+            let size = match width {
+                "mem8" => 1,
+                "mem16" => 2,
+                "mem" | "mem32" => 4,
+                "mem64" => 8,
+                _ => return None,
+            };
+            return Some(Operand::Mem(parse_addr(addr.trim())?, size));
+        }
+        regnum(s).map(Operand::Reg)
+    }
+
+    /// Current value, None if a memory operand is out of range
+    pub(super) fn value(&self, cpu: &RiscvCpu) -> Option<u64> {
+        match *self {
+            Operand::Reg(reg) => Some(cpu.ixu[reg]),
+            Operand::Pc => Some(cpu.pc),
+            Operand::Mem(addr, size) => cpu.read_mem(addr, size),
+        }
+    }
+}
+
+impl Assertion {
+    pub(super) fn parse(text: &str) -> Result<Assertion, String> {
+        let (cond, at) = match text.split_once('@') {
+            Some((cond, at)) => {
+                let at = parse_addr(at.trim()).ok_or(format!("bad pc in assertion: {}", text))?;
+                (cond, Some(at))
+            }
+            None => (text, None),
+        };
+        let (lhs, rhs, equal) = if let Some((lhs, rhs)) = cond.split_once("==") {
+            (lhs, rhs, true)
+        } else if let Some((lhs, rhs)) = cond.split_once("!=") {
+            (lhs, rhs, false)
+        } else {
+            return Err(format!("assertion needs == or !=: {}", text));
+        };
+        Ok(Assertion {
+            text: text.to_string(),
+            lhs: Operand::parse(lhs).ok_or(format!("bad operand in assertion: {}", text))?,
+            equal,
+            value: parse_addr(rhs.trim()).ok_or(format!("bad value in assertion: {}", text))?,
+            at,
+        })
+    }
+
+    /// Evaluate against the cpu. Unreadable memory counts as a failure.
+    pub(super) fn holds(&self, cpu: &RiscvCpu) -> bool {
+        match self.lhs.value(cpu) {
+            Some(value) => (value == self.value) == self.equal,
+            None => false,
+        }
+    }
+}
+
+impl RiscvCpu {
+    /// Check the assertions due at the current pc. Failures are kept in
+    /// `assert_failures` with the value seen.
+    pub(super) fn check_assertions_at_pc(&mut self) {
+        let pc = self.pc;
+        self.check_assertions(|a| a.at == Some(pc));
+    }
+
+    /// Check the assertions due at exit
+    pub(super) fn check_assertions_at_exit(&mut self) {
+        self.check_assertions(|a| a.at.is_none());
+    }
+
+    fn check_assertions(&mut self, due: impl Fn(&Assertion) -> bool) {
+        let mut failures = Vec::new();
+        for a in self.assertions.iter().filter(|a| due(a)) {
+            if !a.holds(self) {
+                let seen = match a.lhs.value(self) {
+                    Some(value) => format!("{:#x}", value),
+                    None => String::from("unreadable"),
+                };
+                failures.push(format!("{} (got {} at pc {:#018x})", a.text, seen, self.pc));
+            }
+        }
+        self.assert_failures.extend(failures);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assertion_parse() {
+        let a = Assertion::parse("mem[0x80001000]==0xdeadbeef").unwrap();
+        assert_eq!(a.lhs, Operand::Mem(0x80001000, 4));
+        assert!(a.equal);
+        assert_eq!(a.value, 0xdeadbeef);
+        assert_eq!(a.at, None);
+
+        let a = Assertion::parse("x10 != 3 @ 0x40").unwrap();
+        assert_eq!(a.lhs, Operand::Reg(REG_A0));
+        assert!(!a.equal);
+        assert_eq!(a.at, Some(0x40));
+
+        assert_eq!(Assertion::parse("mem64[8]==1").unwrap().lhs, Operand::Mem(8, 8));
+        assert!(Assertion::parse("a0=1").is_err());
+        assert!(Assertion::parse("q7==1").is_err());
+        assert!(Assertion::parse("mem12[0]==1").is_err());
+    }
+
+    #[test]
+    fn test_assertion_holds() {
+        let mut cpu = RiscvCpu::new(vec![0x13, 0x05, 0xc0, 0xff]);
+        cpu.ixu[REG_A0] = 5;
+        assert!(Assertion::parse("a0==5").unwrap().holds(&cpu));
+        assert!(Assertion::parse("pc==0").unwrap().holds(&cpu));
+        assert!(Assertion::parse("mem[0]==0xffc00513").unwrap().holds(&cpu));
+        assert!(Assertion::parse("mem8[3]==0xff").unwrap().holds(&cpu));
+        assert!(!Assertion::parse("mem[0x100]!=0").unwrap().holds(&cpu));
+    }
+}
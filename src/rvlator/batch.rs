@@ -0,0 +1,241 @@
+// Batch regression runs.
+//
+//     rvlator batch tests/*.bin --jobs 8 --timeout 10s --junit report.xml -- --tohost 0x1000
+//
+// Every image runs as its own `rvlator run -q IMAGE ARGS...` process, the
+// arguments after -- passed to each. An image passes when that process
+// exits with 0, so a non-zero guest exit code, a tohost failure, a failed
+// assertion or a trap all fail it. One that outlives --timeout is killed.
+
+use super::*;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Outcome {
+    Pass,
+    // Exit code, None when killed by a signal
+    Fail(Option<i32>),
+    Timeout,
+    // Unable to start the run at all
+    Error(String),
+}
+
+pub(super) struct BatchResult {
+    pub image: PathBuf,
+    pub outcome: Outcome,
+    pub time: Duration,
+    // stdout then stderr of the run
+    pub output: String,
+}
+
+/// Run `command` to completion or until `timeout`, capturing its output
+pub(super) fn run_one(image: &Path, mut command: Command, timeout: Duration) -> BatchResult {
+    let start = Instant::now();
+    let result = |outcome, output| BatchResult {
+        image: image.to_path_buf(),
+        outcome,
+        time: start.elapsed(),
+        output,
+    };
+    let mut child = match command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => return result(Outcome::Error(e.to_string()), String::new()),
+    };
+    // Drained on their own threads so a chatty guest can't fill the pipe
+    let drain = |pipe: Option<Box<dyn Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut text = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut text).ok();
+            }
+            text
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = drain(child.stderr.take().map(|p| Box::new(p) as _));
+    let outcome = loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => break Outcome::Pass,
+            Ok(Some(status)) => break Outcome::Fail(status.code()),
+            Ok(None) if start.elapsed() >= timeout => {
+                child.kill().ok();
+                child.wait().ok();
+                break Outcome::Timeout;
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(5)),
+            Err(e) => break Outcome::Error(e.to_string()),
+        }
+    };
+    let output = [stdout, stderr].map(|t| String::from_utf8_lossy(&t.join().unwrap_or_default()).into_owned());
+    result(outcome, output.concat())
+}
+
+/// Run every image on `jobs` threads, results in the order of `images`
+pub(super) fn run_batch(images: &[PathBuf], jobs: usize, timeout: Duration, run_args: &[String]) -> Vec<BatchResult> {
+    let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("rvlator"));
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, images.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(image) = images.get(i) else { break };
+                let mut command = Command::new(&exe);
+                command.args(["run", "-q"]).arg(image).args(run_args);
+                let result = run_one(image, command, timeout);
+                results.lock().unwrap().push((i, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn describe(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::Pass => String::from("pass"),
+        Outcome::Fail(Some(code)) => format!("fail (exit {})", code),
+        Outcome::Fail(None) => String::from("fail (killed)"),
+        Outcome::Timeout => String::from("timeout"),
+        Outcome::Error(e) => format!("error ({})", e),
+    }
+}
+
+/// The summary table, one image per line and the totals
+pub(super) fn summary(results: &[BatchResult]) -> String {
+    let width = results.iter().map(|r| r.image.display().to_string().len()).max().unwrap_or(0);
+    let mut text = String::new();
+    for result in results {
+        let color = match result.outcome {
+            Outcome::Pass => COLOR_GREEN,
+            _ => COLOR_RED,
+        };
+        text.push_str(&format!(
+            "{:<width$}  {:>9.3}s  {color}{}{COLOR_RESET}\n",
+            result.image.display().to_string(),
+            result.time.as_secs_f64(),
+            describe(&result.outcome),
+        ));
+    }
+    let passed = results.iter().filter(|r| r.outcome == Outcome::Pass).count();
+    text.push_str(&format!(
+        "{COLOR_AQUA}[batch]{COLOR_RESET} {} passed, {} failed\n",
+        passed,
+        results.len() - passed
+    ));
+    text
+}
+
+fn xml_escape(text: &str) -> String {
+    text.chars()
+        .filter(|&c| c == '\n' || c == '\t' || !c.is_control())
+        .fold(String::new(), |mut out, c| {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                _ => out.push(c),
+            }
+            out
+        })
+}
+
+/// Drop the color escapes the run prints
+fn strip_colors(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("\x1b[") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+        let end = rest.find('m').map_or(rest.len(), |end| end + 1);
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// JUnit XML with one testcase per image, the run's output attached to
+/// each failure
+pub(super) fn junit(results: &[BatchResult]) -> String {
+    let failures = results.iter().filter(|r| r.outcome != Outcome::Pass).count();
+    let total: f64 = results.iter().map(|r| r.time.as_secs_f64()).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuite name=\"rvlator\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        results.len(),
+        failures,
+        total
+    ));
+    for result in results {
+        let name = xml_escape(&result.image.display().to_string());
+        let time = result.time.as_secs_f64();
+        if result.outcome == Outcome::Pass {
+            xml.push_str(&format!("  <testcase name=\"{}\" time=\"{:.3}\"/>\n", name, time));
+            continue;
+        }
+        xml.push_str(&format!("  <testcase name=\"{}\" time=\"{:.3}\">\n", name, time));
+        xml.push_str(&format!(
+            "    <failure message=\"{}\">{}</failure>\n",
+            xml_escape(&describe(&result.outcome)),
+            xml_escape(&strip_colors(&result.output))
+        ));
+        xml.push_str("  </testcase>\n");
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(image: &str, outcome: Outcome, output: &str) -> BatchResult {
+        BatchResult {
+            image: PathBuf::from(image),
+            outcome,
+            time: Duration::from_millis(1500),
+            output: output.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_batch_reports() {
+        let results = [
+            result("a.bin", Outcome::Pass, ""),
+            result("b<1>.bin", Outcome::Fail(Some(3)), "\x1b[1;31massertion failed: a0 == 1\x1b[0m\n"),
+            result("c.bin", Outcome::Timeout, ""),
+        ];
+        let summary = summary(&results);
+        assert!(summary.contains("b<1>.bin      1.500s"), "{}", summary);
+        assert!(summary.contains("fail (exit 3)"), "{}", summary);
+        assert!(summary.contains("1 passed, 2 failed"), "{}", summary);
+        let xml = junit(&results);
+        assert!(xml.contains("tests=\"3\" failures=\"2\" time=\"4.500\""), "{}", xml);
+        assert!(xml.contains("<testcase name=\"a.bin\" time=\"1.500\"/>"), "{}", xml);
+        assert!(xml.contains("<testcase name=\"b&lt;1&gt;.bin\""), "{}", xml);
+        assert!(xml.contains(">assertion failed: a0 == 1\n</failure>"), "{}", xml);
+        assert!(xml.contains("<failure message=\"timeout\">"), "{}", xml);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_batch_run_one() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2; exit 2"]);
+        let result = run_one(Path::new("x.bin"), command, Duration::from_secs(10));
+        assert_eq!(result.outcome, Outcome::Fail(Some(2)));
+        assert_eq!(result.output, "out\nerr\n");
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let result = run_one(Path::new("x.bin"), command, Duration::from_millis(50));
+        assert_eq!(result.outcome, Outcome::Timeout);
+        assert!(result.time < Duration::from_secs(5));
+    }
+}
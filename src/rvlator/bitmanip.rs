@@ -0,0 +1,466 @@
+// Bit-manipulation extensions and Zicond, in the OP, OP-32, OP-IMM and OP-IMM-32
+// opcodes beside the base instructions. Decoded ahead of those, anything
+// not listed here falls through to the base ISA rows.
+//
+//     Zba     sh1add sh2add sh3add                  (rs1 << n) + rs2
+//             add.uw sh1add.uw sh2add.uw sh3add.uw  the same on the zero extended rs1 word
+//             slli.uw
+//     Zbb     andn orn xnor                         rs2 inverted first
+//             min minu max maxu
+//             rol ror rori rolw rorw roriw
+//             clz ctz cpop clzw ctzw cpopw
+//             sext.b sext.h zext.h orc.b rev8
+//     Zbs     bclr bext binv bset                   bit rs2[5:0] of rs1
+//             bclri bexti binvi bseti               bit shamt
+//     Zbc     clmul clmulh clmulr                   with --isa ..._zbc
+//     Zbkb    pack packh packw brev8                with --isa ..._zbkb, the rest
+//                                                   of Zbkb is in Zbb; zip and
+//                                                   unzip are RV32 only
+//     Zbkc    clmul clmulh                          with --isa ..._zbkc
+//     Zbkx    xperm4 xperm8                         with --isa ..._zbkx, rs2 indexes rs1
+//     Zicond  czero.eqz czero.nez                   zero if rs2 is / is not zero, rs1 otherwise
+
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum BitOp {
+    // Shift, rs1 zero extended from 32 bits first
+    ShAdd(u32, bool),
+    SlliUw(u32),
+    AndN,
+    OrN,
+    Xnor,
+    // Signed, max
+    MinMax(bool, bool),
+    // Right, word
+    Rotate(bool, bool),
+    // Right by shamt, word
+    RotateImm(u32, bool),
+    // Word
+    Clz(bool),
+    Ctz(bool),
+    Cpop(bool),
+    SextB,
+    SextH,
+    ZextH,
+    OrcB,
+    Rev8,
+    // Bit index from shamt for the immediate forms, from rs2 otherwise
+    Bclr(Option<u32>),
+    Bext(Option<u32>),
+    Binv(Option<u32>),
+    Bset(Option<u32>),
+    // Zero when rs2 is not zero rather than when it is
+    CzeroNez(bool),
+    // funct3: 1 clmul, 2 clmulr, 3 clmulh
+    Clmul(u32),
+    // Low halves of rs1 and rs2 side by side: words, bytes, halfwords of a word
+    Pack,
+    PackH,
+    PackW,
+    Brev8,
+    // Element bits, 4 or 8
+    Xperm(u32),
+}
+
+impl BitOp {
+    /// Extension and mnemonic
+    pub(super) fn names(&self) -> (&'static str, &'static str) {
+        match self {
+            BitOp::ShAdd(sh, false) => ("Zba", ["add", "sh1add", "sh2add", "sh3add"][*sh as usize]),
+            BitOp::ShAdd(sh, true) => ("Zba", ["add.uw", "sh1add.uw", "sh2add.uw", "sh3add.uw"][*sh as usize]),
+            BitOp::SlliUw(_) => ("Zba", "slli.uw"),
+            BitOp::AndN => ("Zbb", "andn"),
+            BitOp::OrN => ("Zbb", "orn"),
+            BitOp::Xnor => ("Zbb", "xnor"),
+            BitOp::MinMax(signed, max) => ("Zbb", ["minu", "min", "maxu", "max"][*max as usize * 2 + *signed as usize]),
+            BitOp::Rotate(right, word) => ("Zbb", ["rol", "ror", "rolw", "rorw"][*word as usize * 2 + *right as usize]),
+            BitOp::RotateImm(_, word) => ("Zbb", if *word { "roriw" } else { "rori" }),
+            BitOp::Clz(word) => ("Zbb", if *word { "clzw" } else { "clz" }),
+            BitOp::Ctz(word) => ("Zbb", if *word { "ctzw" } else { "ctz" }),
+            BitOp::Cpop(word) => ("Zbb", if *word { "cpopw" } else { "cpop" }),
+            BitOp::SextB => ("Zbb", "sext.b"),
+            BitOp::SextH => ("Zbb", "sext.h"),
+            BitOp::ZextH => ("Zbb", "zext.h"),
+            BitOp::OrcB => ("Zbb", "orc.b"),
+            BitOp::Rev8 => ("Zbb", "rev8"),
+            BitOp::Bclr(sh) => ("Zbs", if sh.is_some() { "bclri" } else { "bclr" }),
+            BitOp::Bext(sh) => ("Zbs", if sh.is_some() { "bexti" } else { "bext" }),
+            BitOp::Binv(sh) => ("Zbs", if sh.is_some() { "binvi" } else { "binv" }),
+            BitOp::Bset(sh) => ("Zbs", if sh.is_some() { "bseti" } else { "bset" }),
+            BitOp::CzeroNez(nez) => ("Zicond", if *nez { "czero.nez" } else { "czero.eqz" }),
+            BitOp::Clmul(funct3) => ("Zbc", ["", "clmul", "clmulr", "clmulh"][*funct3 as usize]),
+            BitOp::Pack => ("Zbkb", "pack"),
+            BitOp::PackH => ("Zbkb", "packh"),
+            BitOp::PackW => ("Zbkb", "packw"),
+            BitOp::Brev8 => ("Zbkb", "brev8"),
+            BitOp::Xperm(bits) => ("Zbkx", if *bits == 4 { "xperm4" } else { "xperm8" }),
+        }
+    }
+
+    /// The shift amount of the immediate forms
+    pub(super) fn shamt(&self) -> Option<u32> {
+        match self {
+            BitOp::SlliUw(sh) | BitOp::RotateImm(sh, _) => Some(*sh),
+            BitOp::Bclr(sh) | BitOp::Bext(sh) | BitOp::Binv(sh) | BitOp::Bset(sh) => *sh,
+            _ => None,
+        }
+    }
+
+    /// rs2 is a source register
+    pub(super) fn reads_rs2(&self) -> bool {
+        if let BitOp::Bclr(sh) | BitOp::Bext(sh) | BitOp::Binv(sh) | BitOp::Bset(sh) = self {
+            return sh.is_none();
+        }
+        matches!(
+            self,
+            BitOp::ShAdd(..) | BitOp::AndN | BitOp::OrN | BitOp::Xnor | BitOp::MinMax(..) | BitOp::Rotate(..) | BitOp::CzeroNez(_) | BitOp::Clmul(_)
+                | BitOp::Pack | BitOp::PackH | BitOp::PackW | BitOp::Xperm(_)
+        )
+    }
+}
+
+pub(super) fn decode(inst: u32) -> Option<BitOp> {
+    let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let funct7 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+    let funct6 = getfield32!(inst, INST_FUNCT6_WID, INST_FUNCT6_POS);
+    let shamt = getfield32!(inst, INST_SHAMT_WID, INST_SHAMT_POS);
+    let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS);
+    let imm12 = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+    let op = match (opcode, funct7, funct3) {
+        (OPCODE_OP, 0b0010000, 0b010 | 0b100 | 0b110) => BitOp::ShAdd(funct3 >> 1, false),
+        (OPCODE_OP_32, 0b0000100, 0b000) => BitOp::ShAdd(0, true),
+        (OPCODE_OP_32, 0b0010000, 0b010 | 0b100 | 0b110) => BitOp::ShAdd(funct3 >> 1, true),
+        (OPCODE_OP_IMM_32, _, 0b001) if funct6 == 0b000010 => BitOp::SlliUw(shamt),
+        (OPCODE_OP, 0b0100000, 0b111) => BitOp::AndN,
+        (OPCODE_OP, 0b0100000, 0b110) => BitOp::OrN,
+        (OPCODE_OP, 0b0100000, 0b100) => BitOp::Xnor,
+        // min 100, minu 101, max 110, maxu 111
+        (OPCODE_OP, 0b0000101, 0b100..=0b111) => BitOp::MinMax(funct3 & 1 == 0, funct3 & 0b010 != 0),
+        (OPCODE_OP, 0b0000101, 0b001..=0b011) => BitOp::Clmul(funct3),
+        (OPCODE_OP | OPCODE_OP_32, 0b0110000, 0b001 | 0b101) => BitOp::Rotate(funct3 == 0b101, opcode == OPCODE_OP_32),
+        (OPCODE_OP_32, 0b0000100, 0b100) if rs2 == 0 => BitOp::ZextH,
+        // zext.h is packw with x0, pack with x0 is zext.h on RV32 only
+        (OPCODE_OP_32, 0b0000100, 0b100) => BitOp::PackW,
+        (OPCODE_OP, 0b0000100, 0b100) => BitOp::Pack,
+        (OPCODE_OP, 0b0000100, 0b111) => BitOp::PackH,
+        (OPCODE_OP, 0b0010100, 0b010) => BitOp::Xperm(4),
+        (OPCODE_OP, 0b0010100, 0b100) => BitOp::Xperm(8),
+        // Unary ones pick the operation in rs2
+        (OPCODE_OP_IMM | OPCODE_OP_IMM_32, 0b0110000, 0b001) => {
+            let word = opcode == OPCODE_OP_IMM_32;
+            match rs2 {
+                0 => BitOp::Clz(word),
+                1 => BitOp::Ctz(word),
+                2 => BitOp::Cpop(word),
+                4 if !word => BitOp::SextB,
+                5 if !word => BitOp::SextH,
+                _ => return None,
+            }
+        }
+        (OPCODE_OP, 0b0000111, 0b101 | 0b111) => BitOp::CzeroNez(funct3 == 0b111),
+        (OPCODE_OP, 0b0100100, 0b001) => BitOp::Bclr(None),
+        (OPCODE_OP, 0b0100100, 0b101) => BitOp::Bext(None),
+        (OPCODE_OP, 0b0110100, 0b001) => BitOp::Binv(None),
+        (OPCODE_OP, 0b0010100, 0b001) => BitOp::Bset(None),
+        (OPCODE_OP_IMM, _, 0b001) if funct6 == 0b010010 => BitOp::Bclr(Some(shamt)),
+        (OPCODE_OP_IMM, _, 0b101) if funct6 == 0b010010 => BitOp::Bext(Some(shamt)),
+        (OPCODE_OP_IMM, _, 0b001) if funct6 == 0b011010 => BitOp::Binv(Some(shamt)),
+        (OPCODE_OP_IMM, _, 0b001) if funct6 == 0b001010 => BitOp::Bset(Some(shamt)),
+        (OPCODE_OP_IMM, _, 0b101) if funct6 == 0b011000 => BitOp::RotateImm(shamt, false),
+        (OPCODE_OP_IMM_32, 0b0110000, 0b101) => BitOp::RotateImm(rs2, true),
+        (OPCODE_OP_IMM, _, 0b101) if imm12 == 0x287 => BitOp::OrcB,
+        (OPCODE_OP_IMM, _, 0b101) if imm12 == 0x6b8 => BitOp::Rev8,
+        (OPCODE_OP_IMM, _, 0b101) if imm12 == 0x687 => BitOp::Brev8,
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Assembly text of a bit-manipulation instruction, None for anything else
+pub(super) fn disasm(inst: u32) -> Option<String> {
+    let op = decode(inst)?;
+    let rd = REGNAME[getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize];
+    let rs1 = REGNAME[getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize];
+    let rs2 = REGNAME[getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize];
+    let (_, name) = op.names();
+    let text = match op.shamt() {
+        Some(sh) => format!("{} {},{},{}", name, rd, rs1, sh),
+        None if op.reads_rs2() => format!("{} {},{},{}", name, rd, rs1, rs2),
+        None => format!("{} {},{}", name, rd, rs1),
+    };
+    Some(text)
+}
+
+/// Carry-less product, XOR in place of the additions of a multiply
+fn clmul(x: u64, y: u64) -> u128 {
+    (0..64).filter(|i| y >> i & 1 == 1).fold(0, |product, i| product ^ (x as u128) << i)
+}
+
+impl RiscvCpu {
+    pub(super) fn execute_bitmanip(&mut self, inst: u32) -> Result<(), RiscvCpuError> {
+        let op = decode(inst).ok_or(RiscvCpuError::DecodeError)?;
+        let enabled = match op {
+            // Zbkc leaves out clmulr
+            BitOp::Clmul(funct3) => self.isa.zbc || self.isa.zbkc && funct3 != 0b010,
+            BitOp::Pack | BitOp::PackH | BitOp::PackW | BitOp::Brev8 => self.isa.zbkb,
+            BitOp::Xperm(_) => self.isa.zbkx,
+            _ => true,
+        };
+        if !enabled {
+            return Err(RiscvCpuError::DecodeError);
+        }
+        let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize;
+        let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize;
+        let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+        let (x, y) = (self.ixu[rs1], self.ixu[rs2]);
+        let word = |value: u32| value as i32 as u64;
+        let bit = |sh: Option<u32>| 1u64 << sh.unwrap_or(y as u32 & 0x3f);
+
+        self.ixu[rd] = match op {
+            BitOp::ShAdd(sh, false) => (x << sh).wrapping_add(y),
+            BitOp::ShAdd(sh, true) => ((x as u32 as u64) << sh).wrapping_add(y),
+            BitOp::SlliUw(sh) => (x as u32 as u64) << sh,
+            BitOp::AndN => x & !y,
+            BitOp::OrN => x | !y,
+            BitOp::Xnor => !(x ^ y),
+            BitOp::MinMax(true, max) => {
+                let (x, y) = (x as i64, y as i64);
+                (if max { x.max(y) } else { x.min(y) }) as u64
+            }
+            BitOp::MinMax(false, max) => if max { x.max(y) } else { x.min(y) },
+            // Register amounts use rs2[5:0], rs2[4:0] for the word forms
+            BitOp::Rotate(false, false) => x.rotate_left(y as u32 & 0x3f),
+            BitOp::Rotate(true, false) => x.rotate_right(y as u32 & 0x3f),
+            BitOp::Rotate(false, true) => word((x as u32).rotate_left(y as u32 & 0x1f)),
+            BitOp::Rotate(true, true) => word((x as u32).rotate_right(y as u32 & 0x1f)),
+            BitOp::RotateImm(sh, false) => x.rotate_right(sh),
+            BitOp::RotateImm(sh, true) => word((x as u32).rotate_right(sh)),
+            BitOp::Clz(false) => x.leading_zeros() as u64,
+            BitOp::Clz(true) => (x as u32).leading_zeros() as u64,
+            BitOp::Ctz(false) => x.trailing_zeros() as u64,
+            BitOp::Ctz(true) => (x as u32).trailing_zeros() as u64,
+            BitOp::Cpop(false) => x.count_ones() as u64,
+            BitOp::Cpop(true) => (x as u32).count_ones() as u64,
+            BitOp::SextB => x as i8 as u64,
+            BitOp::SextH => x as i16 as u64,
+            BitOp::ZextH => x as u16 as u64,
+            // Each byte becomes all ones if any bit of it is set
+            BitOp::OrcB => (0..8).fold(0, |acc, byte| match (x >> (8 * byte)) & 0xff {
+                0 => acc,
+                _ => acc | 0xff << (8 * byte),
+            }),
+            BitOp::Rev8 => x.swap_bytes(),
+            BitOp::Bclr(sh) => x & !bit(sh),
+            BitOp::Bext(sh) => (x & bit(sh) != 0) as u64,
+            BitOp::Binv(sh) => x ^ bit(sh),
+            BitOp::Bset(sh) => x | bit(sh),
+            BitOp::CzeroNez(nez) => if (y != 0) == nez { 0 } else { x },
+            // Bits 63:0, 126:63 and 127:64 of the 128-bit product
+            BitOp::Clmul(funct3) => (clmul(x, y) >> [0, 0, 63, 64][funct3 as usize]) as u64,
+            BitOp::Pack => x as u32 as u64 | y << 32,
+            BitOp::PackH => x & 0xff | (y & 0xff) << 8,
+            BitOp::PackW => word(x as u16 as u32 | (y as u32) << 16),
+            BitOp::Brev8 => u64::from_le_bytes(x.to_le_bytes().map(u8::reverse_bits)),
+            // Each element of rs2 picks the element of rs1 it indexes, 0 past the end
+            BitOp::Xperm(bits) => (0..64).step_by(bits as usize).fold(0, |acc, pos| {
+                let mask = (1 << bits) - 1;
+                let index = (y >> pos & mask) * bits as u64;
+                match index < 64 {
+                    true => acc | (x >> index & mask) << pos,
+                    false => acc,
+                }
+            }),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_zba() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0xffff_ffff_8000_0001, 0x1000);
+        cpu.execute(encode!(sh1add a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_0000_1002);
+        cpu.execute(encode!(sh3add a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_fffc_0000_1008);
+        cpu.execute(encode!(add.uw a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_1001);
+        cpu.execute(encode!(sh2add.uw a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x2_0000_1004);
+        cpu.execute(encode!(slli.uw a0, a1, 40)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0000_0100_0000_0000);
+        cpu.execute(encode!(slli.uw a0, a1, 4)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8_0000_0010);
+        // sh1add's funct3 with add.uw's funct7 is nothing
+        assert_eq!(decode(encode!(add.uw a0, a1, a2) | 0b010 << 12), None);
+    }
+
+    #[test]
+    fn test_zbb() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0xffff_ffff_8000_0f00, 0x0000_0000_0000_ff0f);
+        cpu.execute(encode!(andn a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0000);
+        cpu.execute(encode!(orn a0, a2, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0000_0000_7fff_ffff);
+        cpu.execute(encode!(xnor a0, a1, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], u64::MAX);
+        cpu.execute(encode!(min a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0f00);
+        cpu.execute(encode!(maxu a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_8000_0f00);
+        cpu.execute(encode!(minu a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xff0f);
+        cpu.execute(encode!(max a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xff0f);
+
+        // Rotates take the amount mod 64, or mod 32 on words
+        cpu.ixu[REG_A3] = 68;
+        cpu.execute(encode!(rol a0, a1, a3)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_fff8_0000_f00f);
+        cpu.execute(encode!(rorw a0, a1, a3)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0000_0000_0800_00f0);
+        cpu.execute(encode!(rori a0, a1, 8)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x00ff_ffff_ff80_000f);
+        cpu.execute(encode!(roriw a0, a1, 12)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_f008_0000);
+
+        cpu.execute(encode!(clz a0, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 48);
+        cpu.execute(encode!(clzw a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        cpu.execute(encode!(ctz a0, zero)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 64);
+        cpu.execute(encode!(ctzw a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 8);
+        cpu.execute(encode!(cpop a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 37);
+        cpu.execute(encode!(cpopw a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 5);
+        cpu.execute(encode!(sext.b a0, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xf);
+        cpu.execute(encode!(sext.h a0, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_ffff_ff0f);
+        cpu.execute(encode!(zext.h a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0f00);
+        cpu.execute(encode!(orc.b a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_ff00_ff00);
+        cpu.execute(encode!(rev8 a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x000f_0080_ffff_ffff);
+        // There is no sext.b on words
+        assert_eq!(decode(encode!(sext.b a0, a1) | 0b1000), None);
+    }
+
+    #[test]
+    fn test_zbs() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        // Register indexes use rs2[5:0]
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0x8000_0000_0000_00f0, 0x47);
+        cpu.execute(encode!(bclr a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_0070);
+        cpu.execute(encode!(bext a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 1);
+        cpu.execute(encode!(binv a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_0070);
+        cpu.execute(encode!(bset a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_00f0);
+        cpu.execute(encode!(bclri a0, a1, 63)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xf0);
+        cpu.execute(encode!(bexti a0, a1, 3)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        cpu.execute(encode!(binvi a0, a1, 32)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0001_0000_00f0);
+        cpu.execute(encode!(bseti a0, a1, 0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_00f1);
+    }
+
+    #[test]
+    fn test_zicond() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0x1234, 0);
+        cpu.execute(encode!(czero.eqz a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        cpu.execute(encode!(czero.nez a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x1234);
+        cpu.ixu[REG_A2] = 1 << 63;
+        cpu.execute(encode!(czero.eqz a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x1234);
+        cpu.execute(encode!(czero.nez a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+    }
+
+    #[test]
+    fn test_zbc() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0x8000_0000_0000_0003, 0x8000_0000_0000_0005);
+        // Only with --isa ..._zbc
+        assert_eq!(cpu.execute(encode!(clmul a0, a1, a2)), Err(RiscvCpuError::DecodeError));
+        cpu.isa.zbc = true;
+        cpu.execute(encode!(clmul a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0000_0000_0000_000f);
+        cpu.execute(encode!(clmulh a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x4000_0000_0000_0003);
+        cpu.execute(encode!(clmulr a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_0006);
+        assert_eq!(clmul(u64::MAX, u64::MAX), 0x5555_5555_5555_5555_5555_5555_5555_5555);
+    }
+
+    #[test]
+    fn test_zbk() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0x0123_4567_89ab_cdef, 0xfedc_ba98_7654_3210);
+        assert_eq!(cpu.execute(encode!(pack a0, a1, a2)), Err(RiscvCpuError::DecodeError));
+        cpu.isa = Isa::parse("rv64gc_zkn").unwrap();
+        cpu.execute(encode!(pack a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x7654_3210_89ab_cdef);
+        cpu.execute(encode!(packh a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x10ef);
+        cpu.execute(encode!(packw a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x3210_cdef);
+        cpu.execute(encode!(packw a0, a2, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_cdef_3210);
+        cpu.execute(encode!(brev8 a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x80c4_a2e6_91d5_b3f7);
+        // Index 8 and up reads as zero
+        cpu.ixu[REG_A2] = 0x0001_0203_0405_0607 | 0xff << 56;
+        cpu.execute(encode!(xperm8 a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x00cd_ab89_6745_2301);
+        cpu.ixu[REG_A2] = 0xfedc_ba98_7654_3210;
+        cpu.execute(encode!(xperm4 a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0123_4567_89ab_cdef);
+        // Zbkc is clmul and clmulh without clmulr
+        cpu.execute(encode!(clmulh a0, a1, a2)).unwrap();
+        assert_eq!(cpu.execute(encode!(clmulr a0, a1, a2)), Err(RiscvCpuError::DecodeError));
+    }
+
+    #[test]
+    fn test_bitmanip_disasm() {
+        assert_eq!(disasm(0x20c5a533).unwrap(), "sh1add a0,a1,a2");
+        assert_eq!(disasm(0x20c5e53b).unwrap(), "sh3add.uw a0,a1,a2");
+        assert_eq!(disasm(0x08c5853b).unwrap(), "add.uw a0,a1,a2");
+        assert_eq!(disasm(0x0835951b).unwrap(), "slli.uw a0,a1,3");
+        assert_eq!(disasm(0x40c5f533).unwrap(), "andn a0,a1,a2");
+        assert_eq!(disasm(0x0ac5d533).unwrap(), "minu a0,a1,a2");
+        assert_eq!(disasm(0x60c5953b).unwrap(), "rolw a0,a1,a2");
+        assert_eq!(disasm(0x6035d513).unwrap(), "rori a0,a1,3");
+        assert_eq!(disasm(0x6025951b).unwrap(), "cpopw a0,a1");
+        assert_eq!(disasm(0x0805c53b).unwrap(), "zext.h a0,a1");
+        assert_eq!(disasm(0x2875d513).unwrap(), "orc.b a0,a1");
+        assert_eq!(disasm(0x6b85d513).unwrap(), "rev8 a0,a1");
+        assert_eq!(disasm(0x0ac5b533).unwrap(), "clmulh a0,a1,a2");
+        assert_eq!(disasm(0x4835d513).unwrap(), "bexti a0,a1,3");
+        assert_eq!(disasm(0x68c59533).unwrap(), "binv a0,a1,a2");
+        assert_eq!(disasm(0x0ec5f533).unwrap(), "czero.nez a0,a1,a2");
+        assert_eq!(disasm(0x08c5c53b).unwrap(), "packw a0,a1,a2");
+        assert_eq!(disasm(0x6875d513).unwrap(), "brev8 a0,a1");
+        assert_eq!(disasm(0x28c5a533).unwrap(), "xperm4 a0,a1,a2");
+        assert_eq!(disasm(0x00c58533), None);
+    }
+}
@@ -0,0 +1,173 @@
+// Branch predictor models.
+//
+// Predictors observe every conditional branch (pc, target, outcome) and
+// count mispredictions. Each misprediction costs `penalty` cycles in the
+// timing estimate.
+
+use std::collections::BTreeMap;
+
+// 2^12 two-bit counters for the bimodal and gshare tables
+const BPRED_INDEX_BITS: u32 = 12;
+const BPRED_DEFAULT_PENALTY: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum PredictorKind {
+    // Backward taken, forward not taken
+    Static,
+    // Per-pc 2-bit saturating counters
+    Bimodal,
+    // 2-bit counters indexed by pc xor global history
+    Gshare,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(super) struct SiteStats {
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64,
+}
+
+// A predictor kind and its misprediction penalty, as --bpred gives them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct BpredConfig {
+    pub kind: PredictorKind,
+    pub penalty: u64,
+}
+
+impl BpredConfig {
+    /// Parse "KIND[:PENALTY]" where KIND is static, bimodal or gshare
+    pub(super) fn parse(spec: &str) -> Option<BpredConfig> {
+        let (kind, penalty) = match spec.split_once(':') {
+            Some((kind, penalty)) => (kind, penalty.parse().ok()?),
+            None => (spec, BPRED_DEFAULT_PENALTY),
+        };
+        let kind = match kind {
+            "static" => PredictorKind::Static,
+            "bimodal" => PredictorKind::Bimodal,
+            "gshare" => PredictorKind::Gshare,
+            _ => return None,
+        };
+        Some(BpredConfig { kind, penalty })
+    }
+}
+
+pub(super) struct BranchPredictor {
+    kind: PredictorKind,
+    penalty: u64,
+    // 2-bit counters, >= 2 predicts taken
+    table: Vec<u8>,
+    history: u64,
+    pub branches: u64,
+    pub mispredicts: u64,
+    pub sites: BTreeMap<u64, SiteStats>,
+}
+
+impl BranchPredictor {
+    pub(super) fn new(config: BpredConfig) -> BranchPredictor {
+        BranchPredictor {
+            kind: config.kind,
+            penalty: config.penalty,
+            // Start weakly not taken
+            table: vec![1; 1 << BPRED_INDEX_BITS],
+            history: 0,
+            branches: 0,
+            mispredicts: 0,
+            sites: BTreeMap::new(),
+        }
+    }
+
+    fn index(&self, pc: u64) -> usize {
+        let mask = (1u64 << BPRED_INDEX_BITS) - 1;
+        match self.kind {
+            PredictorKind::Gshare => (((pc >> 2) ^ self.history) & mask) as usize,
+            _ => ((pc >> 2) & mask) as usize,
+        }
+    }
+
+    pub(super) fn predict(&self, pc: u64, target: u64) -> bool {
+        match self.kind {
+            PredictorKind::Static => target < pc,
+            _ => self.table[self.index(pc)] >= 2,
+        }
+    }
+
+    /// Record the resolved outcome of the branch at pc. Returns true if it was mispredicted.
+    pub(super) fn observe(&mut self, pc: u64, target: u64, taken: bool) -> bool {
+        let mispredicted = self.predict(pc, target) != taken;
+
+        if self.kind != PredictorKind::Static {
+            let idx = self.index(pc);
+            let counter = &mut self.table[idx];
+            if taken {
+                *counter = (*counter + 1).min(3);
+            } else {
+                *counter = counter.saturating_sub(1);
+            }
+            self.history = (self.history << 1) | taken as u64;
+        }
+
+        self.branches += 1;
+        let site = self.sites.entry(pc).or_default();
+        site.executed += 1;
+        if taken {
+            site.taken += 1;
+        }
+        if mispredicted {
+            self.mispredicts += 1;
+            site.mispredicted += 1;
+        }
+        mispredicted
+    }
+
+    pub(super) fn penalty_cycles(&self) -> u64 {
+        self.mispredicts * self.penalty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bpred_parse() {
+        assert_eq!(BpredConfig::parse("static"), Some(BpredConfig { kind: PredictorKind::Static, penalty: 3 }));
+        assert_eq!(BpredConfig::parse("gshare:5"), Some(BpredConfig { kind: PredictorKind::Gshare, penalty: 5 }));
+        assert!(BpredConfig::parse("tage").is_none());
+        assert!(BpredConfig::parse("bimodal:x").is_none());
+    }
+
+    #[test]
+    fn test_bpred_static() {
+        let mut bp = BranchPredictor::new(BpredConfig { kind: PredictorKind::Static, penalty: 3 });
+        // Backward loop branch predicted taken
+        assert!(!bp.observe(0x100, 0x80, true));
+        // Forward branch predicted not taken
+        assert!(bp.observe(0x100, 0x180, true));
+        assert_eq!(bp.penalty_cycles(), 3);
+    }
+
+    #[test]
+    fn test_bpred_bimodal() {
+        let mut bp = BranchPredictor::new(BpredConfig { kind: PredictorKind::Bimodal, penalty: 3 });
+        // Weakly not taken, trains to taken after one miss
+        assert!(bp.observe(0x40, 0x80, true));
+        assert!(!bp.observe(0x40, 0x80, true));
+        assert!(!bp.observe(0x40, 0x80, true));
+        assert_eq!(bp.sites[&0x40].executed, 3);
+        assert_eq!(bp.sites[&0x40].mispredicted, 1);
+    }
+
+    #[test]
+    fn test_bpred_gshare_alternating() {
+        let mut bp = BranchPredictor::new(BpredConfig { kind: PredictorKind::Gshare, penalty: 3 });
+        // An alternating pattern is learnt through the global history
+        for i in 0..64 {
+            bp.observe(0x40, 0x80, i % 2 == 0);
+        }
+        let warm = bp.mispredicts;
+        for i in 0..64 {
+            bp.observe(0x40, 0x80, i % 2 == 0);
+        }
+        assert_eq!(bp.mispredicts, warm);
+    }
+}
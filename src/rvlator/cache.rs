@@ -0,0 +1,162 @@
+// L1 cache timing model.
+//
+// Purely a timing model: data always comes from RiscvCpu::mem, the cache
+// only tracks tags to count hits and misses. Replacement is true LRU.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct CacheConfig {
+    // Total capacity in bytes
+    pub size: usize,
+    // Associativity (ways per set)
+    pub ways: usize,
+    // Line size in bytes
+    pub line: usize,
+    // Extra cycles spent on a miss
+    pub miss_penalty: u64,
+}
+
+impl CacheConfig {
+    /// Parse "SIZE:WAYS:LINE:PENALTY", SIZE may carry a k/K suffix. e.g. "32k:4:64:10"
+    pub(super) fn parse(spec: &str) -> Option<CacheConfig> {
+        let fields: Vec<&str> = spec.split(':').collect();
+        if fields.len() != 4 {
+            return None;
+        }
+        let size = match fields[0].strip_suffix(['k', 'K']) {
+            Some(kb) => kb.parse::<usize>().ok()? * 1024,
+            None => fields[0].parse::<usize>().ok()?,
+        };
+        let config = CacheConfig {
+            size,
+            ways: fields[1].parse().ok()?,
+            line: fields[2].parse().ok()?,
+            miss_penalty: fields[3].parse().ok()?,
+        };
+        // Sets and lines must be powers of two to index with address bits
+        if !config.line.is_power_of_two()
+            || config.ways == 0
+            || !config.size.is_multiple_of(config.line * config.ways)
+            || !(config.size / (config.line * config.ways)).is_power_of_two()
+        {
+            return None;
+        }
+        Some(config)
+    }
+}
+
+pub(super) struct Cache {
+    config: CacheConfig,
+    // Per set tags, most recently used first
+    sets: Vec<Vec<u64>>,
+    pub accesses: u64,
+    pub misses: u64,
+}
+
+impl Cache {
+    pub(super) fn new(config: CacheConfig) -> Cache {
+        let nsets = config.size / (config.line * config.ways);
+        Cache {
+            config,
+            sets: vec![Vec::with_capacity(config.ways); nsets],
+            accesses: 0,
+            misses: 0,
+        }
+    }
+
+    /// Look up addr, filling the line on a miss. Returns true on a hit.
+    pub(super) fn access(&mut self, addr: u64) -> bool {
+        let lineaddr = addr / self.config.line as u64;
+        let set = (lineaddr % self.sets.len() as u64) as usize;
+        let tag = lineaddr / self.sets.len() as u64;
+        let ways = &mut self.sets[set];
+
+        self.accesses += 1;
+        match ways.iter().position(|&t| t == tag) {
+            Some(way) => {
+                ways.remove(way);
+                ways.insert(0, tag);
+                true
+            }
+            None => {
+                self.misses += 1;
+                if ways.len() == self.config.ways {
+                    ways.pop();
+                }
+                ways.insert(0, tag);
+                false
+            }
+        }
+    }
+
+    /// Accesses spanning two lines (misaligned) touch both
+    pub(super) fn access_range(&mut self, addr: u64, size: u64) -> bool {
+        let first = self.access(addr);
+        let line = self.config.line as u64;
+        let last = addr.saturating_add(size.max(1) - 1);
+        if (addr / line) != (last / line) {
+            return self.access(last) && first;
+        }
+        first
+    }
+
+    /// Drop every line, the statistics are kept
+    pub(super) fn invalidate(&mut self) {
+        self.sets.iter_mut().for_each(Vec::clear);
+    }
+
+    /// Drop the lines holding [addr, addr + size), the statistics are kept
+    pub(super) fn invalidate_range(&mut self, addr: u64, size: u64) {
+        let line = self.config.line as u64;
+        let nsets = self.sets.len() as u64;
+        let last = addr.saturating_add(size.max(1) - 1);
+        for lineaddr in addr / line..=last / line {
+            self.sets[(lineaddr % nsets) as usize].retain(|&tag| tag != lineaddr / nsets);
+        }
+    }
+
+    pub(super) fn penalty_cycles(&self) -> u64 {
+        self.misses * self.config.miss_penalty
+    }
+
+    pub(super) fn miss_rate(&self) -> f64 {
+        if self.accesses == 0 {
+            0.0
+        } else {
+            self.misses as f64 / self.accesses as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_parse() {
+        let config = CacheConfig::parse("32k:4:64:10").unwrap();
+        assert_eq!(config.size, 32 * 1024);
+        assert_eq!(config.ways, 4);
+        assert_eq!(config.line, 64);
+        assert_eq!(config.miss_penalty, 10);
+        assert_eq!(None, CacheConfig::parse("32k:4:48:10"));
+        assert_eq!(None, CacheConfig::parse("32k:4:64"));
+    }
+
+    #[test]
+    fn test_cache_lru() {
+        // 2 sets, 2 ways, 16 byte lines
+        let mut cache = Cache::new(CacheConfig::parse("64:2:16:5").unwrap());
+        assert!(!cache.access(0x00));
+        assert!(cache.access(0x04));
+        // Same set (set 0), different tags
+        assert!(!cache.access(0x20));
+        assert!(!cache.access(0x40)); // evicts 0x00
+        assert!(!cache.access(0x00));
+        assert!(cache.access(0x40));
+        assert_eq!(cache.misses, 4);
+        assert_eq!(cache.penalty_cycles(), 20);
+        cache.invalidate();
+        assert!(!cache.access(0x40));
+        assert_eq!(cache.misses, 5);
+    }
+}
@@ -0,0 +1,94 @@
+// Shadow call stack.
+//
+// An emulator-side copy of the return addresses the guest pushes, kept
+// from the link register hints of JAL and JALR: linking into ra or t0 is
+// a call, a JALR through ra or t0 that doesn't link is a return (both at
+// once, a coroutine swap, pops then pushes). Each return is compared with
+// the address its call linked and a return anywhere else, a smashed stack
+// or a ROP gadget, is reported with its pc. Returns with no call on the
+// shadow stack, out of the code that started the run, aren't checked.
+//
+// Each pc is reported once, and the shadow stack is unwound to the
+// mismatching target when a call further down linked it (longjmp), so one
+// bad frame doesn't flood the output.
+
+use super::*;
+use std::collections::BTreeSet;
+
+#[derive(Default)]
+pub(super) struct CallStack {
+    stack: Vec<u64>,
+    reported: BTreeSet<u64>,
+    pub reports: Vec<String>,
+}
+
+/// ra and t0, the link registers of the calling convention
+fn is_link(reg: usize) -> bool {
+    reg == REG_RA || reg == REG_T0
+}
+
+impl CallStack {
+    /// Track the (expanded) instruction retired at pc, which went to next_pc;
+    /// a call links `fallthrough`, which is pc + 2 for a compressed one
+    pub(super) fn retire(&mut self, inst: u32, pc: u64, fallthrough: u64, next_pc: u64) {
+        let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+        let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize;
+        let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize;
+        match opcode {
+            OPCODE_JAL if is_link(rd) => self.stack.push(fallthrough),
+            OPCODE_JALR => {
+                if is_link(rs1) && rs1 != rd {
+                    self.ret(pc, next_pc);
+                }
+                if is_link(rd) {
+                    self.stack.push(fallthrough);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ret(&mut self, pc: u64, target: u64) {
+        let Some(expected) = self.stack.pop() else { return };
+        if target == expected {
+            return;
+        }
+        if self.reported.insert(pc) {
+            self.reports.push(format!("return to {:#018x} at pc {:#018x}, expected {:#018x}", target, pc, expected));
+        }
+        if let Some(depth) = self.stack.iter().rposition(|&addr| addr == target) {
+            self.stack.truncate(depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_call_stack() {
+        // main calls f through ra, f calls g through t0, and g overwrites
+        // f's return address before returning
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(jal ra, 8)) // main
+            .inst(encode!(jal zero, 20))
+            .inst(encode!(jal t0, 8)) // f
+            .inst(encode!(jalr zero, 0(ra)))
+            .inst(encode!(addi ra, ra, 20)) // g
+            .inst(encode!(jalr zero, 0(t0)))
+            .cpu();
+        cpu.callstack = Some(CallStack::default());
+        while cpu.fetch().is_ok() {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, 0x18);
+        let callstack = cpu.callstack.as_ref().unwrap();
+        assert_eq!(callstack.stack.len(), 0);
+        assert_eq!(
+            callstack.reports,
+            ["return to 0x0000000000000018 at pc 0x000000000000000c, expected 0x0000000000000004"]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+// Periodic checkpoints.
+//
+// With --checkpoint-every N the machine state is saved to the checkpoint
+// directory every N retired instructions as ckpt-<instret>.bin, keeping
+// the newest --checkpoint-keep files. --restore FILE resumes from one, so
+// a crash deep into a run can be looked at again from shortly before it.
+//
+// The file is little-endian:
+//
+//     "RVLCKPT2"
+//     pc, instret, cycle, brk, mem_base, x0-x31,
+//     f0-f31, fcsr                                  u64 each
+//     CSR count, then address and value of each     u64 each
+//     memory length                                 u64
+//     memory                                        bytes
+//
+// Timing model, cache and analysis state isn't saved, those start afresh.
+
+use super::*;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RVLCKPT2";
+// The u64s ahead of the CSRs
+const CHECKPOINT_REGS: usize = 5 + 32 + 32 + 1;
+
+pub(super) struct Checkpointer {
+    every: u64,
+    dir: PathBuf,
+    keep: usize,
+    // Oldest first
+    saved: VecDeque<PathBuf>,
+}
+
+impl Checkpointer {
+    pub(super) fn new(every: u64, dir: PathBuf, keep: usize) -> Checkpointer {
+        Checkpointer {
+            every,
+            dir,
+            keep,
+            saved: VecDeque::new(),
+        }
+    }
+}
+
+impl RiscvCpu {
+    pub(super) fn save_checkpoint(&self, path: &Path) -> std::io::Result<()> {
+        let mut data = Vec::with_capacity(CHECKPOINT_MAGIC.len() + (CHECKPOINT_REGS + 2) * 8 + self.mem.len());
+        data.extend_from_slice(CHECKPOINT_MAGIC);
+        let csrs = self.csrs.iter().flat_map(|(&csr, &value)| [csr as u64, value]);
+        for value in [self.pc, self.instret, self.cycle, self.brk, self.mem_base]
+            .into_iter()
+            .chain(self.ixu)
+            .chain(self.fpr)
+            .chain([self.fcsr as u64, self.csrs.len() as u64])
+            .chain(csrs)
+            .chain([self.mem.len() as u64])
+        {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&self.mem);
+        fs::write(path, data)
+    }
+
+    pub(super) fn restore_checkpoint(&mut self, path: &Path) -> Result<(), String> {
+        let data = fs::read(path).map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        let bad = || format!("{} is not an rvlator checkpoint", path.display());
+        let body = data.strip_prefix(CHECKPOINT_MAGIC).ok_or_else(bad)?;
+        let mut words = body.chunks_exact(8).map(|b| u64::from_le_bytes(b.try_into().unwrap()));
+        let regs: Vec<u64> = words.by_ref().take(CHECKPOINT_REGS).collect();
+        let count = words.next();
+        let pairs: Vec<u64> = words.by_ref().take(count.unwrap_or(0).saturating_mul(2) as usize).collect();
+        let len = words.next();
+        let header = (regs.len() + pairs.len() + 2) * 8;
+        // Only CSRs this machine keeps as storage
+        let known = pairs.chunks(2).all(|pair| self.csrs.contains_key(&(pair[0] as u32)));
+        if regs.len() < CHECKPOINT_REGS
+            || count.and_then(|count| count.checked_mul(2)) != Some(pairs.len() as u64)
+            || !known
+            || len.and_then(|len| (len as usize).checked_add(header)) != Some(body.len())
+        {
+            return Err(bad());
+        }
+        [self.pc, self.instret, self.cycle, self.brk, self.mem_base] = regs[..5].try_into().unwrap();
+        self.ixu.copy_from_slice(&regs[5..37]);
+        self.fpr.copy_from_slice(&regs[37..69]);
+        self.fcsr = regs[69] as u32;
+        self.csrs.extend(pairs.chunks(2).map(|pair| (pair[0] as u32, pair[1])));
+        self.mem = body[header..].to_vec();
+        Ok(())
+    }
+
+    /// Save a checkpoint if the step that started at `retired` crossed a
+    /// --checkpoint-every boundary, dropping the oldest beyond the limit
+    pub(super) fn checkpoint(&mut self, retired: u64) {
+        let Some(mut ckpt) = self.checkpointer.take() else { return };
+        if self.instret / ckpt.every == retired / ckpt.every {
+            self.checkpointer = Some(ckpt);
+            return;
+        }
+        let path = ckpt.dir.join(format!("ckpt-{:016}.bin", self.instret));
+        if let Err(e) = self.save_checkpoint(&path) {
+            println!("{COLOR_RED}unable to write {}: {}, checkpoints stopped{COLOR_RESET}", path.display(), e);
+            return;
+        }
+        ckpt.saved.push_back(path);
+        while ckpt.saved.len() > ckpt.keep {
+            if let Some(old) = ckpt.saved.pop_front() {
+                fs::remove_file(old).ok();
+            }
+        }
+        self.checkpointer = Some(ckpt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("rvlator-ckpt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 1))
+            .inst(encode!(addi a1, zero, 2))
+            .inst(encode!(addi a2, zero, 3))
+            .cpu();
+        cpu.fusion = false;
+        cpu.checkpointer = Some(Checkpointer::new(1, dir.clone(), 2));
+        while cpu.fetch().is_ok() {
+            let retired = cpu.instret;
+            cpu.step().unwrap();
+            cpu.checkpoint(retired);
+        }
+        let mut saved: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        saved.sort();
+        assert_eq!(saved.len(), 2);
+        assert!(saved[0].ends_with("ckpt-0000000000000002.bin"));
+
+        let mut restored = ProgramBuilder::new().cpu();
+        restored.restore_checkpoint(&saved[0]).unwrap();
+        assert_eq!((restored.pc, restored.instret), (8, 2));
+        assert_eq!(restored.ixu[REG_A1], 2);
+        assert_eq!(restored.mem, cpu.mem);
+
+        fs::write(&saved[0], b"RVLCKPT2").unwrap();
+        assert!(restored.restore_checkpoint(&saved[0]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_fp_and_csr_state() {
+        let path = std::env::temp_dir().join(format!("rvlator-ckpt-fp-{}.bin", std::process::id()));
+        let mut cpu = ProgramBuilder::new().inst(encode!(addi a0, zero, 1)).cpu();
+        cpu.fpr[10] = 0xffff_ffff_0000_0000 | 1.5f32.to_bits() as u64;
+        cpu.fpr[31] = 2.5f64.to_bits();
+        // frm rdn, NX accrued
+        cpu.fcsr = 0b010_00001;
+        cpu.csrs.insert(csr::number("mscratch").unwrap(), 0x1234);
+        cpu.csrs.insert(csr::CSR_SSP, 0x8000);
+        cpu.save_checkpoint(&path).unwrap();
+
+        let mut restored = ProgramBuilder::new().cpu();
+        restored.restore_checkpoint(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.fpr, cpu.fpr);
+        assert_eq!(restored.fcsr, 0b010_00001);
+        assert_eq!(restored.csrs, cpu.csrs);
+    }
+}
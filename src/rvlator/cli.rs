@@ -0,0 +1,732 @@
+// Command line interface.
+//
+//     rvlator run IMAGE [OPTIONS]      run until the pc leaves the image
+//     rvlator disasm IMAGE             list the image
+//     rvlator analyze FILE             instruction set usage of an ELF
+//     rvlator debug IMAGE [OPTIONS]    interactive debugger
+//     rvlator torture SEED [COUNT]     fused/unfused lockstep checker
+//     rvlator batch IMAGE... [-- ARGS] run many images, report pass/fail
+//
+// IMAGE is a flat binary loaded at --mem-base.
+
+use super::*;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(name = "rvlator", version, about = "A RISC-V 64-bit emulator")]
+pub(super) struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub(super) enum Command {
+    /// Run an image until the pc leaves it
+    Run {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Dump memory [BEGIN, END) on exit in the RISCOF signature format
+        #[arg(long, num_args = 3, value_names = ["FILE", "BEGIN", "END"])]
+        signature: Option<Vec<String>>,
+        /// Rhai script with on_start/on_break/on_output/on_trap/on_exit
+        /// callbacks (needs the script feature)
+        #[arg(long, value_name = "FILE")]
+        script: Option<PathBuf>,
+    },
+    /// Disassemble an image
+    Disasm {
+        /// Flat binary image
+        image: PathBuf,
+        /// Address of the first instruction
+        #[arg(long, value_name = "ADDR", value_parser = parse_u64, default_value = "0x0")]
+        base: u64,
+    },
+    /// Report the extensions and instructions an ELF or flat image uses
+    Analyze {
+        /// ELF file, or a flat binary taken as all code
+        image: PathBuf,
+    },
+    /// Load an image and step through it interactively
+    Debug {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Narrate the datapath stages of every step
+        #[arg(long)]
+        narrate: bool,
+    },
+    // LATER: `run-user ELF ARGS...`, Linux user-mode emulation. Loads a
+    // static RISC-V Linux ELF and services its ecalls (read, write, openat,
+    // mmap, brk, exit_group, clock_gettime, ...) with host calls. Blocked
+    // on an ELF loader: images are flat binaries, so there is no entry
+    // point, program headers or initial brk to start from.
+    // LATER: The run-user initial stack: argv and envp strings, then the
+    // auxv (AT_PHDR, AT_PHENT, AT_PHNUM, AT_ENTRY, AT_PAGESZ, AT_RANDOM)
+    // laid out the way the kernel does, so libc's _start works unchanged.
+    // LATER: A guest fd table over host fds, with path syscalls resolved
+    // under --sysroot DIR and refused outside it unless allowed.
+    // LATER: Dynamically linked binaries: load PT_INTERP (ld-linux-riscv64)
+    // from the sysroot and jump to it with AT_BASE set, and add the extra
+    // syscalls the dynamic linker makes (mmap of files, mprotect, ...).
+    /// Check random programs with and without macro-op fusion in lockstep
+    Torture {
+        /// First seed
+        #[arg(value_parser = parse_u64)]
+        seed: u64,
+        /// Number of programs, from consecutive seeds
+        #[arg(default_value_t = 1)]
+        count: u64,
+    },
+    /// Run many images in parallel and report which pass
+    Batch {
+        /// Flat binary images, each run as `rvlator run -q IMAGE ARGS...`
+        #[arg(required = true)]
+        images: Vec<PathBuf>,
+        /// Images run at once, the number of host CPUs by default
+        #[arg(short, long, value_name = "N")]
+        jobs: Option<usize>,
+        /// Kill and fail a run that takes longer (ms, s or m suffix)
+        #[arg(long, value_name = "TIME", value_parser = parse_duration, default_value = "60s")]
+        timeout: std::time::Duration,
+        /// Write a JUnit XML report
+        #[arg(long, value_name = "FILE")]
+        junit: Option<PathBuf>,
+        /// Options for every run, after --
+        #[arg(last = true)]
+        run_args: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(super) enum PipelineMode {
+    Forward,
+    Noforward,
+}
+
+// LATER: Device options (--uart, --clint, --syscon, ...) once there is a
+// bus to put devices on. Memory is a single flat region today.
+// LATER: --serial pty|tcp:PORT|file:PATH for the console UART, keeping
+// rvlator's own stdout for logs. Needs the UART itself first, which needs
+// MMIO dispatch on a bus to sit on.
+// LATER: Several UARTs, each at its own base address and bound to its
+// own backend (--uart 0x10000000=stdio --uart 0x10001000=file:log.txt),
+// once devices can be mapped on a bus.
+// LATER: A SiFive PWM block (pwmcfg, pwmcount, pwmcmp0-3 and their
+// interrupts), with duty-cycle changes logged so --assert can check LED
+// patterns. Needs MMIO dispatch and an interrupt controller first.
+// LATER: An OpenCores-style I2C master (prescale, ctrl, txr/rxr, cr/sr)
+// with a trait for slaves implemented in Rust, attached by bus address
+// (24C02 EEPROM, LM75 temperature sensor) for driver tests. No MMIO yet.
+// LATER: A PCIe ECAM window with type 0 config space per device, BAR
+// sizing and MSI writes into an interrupt controller, then virtio-pci
+// transports. This needs the bus, interrupts and virtio devices first.
+// LATER: An ivshmem-like device mapping a host file (mmap'd, shared with
+// another process or rvlator instance) as a BAR, plus a doorbell register
+// raising an interrupt on the peer. Waits on the bus and interrupts.
+// LATER: --bios none, rvlator answering SBI ecalls from S-mode itself
+// (DBCN/legacy console, TIME, IPI, HSM hart_start, SRST) so a kernel boots
+// without OpenSBI. Waits on privilege modes and ecall traps.
+#[derive(Args, Clone)]
+pub(super) struct MachineArgs {
+    /// Flat binary image
+    pub image: PathBuf,
+    /// Guest memory size, the image is zero padded up to it (k/M/G suffix)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub mem_size: Option<u64>,
+    /// Guest address the image is loaded at
+    #[arg(long, value_name = "ADDR", value_parser = parse_u64, default_value = "0x0")]
+    pub mem_base: u64,
+    /// Initial pc, the load address by default
+    #[arg(long, value_name = "ADDR", value_parser = parse_u64)]
+    pub reset_vector: Option<u64>,
+    /// Trace the disassembly, -vv also dumps the registers after every instruction
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+    /// Print nothing but errors and failed assertions
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// When to print the registers: end, every-N or never. Defaults to end,
+    /// never with -q and every-1 with -vv
+    #[arg(long, value_name = "WHEN", value_parser = parse_dump_regs)]
+    pub dump_regs: Option<RegDump>,
+    /// Print the encoding fields, operands and results of every instruction
+    #[arg(long)]
+    pub explain: bool,
+    // LATER: --vector zve32x|zve64x|zve64d|v selecting ELEN and the FP
+    // element types, trapping anything outside the subset, once RVV exists
+    /// Taint source, a memory region; repeatable
+    #[arg(long, value_name = "ADDR:LEN", value_parser = parse_region)]
+    pub taint_mem: Vec<(u64, u64)>,
+    /// Taint source, the initial value of a register; repeatable
+    #[arg(long, value_name = "REG", value_parser = parse_reg)]
+    pub taint_reg: Vec<usize>,
+    /// Report when the instruction at PC reads tainted data; repeatable
+    #[arg(long, value_name = "PC", value_parser = parse_u64)]
+    pub taint_sink: Vec<u64>,
+    /// Report loads from memory that was never loaded or stored to
+    #[arg(long)]
+    pub memcheck: bool,
+    /// With --memcheck, also report reads of registers never written
+    #[arg(long, requires = "memcheck")]
+    pub memcheck_regs: bool,
+    /// Keep a shadow call stack and report returns to anywhere but the caller
+    #[arg(long)]
+    pub callcheck: bool,
+    /// Restrict ADDR:LEN to PERMS (r, w, x, s for a shadow stack, or -), faulting other accesses;
+    /// repeatable, later regions take precedence
+    #[arg(long, value_name = "ADDR:LEN:PERMS", value_parser = parse_protect)]
+    pub protect: Vec<Region>,
+    /// Stop with a stack overflow on any access to ADDR:LEN; repeatable
+    #[arg(long, value_name = "ADDR:LEN", value_parser = parse_region)]
+    pub stack_guard: Vec<(u64, u64)>,
+    /// Print every syscall with its arguments and result
+    #[arg(long)]
+    pub strace: bool,
+    /// Print every CSR access with the pc, the value read and the value written
+    #[arg(long)]
+    pub csr_trace: bool,
+    /// Give the host CPU away on every PAUSE, for guests that spin on it
+    #[arg(long)]
+    pub pause_yield: bool,
+    /// Give the host CPU away in load, compare, branch polling loops
+    #[arg(long)]
+    pub spin_yield: bool,
+    /// Guest time source: one tick per retired instruction, or the host clock
+    #[arg(long, value_name = "MODE", default_value = "host")]
+    pub timebase: TimebaseMode,
+    /// Guest timebase ticks per second
+    #[arg(long, value_name = "HZ", value_parser = parse_u64, default_value = "10000000")]
+    pub timebase_freq: u64,
+    /// Instructions per timebase tick in instret mode, N or N/TICKS
+    #[arg(long, value_name = "RATIO", value_parser = parse_ratio, default_value = "1")]
+    pub timebase_ratio: (u64, u64),
+    /// JEDEC vendor ID mvendorid reads, bank and offset
+    #[arg(long, value_name = "ID", value_parser = parse_u64, default_value = "0")]
+    pub mvendorid: u64,
+    /// Microarchitecture ID marchid reads
+    #[arg(long, value_name = "ID", value_parser = parse_u64, default_value = "0")]
+    pub marchid: u64,
+    /// Implementation version mimpid reads
+    #[arg(long, value_name = "ID", value_parser = parse_u64, default_value = "0")]
+    pub mimpid: u64,
+    /// Address of the configuration structure mconfigptr points at
+    #[arg(long, value_name = "ADDR", value_parser = parse_u64, default_value = "0")]
+    pub mconfigptr: u64,
+    /// Save a checkpoint every N retired instructions
+    #[arg(long, value_name = "N", value_parser = parse_u64, requires = "checkpoint_dir")]
+    pub checkpoint_every: Option<u64>,
+    /// Directory for --checkpoint-every files
+    #[arg(long, value_name = "DIR")]
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Number of checkpoints kept, the oldest are deleted
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    pub checkpoint_keep: usize,
+    /// Resume from a checkpoint file instead of reset
+    #[arg(long, value_name = "FILE")]
+    pub restore: Option<PathBuf>,
+    /// Seed for everything random in the emulator
+    #[arg(long, value_name = "N", value_parser = parse_u64, default_value = "0")]
+    pub seed: u64,
+    /// x1-x31 at reset: zero, pattern:VALUE or random (from --seed)
+    #[arg(long, value_name = "FILL", value_parser = parse_reset_fill, default_value = "zero")]
+    pub reset_regs: ResetFill,
+    /// Memory past the image at reset: zero, pattern:VALUE or random
+    #[arg(long, value_name = "FILL", value_parser = parse_reset_fill, default_value = "zero")]
+    pub reset_mem: ResetFill,
+    /// Stop when EXPR becomes true (REG|pc|memN[ADDR] ==|!= VALUE) or its
+    /// operand changes (OPERAND changes); repeatable
+    #[arg(long = "watch", value_name = "EXPR", value_parser = Watch::parse)]
+    pub watches: Vec<Watch>,
+    /// Stop the run when the pc reaches ADDR, before executing it, and
+    /// report the state there
+    #[arg(long, value_name = "ADDR", value_parser = parse_until)]
+    pub run_until: Option<u64>,
+    /// Stop the run when the function it starts in returns, at the ra it
+    /// starts with (from --reset-regs or --restore)
+    #[arg(long, conflicts_with = "run_until")]
+    pub run_until_return: bool,
+    /// Count loads and stores per block of SIZE bytes and list them at exit
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, num_args = 0..=1, default_missing_value = "4k")]
+    pub heatmap: Option<u64>,
+    /// Write an objdump-style listing of the image with execution counts
+    #[arg(long, value_name = "FILE")]
+    pub listing: Option<PathBuf>,
+    /// Decode the T-Head (XuanTie C906/C910) vendor extensions
+    #[arg(long)]
+    pub xthead: bool,
+    /// ISA string turning on optional extensions, e.g. rv64gc_zbc
+    #[arg(long, value_name = "ISA", value_parser = Isa::parse, default_value = "rv64gc")]
+    pub isa: Isa,
+    /// Execute fusable instruction pairs one at a time
+    #[arg(long)]
+    pub no_fusion: bool,
+    /// Write a commit log, one line per retired instruction
+    #[arg(long, value_name = "FILE")]
+    pub commit_log: Option<PathBuf>,
+    /// Copy guest stdout/stderr to FILE, lines prefixed with instret and time
+    #[arg(long, value_name = "FILE")]
+    pub console_log: Option<PathBuf>,
+    /// HTIF tohost address, a write of (STATUS << 1) | 1 ends the run
+    #[arg(long, value_name = "ADDR", value_parser = parse_u64)]
+    pub tohost: Option<u64>,
+    /// Check guest state at exit, or at PC; repeatable
+    #[arg(long = "assert", value_name = "EXPR==VALUE[@PC]", value_parser = Assertion::parse)]
+    pub assertions: Vec<Assertion>,
+    /// L1 instruction cache model
+    #[arg(long, value_name = "SIZE:WAYS:LINE:PENALTY", value_parser = parse_cache)]
+    pub icache: Option<CacheConfig>,
+    /// L1 data cache model
+    #[arg(long, value_name = "SIZE:WAYS:LINE:PENALTY", value_parser = parse_cache)]
+    pub dcache: Option<CacheConfig>,
+    /// Branch predictor model, KIND is static, bimodal or gshare
+    #[arg(long, value_name = "KIND[:PENALTY]", value_parser = parse_bpred)]
+    pub bpred: Option<BpredConfig>,
+    /// 5-stage pipeline timing model
+    #[arg(long, value_name = "MODE")]
+    pub pipeline: Option<PipelineMode>,
+    /// Per-class cycle counts, CLASS is alu|mul|div|load|store|branch|jump|system
+    #[arg(long, value_name = "CLASS=N,...", value_parser = parse_latency)]
+    pub latency: Option<LatencyTable>,
+}
+
+impl MachineArgs {
+    /// Load the image and configure a cpu. Also returns the address just
+    /// past the image, where a run stops.
+    pub(super) fn build(&self) -> Result<(RiscvCpu, u64), String> {
+        let mut image = fs::read(&self.image)
+            .map_err(|e| format!("unable to read {}: {}", self.image.display(), e))?;
+        let end = self
+            .mem_base
+            .checked_add(image.len() as u64)
+            .ok_or(format!("image doesn't fit between --mem-base {:#x} and the top of memory", self.mem_base))?;
+        if let Some(size) = self.mem_size {
+            if size < image.len() as u64 {
+                return Err(format!("image is larger than --mem-size {:#x}", size));
+            }
+            if self.mem_base.checked_add(size).is_none() {
+                return Err(format!("--mem-size {:#x} doesn't fit above --mem-base {:#x}", size, self.mem_base));
+            }
+            image.resize(size as usize, 0);
+        }
+
+        let image_len = (end - self.mem_base) as usize;
+        let mut cpu = RiscvCpu::new(image);
+        cpu.mem_base = self.mem_base;
+        cpu.brk = end;
+        cpu.pc = self.reset_vector.unwrap_or(self.mem_base);
+        cpu.fusion = !self.no_fusion;
+        cpu.explain = self.explain;
+        cpu.xthead = self.xthead;
+        cpu.isa = self.isa;
+        cpu.strace = self.strace;
+        cpu.csr_trace = self.csr_trace;
+        cpu.pause_yield = self.pause_yield;
+        cpu.spin = self.spin_yield.then(SpinDetector::default);
+        if self.mvendorid > u32::MAX as u64 {
+            return Err(format!("--mvendorid {:#x} doesn't fit in 32 bits", self.mvendorid));
+        }
+        if !self.mconfigptr.is_multiple_of(8) {
+            return Err(format!("--mconfigptr {:#x} isn't 8-byte aligned", self.mconfigptr));
+        }
+        cpu.csrs.insert(csr::CSR_MVENDORID, self.mvendorid);
+        cpu.csrs.insert(csr::CSR_MARCHID, self.marchid);
+        cpu.csrs.insert(csr::CSR_MIMPID, self.mimpid);
+        cpu.csrs.insert(csr::CSR_MCONFIGPTR, self.mconfigptr);
+        if self.listing.is_some() {
+            cpu.exec_counts = Some(BTreeMap::new());
+            cpu.listing = self.listing.clone();
+        }
+        match self.heatmap {
+            Some(0) => return Err(String::from("--heatmap block size must be non-zero")),
+            granule => cpu.heatmap = granule.map(Heatmap::new),
+        }
+        cpu.rng = Rng::new(self.seed);
+        for reg in 1..32 {
+            cpu.ixu[reg] = match self.reset_regs {
+                ResetFill::Zero => 0,
+                ResetFill::Pattern(value) => value,
+                ResetFill::Random => cpu.rng.next(),
+            };
+        }
+        // Filled in aligned 64-bit words, around the image
+        let first_word = image_len / 8 * 8;
+        for (i, chunk) in cpu.mem[first_word..].chunks_mut(8).enumerate() {
+            let value = match self.reset_mem {
+                ResetFill::Zero => 0,
+                ResetFill::Pattern(value) => value,
+                ResetFill::Random => cpu.rng.next(),
+            };
+            let skip = match i {
+                0 => image_len - first_word,
+                _ => 0,
+            };
+            let len = chunk.len();
+            chunk[skip..].copy_from_slice(&value.to_le_bytes()[skip..len]);
+        }
+        if let (Some(every), Some(dir)) = (self.checkpoint_every, &self.checkpoint_dir) {
+            if every == 0 {
+                return Err(String::from("--checkpoint-every must be non-zero"));
+            }
+            fs::create_dir_all(dir).map_err(|e| format!("unable to create {}: {}", dir.display(), e))?;
+            cpu.checkpointer = Some(Checkpointer::new(every, dir.clone(), self.checkpoint_keep));
+        }
+        if let Some(path) = &self.restore {
+            cpu.restore_checkpoint(path)?;
+        }
+        if self.timebase_freq == 0 {
+            return Err(String::from("--timebase-freq must be non-zero"));
+        }
+        cpu.timebase = Timebase::new(self.timebase, self.timebase_freq);
+        cpu.timebase.ratio = self.timebase_ratio;
+        if self.memcheck {
+            cpu.memcheck = Some(MemCheck::new(self.mem_base, cpu.mem.len(), image_len, self.memcheck_regs));
+        }
+        if self.callcheck {
+            cpu.callstack = Some(CallStack::default());
+        }
+        if !self.protect.is_empty() || !self.stack_guard.is_empty() {
+            let mut protect = Protect::default();
+            for &region in &self.protect {
+                protect.add_region(region);
+            }
+            for &(start, len) in &self.stack_guard {
+                protect.add_guard(start, len);
+            }
+            cpu.protect = Some(protect);
+        }
+        if !self.taint_mem.is_empty() || !self.taint_reg.is_empty() {
+            let mut taint = Taint::default();
+            for &(addr, len) in &self.taint_mem {
+                taint.taint_mem(addr, len);
+            }
+            for &reg in &self.taint_reg {
+                taint.taint_reg(reg);
+            }
+            for &pc in &self.taint_sink {
+                taint.add_sink(pc);
+            }
+            cpu.taint = Some(taint);
+        }
+        cpu.verbosity = match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Summary,
+            (false, _) => Verbosity::Trace,
+        };
+        cpu.dump_regs = self.dump_regs.unwrap_or(match (self.quiet, self.verbose) {
+            (true, _) => RegDump::Never,
+            (false, 0 | 1) => RegDump::End,
+            (false, _) => RegDump::Every(1),
+        });
+        if let Some(path) = &self.commit_log {
+            let file = fs::File::create(path)
+                .map_err(|e| format!("unable to create {}: {}", path.display(), e))?;
+            cpu.commit_log = Some(Box::new(std::io::BufWriter::new(file)));
+        }
+        if let Some(path) = &self.console_log {
+            let file = fs::File::create(path)
+                .map_err(|e| format!("unable to create {}: {}", path.display(), e))?;
+            cpu.console_log = Some(syscall::ConsoleLog::new(Box::new(file)));
+        }
+        cpu.tohost = self.tohost;
+        cpu.assertions = self.assertions.clone();
+        cpu.icache = self.icache.map(Cache::new);
+        cpu.dcache = self.dcache.map(Cache::new);
+        cpu.bpred = self.bpred.map(BranchPredictor::new);
+        cpu.pipe = self.pipeline.map(|mode| Pipeline::new(matches!(mode, PipelineMode::Forward)));
+        cpu.latency = self.latency;
+        cpu.run_until = match self.run_until_return {
+            true if cpu.ixu[REG_RA] == cpu.pc => {
+                return Err(String::from("--run-until-return needs ra to hold the caller's return address, not the pc"));
+            }
+            true => Some(cpu.ixu[REG_RA]),
+            false => self.run_until,
+        };
+        cpu.watches = self.watches.clone();
+        cpu.arm_watches();
+        Ok((cpu, end))
+    }
+}
+
+/// Print a usage error in clap's format and exit
+pub(super) fn usage_error(msg: String) -> ! {
+    Cli::command().error(clap::error::ErrorKind::InvalidValue, msg).exit()
+}
+
+fn parse_u64(s: &str) -> Result<u64, String> {
+    parse_addr(s).ok_or(format!("expected a decimal or 0x prefixed number, got {}", s))
+}
+
+/// Time with an ms, s or m suffix, seconds without one
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let (num, millis) = match s.strip_suffix("ms") {
+        Some(num) => (num, 1),
+        None => match s.strip_suffix('s') {
+            Some(num) => (num, 1000),
+            None => match s.strip_suffix('m') {
+                Some(num) => (num, 60_000),
+                None => (s, 1000),
+            },
+        },
+    };
+    parse_u64(num)?
+        .checked_mul(millis)
+        .map(std::time::Duration::from_millis)
+        .ok_or(format!("time too large: {}", s))
+}
+
+/// Size with an optional k, M or G suffix
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, shift) = match s.strip_suffix(['k', 'K']) {
+        Some(num) => (num, 10),
+        None => match s.strip_suffix('M') {
+            Some(num) => (num, 20),
+            None => match s.strip_suffix('G') {
+                Some(num) => (num, 30),
+                None => (s, 0),
+            },
+        },
+    };
+    parse_u64(num)?
+        .checked_mul(1 << shift)
+        .ok_or(format!("size too large: {}", s))
+}
+
+fn parse_region(s: &str) -> Result<(u64, u64), String> {
+    let (addr, len) = s.split_once(':').ok_or(String::from("region must be ADDR:LEN"))?;
+    Ok((parse_u64(addr)?, parse_size(len)?))
+}
+
+fn parse_protect(s: &str) -> Result<Region, String> {
+    let (region, perms) = s.rsplit_once(':').ok_or(String::from("region must be ADDR:LEN:PERMS"))?;
+    let (start, len) = parse_region(region)?;
+    if perms.is_empty() || !perms.chars().all(|c| "rwxs-".contains(c)) {
+        return Err(format!("permissions must be made of r, w, x, s or -, got {}", perms));
+    }
+    Ok(Region {
+        start,
+        len,
+        read: perms.contains('r'),
+        write: perms.contains('w'),
+        exec: perms.contains('x'),
+        shadow: perms.contains('s'),
+    })
+}
+
+/// N or N/M, both non-zero
+fn parse_ratio(s: &str) -> Result<(u64, u64), String> {
+    let (num, den) = s.split_once('/').unwrap_or((s, "1"));
+    match (parse_u64(num)?, parse_u64(den)?) {
+        (0, _) | (_, 0) => Err(format!("ratio must be non-zero, got {}", s)),
+        ratio => Ok(ratio),
+    }
+}
+
+/// An address, or sym:NAME for a symbol
+fn parse_until(s: &str) -> Result<u64, String> {
+    match s.strip_prefix("sym:") {
+        // LATER: Look NAME up in the ELF symbol table once images can be ELF
+        Some(name) => Err(format!("no symbol table to find {} in, IMAGE is a flat binary", name)),
+        None => parse_u64(s),
+    }
+}
+
+fn parse_reset_fill(s: &str) -> Result<ResetFill, String> {
+    match s {
+        "zero" => Ok(ResetFill::Zero),
+        "random" => Ok(ResetFill::Random),
+        _ => match s.strip_prefix("pattern:").and_then(parse_addr) {
+            Some(value) => Ok(ResetFill::Pattern(value)),
+            None => Err(String::from("reset fill must be zero, pattern:VALUE or random")),
+        },
+    }
+}
+
+fn parse_reg(s: &str) -> Result<usize, String> {
+    regnum(s).ok_or(format!("unknown register {}", s))
+}
+
+fn parse_dump_regs(s: &str) -> Result<RegDump, String> {
+    match s {
+        "end" => Ok(RegDump::End),
+        "never" => Ok(RegDump::Never),
+        _ => match s.strip_prefix("every-").and_then(|n| n.parse().ok()) {
+            Some(0) | None => Err(String::from("dump-regs must be end, every-N (N > 0) or never")),
+            Some(n) => Ok(RegDump::Every(n)),
+        },
+    }
+}
+
+fn parse_cache(s: &str) -> Result<CacheConfig, String> {
+    CacheConfig::parse(s).ok_or(String::from("cache config must be SIZE:WAYS:LINE:PENALTY"))
+}
+
+fn parse_bpred(s: &str) -> Result<BpredConfig, String> {
+    BpredConfig::parse(s).ok_or(String::from("branch predictor must be static|bimodal|gshare[:PENALTY]"))
+}
+
+fn parse_latency(s: &str) -> Result<LatencyTable, String> {
+    LatencyTable::parse(s).ok_or(String::from(
+        "latency must be CLASS=N,... with CLASS alu|mul|div|load|store|branch|jump|system",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_parse() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from([
+            "rvlator", "run", "a.bin", "--mem-size", "64k", "--mem-base", "0x80000000",
+            "--pipeline", "noforward", "--assert", "a0==1", "--assert", "a1==2",
+            "--signature", "sig", "0x10", "0x20",
+        ])
+        .unwrap();
+        let Command::Run { machine, signature, .. } = cli.command else { panic!() };
+        assert_eq!(machine.mem_size, Some(64 * 1024));
+        assert_eq!(machine.mem_base, 0x80000000);
+        assert_eq!(machine.assertions.len(), 2);
+        assert_eq!(signature.unwrap(), ["sig", "0x10", "0x20"]);
+
+        assert!(Cli::try_parse_from(["rvlator", "run", "a.bin", "--bpred", "perceptron"]).is_err());
+        assert!(Cli::try_parse_from(["rvlator", "run", "a.bin", "--assert", "a0=1"]).is_err());
+        assert!(Cli::try_parse_from(["rvlator", "a.bin"]).is_err());
+        assert!(Cli::try_parse_from(["rvlator", "run", "a.bin", "-q", "-v"]).is_err());
+        let cli = Cli::try_parse_from(["rvlator", "run", "a.bin", "-vv"]).unwrap();
+        let Command::Run { machine, .. } = cli.command else { panic!() };
+        assert_eq!(machine.verbose, 2);
+    }
+
+    #[test]
+    fn test_build_reset_fill() {
+        let image = std::env::temp_dir().join(format!("rvlator-seed-{}.bin", std::process::id()));
+        fs::write(&image, [0x13, 0, 0, 0]).unwrap();
+        let build = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["rvlator", "run", image.to_str().unwrap(), "--mem-size", "16"], args].concat());
+            let Command::Run { machine, .. } = cli.unwrap().command else { panic!() };
+            machine.build().unwrap().0
+        };
+        let cpu = build(&["--reset-regs", "random", "--reset-mem", "random", "--seed", "7"]);
+        assert_eq!(cpu.ixu[REG_ZERO], 0);
+        assert_ne!(cpu.ixu[REG_A0], 0);
+        assert_eq!(cpu.mem[..4], [0x13, 0, 0, 0]);
+        let again = build(&["--reset-regs", "random", "--reset-mem", "random", "--seed", "7"]);
+        assert_eq!((again.ixu, &again.mem), (cpu.ixu, &cpu.mem));
+        assert_ne!(build(&["--reset-regs", "random", "--seed", "8"]).ixu, cpu.ixu);
+
+        let cpu = build(&["--reset-regs", "pattern:0xdeadbeef", "--reset-mem", "pattern:0x1122334455667788"]);
+        assert_eq!(cpu.ixu[REG_T6], 0xdeadbeef);
+        assert_eq!(cpu.read_mem(4, 4), Some(0x11223344));
+        assert_eq!(cpu.read_mem(8, 8), Some(0x1122334455667788));
+        assert!(parse_reset_fill("pattern:").is_err());
+        fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn test_build_mem_base() {
+        let image = std::env::temp_dir().join(format!("rvlator-base-{}.bin", std::process::id()));
+        fs::write(&image, [0x13, 0, 0, 0]).unwrap();
+        let build = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["rvlator", "run", image.to_str().unwrap()], args].concat());
+            let Command::Run { machine, .. } = cli.unwrap().command else { panic!() };
+            machine.build().map(|(_, end)| end)
+        };
+        assert_eq!(build(&["--mem-base", "0xfffffffffffff000"]), Ok(0xfffffffffffff004));
+        // Memory that would wrap past the top of the address space
+        assert!(build(&["--mem-base", "0xfffffffffffffffe"]).is_err());
+        assert!(build(&["--mem-base", "0xffffffffffff0000", "--mem-size", "1M"]).is_err());
+        fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn test_build_identity() {
+        let image = std::env::temp_dir().join(format!("rvlator-identity-{}.bin", std::process::id()));
+        fs::write(&image, [0x13, 0, 0, 0]).unwrap();
+        let build = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["rvlator", "run", image.to_str().unwrap()], args].concat());
+            let Command::Run { machine, .. } = cli.unwrap().command else { panic!() };
+            machine.build().map(|(cpu, _)| cpu)
+        };
+        let cpu = build(&["--mvendorid", "0x489", "--marchid", "0x8000000000000007", "--mconfigptr", "0x1000"]).unwrap();
+        assert_eq!(cpu.csr_read(csr::CSR_MVENDORID), Some(0x489));
+        assert_eq!(cpu.csr_read(csr::CSR_MARCHID), Some(0x8000000000000007));
+        assert_eq!(cpu.csr_read(csr::CSR_MIMPID), Some(0));
+        assert_eq!(cpu.csr_read(csr::CSR_MCONFIGPTR), Some(0x1000));
+        assert!(build(&["--mvendorid", "0x100000000"]).is_err());
+        assert!(build(&["--mconfigptr", "0x1004"]).is_err());
+        fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn test_build_run_until_return() {
+        let image = std::env::temp_dir().join(format!("rvlator-return-{}.bin", std::process::id()));
+        fs::write(&image, [0x13, 0, 0, 0]).unwrap();
+        let build = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["rvlator", "run", image.to_str().unwrap(), "--run-until-return"], args].concat());
+            let Command::Run { machine, .. } = cli.unwrap().command else { panic!() };
+            machine.build().map(|(cpu, _)| cpu.run_until)
+        };
+        assert_eq!(build(&["--reset-regs", "pattern:0x40"]), Ok(Some(0x40)));
+        assert!(build(&[]).is_err());
+        assert!(Cli::try_parse_from(["rvlator", "run", "a.bin", "--run-until-return", "--run-until", "0x10"]).is_err());
+        fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn test_parse_dump_regs() {
+        assert_eq!(parse_dump_regs("end"), Ok(RegDump::End));
+        assert_eq!(parse_dump_regs("never"), Ok(RegDump::Never));
+        assert_eq!(parse_dump_regs("every-100"), Ok(RegDump::Every(100)));
+        assert!(parse_dump_regs("every-0").is_err());
+        assert!(parse_dump_regs("every").is_err());
+    }
+
+    #[test]
+    fn test_parse_region() {
+        assert_eq!(parse_region("0x1000:4k"), Ok((0x1000, 4096)));
+        assert!(parse_region("0x1000").is_err());
+        let region = parse_protect("0x1000:4k:rx").unwrap();
+        assert!(region.read && region.exec && !region.write);
+        assert!(parse_protect("0x1000:4k").is_err());
+        assert!(parse_protect("0x1000:4k:rq").is_err());
+        assert_eq!(parse_reg("fp"), Ok(REG_S0));
+        assert!(parse_reg("x32").is_err());
+    }
+
+    #[test]
+    fn test_parse_ratio() {
+        assert_eq!(parse_ratio("4"), Ok((4, 1)));
+        assert_eq!(parse_ratio("1/8"), Ok((1, 8)));
+        assert!(parse_ratio("0").is_err());
+        assert!(parse_ratio("1/x").is_err());
+    }
+
+    #[test]
+    fn test_parse_until() {
+        assert_eq!(parse_until("0x80000100"), Ok(0x80000100));
+        assert!(parse_until("sym:panic_handler").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("0x1000"), Ok(4096));
+        assert_eq!(parse_size("16K"), Ok(16 * 1024));
+        assert_eq!(parse_size("2M"), Ok(2 << 20));
+        assert!(parse_size("2T").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        use std::time::Duration;
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("1h").is_err());
+        assert!(Cli::try_parse_from(["rvlator", "batch", "a.bin", "b.bin", "--", "--tohost", "0x1000"]).is_ok());
+        assert!(Cli::try_parse_from(["rvlator", "batch"]).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+// C extension: 16-bit instructions and their 32-bit expansions.
+//
+// A parcel whose low two bits aren't 11 is a compressed instruction. It
+// executes as the 32-bit instruction it expands to, with the pc stepping
+// by 2 and jumps linking pc + 2. RV64C quadrants:
+//
+//     00  c.addi4spn c.fld c.lw c.ld c.fsd c.sw c.sd
+//     01  c.addi c.addiw c.li c.addi16sp c.lui c.srli c.srai c.andi
+//         c.sub c.xor c.or c.and c.subw c.addw c.j c.beqz c.bnez
+//     10  c.slli c.fldsp c.lwsp c.ldsp c.jr c.mv c.ebreak c.jalr c.add
+//         c.fsdsp c.swsp c.sdsp
+//
+// Zicfiss adds c.sspush x1 and c.sspopchk x5 in the reserved c.lui x1,0 and
+// c.lui x5,0 (c.mop.1, c.mop.5), expanding to sspush and sspopchk.
+//
+// HINTs (rd = zero, c.srli/c.srai by 0, ...) expand like any other and
+// do nothing. Reserved encodings, including the all-zero parcel, don't
+// expand.
+
+use super::*;
+
+fn enc_i(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn enc_s(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+    (imm & 0xfe0) << 20 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+fn enc_r(opcode: u32, funct7: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn enc_b(funct3: u32, rs1: u32, rs2: u32, offset: u32) -> u32 {
+    (offset >> 12 & 1) << 31
+        | (offset >> 5 & 0x3f) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | (offset >> 1 & 0xf) << 8
+        | (offset >> 11 & 1) << 7
+        | OPCODE_BRANCH
+}
+
+fn enc_j(rd: u32, offset: u32) -> u32 {
+    (offset >> 20 & 1) << 31
+        | (offset >> 1 & 0x3ff) << 21
+        | (offset >> 11 & 1) << 20
+        | (offset >> 12 & 0xff) << 12
+        | rd << 7
+        | OPCODE_JAL
+}
+
+/// Bits `hi..=lo` of the parcel, moved up to bit `to`
+fn bits(c: u32, hi: u32, lo: u32, to: u32) -> u32 {
+    (c >> lo & ((1 << (hi - lo + 1)) - 1)) << to
+}
+
+/// Sign extend the low `width` bits to 32
+fn sext(value: u32, width: u32) -> u32 {
+    ((value << (32 - width)) as i32 >> (32 - width)) as u32
+}
+
+/// The mnemonic and 32-bit expansion of a 16-bit instruction, None when
+/// reserved or not a 16-bit instruction at all
+pub(super) fn expand(c: u32) -> Option<(&'static str, u32)> {
+    if c & 0b11 == 0b11 || c > 0xffff {
+        return None;
+    }
+    let funct3 = c >> 13;
+    // Full register fields and the x8-x15 ones of the CIW/CL/CS/CA/CB formats
+    let rd = bits(c, 11, 7, 0);
+    let rs2 = bits(c, 6, 2, 0);
+    let rd_ = 8 + bits(c, 4, 2, 0);
+    let rs1_ = 8 + bits(c, 9, 7, 0);
+    // CI immediate, imm[5] in c[12] and imm[4:0] in c[6:2]
+    let imm6 = sext(bits(c, 12, 12, 5) | bits(c, 6, 2, 0), 6);
+    let shamt = bits(c, 12, 12, 5) | bits(c, 6, 2, 0);
+    // CL/CS offsets scaled by 4 and by 8
+    let uimm_w = bits(c, 12, 10, 3) | bits(c, 6, 6, 2) | bits(c, 5, 5, 6);
+    let uimm_d = bits(c, 12, 10, 3) | bits(c, 6, 5, 6);
+    let expansion = match (c & 0b11, funct3) {
+        (0b00, 0b000) => {
+            let nzuimm = bits(c, 12, 11, 4) | bits(c, 10, 7, 6) | bits(c, 6, 6, 2) | bits(c, 5, 5, 3);
+            match nzuimm {
+                0 => return None,
+                _ => ("c.addi4spn", enc_i(OPCODE_OP_IMM, 0b000, rd_, REG_SP as u32, nzuimm)),
+            }
+        }
+        (0b00, 0b001) => ("c.fld", enc_i(OPCODE_LOAD_FP, 0b011, rd_, rs1_, uimm_d)),
+        (0b00, 0b010) => ("c.lw", enc_i(OPCODE_LOAD, 0b010, rd_, rs1_, uimm_w)),
+        (0b00, 0b011) => ("c.ld", enc_i(OPCODE_LOAD, 0b011, rd_, rs1_, uimm_d)),
+        (0b00, 0b101) => ("c.fsd", enc_s(OPCODE_STORE_FP, 0b011, rs1_, rd_, uimm_d)),
+        (0b00, 0b110) => ("c.sw", enc_s(OPCODE_STORE, 0b010, rs1_, rd_, uimm_w)),
+        (0b00, 0b111) => ("c.sd", enc_s(OPCODE_STORE, 0b011, rs1_, rd_, uimm_d)),
+        (0b01, 0b000) if rd == 0 => ("c.nop", enc_i(OPCODE_OP_IMM, 0b000, 0, 0, imm6)),
+        (0b01, 0b000) => ("c.addi", enc_i(OPCODE_OP_IMM, 0b000, rd, rd, imm6)),
+        (0b01, 0b001) if rd != 0 => ("c.addiw", enc_i(OPCODE_OP_IMM_32, 0b000, rd, rd, imm6)),
+        (0b01, 0b010) => ("c.li", enc_i(OPCODE_OP_IMM, 0b000, rd, REG_ZERO as u32, imm6)),
+        (0b01, 0b011) if rd == REG_SP as u32 => {
+            let nzimm = sext(bits(c, 12, 12, 9) | bits(c, 6, 6, 4) | bits(c, 5, 5, 6) | bits(c, 4, 3, 7) | bits(c, 2, 2, 5), 10);
+            match nzimm {
+                0 => return None,
+                _ => ("c.addi16sp", enc_i(OPCODE_OP_IMM, 0b000, rd, rd, nzimm)),
+            }
+        }
+        (0b01, 0b011) => match imm6 {
+            0 if rd == REG_RA as u32 => ("c.sspush", 0xce104073),
+            0 if rd == REG_T0 as u32 => ("c.sspopchk", 0xcdc2c073),
+            0 => return None,
+            _ => ("c.lui", (imm6 & 0xfffff) << 12 | rd << 7 | OPCODE_LUI),
+        },
+        (0b01, 0b100) => match bits(c, 11, 10, 0) {
+            0b00 => ("c.srli", enc_i(OPCODE_OP_IMM, 0b101, rs1_, rs1_, shamt)),
+            0b01 => ("c.srai", enc_i(OPCODE_OP_IMM, 0b101, rs1_, rs1_, 0x400 | shamt)),
+            0b10 => ("c.andi", enc_i(OPCODE_OP_IMM, 0b111, rs1_, rs1_, imm6)),
+            _ => match (bits(c, 12, 12, 0), bits(c, 6, 5, 0)) {
+                (0, 0b00) => ("c.sub", enc_r(OPCODE_OP, 0b0100000, 0b000, rs1_, rs1_, rd_)),
+                (0, 0b01) => ("c.xor", enc_r(OPCODE_OP, 0b0000000, 0b100, rs1_, rs1_, rd_)),
+                (0, 0b10) => ("c.or", enc_r(OPCODE_OP, 0b0000000, 0b110, rs1_, rs1_, rd_)),
+                (0, _) => ("c.and", enc_r(OPCODE_OP, 0b0000000, 0b111, rs1_, rs1_, rd_)),
+                (_, 0b00) => ("c.subw", enc_r(OPCODE_OP_32, 0b0100000, 0b000, rs1_, rs1_, rd_)),
+                (_, 0b01) => ("c.addw", enc_r(OPCODE_OP_32, 0b0000000, 0b000, rs1_, rs1_, rd_)),
+                _ => return None,
+            },
+        },
+        (0b01, 0b101) => {
+            let offset = bits(c, 12, 12, 11)
+                | bits(c, 11, 11, 4)
+                | bits(c, 10, 9, 8)
+                | bits(c, 8, 8, 10)
+                | bits(c, 7, 7, 6)
+                | bits(c, 6, 6, 7)
+                | bits(c, 5, 3, 1)
+                | bits(c, 2, 2, 5);
+            ("c.j", enc_j(REG_ZERO as u32, sext(offset, 12)))
+        }
+        (0b01, 0b110 | 0b111) => {
+            let offset = sext(bits(c, 12, 12, 8) | bits(c, 11, 10, 3) | bits(c, 6, 5, 6) | bits(c, 4, 3, 1) | bits(c, 2, 2, 5), 9);
+            match funct3 {
+                0b110 => ("c.beqz", enc_b(0b000, rs1_, REG_ZERO as u32, offset)),
+                _ => ("c.bnez", enc_b(0b001, rs1_, REG_ZERO as u32, offset)),
+            }
+        }
+        (0b10, 0b000) => ("c.slli", enc_i(OPCODE_OP_IMM, 0b001, rd, rd, shamt)),
+        (0b10, 0b001) => {
+            let uimm = bits(c, 12, 12, 5) | bits(c, 6, 5, 3) | bits(c, 4, 2, 6);
+            ("c.fldsp", enc_i(OPCODE_LOAD_FP, 0b011, rd, REG_SP as u32, uimm))
+        }
+        (0b10, 0b010) if rd != 0 => {
+            let uimm = bits(c, 12, 12, 5) | bits(c, 6, 4, 2) | bits(c, 3, 2, 6);
+            ("c.lwsp", enc_i(OPCODE_LOAD, 0b010, rd, REG_SP as u32, uimm))
+        }
+        (0b10, 0b011) if rd != 0 => {
+            let uimm = bits(c, 12, 12, 5) | bits(c, 6, 5, 3) | bits(c, 4, 2, 6);
+            ("c.ldsp", enc_i(OPCODE_LOAD, 0b011, rd, REG_SP as u32, uimm))
+        }
+        (0b10, 0b100) => match (bits(c, 12, 12, 0), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => ("c.jr", enc_i(OPCODE_JALR, 0b000, REG_ZERO as u32, rd, 0)),
+            (0, _, _) => ("c.mv", enc_r(OPCODE_OP, 0, 0b000, rd, REG_ZERO as u32, rs2)),
+            (_, 0, 0) => ("c.ebreak", INST_EBREAK),
+            (_, _, 0) => ("c.jalr", enc_i(OPCODE_JALR, 0b000, REG_RA as u32, rd, 0)),
+            _ => ("c.add", enc_r(OPCODE_OP, 0, 0b000, rd, rd, rs2)),
+        },
+        (0b10, 0b101) => {
+            let uimm = bits(c, 12, 10, 3) | bits(c, 9, 7, 6);
+            ("c.fsdsp", enc_s(OPCODE_STORE_FP, 0b011, REG_SP as u32, rs2, uimm))
+        }
+        (0b10, 0b110) => {
+            let uimm = bits(c, 12, 9, 2) | bits(c, 8, 7, 6);
+            ("c.swsp", enc_s(OPCODE_STORE, 0b010, REG_SP as u32, rs2, uimm))
+        }
+        (0b10, 0b111) => {
+            let uimm = bits(c, 12, 10, 3) | bits(c, 9, 7, 6);
+            ("c.sdsp", enc_s(OPCODE_STORE, 0b011, REG_SP as u32, rs2, uimm))
+        }
+        _ => return None,
+    };
+    Some(expansion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(c: u32) -> String {
+        let (name, inst) = expand(c).unwrap();
+        format!("{} = {}", name, disasm(inst))
+    }
+
+    #[test]
+    fn test_expand() {
+        // Encodings from the assembler
+        assert_eq!(expanded(0x0040), "c.addi4spn = addi s0,sp,4");
+        assert_eq!(expanded(0x6580), "c.ld = ld s0,8(a1)");
+        assert_eq!(expanded(0xc1c0), "c.sw = sw s0,4(a1)");
+        assert_eq!(expanded(0x0001), "c.nop = addi z0,z0,0");
+        assert_eq!(expanded(0x157d), "c.addi = addi a0,a0,-1");
+        assert_eq!(expanded(0x2505), "c.addiw = addiw a0,a0,1");
+        assert_eq!(expanded(0x4501), "c.li = addi a0,z0,0");
+        assert_eq!(expanded(0x7139), "c.addi16sp = addi sp,sp,-64");
+        assert_eq!(expanded(0x757d), "c.lui = lui a0,-1");
+        assert_eq!(expanded(0x8105), "c.srli = srli a0,a0,1");
+        assert_eq!(expanded(0x957d), "c.srai = srai a0,a0,63");
+        assert_eq!(expanded(0x8905), "c.andi = andi a0,a0,1");
+        assert_eq!(expanded(0x8d0d), "c.sub = sub a0,a0,a1");
+        assert_eq!(expanded(0x9d2d), "c.addw = addw a0,a0,a1");
+        assert_eq!(expanded(0xbffd), "c.j = jal z0,-2");
+        assert_eq!(expanded(0xc119), "c.beqz = beq a0,z0,6");
+        assert_eq!(expanded(0xfd6d), "c.bnez = bne a0,z0,-6");
+        assert_eq!(expanded(0x0506), "c.slli = slli a0,a0,1");
+        assert_eq!(expanded(0x6522), "c.ldsp = ld a0,8(sp)");
+        assert_eq!(expanded(0x4512), "c.lwsp = lw a0,4(sp)");
+        assert_eq!(expanded(0x8082), "c.jr = jalr z0,0(ra)");
+        assert_eq!(expanded(0x852e), "c.mv = add a0,z0,a1");
+        assert_eq!(expanded(0x9002), "c.ebreak = ebreak");
+        assert_eq!(expanded(0x9502), "c.jalr = jalr ra,0(a0)");
+        assert_eq!(expanded(0x952e), "c.add = add a0,a0,a1");
+        assert_eq!(expanded(0xe406), "c.sdsp = sd ra,8(sp)");
+        assert_eq!(expanded(0xc22a), "c.swsp = sw a0,4(sp)");
+        assert_eq!(expanded(0x6081), "c.sspush = sspush ra");
+        assert_eq!(expanded(0x6281), "c.sspopchk = sspopchk t0");
+        // Reserved: all zero, c.addi16sp 0, c.lui 0, c.jr zero, c.lwsp zero
+        for c in [0x0000, 0x6101, 0x6501, 0x8002, 0x4002] {
+            assert_eq!(expand(c), None, "{:#06x}", c);
+        }
+        assert_eq!(expand(0x00000513), None);
+    }
+}
@@ -0,0 +1,300 @@
+// Scalar cryptography, the RV64 AES and SHA-2 instructions in OP and
+// OP-IMM, each extension turned on by its name in --isa:
+//
+//     Zkne    aes64es aes64esm                    ShiftRows, SubBytes (, MixColumns)
+//             aes64ks1i aes64ks2                  key schedule, shared with Zknd
+//     Zknd    aes64ds aes64dsm aes64im            the inverse steps
+//     Zknh    sha256sig0 sha256sig1 sha256sum0 sha256sum1
+//             sha512sig0 sha512sig1 sha512sum0 sha512sum1
+//
+// An AES round works on the 128-bit state split over two registers, the
+// low half in rs1 and the high half in rs2, and produces one half of the
+// next state; swapping rs1 and rs2 gives the other half. Bytes are in
+// column order, byte i of the state is row i % 4 of column i / 4.
+
+use super::*;
+
+// Round constants of the key schedule, aes64ks1i's rnum 0-9
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+/// Multiply in GF(2^8) modulo the AES polynomial x^8 + x^4 + x^3 + x + 1
+const fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+/// SubBytes: the multiplicative inverse (a^254) through the affine map
+const fn sbox() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let (mut inverse, mut n) = (1u8, 0);
+        while n < 254 {
+            inverse = gmul(inverse, i as u8);
+            n += 1;
+        }
+        let b = if i == 0 { 0 } else { inverse };
+        table[i] = b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63;
+        i += 1;
+    }
+    table
+}
+
+const fn inverse_sbox() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[SBOX[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+const SBOX: [u8; 256] = sbox();
+const INV_SBOX: [u8; 256] = inverse_sbox();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum CryptoOp {
+    // Decrypt, mix columns
+    AesRound(bool, bool),
+    AesIm,
+    AesKs1i(u32),
+    AesKs2,
+    // imm[1:0]: sum0, sum1, sig0, sig1
+    Sha256(u32),
+    Sha512(u32),
+}
+
+impl CryptoOp {
+    /// Extension and mnemonic
+    pub(super) fn names(&self) -> (&'static str, &'static str) {
+        match self {
+            CryptoOp::AesRound(false, mix) => ("Zkne", if *mix { "aes64esm" } else { "aes64es" }),
+            CryptoOp::AesRound(true, mix) => ("Zknd", if *mix { "aes64dsm" } else { "aes64ds" }),
+            CryptoOp::AesIm => ("Zknd", "aes64im"),
+            CryptoOp::AesKs1i(_) => ("Zkne", "aes64ks1i"),
+            CryptoOp::AesKs2 => ("Zkne", "aes64ks2"),
+            CryptoOp::Sha256(n) => ("Zknh", ["sha256sum0", "sha256sum1", "sha256sig0", "sha256sig1"][*n as usize]),
+            CryptoOp::Sha512(n) => ("Zknh", ["sha512sum0", "sha512sum1", "sha512sig0", "sha512sig1"][*n as usize]),
+        }
+    }
+
+    /// rs2 is a source register
+    pub(super) fn reads_rs2(&self) -> bool {
+        matches!(self, CryptoOp::AesRound(..) | CryptoOp::AesKs2)
+    }
+}
+
+pub(super) fn decode(inst: u32) -> Option<CryptoOp> {
+    let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let funct7 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+    let imm12 = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+    let op = match (opcode, funct3) {
+        (OPCODE_OP, 0b000) => match funct7 {
+            0b0011001 | 0b0011011 | 0b0011101 | 0b0011111 => CryptoOp::AesRound(funct7 & 0b100 != 0, funct7 & 0b10 != 0),
+            0b0111111 => CryptoOp::AesKs2,
+            _ => return None,
+        },
+        (OPCODE_OP_IMM, 0b001) => match imm12 {
+            0x300 => CryptoOp::AesIm,
+            // rnum 0xb-0xf are reserved
+            0x310..=0x31a => CryptoOp::AesKs1i(imm12 & 0xf),
+            0x100..=0x103 => CryptoOp::Sha256(imm12 & 0b11),
+            0x104..=0x107 => CryptoOp::Sha512(imm12 & 0b11),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Assembly text of a crypto instruction, None for anything else
+pub(super) fn disasm(inst: u32) -> Option<String> {
+    let op = decode(inst)?;
+    let rd = REGNAME[getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize];
+    let rs1 = REGNAME[getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize];
+    let rs2 = REGNAME[getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize];
+    let (_, name) = op.names();
+    let text = match op {
+        CryptoOp::AesKs1i(rnum) => format!("{} {},{},{}", name, rd, rs1, rnum),
+        _ if op.reads_rs2() => format!("{} {},{},{}", name, rd, rs1, rs2),
+        _ => format!("{} {},{}", name, rd, rs1),
+    };
+    Some(text)
+}
+
+/// MixColumns (or InvMixColumns) on one column, row 0 in the low byte
+fn mix_column(column: u32, inverse: bool) -> u32 {
+    let s = column.to_le_bytes();
+    let m = match inverse {
+        false => [2, 3, 1, 1],
+        true => [14, 11, 13, 9],
+    };
+    // Each row is the one above rotated right
+    let row = |r: usize| (0..4).fold(0, |acc, i| acc ^ gmul(s[i], m[(i + 4 - r) % 4]));
+    u32::from_le_bytes([row(0), row(1), row(2), row(3)])
+}
+
+/// Both columns of a half state
+fn mix_columns(half: u64, inverse: bool) -> u64 {
+    mix_column(half as u32, inverse) as u64 | (mix_column((half >> 32) as u32, inverse) as u64) << 32
+}
+
+/// One AES round on the state rs2:rs1, the low half of ShiftRows (or
+/// InvShiftRows) and SubBytes, then MixColumns if `mix`
+fn aes_round(rs1: u64, rs2: u64, decrypt: bool, mix: bool) -> u64 {
+    let state = (rs2 as u128) << 64 | rs1 as u128;
+    let sbox = if decrypt { &INV_SBOX } else { &SBOX };
+    let bytes: [u8; 8] = std::array::from_fn(|i| {
+        let (column, row) = (i / 4, i % 4);
+        // Row r rotates left by r columns, right to undo it
+        let from = if decrypt { (column + 4 - row) % 4 } else { (column + row) % 4 };
+        sbox[(state >> (8 * (from * 4 + row))) as u8 as usize]
+    });
+    let half = u64::from_le_bytes(bytes);
+    if mix {
+        mix_columns(half, decrypt)
+    } else {
+        half
+    }
+}
+
+/// The four SHA-256 functions on the low word, sign extended
+fn sha256(n: u32, x: u64) -> u64 {
+    let x = x as u32;
+    let value = match n {
+        0 => x.rotate_right(2) ^ x.rotate_right(13) ^ x.rotate_right(22),
+        1 => x.rotate_right(6) ^ x.rotate_right(11) ^ x.rotate_right(25),
+        2 => x.rotate_right(7) ^ x.rotate_right(18) ^ x >> 3,
+        _ => x.rotate_right(17) ^ x.rotate_right(19) ^ x >> 10,
+    };
+    value as i32 as u64
+}
+
+fn sha512(n: u32, x: u64) -> u64 {
+    match n {
+        0 => x.rotate_right(28) ^ x.rotate_right(34) ^ x.rotate_right(39),
+        1 => x.rotate_right(14) ^ x.rotate_right(18) ^ x.rotate_right(41),
+        2 => x.rotate_right(1) ^ x.rotate_right(8) ^ x >> 7,
+        _ => x.rotate_right(19) ^ x.rotate_right(61) ^ x >> 6,
+    }
+}
+
+impl RiscvCpu {
+    pub(super) fn execute_crypto(&mut self, inst: u32) -> Result<(), RiscvCpuError> {
+        let op = decode(inst).ok_or(RiscvCpuError::DecodeError)?;
+        let enabled = match op {
+            CryptoOp::AesRound(false, _) => self.isa.zkne,
+            CryptoOp::AesRound(true, _) | CryptoOp::AesIm => self.isa.zknd,
+            // The key schedule is in both
+            CryptoOp::AesKs1i(_) | CryptoOp::AesKs2 => self.isa.zkne || self.isa.zknd,
+            CryptoOp::Sha256(_) | CryptoOp::Sha512(_) => self.isa.zknh,
+        };
+        if !enabled {
+            return Err(RiscvCpuError::DecodeError);
+        }
+        let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize;
+        let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize;
+        let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+        let (x, y) = (self.ixu[rs1], self.ixu[rs2]);
+
+        self.ixu[rd] = match op {
+            CryptoOp::AesRound(decrypt, mix) => aes_round(x, y, decrypt, mix),
+            CryptoOp::AesIm => mix_columns(x, true),
+            // SubWord(RotWord(w)) ^ rcon on the high word, rnum 0xa skips
+            // the rotation and the constant
+            CryptoOp::AesKs1i(rnum) => {
+                let word = (x >> 32) as u32;
+                let word = if rnum == 0xa { word } else { word.rotate_right(8) };
+                let word = u32::from_le_bytes(word.to_le_bytes().map(|b| SBOX[b as usize]));
+                let word = word ^ RCON.get(rnum as usize).copied().unwrap_or(0) as u32;
+                (word as u64) << 32 | word as u64
+            }
+            CryptoOp::AesKs2 => {
+                let w0 = (x >> 32) as u32 ^ y as u32;
+                let w1 = w0 ^ (y >> 32) as u32;
+                (w1 as u64) << 32 | w0 as u64
+            }
+            CryptoOp::Sha256(n) => sha256(n, x),
+            CryptoOp::Sha512(n) => sha512(n, x),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_crypto_tables() {
+        assert_eq!((SBOX[0x00], SBOX[0x53], SBOX[0xff]), (0x63, 0xed, 0x16));
+        assert_eq!(INV_SBOX[0xed], 0x53);
+        // FIPS-197 MixColumns example column db 13 53 45 -> 8e 4d a1 bc
+        assert_eq!(mix_column(0x4553_13db, false), 0xbca1_4d8e);
+        assert_eq!(mix_column(0xbca1_4d8e, true), 0x4553_13db);
+    }
+
+    #[test]
+    fn test_zkn_aes() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        // FIPS-197 appendix B, the state entering round 1
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0x2be2_f4a0_bee3_3d19, 0x0848_f8e9_2a8d_c69a);
+        assert_eq!(cpu.execute(encode!(aes64esm a0, a1, a2)), Err(RiscvCpuError::DecodeError));
+        cpu.isa = Isa::parse("rv64gc_zkne_zknd").unwrap();
+        cpu.execute(encode!(aes64esm a0, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x9a19_cbe0_e581_6604);
+        cpu.execute(encode!(aes64esm a3, a2, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A3], 0x4c26_0628_7ad3_f848);
+        cpu.execute(encode!(aes64es a4, a1, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A4], 0xae52_b4e0_305d_bfd4);
+        cpu.execute(encode!(aes64es a5, a2, a1)).unwrap();
+        // The inverse steps take it back
+        cpu.execute(encode!(aes64ds a6, a4, a5)).unwrap();
+        assert_eq!(cpu.ixu[REG_A6], 0x2be2_f4a0_bee3_3d19);
+        cpu.execute(encode!(aes64im a6, a0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A6], 0xae52_b4e0_305d_bfd4);
+        cpu.execute(encode!(aes64dsm a6, a4, a5)).unwrap();
+        assert_eq!(cpu.ixu[REG_A6], mix_columns(0x2be2_f4a0_bee3_3d19, true));
+
+        // Round key 1 from the cipher key 2b7e1516 28aed2a6 abf71588 09cf4f3c
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0xa6d2_ae28_1615_7e2b, 0x3c4f_cf09_8815_f7ab);
+        cpu.execute(encode!(aes64ks1i a3, a2, 0)).unwrap();
+        cpu.execute(encode!(aes64ks2 a4, a3, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A4], 0xb12c_5488_17fe_faa0);
+        cpu.execute(encode!(aes64ks2 a5, a4, a2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A5], 0x0576_6c2a_3939_a323);
+        cpu.ixu[REG_A1] = 0x0123_4567_89ab_cdef;
+        cpu.execute(encode!(aes64ks1i a0, a1, 10)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x7c26_6e85_7c26_6e85);
+        assert_eq!(decode(encode!(aes64ks1i a0, a1, 11)), None);
+    }
+
+    #[test]
+    fn test_zkn_sha() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        cpu.isa.zknh = true;
+        cpu.ixu[REG_A1] = 0xffff_ffff_1234_5678;
+        cpu.execute(encode!(sha256sig0 a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_e7fc_e6ee);
+        cpu.execute(encode!(sha256sum1 a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x3561_abda);
+        cpu.ixu[REG_A1] = 0x0123_4567_89ab_cdef;
+        cpu.execute(encode!(sha512sig1 a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x70a3_460d_bbd4_317a);
+        cpu.execute(encode!(sha512sum0 a0, a1)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xb7c5_7a10_0c7e_c1ab);
+        assert_eq!(disasm(0x1075_9513).unwrap(), "sha512sig1 a0,a1");
+        assert_eq!(disasm(0x31a5_9513).unwrap(), "aes64ks1i a0,a1,10");
+        assert_eq!(disasm(0x7ec5_8533).unwrap(), "aes64ks2 a0,a1,a2");
+    }
+}
@@ -0,0 +1,433 @@
+// Zicsr, the CSR instructions and the CSR file behind them.
+//
+//     csrrw csrrs csrrc         write, set or clear with x[rs1]
+//     csrrwi csrrsi csrrci      the same with rs1 as a 5-bit unsigned immediate
+//
+// The old value is read before the write and lands in rd, so rd == rs1
+// works. csrrw with rd = x0 doesn't need the read and csrrs/csrrc with
+// rs1 = x0 (or uimm 0) don't write at all, which is what makes a read of
+// a read-only CSR legal. Reads have no side effects here but for seed's,
+// so only the write half of that is visible elsewhere. CSRs with address[11:10] = 11 are
+// read-only and writing one is illegal, as is touching one that doesn't
+// exist.
+//
+// Only machine mode exists, the CSR file is:
+//
+//     fflags frm fcsr             views of the fcsr field
+//     cycle time instret          read-only shadows of the counters
+//     mcycle minstret
+//     mvendorid marchid mimpid    --mvendorid, --marchid, --mimpid (0)
+//     mconfigptr                  --mconfigptr (0, no configuration structure)
+//     mhartid                     reads 0
+//     misa                        WARL, fixed, writes are ignored
+//     mstatus                     WARL, MIE, MPIE and MBE, MPP reads M
+//     mtvec                       WARL, direct and vectored modes only
+//     mie mip                     WARL, MSI MTI MEI, mip read-only
+//     mscratch mepc mcause mtval  mepc[0] is always 0
+//     menvcfg                     WARL, CBIE CBCFE CBZE, which gate S and U mode only
+//     vxsat                       P saturation flag (OV), only with --features packed
+//     ssp                         Zicfiss shadow stack pointer, only with it
+//     seed                        Zkr entropy source, only with it
+//
+// seed must be accessed with a write (csrrw, or csrrs/csrrc with a
+// non-zero rs1), the value written is ignored. The first poll after reset
+// finds the self test running (BIST), the next SEED_POOL each return ES16
+// and 16 bits of entropy, then one finds the pool drained (WAIT), and so
+// on. The entropy comes from the machine's Rng, so it repeats with --seed.
+// LATER: Counter gating, a cycle/time/instret/hpmcounter read in S or U
+// mode traps unless the matching mcounteren (and in U mode scounteren)
+// bit is set. Blocked on S and U mode, not on the CSR instructions:
+// with machine mode alone mcounteren doesn't exist and nothing is gated.
+//
+// --csr-trace prints every access with the pc, the value read and, for a
+// write, the value the CSR holds after it; mstatus and mcause are split
+// into their fields:
+//
+//     0x0000000000000010 mstatus 0x0000000000001800 -> 0x0000000000001808 (MIE=1 MPIE=0 MPP=3)
+
+use super::*;
+
+pub(super) const CSR_FFLAGS: u32 = 0x001;
+pub(super) const CSR_FRM: u32 = 0x002;
+pub(super) const CSR_FCSR: u32 = 0x003;
+pub(super) const CSR_VXSAT: u32 = 0x009;
+pub(super) const CSR_SSP: u32 = 0x011;
+pub(super) const CSR_SEED: u32 = 0x015;
+pub(super) const CSR_CYCLE: u32 = 0xc00;
+pub(super) const CSR_TIME: u32 = 0xc01;
+pub(super) const CSR_INSTRET: u32 = 0xc02;
+pub(super) const CSR_MCYCLE: u32 = 0xb00;
+pub(super) const CSR_MINSTRET: u32 = 0xb02;
+pub(super) const CSR_MVENDORID: u32 = 0xf11;
+pub(super) const CSR_MARCHID: u32 = 0xf12;
+pub(super) const CSR_MIMPID: u32 = 0xf13;
+pub(super) const CSR_MHARTID: u32 = 0xf14;
+pub(super) const CSR_MCONFIGPTR: u32 = 0xf15;
+pub(super) const CSR_MSTATUS: u32 = 0x300;
+pub(super) const CSR_MISA: u32 = 0x301;
+pub(super) const CSR_MIE: u32 = 0x304;
+pub(super) const CSR_MTVEC: u32 = 0x305;
+pub(super) const CSR_MENVCFG: u32 = 0x30a;
+pub(super) const CSR_MSCRATCH: u32 = 0x340;
+pub(super) const CSR_MEPC: u32 = 0x341;
+pub(super) const CSR_MCAUSE: u32 = 0x342;
+pub(super) const CSR_MTVAL: u32 = 0x343;
+pub(super) const CSR_MIP: u32 = 0x344;
+
+const CSR_NAMES: [(u32, &str); 26] = [
+    (CSR_FFLAGS, "fflags"),
+    (CSR_FRM, "frm"),
+    (CSR_FCSR, "fcsr"),
+    (CSR_VXSAT, "vxsat"),
+    (CSR_SSP, "ssp"),
+    (CSR_SEED, "seed"),
+    (CSR_CYCLE, "cycle"),
+    (CSR_TIME, "time"),
+    (CSR_INSTRET, "instret"),
+    (CSR_MCYCLE, "mcycle"),
+    (CSR_MINSTRET, "minstret"),
+    (CSR_MVENDORID, "mvendorid"),
+    (CSR_MARCHID, "marchid"),
+    (CSR_MIMPID, "mimpid"),
+    (CSR_MHARTID, "mhartid"),
+    (CSR_MCONFIGPTR, "mconfigptr"),
+    (CSR_MSTATUS, "mstatus"),
+    (CSR_MISA, "misa"),
+    (CSR_MIE, "mie"),
+    (CSR_MTVEC, "mtvec"),
+    (CSR_MENVCFG, "menvcfg"),
+    (CSR_MSCRATCH, "mscratch"),
+    (CSR_MEPC, "mepc"),
+    (CSR_MCAUSE, "mcause"),
+    (CSR_MTVAL, "mtval"),
+    (CSR_MIP, "mip"),
+];
+
+// The CSRs that are plain storage, kept in RiscvCpu::csrs. The machine
+// information ones are read-only, the machine config sets them.
+const CSR_STORED: [u32; 15] = [
+    CSR_VXSAT,
+    CSR_SSP,
+    CSR_MVENDORID,
+    CSR_MARCHID,
+    CSR_MIMPID,
+    CSR_MCONFIGPTR,
+    CSR_MSTATUS,
+    CSR_MIE,
+    CSR_MTVEC,
+    CSR_MENVCFG,
+    CSR_MSCRATCH,
+    CSR_MEPC,
+    CSR_MCAUSE,
+    CSR_MTVAL,
+    CSR_MIP,
+];
+
+// mstatus.MIE, MPIE and MBE, MPP is hardwired to M
+pub(super) const MSTATUS_MBE: u64 = 1 << 37;
+const MSTATUS_WRITABLE: u64 = 1 << 3 | 1 << 7 | MSTATUS_MBE;
+const MSTATUS_MPP_M: u64 = 0b11 << 11;
+// Machine software, timer and external interrupts
+const MIE_WRITABLE: u64 = 1 << 3 | 1 << 7 | 1 << 11;
+// menvcfg.CBIE (2 bits), CBCFE and CBZE
+const MENVCFG_WRITABLE: u64 = 0b1111 << 4;
+const MENVCFG_CBIE: u64 = 0b11 << 4;
+// misa.MXL for RV64
+const MISA_MXL_64: u64 = 2 << 62;
+// seed.OPST, and the ES16 polls between two WAITs
+const SEED_BIST: u64 = 0b00 << 30;
+const SEED_WAIT: u64 = 0b01 << 30;
+const SEED_ES16: u64 = 0b10 << 30;
+const SEED_POOL: u64 = 8;
+
+/// Name of a CSR, None if it isn't in the CSR file
+pub(super) fn name(csr: u32) -> Option<&'static str> {
+    CSR_NAMES.iter().find(|&&(number, _)| number == csr).map(|&(_, name)| name)
+}
+
+/// Address of the CSR called `name`
+pub(super) fn number(name: &str) -> Option<u32> {
+    CSR_NAMES.iter().find(|&&(_, n)| n == name).map(|&(number, _)| number)
+}
+
+/// The fields of the CSRs --csr-trace splits up
+fn fields(csr: u32, value: u64) -> Option<String> {
+    match csr {
+        CSR_MSTATUS => Some(format!("MIE={} MPIE={} MPP={}", value >> 3 & 1, value >> 7 & 1, value >> 11 & 0b11)),
+        CSR_MCAUSE => Some(format!("Interrupt={} Code={}", value >> 63, value & !(1 << 63))),
+        _ => None,
+    }
+}
+
+/// --csr-trace line for an access to `csr` at pc, `new` when it wrote
+fn trace_line(pc: u64, csr: u32, old: u64, new: Option<u64>) -> String {
+    let name = name(csr).map_or_else(|| format!("{:#x}", csr), String::from);
+    let mut line = format!("{:#018x} {} {:#018x}", pc, name, old);
+    if let Some(new) = new {
+        line.push_str(&format!(" -> {:#018x}", new));
+    }
+    if let Some(fields) = fields(csr, new.unwrap_or(old)) {
+        line.push_str(&format!(" ({})", fields));
+    }
+    line
+}
+
+/// The value a write of `value` leaves in a stored CSR holding `old`
+fn warl(csr: u32, old: u64, value: u64) -> u64 {
+    match csr {
+        CSR_MSTATUS => old & !MSTATUS_WRITABLE | value & MSTATUS_WRITABLE | MSTATUS_MPP_M,
+        CSR_MIE => value & MIE_WRITABLE,
+        // Pending bits come from the interrupt sources, not software
+        CSR_MIP => old,
+        // OV is the only bit
+        CSR_VXSAT => value & 1,
+        // Modes 2 and 3 are reserved, fall back to direct
+        CSR_MTVEC if value & 0b11 >= 2 => value & !0b11,
+        // CBIE 10 is reserved, keep the old field
+        CSR_MENVCFG if value & MENVCFG_CBIE == 0b10 << 4 => old & MENVCFG_CBIE | value & MENVCFG_WRITABLE & !MENVCFG_CBIE,
+        CSR_MENVCFG => value & MENVCFG_WRITABLE,
+        // IALIGN is 16 with the C extension
+        CSR_MEPC => value & !1,
+        _ => value,
+    }
+}
+
+/// The stored CSRs at reset
+pub(super) fn reset() -> BTreeMap<u32, u64> {
+    CSR_STORED.iter().map(|&csr| (csr, warl(csr, 0, 0))).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CsrOp {
+    Write,
+    Set,
+    Clear,
+}
+
+/// The operation and whether rs1 is an immediate, None for ecall/ebreak
+/// and the reserved funct3
+fn decode(inst: u32) -> Option<(CsrOp, bool)> {
+    if getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS) != OPCODE_SYSTEM {
+        return None;
+    }
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let op = match funct3 & 0b011 {
+        0b01 => CsrOp::Write,
+        0b10 => CsrOp::Set,
+        0b11 => CsrOp::Clear,
+        _ => return None,
+    };
+    Some((op, funct3 & 0b100 != 0))
+}
+
+/// Assembly text of a CSR instruction, None for anything else
+pub(super) fn disasm(inst: u32) -> Option<String> {
+    let (op, imm) = decode(inst)?;
+    let rd = REGNAME[getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize];
+    let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS);
+    let csr = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+    let csr = name(csr).map_or_else(|| format!("{:#x}", csr), String::from);
+    let name = match op {
+        CsrOp::Write => "csrrw",
+        CsrOp::Set => "csrrs",
+        CsrOp::Clear => "csrrc",
+    };
+    Some(match imm {
+        true => format!("{}i {},{},{}", name, rd, csr, rs1),
+        false => format!("{} {},{},{}", name, rd, csr, REGNAME[rs1 as usize]),
+    })
+}
+
+impl RiscvCpu {
+    /// misa: RV64 and the single-letter extensions that decode
+    fn misa(&self) -> u64 {
+        let mut extensions = String::from(if self.isa.zfinx { "acim" } else { "acdfim" });
+        if self.xthead {
+            extensions.push('x');
+        }
+        extensions.bytes().fold(MISA_MXL_64, |misa, ext| misa | 1 << (ext - b'a'))
+    }
+
+    /// The next poll of seed
+    fn seed(&mut self) -> u64 {
+        let poll = self.seed_polls;
+        self.seed_polls += 1;
+        match poll {
+            0 => SEED_BIST,
+            _ if poll.is_multiple_of(SEED_POOL + 1) => SEED_WAIT,
+            _ => SEED_ES16 | self.rng.next() & 0xffff,
+        }
+    }
+
+    /// Value of `csr`, None if there is no such CSR, or for seed, which
+    /// can't be read without drawing from it
+    pub(super) fn csr_read(&self, csr: u32) -> Option<u64> {
+        let value = match csr {
+            CSR_FFLAGS => (self.fcsr & 0x1f) as u64,
+            CSR_FRM => (self.fcsr >> 5 & 0b111) as u64,
+            CSR_FCSR => (self.fcsr & 0xff) as u64,
+            CSR_CYCLE | CSR_MCYCLE => self.cycle,
+            CSR_TIME => self.timebase.ticks(self.instret),
+            CSR_INSTRET | CSR_MINSTRET => self.instret,
+            CSR_MHARTID => 0,
+            CSR_MISA => self.misa(),
+            CSR_VXSAT if !cfg!(feature = "packed") => return None,
+            CSR_SSP if !self.isa.zicfiss => return None,
+            _ => *self.csrs.get(&csr)?,
+        };
+        Some(value)
+    }
+
+    /// Write `value` to a writable `csr`, keeping only what it can hold
+    pub(super) fn csr_write(&mut self, csr: u32, value: u64) {
+        match csr {
+            CSR_FFLAGS => self.fcsr = self.fcsr & !0x1f | value as u32 & 0x1f,
+            CSR_FRM => self.fcsr = self.fcsr & 0x1f | (value as u32 & 0b111) << 5,
+            CSR_FCSR => self.fcsr = value as u32 & 0xff,
+            // The cycles of the writing instruction still count after the
+            // write. Its retirement doesn't: the next instruction reads value
+            CSR_MCYCLE => self.cycle = value,
+            CSR_MINSTRET => self.instret = value.wrapping_sub(1),
+            CSR_MISA => {}
+            _ => {
+                if let Some(old) = self.csrs.get_mut(&csr) {
+                    *old = warl(csr, *old, value);
+                }
+            }
+        }
+    }
+
+    pub(super) fn execute_csr(&mut self, inst: u32) -> Result<(), RiscvCpuError> {
+        let (op, imm) = decode(inst).ok_or(RiscvCpuError::DecodeError)?;
+        let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize;
+        let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize;
+        let csr = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+        let source = if imm { rs1 as u64 } else { self.ixu[rs1] };
+        let write = op == CsrOp::Write || rs1 != 0;
+        if write && csr >> 10 == 0b11 {
+            return Err(RiscvCpuError::DecodeError);
+        }
+        if csr == CSR_SEED {
+            if !self.isa.zkr || !write {
+                return Err(RiscvCpuError::DecodeError);
+            }
+            self.ixu[rd] = self.seed();
+            self.csr_observe(csr, self.ixu[rd], None);
+            return Ok(());
+        }
+        let old = self.csr_read(csr).ok_or(RiscvCpuError::DecodeError)?;
+        if write {
+            let value = match op {
+                CsrOp::Write => source,
+                CsrOp::Set => old | source,
+                CsrOp::Clear => old & !source,
+            };
+            self.csr_write(csr, value);
+        }
+        self.csr_observe(csr, old, write.then(|| self.csr_read(csr).unwrap_or(old)));
+        self.ixu[rd] = old;
+        Ok(())
+    }
+
+    /// CSR access hook, counted for the summary and printed with --csr-trace
+    fn csr_observe(&mut self, csr: u32, old: u64, new: Option<u64>) {
+        *self.csr_counts.entry(csr).or_default() += 1;
+        if self.csr_trace {
+            println!("{COLOR_GREY}{}{COLOR_RESET}", trace_line(self.pc, csr, old, new));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_csr_decode() {
+        assert_eq!(disasm(0xb0002573).unwrap(), "csrrs a0,mcycle,z0");
+        assert_eq!(disasm(0x34059073).unwrap(), "csrrw z0,mscratch,a1");
+        assert_eq!(disasm(0x3002f573).unwrap(), "csrrci a0,mstatus,5");
+        assert_eq!(disasm(0x7c002573).unwrap(), "csrrs a0,0x7c0,z0");
+        assert_eq!(disasm(0x00000073), None);
+        assert_eq!(disasm(0x00004073), None);
+        assert_eq!(number("mtvec"), Some(0x305));
+        assert_eq!(name(0xc01), Some("time"));
+    }
+
+    #[test]
+    fn test_csr_trace_line() {
+        assert_eq!(trace_line(0x10, CSR_MSCRATCH, 5, None), "0x0000000000000010 mscratch 0x0000000000000005");
+        assert_eq!(
+            trace_line(0x10, CSR_MSTATUS, 0x1800, Some(0x1808)),
+            "0x0000000000000010 mstatus 0x0000000000001800 -> 0x0000000000001808 (MIE=1 MPIE=0 MPP=3)"
+        );
+        assert_eq!(
+            trace_line(0x14, CSR_MCAUSE, 1 << 63 | 7, None),
+            "0x0000000000000014 mcause 0x8000000000000007 (Interrupt=1 Code=7)"
+        );
+    }
+
+    #[test]
+    fn test_csr_warl() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        assert_eq!(cpu.csr_read(CSR_MSTATUS), Some(MSTATUS_MPP_M));
+        cpu.csr_write(CSR_MSTATUS, u64::MAX);
+        assert_eq!(cpu.csr_read(CSR_MSTATUS), Some(MSTATUS_MPP_M | MSTATUS_WRITABLE));
+        cpu.csr_write(CSR_MTVEC, 0x8000_0103);
+        assert_eq!(cpu.csr_read(CSR_MTVEC), Some(0x8000_0100));
+        cpu.csr_write(CSR_MTVEC, 0x8000_0101);
+        assert_eq!(cpu.csr_read(CSR_MTVEC), Some(0x8000_0101));
+        cpu.csr_write(CSR_MEPC, 0x1003);
+        assert_eq!(cpu.csr_read(CSR_MEPC), Some(0x1002));
+        cpu.csr_write(CSR_MIP, u64::MAX);
+        assert_eq!(cpu.csr_read(CSR_MIP), Some(0));
+        cpu.csr_write(CSR_MIE, u64::MAX);
+        assert_eq!(cpu.csr_read(CSR_MIE), Some(MIE_WRITABLE));
+        cpu.csr_write(CSR_MENVCFG, u64::MAX);
+        assert_eq!(cpu.csr_read(CSR_MENVCFG), Some(0xf0));
+        cpu.csr_write(CSR_MENVCFG, 0xa0);
+        assert_eq!(cpu.csr_read(CSR_MENVCFG), Some(0xb0));
+        let misa = cpu.csr_read(CSR_MISA).unwrap();
+        cpu.csr_write(CSR_MISA, 0);
+        assert_eq!(cpu.csr_read(CSR_MISA), Some(misa));
+        assert_eq!(misa, MISA_MXL_64 | 0x112d);
+        assert_eq!(cpu.csr_read(0x7c0), None);
+    }
+
+    #[test]
+    fn test_csr_seed() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        // csrrw a0, seed, zero ; csrrs a0, seed, zero
+        let (poll, read) = (0x01501573, 0x01502573);
+        assert_eq!(cpu.execute(poll), Err(RiscvCpuError::DecodeError));
+        cpu.isa.zkr = true;
+        assert_eq!(cpu.execute(read), Err(RiscvCpuError::DecodeError));
+        assert_eq!(cpu.csr_read(CSR_SEED), None);
+        let mut opst = Vec::new();
+        for _ in 0..11 {
+            cpu.execute(poll).unwrap();
+            assert_eq!(cpu.ixu[REG_A0] & !(0b11 << 30 | 0xffff), 0);
+            opst.push(cpu.ixu[REG_A0] >> 30);
+        }
+        assert_eq!(opst, [0, 2, 2, 2, 2, 2, 2, 2, 2, 1, 2]);
+        // The same seed draws the same entropy
+        let mut again = RiscvCpu::new(vec![0; 64]);
+        again.isa.zkr = true;
+        for _ in 0..11 {
+            again.execute(poll).unwrap();
+        }
+        assert_eq!(again.ixu[REG_A0], cpu.ixu[REG_A0]);
+    }
+
+    #[test]
+    fn test_csr_counts() {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        cpu.execute(encode!(csrrw zero, mscratch, a1)).unwrap();
+        cpu.execute(encode!(csrrs a0, mscratch, zero)).unwrap();
+        cpu.execute(encode!(csrrs a0, mstatus, zero)).unwrap();
+        // A CSR that doesn't exist isn't an access
+        assert!(cpu.execute(0x7c002573).is_err());
+        assert_eq!(cpu.csr_counts, BTreeMap::from([(CSR_MSTATUS, 1), (CSR_MSCRATCH, 2)]));
+    }
+}
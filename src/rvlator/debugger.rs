@@ -0,0 +1,281 @@
+// Interactive debugger.
+//
+//     s, step [N]       execute N instructions (1), an empty line steps once
+//     c, continue       run to a breakpoint or the end of the image
+//     b, break ADDR     set or clear a breakpoint
+//     r, regs           print the registers
+//     x ADDR [N]        dump N 32-bit words (4)
+//     d [ADDR] [N]      disassemble N instructions (8) from ADDR (pc)
+//     n, narrate        narrate the datapath stages of each step, on/off
+//     t, strace         trace syscalls, on/off
+//     w, watch EXPR     stop when EXPR becomes true or changes, again to remove
+//     l, load           reload the (rebuilt) image and reset, breakpoints stay
+//     q, quit
+//
+// LATER: A GDB remote stub next to this debugger, with a target.xml that
+// lists the CSRs, f0-f31 and v0-v31 alongside the GPRs and pc so `info
+// registers mstatus` works. Blocked on the stub itself; the f registers
+// and the CSR file are there to describe, v0-v31 wait on RVV.
+// LATER: A Debug Adapter Protocol server (stdio or TCP) mapping launch,
+// setBreakpoints, next/stepIn and variables onto this loop. Source-level
+// breakpoints need DWARF line tables, so ELF images, first.
+
+use super::*;
+use explain::narrate;
+use std::collections::BTreeSet;
+use std::io::BufRead;
+
+const DEBUG_HELP: &str = "\
+s, step [N]      execute N instructions
+c, continue      run to a breakpoint or the end of the image
+b, break ADDR    set or clear a breakpoint
+r, regs          print the registers
+x ADDR [N]       dump N 32-bit words
+d [ADDR] [N]     disassemble N instructions
+n, narrate       narrate the datapath stages of each step, on/off
+t, strace        trace syscalls, on/off
+w, watch EXPR    stop when EXPR becomes true (a0==0) or changes (mem[ADDR] changes)
+l, load          reload the image and reset
+q, quit";
+
+/// Parse an optional numeric argument, `default` when absent
+fn arg(word: Option<&str>, default: u64) -> Result<u64, String> {
+    match word {
+        Some(word) => parse_addr(word).ok_or(format!("bad number {}", word)),
+        None => Ok(default),
+    }
+}
+
+impl RiscvCpu {
+    /// Step once if the pc is still inside [mem_base, end). False when the
+    /// program is over, either by leaving the image or by failing.
+    fn debug_step(&mut self, end: u64, narrate: bool) -> bool {
+        if let Some(code) = self.exit_code {
+            println!("guest exited with code {}", code);
+            return false;
+        }
+        if self.pc < self.mem_base || self.pc >= end {
+            println!("program finished at pc {:#018x}", self.pc);
+            return false;
+        }
+        // A step is one instruction, even of a fusable pair, and a
+        // breakpoint may sit on the second one
+        let fusion = std::mem::replace(&mut self.fusion, false);
+        let stepped = match narrate {
+            true => self.narrated_step(),
+            false => match self.step() {
+                Ok(()) => true,
+                Err(e) => {
+                    println!("{COLOR_RED}{:?} at pc {:#018x}{COLOR_RESET}", e, self.pc);
+                    false
+                }
+            },
+        };
+        self.fusion = fusion;
+        stepped && !self.check_watches()
+    }
+
+    /// Step a single instruction and narrate it
+    fn narrated_step(&mut self) -> bool {
+        let (pc, before) = (self.pc, self.ixu);
+        let Ok(inst) = self.fetch() else { return false };
+        match self.step() {
+            Ok(()) => {
+                print!("{}", narrate(pc, self.pc, inst, &before, &self.ixu));
+                true
+            }
+            Err(e) => {
+                println!("{COLOR_RED}{:?} at pc {:#018x}{COLOR_RESET}", e, self.pc);
+                false
+            }
+        }
+    }
+
+    /// Run debugger commands from `input` until it is exhausted or quit.
+    /// `reload` builds a fresh machine from the image on disk for `l`.
+    pub(super) fn debug(
+        &mut self,
+        mut end: u64,
+        mut narrate: bool,
+        reload: impl Fn() -> Result<(RiscvCpu, u64), String>,
+        input: impl BufRead,
+    ) {
+        let mut breakpoints = BTreeSet::new();
+        print!("{:#018x}: {}\n(rvdb) ", self.pc, self.fetch().map_or(String::from("?"), disasm));
+        std::io::stdout().flush().ok();
+        for line in input.lines() {
+            let Ok(line) = line else { break };
+            let mut words = line.split_whitespace();
+            let cmd = words.next().unwrap_or("s");
+            let result = match cmd {
+                "s" | "step" => arg(words.next(), 1).map(|n| {
+                    for _ in 0..n {
+                        if !self.debug_step(end, narrate) {
+                            break;
+                        }
+                    }
+                }),
+                "c" | "continue" => {
+                    // Always make progress off a breakpoint we are stopped on
+                    while self.debug_step(end, narrate) && !breakpoints.contains(&self.pc) {}
+                    Ok(())
+                }
+                "b" | "break" => match words.next().and_then(parse_addr) {
+                    Some(addr) if breakpoints.remove(&addr) => {
+                        println!("breakpoint cleared at {:#018x}", addr);
+                        Ok(())
+                    }
+                    Some(addr) => {
+                        breakpoints.insert(addr);
+                        println!("breakpoint set at {:#018x}", addr);
+                        Ok(())
+                    }
+                    None => Err(String::from("break needs an address")),
+                },
+                "r" | "regs" => {
+                    self.print_registers();
+                    Ok(())
+                }
+                "x" => match words.next().and_then(parse_addr) {
+                    Some(addr) => arg(words.next(), 4).map(|n| {
+                        for addr in (addr..).step_by(4).take(n as usize) {
+                            match self.read_mem(addr, 4) {
+                                Some(word) => println!("{:#018x}: {:#010x}", addr, word),
+                                None => println!("{:#018x}: unreadable", addr),
+                            }
+                        }
+                    }),
+                    None => Err(String::from("x needs an address")),
+                },
+                "d" => arg(words.next(), self.pc).and_then(|mut addr| {
+                    arg(words.next(), 8).map(|n| {
+                        for _ in 0..n {
+                            let Ok(inst) = self.fetch_at(addr) else { break };
+                            println!("{:#018x}: {}  {}", addr, disasm::hex(inst), disasm(inst));
+                            addr += inst_len(inst);
+                        }
+                    })
+                }),
+                "t" | "strace" => {
+                    self.strace = !self.strace;
+                    println!("syscall trace {}", if self.strace { "on" } else { "off" });
+                    Ok(())
+                }
+                "l" | "load" => reload().map(|(cpu, new_end)| {
+                    // The logs and the syscall trace carry on
+                    let commit_log = self.commit_log.take();
+                    let console_log = self.console_log.take();
+                    let strace = self.strace;
+                    *self = cpu;
+                    self.commit_log = commit_log;
+                    self.console_log = console_log;
+                    self.strace = strace;
+                    end = new_end;
+                    println!("reloaded, {} bytes of image", end - self.mem_base);
+                }),
+                "w" | "watch" => {
+                    let text = words.collect::<Vec<_>>().join(" ");
+                    match self.watches.iter().position(|w| w.text == text) {
+                        Some(idx) => {
+                            self.watches.remove(idx);
+                            println!("watch removed: {}", text);
+                            Ok(())
+                        }
+                        None => Watch::parse(&text).map(|mut watch| {
+                            watch.arm(self);
+                            self.watches.push(watch);
+                            println!("watching {}", text);
+                        }),
+                    }
+                }
+                "n" | "narrate" => {
+                    narrate = !narrate;
+                    println!("narration {}", if narrate { "on" } else { "off" });
+                    Ok(())
+                }
+                "h" | "help" => {
+                    println!("{}", DEBUG_HELP);
+                    Ok(())
+                }
+                "q" | "quit" => return,
+                _ => Err(format!("unknown command {}, h for help", cmd)),
+            };
+            if let Err(e) = result {
+                println!("{}", e);
+            }
+            print!("{:#018x}: {}\n(rvdb) ", self.pc, self.fetch().map_or(String::from("?"), disasm));
+            std::io::stdout().flush().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    fn no_reload() -> Result<(RiscvCpu, u64), String> {
+        Err(String::from("no image"))
+    }
+
+    #[test]
+    fn test_debug_commands() {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 1))
+            .inst(encode!(addi a1, zero, 2))
+            .inst(encode!(addi a2, zero, 3))
+            .inst(encode!(addi a3, zero, 4))
+            .cpu();
+        cpu.debug(16, false, no_reload, "s\nbogus\nb 0xc\nc\n".as_bytes());
+        assert_eq!(cpu.pc, 0xc);
+        assert_eq!(cpu.ixu[REG_A2], 3);
+        assert_eq!(cpu.ixu[REG_A3], 0);
+
+        // Continue steps off the breakpoint and stops at the end of the image
+        cpu.debug(16, false, no_reload, "c\nc\n".as_bytes());
+        assert_eq!(cpu.pc, 16);
+        assert_eq!(cpu.ixu[REG_A3], 4);
+    }
+
+    #[test]
+    fn test_debug_fusion() {
+        // Fusion stays on, but a breakpoint on the second of a fusable
+        // pair still stops there and a step runs one instruction
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(lui a0, 0x12345))
+            .inst(encode!(addi a0, a0, 1))
+            .inst(encode!(lui a1, 0x12345))
+            .inst(encode!(addi a1, a1, 2))
+            .cpu();
+        cpu.debug(16, false, no_reload, "b 0x4\nc\n".as_bytes());
+        assert_eq!((cpu.pc, cpu.ixu[REG_A0]), (4, 0x12345000));
+        cpu.debug(16, false, no_reload, "s\ns\n".as_bytes());
+        assert_eq!((cpu.pc, cpu.ixu[REG_A1]), (12, 0x12345000));
+        assert_eq!(cpu.instret, 3);
+        assert!(cpu.fusion);
+    }
+
+    #[test]
+    fn test_debug_narrate() {
+        // A fusable pair is still stepped one instruction at a time
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(lui a0, 0x12345))
+            .inst(encode!(addi a0, a0, -1))
+            .cpu();
+        cpu.debug(8, false, no_reload, "n\ns\n".as_bytes());
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.ixu[REG_A0], 0x12345000);
+        assert!(cpu.fusion);
+    }
+
+    #[test]
+    fn test_debug_reload() {
+        let build = || ProgramBuilder::new().inst(encode!(addi a0, zero, 1)).cpu();
+        let mut cpu = build();
+        cpu.debug(4, false, || Ok((build(), 4)), "b 0x0\ns\nl\nc\n".as_bytes());
+        // Reset by the reload, then continue stepped off the breakpoint
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.instret, 1);
+        assert_eq!(cpu.ixu[REG_A0], 1);
+    }
+}
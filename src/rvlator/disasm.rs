@@ -0,0 +1,312 @@
+// Disassembler.
+//
+// Text matches what the execute trace has always printed: no-alias
+// mnemonics, short register names and signed decimal immediates.
+//
+// Compressed instructions print their own mnemonic with the operands of
+// the 32-bit expansion (c.addi a0,a0,-1), reserved ones as .half.
+//
+// `listing` is the same text for a whole image, each line led by the
+// number of times the instruction executed (- for never), for --listing:
+//
+//            12  0x0000000000000004: 00150593  addi a1,a0,1
+//             -  0x0000000000000008: 00000073  ecall
+
+use super::*;
+
+/// disasm(instruction) returns the assembly text, `.word` for anything
+/// the cpu would not decode
+pub(super) fn disasm(inst: u32) -> String {
+    let illegal = format!(".word {:#010x}", inst);
+    match inst_type(inst) {
+        RiscvInstType::Bit16 => return compressed(inst),
+        RiscvInstType::Illegal => return illegal,
+        RiscvInstType::Bit32 => {}
+    }
+
+    let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+    let rd = REGNAME[getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize];
+    let rs1 = REGNAME[getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize];
+    let rs2 = REGNAME[getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize];
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let funct6 = getfield32!(inst, INST_FUNCT6_WID, INST_FUNCT6_POS);
+    let simm12 = signext12to64(getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS)) as i64;
+    let simm_s = signext12to64(
+        getfield32!(inst, INST_IMM11_5_WID, INST_IMM11_5_POS) << 5 | getfield32!(inst, INST_IMM4_0_WID, INST_IMM4_0_POS),
+    ) as i64;
+    let simm20 = signext20to64(getfield32!(inst, INST_IMM31_12_WID, INST_IMM31_12_POS)) as i64;
+    let shamt = getfield32!(inst, INST_SHAMT_WID, INST_SHAMT_POS);
+
+    if let Some(text) = bitmanip::disasm(inst).or_else(|| crypto::disasm(inst)).or_else(|| shadowstack::disasm(inst)) {
+        return text;
+    }
+    // Named HINTs, the rest print as the instruction they are
+    match hint(inst) {
+        Some("hint") | None => {}
+        Some(name) if opcode == OPCODE_OP_IMM => return format!("{} {}({})", name, simm12 & !0x1f, rs1),
+        Some(name) => return String::from(name),
+    }
+    match opcode {
+        OPCODE_AUIPC => format!("auipc {},{}", rd, simm20),
+        OPCODE_LUI => format!("lui {},{}", rd, simm20),
+        OPCODE_OP_IMM => match (funct3, funct6) {
+            (0b000, _) => format!("addi {},{},{}", rd, rs1, simm12),
+            (0b001, 0b000000) => format!("slli {},{},{}", rd, rs1, shamt),
+            (0b010, _) => format!("slti {},{},{}", rd, rs1, simm12),
+            (0b011, _) => format!("sltiu {},{},{}", rd, rs1, simm12),
+            (0b100, _) => format!("xori {},{},{}", rd, rs1, simm12),
+            (0b101, 0b000000) => format!("srli {},{},{}", rd, rs1, shamt),
+            (0b101, 0b010000) => format!("srai {},{},{}", rd, rs1, shamt),
+            (0b110, _) => format!("ori {},{},{}", rd, rs1, simm12),
+            (0b111, _) => format!("andi {},{},{}", rd, rs1, simm12),
+            _ => illegal,
+        },
+        OPCODE_LOAD => match funct3 {
+            0b111 => illegal,
+            _ => {
+                let name = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"][funct3 as usize];
+                format!("{} {},{}({})", name, rd, simm12, rs1)
+            }
+        },
+        OPCODE_STORE => match funct3 {
+            0b000..=0b011 => {
+                let name = ["sb", "sh", "sw", "sd"][funct3 as usize];
+                format!("{} {},{}({})", name, rs2, simm_s, rs1)
+            }
+            _ => illegal,
+        },
+        OPCODE_OP => {
+            let name = match (getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS), funct3) {
+                (0b0000000, _) => ["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"][funct3 as usize],
+                (0b0100000, 0b000) => "sub",
+                (0b0100000, 0b101) => "sra",
+                (0b0000001, _) => ["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"][funct3 as usize],
+                _ => return illegal,
+            };
+            format!("{} {},{},{}", name, rd, rs1, rs2)
+        }
+        OPCODE_OP_IMM_32 => match (funct3, getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS)) {
+            (0b000, _) => format!("addiw {},{},{}", rd, rs1, simm12),
+            (0b001, 0b0000000) => format!("slliw {},{},{}", rd, rs1, shamt),
+            (0b101, 0b0000000) => format!("srliw {},{},{}", rd, rs1, shamt),
+            (0b101, 0b0100000) => format!("sraiw {},{},{}", rd, rs1, shamt & 0x1f),
+            _ => illegal,
+        },
+        OPCODE_OP_32 => {
+            let name = match (getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS), funct3) {
+                (0b0000000, 0b000) => "addw",
+                (0b0100000, 0b000) => "subw",
+                (0b0000000, 0b001) => "sllw",
+                (0b0000000, 0b101) => "srlw",
+                (0b0100000, 0b101) => "sraw",
+                (0b0000001, 0b000) => "mulw",
+                (0b0000001, 0b100..=0b111) => ["divw", "divuw", "remw", "remuw"][funct3 as usize - 4],
+                _ => return illegal,
+            };
+            format!("{} {},{},{}", name, rd, rs1, rs2)
+        }
+        OPCODE_AMO => {
+            let width = match funct3 {
+                0b010 => "w",
+                0b011 => "d",
+                0b100 if inst >> 27 == 0b00101 => "q",
+                _ => return illegal,
+            };
+            // aq and rl in inst[26:25]
+            let order = ["", ".rl", ".aq", ".aqrl"][getfield32!(inst, 2, 25) as usize];
+            let name = match getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS) >> 2 {
+                0b00010 if getfield32!(inst, INST_RS2_WID, INST_RS2_POS) == 0 => {
+                    return format!("lr.{}{} {},({})", width, order, rd, rs1)
+                }
+                0b00011 => "sc",
+                0b00001 => "amoswap",
+                0b00000 => "amoadd",
+                0b00100 => "amoxor",
+                0b01100 => "amoand",
+                0b01000 => "amoor",
+                0b10000 => "amomin",
+                0b10100 => "amomax",
+                0b11000 => "amominu",
+                0b11100 => "amomaxu",
+                0b00101 => "amocas",
+                _ => return illegal,
+            };
+            format!("{}.{}{} {},{},({})", name, width, order, rd, rs2, rs1)
+        }
+        OPCODE_JAL => format!("jal {},{}", rd, jump_offset(inst) as i64),
+        OPCODE_JALR if funct3 == 0 => format!("jalr {},{}({})", rd, simm12, rs1),
+        OPCODE_BRANCH => match funct3 {
+            0b010 | 0b011 => illegal,
+            _ => {
+                let name = ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"][funct3 as usize];
+                format!("{} {},{},{}", name, rs1, rs2, branch_offset(inst) as i64)
+            }
+        },
+        OPCODE_MISC_MEM => match funct3 {
+            0b000 if inst >> 28 == 0b1000 => String::from("fence.tso"),
+            0b000 => format!("fence {},{}", fence_set(inst >> 24), fence_set(inst >> 20)),
+            0b001 => String::from("fence.i"),
+            0b010 => match inst >> INST_IMM11_0_POS {
+                0b000 => format!("cbo.inval ({})", rs1),
+                0b001 => format!("cbo.clean ({})", rs1),
+                0b010 => format!("cbo.flush ({})", rs1),
+                0b100 => format!("cbo.zero ({})", rs1),
+                _ => illegal,
+            },
+            _ => illegal,
+        },
+        OPCODE_SYSTEM if inst == INST_ECALL => String::from("ecall"),
+        OPCODE_SYSTEM if inst == INST_EBREAK => String::from("ebreak"),
+        OPCODE_SYSTEM if inst == INST_WRS_NTO => String::from("wrs.nto"),
+        OPCODE_SYSTEM if inst == INST_WRS_STO => String::from("wrs.sto"),
+        OPCODE_SYSTEM => csr::disasm(inst).unwrap_or(illegal),
+        OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD | OPCODE_OP_FP => {
+            fpu::disasm(inst).unwrap_or(illegal)
+        }
+        OPCODE_CUSTOM0 => xthead::disasm(inst).unwrap_or(illegal),
+        #[cfg(feature = "packed")]
+        OPCODE_OP_P => packed::disasm(inst).unwrap_or(illegal),
+        _ => illegal,
+    }
+}
+
+fn compressed(inst: u32) -> String {
+    let text = compressed::expand(inst).and_then(|(name, expansion)| {
+        let text = disasm(expansion);
+        match text.split_once(' ') {
+            _ if text.starts_with('.') => None,
+            Some((_, operands)) => Some(format!("{} {}", name, operands)),
+            None => Some(String::from(name)),
+        }
+    });
+    text.unwrap_or_else(|| format!(".half {:#06x}", inst))
+}
+
+/// Encoding as hex, 4 digits for a 16-bit instruction padded to the width
+/// of a 32-bit one
+pub(super) fn hex(inst: u32) -> String {
+    match inst_len(inst) {
+        2 => format!("{:04x}    ", inst),
+        _ => format!("{:08x}", inst),
+    }
+}
+
+/// FENCE predecessor or successor set in the low 4 bits, as iorw letters
+fn fence_set(bits: u32) -> String {
+    let set: String = "iorw".chars().enumerate().filter(|&(i, _)| bits & (0b1000 >> i) != 0).map(|(_, c)| c).collect();
+    match set.is_empty() {
+        true => String::from("0"),
+        false => set,
+    }
+}
+
+/// One line per instruction of `code` at `base`, without counts when
+/// there are none
+pub(super) fn listing(code: &[u8], base: u64, counts: Option<&BTreeMap<u64, u64>>) -> String {
+    let mut text = String::new();
+    let mut off = 0;
+    while off < code.len() {
+        let mut bytes = [0; 4];
+        let word = &code[off..code.len().min(off + 4)];
+        bytes[..word.len()].copy_from_slice(word);
+        let inst = match inst_len(u32::from_le_bytes(bytes)) {
+            2 => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+            _ => u32::from_le_bytes(bytes),
+        };
+        let pc = base + off as u64;
+        off += inst_len(inst) as usize;
+        if let Some(counts) = counts {
+            match counts.get(&pc) {
+                Some(count) => text.push_str(&format!("{:>14}  ", count)),
+                None => text.push_str(&format!("{:>14}  ", "-")),
+            }
+        }
+        text.push_str(&format!("{:#018x}: {}  {}\n", pc, hex(inst), disasm(inst)));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disasm() {
+        assert_eq!(disasm(0xffc00513), "addi a0,z0,-4");
+        assert_eq!(disasm(0x03c51613), "slli a2,a0,60");
+        assert_eq!(disasm(0x4015d713), "srai a4,a1,1");
+        assert_eq!(disasm(0xffc5b793), "sltiu a5,a1,-4");
+        assert_eq!(disasm(0x0dead997), "auipc s3,57005");
+        assert_eq!(disasm(0x0deada37), "lui s4,57005");
+        assert_eq!(disasm(0x00000073), "ecall");
+        assert_eq!(disasm(0x00100073), "ebreak");
+        assert_eq!(disasm(0x01d00073), "wrs.sto");
+        assert_eq!(disasm(0x00000873), ".word 0x00000873");
+        assert_eq!(disasm(0x00813503), "ld a0,8(sp)");
+        assert_eq!(disasm(0xfff54583), "lbu a1,-1(a0)");
+        assert_eq!(disasm(0xfe113c23), "sd ra,-8(sp)");
+        assert_eq!(disasm(0x00017503), ".word 0x00017503");
+        assert_eq!(disasm(0xfeb51ee3), "bne a0,a1,-4");
+        assert_eq!(disasm(0x00c5f463), "bgeu a1,a2,8");
+        assert_eq!(disasm(0x00c5a463), ".word 0x00c5a463");
+        assert_eq!(disasm(0xffdff06f), "jal z0,-4");
+        assert_eq!(disasm(0x00008067), "jalr z0,0(ra)");
+        assert_eq!(disasm(0x00c58533), "add a0,a1,a2");
+        assert_eq!(disasm(0x407352b3), "sra t0,t1,t2");
+        assert_eq!(disasm(0x407312b3), ".word 0x407312b3");
+        assert_eq!(disasm(0x0005051b), "addiw a0,a0,0");
+        assert_eq!(disasm(0x41f5d51b), "sraiw a0,a1,31");
+        assert_eq!(disasm(0x40c5853b), "subw a0,a1,a2");
+        assert_eq!(disasm(0x0215951b), ".word 0x0215951b");
+        assert_eq!(disasm(0x02c5b533), "mulhu a0,a1,a2");
+        assert_eq!(disasm(0x02c5e533), "rem a0,a1,a2");
+        assert_eq!(disasm(0x02c5d53b), "divuw a0,a1,a2");
+        assert_eq!(disasm(0x02c5953b), ".word 0x02c5953b");
+        assert_eq!(disasm(0x00b6252f), "amoadd.w a0,a1,(a2)");
+        assert_eq!(disasm(0x1405b52f), "lr.d.aq a0,(a1)");
+        assert_eq!(disasm(0x1ec5b52f), "sc.d.aqrl a0,a2,(a1)");
+        assert_eq!(disasm(0xe0b6252f), "amomaxu.w a0,a1,(a2)");
+        assert_eq!(disasm(0x10b6252f), ".word 0x10b6252f");
+        assert_eq!(disasm(0x2cb6252f), "amocas.w.aq a0,a1,(a2)");
+        assert_eq!(disasm(0x28e5c62f), "amocas.q a2,a4,(a1)");
+        assert_eq!(disasm(0x00b6452f), ".word 0x00b6452f");
+        assert_eq!(disasm(0x0ff0000f), "fence iorw,iorw");
+        assert_eq!(disasm(0x0230000f), "fence r,rw");
+        assert_eq!(disasm(0x8330000f), "fence.tso");
+        assert_eq!(disasm(0x0000100f), "fence.i");
+        assert_eq!(disasm(0x0100000f), "pause");
+        assert_eq!(disasm(0x00300033), "ntl.pall");
+        assert_eq!(disasm(0x04156013), "prefetch.r 64(a0)");
+        assert_eq!(disasm(0x00156513), "ori a0,a0,1");
+        assert_eq!(disasm(0x0045200f), "cbo.zero (a0)");
+        assert_eq!(disasm(0x0035200f), ".word 0x0035200f");
+        assert_eq!(disasm(0x0000300f), ".word 0x0000300f");
+        assert_eq!(disasm(0x00000000), ".half 0x0000");
+        assert_eq!(disasm(0x157d), "c.addi a0,a0,-1");
+        assert_eq!(disasm(0x9002), "c.ebreak");
+        assert_eq!(disasm(0x2002), "c.fldsp ft0,0(sp)");
+        assert_eq!(disasm(0x4002), ".half 0x4002");
+        assert_eq!(disasm(0x08051613), ".word 0x08051613");
+    }
+
+    #[test]
+    fn test_listing() {
+        let code = [0x13, 0x05, 0xc0, 0xff, 0x73, 0, 0, 0];
+        assert_eq!(
+            listing(&code, 0x100, None),
+            "0x0000000000000100: ffc00513  addi a0,z0,-4\n0x0000000000000104: 00000073  ecall\n"
+        );
+        let counts = BTreeMap::from([(0x100, 12)]);
+        let text = listing(&code, 0x100, Some(&counts));
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[0], "            12  0x0000000000000100: ffc00513  addi a0,z0,-4");
+        assert_eq!(lines[1], "             -  0x0000000000000104: 00000073  ecall");
+
+        // c.addi a0,-1 then the addi, which starts at the next parcel
+        let code = [0x7d, 0x15, 0x13, 0x05, 0xc0, 0xff];
+        assert_eq!(
+            listing(&code, 0x100, None),
+            "0x0000000000000100: 157d      c.addi a0,a0,-1\n0x0000000000000102: ffc00513  addi a0,z0,-4\n"
+        );
+    }
+}
@@ -0,0 +1,641 @@
+// Explain mode and datapath narration, teaching aids.
+//
+// Explain mode shows every retired instruction with its bits split into the
+// fields of its encoding format, the operands it read and the registers it
+// wrote:
+//
+//     0x0000000000000000: ffc00513  addi a0,z0,-4
+//         I-type  imm[11:0]=111111111100 rs1=00000 funct3=000 rd=01010 opcode=0010011
+//         read    z0 = 0x0000000000000000, imm = -4
+//         write   a0 = 0xfffffffffffffffc
+//
+// Narration walks the same instruction through the datapath stages, for
+// single stepping in the debugger:
+//
+//     fetch   ffc00513 from pc 0x0000000000000000
+//     decode  ADDI rd=a0 rs1=z0 imm=-4
+//     read    z0 = 0x0000000000000000
+//     execute 0x0000000000000000 + -4 = 0xfffffffffffffffc
+//     write   a0 = 0xfffffffffffffffc
+//     next    pc = 0x0000000000000004
+//
+// Compressed instructions are shown as the 32-bit instruction they expand
+// to, after a line giving the expansion.
+
+use super::*;
+
+/// The explanation of `inst` given the registers before and after it
+pub(super) fn explain(pc: u64, inst: u32, before: &[u64; 32], after: &[u64; 32]) -> String {
+    let mut text = format!("{:#018x}: {}  {}\n", pc, disasm::hex(inst), disasm(inst));
+    let raw = inst;
+    let inst = expanded(inst);
+    if inst != raw {
+        text.push_str(&format!("    expands to {:08x}  {}\n", inst, disasm(inst)));
+    }
+    let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+    let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS);
+    let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS);
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let imm12 = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+    let imm20 = getfield32!(inst, INST_IMM31_12_WID, INST_IMM31_12_POS);
+    let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS);
+    let imm11_5 = getfield32!(inst, INST_IMM11_5_WID, INST_IMM11_5_POS);
+    let imm4_0 = getfield32!(inst, INST_IMM4_0_WID, INST_IMM4_0_POS);
+
+    let (fields, read) = match opcode {
+        OPCODE_LUI | OPCODE_AUIPC => (
+            format!("U-type  imm[31:12]={:020b} rd={:05b} opcode={:07b}", imm20, rd, opcode),
+            format!("imm = {:#x} << 12", imm20),
+        ),
+        OPCODE_OP_IMM if funct3 == 0b001 || funct3 == 0b101 => {
+            let funct6 = getfield32!(inst, INST_FUNCT6_WID, INST_FUNCT6_POS);
+            let shamt = getfield32!(inst, INST_SHAMT_WID, INST_SHAMT_POS);
+            (
+                format!(
+                    "I-type  funct6={:06b} shamt={:06b} rs1={:05b} funct3={:03b} rd={:05b} opcode={:07b}",
+                    funct6, shamt, rs1, funct3, rd, opcode
+                ),
+                format!("{} = {:#018x}, shamt = {}", REGNAME[rs1 as usize], before[rs1 as usize], shamt),
+            )
+        }
+        OPCODE_OP | OPCODE_OP_32 | OPCODE_AMO => (
+            format!(
+                "R-type  funct7={:07b} rs2={:05b} rs1={:05b} funct3={:03b} rd={:05b} opcode={:07b}",
+                imm11_5, rs2, rs1, funct3, rd, opcode
+            ),
+            format!(
+                "{} = {:#018x}, {} = {:#018x}",
+                REGNAME[rs1 as usize],
+                before[rs1 as usize],
+                REGNAME[rs2 as usize],
+                before[rs2 as usize]
+            ),
+        ),
+        OPCODE_STORE => (
+            format!(
+                "S-type  imm[11:5]={:07b} rs2={:05b} rs1={:05b} funct3={:03b} imm[4:0]={:05b} opcode={:07b}",
+                imm11_5, rs2, rs1, funct3, imm4_0, opcode
+            ),
+            format!(
+                "{} = {:#018x}, {} = {:#018x}, imm = {}",
+                REGNAME[rs1 as usize],
+                before[rs1 as usize],
+                REGNAME[rs2 as usize],
+                before[rs2 as usize],
+                signext12to64(imm11_5 << 5 | imm4_0) as i64
+            ),
+        ),
+        OPCODE_BRANCH => (
+            format!(
+                "B-type  imm[12|10:5]={:07b} rs2={:05b} rs1={:05b} funct3={:03b} imm[4:1|11]={:05b} opcode={:07b}",
+                imm11_5, rs2, rs1, funct3, imm4_0, opcode
+            ),
+            format!(
+                "{} = {:#018x}, {} = {:#018x}, offset = {}",
+                REGNAME[rs1 as usize],
+                before[rs1 as usize],
+                REGNAME[rs2 as usize],
+                before[rs2 as usize],
+                branch_offset(inst) as i64
+            ),
+        ),
+        OPCODE_JAL => (
+            format!("J-type  imm[20|10:1|11|19:12]={:020b} rd={:05b} opcode={:07b}", imm20, rd, opcode),
+            format!("offset = {}", jump_offset(inst) as i64),
+        ),
+        OPCODE_OP_IMM_32 if funct3 == 0b001 || funct3 == 0b101 => {
+            let shamt = getfield32!(inst, 5, INST_SHAMT_POS);
+            (
+                format!(
+                    "I-type  funct7={:07b} shamt={:05b} rs1={:05b} funct3={:03b} rd={:05b} opcode={:07b}",
+                    imm11_5, shamt, rs1, funct3, rd, opcode
+                ),
+                format!("{} = {:#018x}, shamt = {}", REGNAME[rs1 as usize], before[rs1 as usize], shamt),
+            )
+        }
+        // FP registers aren't shown, only the x register an FP load or
+        // store addresses with
+        OPCODE_OP_FP => (
+            format!(
+                "R-type  funct7={:07b} rs2={:05b} rs1={:05b} rm={:03b} rd={:05b} opcode={:07b}",
+                imm11_5, rs2, rs1, funct3, rd, opcode
+            ),
+            String::new(),
+        ),
+        OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD => (
+            format!(
+                "R4-type rs3={:05b} fmt={:02b} rs2={:05b} rs1={:05b} rm={:03b} rd={:05b} opcode={:07b}",
+                imm11_5 >> 2,
+                imm11_5 & 0b11,
+                rs2,
+                rs1,
+                funct3,
+                rd,
+                opcode
+            ),
+            String::new(),
+        ),
+        OPCODE_STORE_FP => (
+            format!(
+                "S-type  imm[11:5]={:07b} rs2={:05b} rs1={:05b} funct3={:03b} imm[4:0]={:05b} opcode={:07b}",
+                imm11_5, rs2, rs1, funct3, imm4_0, opcode
+            ),
+            format!(
+                "{} = {:#018x}, imm = {}",
+                REGNAME[rs1 as usize],
+                before[rs1 as usize],
+                signext12to64(imm11_5 << 5 | imm4_0) as i64
+            ),
+        ),
+        OPCODE_OP_IMM | OPCODE_OP_IMM_32 | OPCODE_LOAD | OPCODE_LOAD_FP | OPCODE_JALR | OPCODE_SYSTEM | OPCODE_MISC_MEM => (
+            format!(
+                "I-type  imm[11:0]={:012b} rs1={:05b} funct3={:03b} rd={:05b} opcode={:07b}",
+                imm12, rs1, funct3, rd, opcode
+            ),
+            format!(
+                "{} = {:#018x}, imm = {}",
+                REGNAME[rs1 as usize],
+                before[rs1 as usize],
+                signext12to64(imm12) as i64
+            ),
+        ),
+        _ => (format!("opcode={:07b}", opcode), String::new()),
+    };
+    text.push_str(&format!("    {}\n", fields));
+    if !read.is_empty() {
+        text.push_str(&format!("    read    {}\n", read));
+    }
+
+    let mut writes: Vec<_> = (0..32)
+        .filter(|&reg| before[reg] != after[reg])
+        .map(|reg| format!("{} = {:#018x}", REGNAME[reg], after[reg]))
+        .collect();
+    if let Some((addr, size, value)) = store(inst, before) {
+        writes.push(format!("mem{}[{:#x}] = {:#x}", size * 8, addr, value));
+    }
+    match writes.is_empty() {
+        true => text.push_str("    write   nothing\n"),
+        false => text.push_str(&format!("    write   {}\n", writes.join(", "))),
+    }
+    text
+}
+
+/// Address, size and value of the memory `inst` writes if it is a store
+fn store(inst: u32, before: &[u64; 32]) -> Option<(u64, u64, u64)> {
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    if getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS) != OPCODE_STORE || funct3 > 0b011 {
+        return None;
+    }
+    let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize;
+    let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+    let imm = getfield32!(inst, INST_IMM11_5_WID, INST_IMM11_5_POS) << 5 | getfield32!(inst, INST_IMM4_0_WID, INST_IMM4_0_POS);
+    let size = 1u64 << funct3;
+    let value = match size {
+        8 => before[rs2],
+        _ => before[rs2] & ((1 << (size * 8)) - 1),
+    };
+    Some((before[rs1].wrapping_add(signext12to64(imm)), size, value))
+}
+
+/// The datapath stages of `inst` at pc given the registers before and
+/// after it and the pc it went on to
+pub(super) fn narrate(pc: u64, next_pc: u64, inst: u32, before: &[u64; 32], after: &[u64; 32]) -> String {
+    let raw = inst;
+    let inst = expanded(inst);
+    let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+    let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize;
+    let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize;
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let funct6 = getfield32!(inst, INST_FUNCT6_WID, INST_FUNCT6_POS);
+    let simm12 = signext12to64(getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS)) as i64;
+    let simm20 = signext20to64(getfield32!(inst, INST_IMM31_12_WID, INST_IMM31_12_POS));
+    let shamt = getfield32!(inst, INST_SHAMT_WID, INST_SHAMT_POS);
+    let mnemonic = disasm(raw).split(' ').next().unwrap_or_default().to_uppercase();
+    let x = before[rs1];
+    let link = pc.wrapping_add(inst_len(raw));
+
+    let mut text = format!("fetch   {} from pc {:#018x}\n", disasm::hex(raw).trim_end(), pc);
+    if inst != raw {
+        text.push_str(&format!("expand  {:08x}  {}\n", inst, disasm(inst)));
+    }
+    let (decode, read, execute) = match opcode {
+        _ if bitmanip::decode(inst).is_some() => {
+            let op = bitmanip::decode(inst).unwrap();
+            let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+            let text = disasm(inst);
+            let operands = text.split_once(' ').map_or("", |(_, operands)| operands).to_string();
+            let (read, args) = match op.shamt() {
+                Some(sh) => (vec![rs1], format!("{:#018x}, {}", x, sh)),
+                None if op.reads_rs2() => (vec![rs1, rs2], format!("{:#018x}, {:#018x}", x, before[rs2])),
+                None => (vec![rs1], format!("{:#018x}", x)),
+            };
+            (operands, read, format!("{}({}) = {:#018x}", op.names().1, args, after[rd]))
+        }
+        _ if crypto::decode(inst).is_some() => {
+            let op = crypto::decode(inst).unwrap();
+            let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+            let text = disasm(inst);
+            let operands = text.split_once(' ').map_or("", |(_, operands)| operands).to_string();
+            let (read, args) = match op {
+                crypto::CryptoOp::AesKs1i(rnum) => (vec![rs1], format!("{:#018x}, {}", x, rnum)),
+                _ if op.reads_rs2() => (vec![rs1, rs2], format!("{:#018x}, {:#018x}", x, before[rs2])),
+                _ => (vec![rs1], format!("{:#018x}", x)),
+            };
+            (operands, read, format!("{}({}) = {:#018x}", op.names().1, args, after[rd]))
+        }
+        OPCODE_LUI => (
+            format!("rd={} imm={:#x}", REGNAME[rd], simm20 & 0xfffff),
+            vec![],
+            format!("{:#x} << 12 = {:#018x}", simm20 & 0xfffff, after[rd]),
+        ),
+        OPCODE_AUIPC => (
+            format!("rd={} imm={:#x}", REGNAME[rd], simm20 & 0xfffff),
+            vec![],
+            format!("pc + ({:#x} << 12) = {:#018x}", simm20 & 0xfffff, after[rd]),
+        ),
+        OPCODE_OP_IMM | OPCODE_OP_IMM_32 if funct3 == 0b001 || funct3 == 0b101 => {
+            let shamt = match opcode {
+                OPCODE_OP_IMM_32 => shamt & 0x1f,
+                _ => shamt,
+            };
+            let op = match (funct3, funct6) {
+                (0b001, _) => "<<",
+                (_, 0b010000) => ">>s",
+                _ => ">>u",
+            };
+            (
+                format!("rd={} rs1={} shamt={}", REGNAME[rd], REGNAME[rs1], shamt),
+                vec![rs1],
+                format!("{:#018x} {} {} = {:#018x}", x, op, shamt, after[rd]),
+            )
+        }
+        OPCODE_OP_IMM | OPCODE_OP_IMM_32 => {
+            let op = match funct3 {
+                0b000 => "+",
+                0b010 => "<s",
+                0b011 => "<u",
+                0b100 => "^",
+                0b110 => "|",
+                _ => "&",
+            };
+            (
+                format!("rd={} rs1={} imm={}", REGNAME[rd], REGNAME[rs1], simm12),
+                vec![rs1],
+                format!("{:#018x} {} {} = {:#018x}", x, op, simm12, after[rd]),
+            )
+        }
+        OPCODE_OP | OPCODE_OP_32 => {
+            let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+            let funct7 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+            let op = match (funct3, funct6) {
+                _ if funct7 == 0b0000001 => ["*", "*h", "*hsu", "*hu", "/s", "/u", "%s", "%u"][funct3 as usize],
+                (0b000, 0b010000) => "-",
+                (0b101, 0b010000) => ">>s",
+                _ => ["+", "<<", "<s", "<u", "^", ">>u", "|", "&"][funct3 as usize],
+            };
+            (
+                format!("rd={} rs1={} rs2={}", REGNAME[rd], REGNAME[rs1], REGNAME[rs2]),
+                vec![rs1, rs2],
+                format!("{:#018x} {} {:#018x} = {:#018x}", x, op, before[rs2], after[rd]),
+            )
+        }
+        OPCODE_LOAD => (
+            format!("rd={} rs1={} imm={}", REGNAME[rd], REGNAME[rs1], simm12),
+            vec![rs1],
+            format!(
+                "load {} bytes from {:#018x} + {} = {:#018x}",
+                1 << (funct3 & 0b11),
+                x,
+                simm12,
+                x.wrapping_add(simm12 as u64)
+            ),
+        ),
+        OPCODE_STORE => {
+            let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+            let (addr, size, _) = store(inst, before).unwrap_or_default();
+            let imm = addr.wrapping_sub(x) as i64;
+            (
+                format!("rs1={} rs2={} imm={}", REGNAME[rs1], REGNAME[rs2], imm),
+                vec![rs1, rs2],
+                format!("store {} bytes to {:#018x} + {} = {:#018x}", size, x, imm, addr),
+            )
+        }
+        OPCODE_AMO => {
+            let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+            // 4, 8 and amocas.q's 16
+            let size = 1 << funct3;
+            let execute = match getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS) >> 2 {
+                0b00010 => format!("load {} bytes from {:#018x} and reserve them", size, x),
+                0b00011 => format!("store {} bytes to {:#018x} if still reserved, rd = 0 on success", size, x),
+                0b00101 => format!(
+                    "load {} bytes from {:#018x}, store {} there if they equal {}, rd = the loaded value",
+                    size, x, REGNAME[rs2], REGNAME[rd]
+                ),
+                funct5 => {
+                    let op = match funct5 {
+                        0b00001 => "replaced by",
+                        0b00000 => "+",
+                        0b00100 => "^",
+                        0b01100 => "&",
+                        0b01000 => "|",
+                        0b10000 => "min",
+                        0b10100 => "max",
+                        0b11000 => "minu",
+                        _ => "maxu",
+                    };
+                    format!(
+                        "load {} bytes from {:#018x}, store them {} {:#018x}, rd = the loaded value",
+                        size, x, op, before[rs2]
+                    )
+                }
+            };
+            (
+                format!("rd={} rs1={} rs2={}", REGNAME[rd], REGNAME[rs1], REGNAME[rs2]),
+                vec![rs1, rs2],
+                execute,
+            )
+        }
+        OPCODE_JAL => {
+            let offset = jump_offset(inst) as i64;
+            (
+                format!("rd={} offset={}", REGNAME[rd], offset),
+                vec![],
+                format!("pc + {} = {:#018x}, link pc + {} = {:#018x}", offset, next_pc, inst_len(raw), link),
+            )
+        }
+        OPCODE_JALR => (
+            format!("rd={} rs1={} imm={}", REGNAME[rd], REGNAME[rs1], simm12),
+            vec![rs1],
+            format!(
+                "({:#018x} + {}) & ~1 = {:#018x}, link pc + {} = {:#018x}",
+                x,
+                simm12,
+                next_pc,
+                inst_len(raw),
+                link
+            ),
+        ),
+        OPCODE_BRANCH => {
+            let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+            let offset = branch_offset(inst) as i64;
+            let op = ["==", "!=", "", "", "<s", ">=s", "<u", ">=u"][funct3 as usize];
+            let outcome = match next_pc == link {
+                true => String::from("not taken"),
+                false => format!("taken to pc + {} = {:#018x}", offset, next_pc),
+            };
+            (
+                format!("rs1={} rs2={} offset={}", REGNAME[rs1], REGNAME[rs2], offset),
+                vec![rs1, rs2],
+                format!("{:#018x} {} {:#018x}, {}", x, op, before[rs2], outcome),
+            )
+        }
+        OPCODE_MISC_MEM if funct3 == 0b010 => {
+            let block = before[rs1] & !(CBO_BLOCK - 1);
+            (
+                format!("rs1={}", REGNAME[rs1]),
+                vec![rs1],
+                match simm12 {
+                    0b100 => format!("zero M[{:#018x}..+{}]", block, CBO_BLOCK),
+                    _ => format!("nothing to do for block {:#018x}, memory is never stale", block),
+                },
+            )
+        }
+        OPCODE_MISC_MEM => (
+            format!("pred={:04b} succ={:04b}", inst >> 24 & 0xf, inst >> 20 & 0xf),
+            vec![],
+            String::from(match funct3 {
+                0b001 => "nothing to do, fetch already sees earlier stores",
+                _ => "nothing to do, memory accesses are already in program order",
+            }),
+        ),
+        OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD | OPCODE_OP_FP => {
+            let text = disasm(inst);
+            let operands = text.split_once(' ').map_or("", |(_, operands)| operands).to_string();
+            let fp = fpu::decode(inst);
+            let int_source = fp.is_some_and(|fp| fp.op.int_source());
+            let size = fp.map_or(0, |fp| fp.fmt.width() / 8);
+            let execute = match opcode {
+                OPCODE_LOAD_FP | OPCODE_STORE_FP => {
+                    let imm = match opcode {
+                        OPCODE_LOAD_FP => simm12,
+                        _ => signext12to64(getfield32!(inst, INST_IMM11_5_WID, INST_IMM11_5_POS) << 5 | rd as u32) as i64,
+                    };
+                    let dir = if opcode == OPCODE_LOAD_FP { "load" } else { "store" };
+                    let to = if opcode == OPCODE_LOAD_FP { "from" } else { "to" };
+                    format!("{} {} bytes {} {:#018x} + {} = {:#018x}", dir, size, to, x, imm, x.wrapping_add(imm as u64))
+                }
+                _ => String::from("computed in the FP unit on the f registers"),
+            };
+            (operands, if int_source { vec![rs1] } else { vec![] }, execute)
+        }
+        OPCODE_SYSTEM if shadowstack::decode(inst).is_some() => {
+            let text = disasm(inst);
+            let operands = text.split_once(' ').map_or("", |(_, operands)| operands).to_string();
+            match shadowstack::decode(inst) {
+                Some(shadowstack::SsOp::Push(rs2)) => (operands, vec![rs2], format!("ssp -= 8, store {} at the new ssp", REGNAME[rs2])),
+                Some(shadowstack::SsOp::PopChk(rs1)) => (
+                    operands,
+                    vec![rs1],
+                    format!("load the entry at ssp, a software-check exception unless it equals {}, ssp += 8", REGNAME[rs1]),
+                ),
+                _ => (operands, vec![], String::from("rd = ssp")),
+            }
+        }
+        OPCODE_SYSTEM if funct3 != 0 => {
+            let text = disasm(inst);
+            let operands = text.split_once(' ').map_or("", |(_, operands)| operands).to_string();
+            let number = getfield32!(inst, INST_IMM11_0_WID, INST_IMM11_0_POS);
+            let csr = csr::name(number).map_or_else(|| format!("{:#x}", number), String::from);
+            let (imm, source) = match funct3 & 0b100 {
+                0 => (false, format!("{:#018x}", x)),
+                _ => (true, format!("{}", rs1)),
+            };
+            let execute = match funct3 & 0b011 {
+                0b01 => format!("rd = the old {}, then {} = {}", csr, csr, source),
+                _ if rs1 == 0 => format!("rd = {}, nothing to write", csr),
+                0b10 => format!("rd = the old {}, then {} |= {}", csr, csr, source),
+                _ => format!("rd = the old {}, then {} &= ~{}", csr, csr, source),
+            };
+            (operands, if imm { vec![] } else { vec![rs1] }, execute)
+        }
+        OPCODE_SYSTEM if inst == INST_WRS_NTO || inst == INST_WRS_STO => (
+            String::new(),
+            vec![],
+            String::from("wait for a store to the reservation set, nothing else stores so the wait times out"),
+        ),
+        OPCODE_SYSTEM => (
+            format!("a7={}", before[REG_A7]),
+            vec![],
+            String::from("system call serviced by the emulator"),
+        ),
+        _ => (String::new(), vec![], String::from("illegal instruction")),
+    };
+    text.push_str(&format!("decode  {} {}\n", mnemonic, decode));
+    for reg in read {
+        text.push_str(&format!("read    {} = {:#018x}\n", REGNAME[reg], before[reg]));
+    }
+    text.push_str(&format!("execute {}\n", execute));
+    for reg in (0..32).filter(|&reg| before[reg] != after[reg]) {
+        text.push_str(&format!("write   {} = {:#018x}\n", REGNAME[reg], after[reg]));
+    }
+    if let Some((addr, size, value)) = store(inst, before) {
+        text.push_str(&format!("write   mem{}[{:#x}] = {:#x}\n", size * 8, addr, value));
+    }
+    // Stores and branches have no rd, those bits are part of the offset,
+    // fences write nothing and f0 is an ordinary register
+    let fp_dest = fpu::decode(inst).is_some_and(|fp| !fp.op.int_dest());
+    if !matches!(opcode, OPCODE_SYSTEM | OPCODE_STORE | OPCODE_BRANCH | OPCODE_MISC_MEM) && !fp_dest && rd == REG_ZERO {
+        text.push_str("write   z0 is hardwired to zero, the result is dropped\n");
+    }
+    text.push_str(&format!("next    pc = {:#018x}\n", next_pc));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_explain() {
+        let before = [0; 32];
+        let mut after = [0; 32];
+        after[REG_A0] = 0xfffffffffffffffc;
+        assert_eq!(
+            explain(0, encode!(addi a0, zero, -4), &before, &after),
+            "0x0000000000000000: ffc00513  addi a0,z0,-4\n    \
+             I-type  imm[11:0]=111111111100 rs1=00000 funct3=000 rd=01010 opcode=0010011\n    \
+             read    z0 = 0x0000000000000000, imm = -4\n    \
+             write   a0 = 0xfffffffffffffffc\n"
+        );
+
+        let text = explain(4, encode!(srai a4, a1, 1), &after, &after);
+        assert!(text.contains("funct6=010000 shamt=000001"), "{}", text);
+        assert!(text.contains("write   nothing"), "{}", text);
+
+        let text = explain(8, encode!(lui s4, 0xdead), &before, &before);
+        assert!(text.contains("U-type  imm[31:12]=00001101111010101101 rd=10100"), "{}", text);
+
+        let mut regs = [0; 32];
+        (regs[REG_SP], regs[REG_A1]) = (0x100, 0x1234);
+        let text = explain(12, encode!(sh a1, -2(sp)), &regs, &regs);
+        assert!(text.contains("S-type  imm[11:5]=1111111 rs2=01011 rs1=00010 funct3=001 imm[4:0]=11110"), "{}", text);
+        assert!(text.contains("write   mem16[0xfe] = 0x1234"), "{}", text);
+
+        let text = explain(16, encode!(beq a1, zero, -8), &regs, &regs);
+        assert!(text.contains("B-type  imm[12|10:5]=1111111 rs2=00000 rs1=01011 funct3=000 imm[4:1|11]=11001"), "{}", text);
+        assert!(text.contains("offset = -8"), "{}", text);
+
+        let text = explain(24, encode!(sra t0, t1, t2), &regs, &regs);
+        assert!(text.contains("R-type  funct7=0100000 rs2=00111 rs1=00110 funct3=101 rd=00101"), "{}", text);
+
+        let text = explain(28, encode!(sraiw a0, a1, 4), &regs, &regs);
+        assert!(text.contains("I-type  funct7=0100000 shamt=00100 rs1=01011"), "{}", text);
+
+        let text = explain(20, encode!(jal zero, -4), &regs, &regs);
+        assert!(text.contains("J-type  imm[20|10:1|11|19:12]=11111111110111111111 rd=00000"), "{}", text);
+
+        let text = explain(36, encode!(fmadd.s fa0, fa1, fa2, fa3), &regs, &regs);
+        assert!(text.contains("R4-type rs3=01101 fmt=00 rs2=01100 rs1=01011 rm=111 rd=01010 opcode=1000011"), "{}", text);
+        assert!(!text.contains("read"), "{}", text);
+
+        // c.addi a0,-1
+        let text = explain(32, 0x157d, &regs, &regs);
+        assert!(text.starts_with("0x0000000000000020: 157d      c.addi a0,a0,-1\n"), "{}", text);
+        assert!(text.contains("    expands to fff50513  addi a0,a0,-1\n"), "{}", text);
+        assert!(text.contains("I-type  imm[11:0]=111111111111 rs1=01010"), "{}", text);
+    }
+
+    #[test]
+    fn test_narrate() {
+        let before = [0; 32];
+        let mut after = [0; 32];
+        after[REG_A0] = 0xfffffffffffffffc;
+        assert_eq!(
+            narrate(0, 4, encode!(addi a0, zero, -4), &before, &after),
+            "fetch   ffc00513 from pc 0x0000000000000000\n\
+             decode  ADDI rd=a0 rs1=z0 imm=-4\n\
+             read    z0 = 0x0000000000000000\n\
+             execute 0x0000000000000000 + -4 = 0xfffffffffffffffc\n\
+             write   a0 = 0xfffffffffffffffc\n\
+             next    pc = 0x0000000000000004\n"
+        );
+
+        let text = narrate(4, 8, encode!(srai zero, a0, 1), &after, &after);
+        assert!(text.contains("decode  SRAI rd=z0 rs1=a0 shamt=1"), "{}", text);
+        assert!(text.contains(">>s 1"), "{}", text);
+        assert!(text.contains("hardwired to zero"), "{}", text);
+
+        let mut regs = [0; 32];
+        (regs[REG_SP], regs[REG_A1]) = (0x100, 0xffff_1234);
+        let text = narrate(8, 12, encode!(sw a1, 8(sp)), &regs, &regs);
+        assert!(text.contains("decode  SW rs1=sp rs2=a1 imm=8"), "{}", text);
+        assert!(text.contains("read    a1 = 0x00000000ffff1234"), "{}", text);
+        assert!(text.contains("write   mem32[0x108] = 0xffff1234"), "{}", text);
+        assert!(!text.contains("hardwired"), "{}", text);
+
+        let text = narrate(0x20, 0x10, encode!(bltu sp, a1, -16), &regs, &regs);
+        assert!(text.contains("decode  BLTU rs1=sp rs2=a1 offset=-16"), "{}", text);
+        assert!(text.contains("<u 0x00000000ffff1234, taken to pc + -16 = 0x0000000000000010"), "{}", text);
+        assert!(text.contains("next    pc = 0x0000000000000010"), "{}", text);
+
+        // c.jalr a0 links pc + 2
+        let text = narrate(0x20, 0x40, 0x9502, &regs, &regs);
+        assert!(text.contains("fetch   9502 from pc 0x0000000000000020\nexpand  000500e7  jalr ra,0(a0)\n"), "{}", text);
+        assert!(text.contains("decode  C.JALR rd=ra rs1=a0 imm=0"), "{}", text);
+        assert!(text.contains("link pc + 2 = 0x0000000000000022"), "{}", text);
+
+        let text = narrate(0x14, 0x18, encode!(sub a0, sp, a1), &regs, &regs);
+        assert!(text.contains("decode  SUB rd=a0 rs1=sp rs2=a1"), "{}", text);
+        assert!(text.contains("0x0000000000000100 - 0x00000000ffff1234"), "{}", text);
+
+        let text = narrate(0x14, 0x18, encode!(divu a0, sp, a1), &regs, &regs);
+        assert!(text.contains("0x0000000000000100 /u 0x00000000ffff1234"), "{}", text);
+
+        let text = narrate(0x18, 0x1c, encode!(sraiw a0, a1, 4), &regs, &regs);
+        assert!(text.contains("decode  SRAIW rd=a0 rs1=a1 shamt=4"), "{}", text);
+
+        let text = narrate(0x10, 0x40, encode!(jal ra, 0x30), &regs, &regs);
+        assert!(text.contains("decode  JAL rd=ra offset=48"), "{}", text);
+        assert!(text.contains("link pc + 4 = 0x0000000000000014"), "{}", text);
+
+        let text = narrate(0x1c, 0x20, encode!(amoor.d a0, a1, (sp)), &regs, &regs);
+        assert!(text.contains("decode  AMOOR.D rd=a0 rs1=sp rs2=a1"), "{}", text);
+        assert!(text.contains("store them | 0x00000000ffff1234"), "{}", text);
+
+        let text = narrate(0x1c, 0x20, encode!(amocas.q a2, a4, (sp)), &regs, &regs);
+        assert!(text.contains("load 16 bytes from"), "{}", text);
+        assert!(text.contains("store a4 there if they equal a2"), "{}", text);
+
+        let text = narrate(0x20, 0x24, encode!(flw ft0, 8(sp)), &regs, &regs);
+        assert!(text.contains("decode  FLW ft0,8(sp)"), "{}", text);
+        assert!(text.contains("load 4 bytes from 0x0000000000000100 + 8 = 0x0000000000000108"), "{}", text);
+        assert!(!text.contains("hardwired"), "{}", text);
+
+        let text = narrate(0x24, 0x28, encode!(fcvt.s.w fa0, a1), &regs, &regs);
+        assert!(text.contains("read    a1 = 0x00000000ffff1234"), "{}", text);
+
+        let text = narrate(0x28, 0x2c, encode!(csrrw a0, mscratch, a1), &regs, &regs);
+        assert!(text.contains("decode  CSRRW a0,mscratch,a1"), "{}", text);
+        assert!(text.contains("read    a1 = 0x00000000ffff1234"), "{}", text);
+        assert!(text.contains("rd = the old mscratch, then mscratch = 0x00000000ffff1234"), "{}", text);
+        let text = narrate(0x2c, 0x30, encode!(csrrsi a0, mstatus, 0), &regs, &regs);
+        assert!(text.contains("rd = mstatus, nothing to write"), "{}", text);
+
+        let mut after = regs;
+        after[REG_A0] = 0x0000_0002_fffd_369c;
+        let text = narrate(0x30, 0x34, encode!(sh1add.uw a0, a1, a1), &regs, &after);
+        assert!(text.contains("decode  SH1ADD.UW a0,a1,a1"), "{}", text);
+        assert!(text.contains("sh1add.uw(0x00000000ffff1234, 0x00000000ffff1234) = 0x00000002fffd369c"), "{}", text);
+
+        let text = narrate(0x34, 0x38, encode!(cpop a0, a1), &regs, &regs);
+        assert!(text.contains("execute cpop(0x00000000ffff1234) = 0x0000000000000000"), "{}", text);
+
+        let text = narrate(0x40, 0x44, encode!(fence), &regs, &regs);
+        assert!(text.contains("decode  FENCE pred=1111 succ=1111"), "{}", text);
+        assert!(!text.contains("hardwired"), "{}", text);
+
+        let text = narrate(0x44, 0x48, encode!(cbo.zero (a1)), &regs, &regs);
+        assert!(text.contains("execute zero M[0x00000000ffff1200..+64]"), "{}", text);
+    }
+}
@@ -0,0 +1,434 @@
+// F, D and Zfh extensions, single, double and half-precision floating point,
+// and the Zfa additions to them.
+//
+// 32 registers f0-f31 beside the integer ones and fcsr holding the dynamic
+// rounding mode (frm) and the accrued exception flags (fflags). The
+// arithmetic itself is in softfloat, which rounds with every mode and
+// reports the flags. Each instruction comes in a .s, a .d and a .h form:
+//
+//     flw fsw fld fsd flh fsh                 LOAD-FP, STORE-FP
+//     fmadd fmsub fnmsub fnmadd               single rounding
+//     fadd fsub fmul fdiv fsqrt fmin fmax
+//     fsgnj fsgnjn fsgnjx                     sign injection, never raise flags
+//     fcvt.{w,wu,l,lu}.s fcvt.s.{w,wu,l,lu}   saturating, invalid out of range
+//     fcvt.s.d fcvt.d.s fcvt.{s,d}.h fcvt.h.{s,d}
+//     fmv.x.w fmv.w.x fmv.x.d fmv.d.x         raw bits
+//     fmv.x.h fmv.h.x
+//     feq flt fle fclass
+//
+// Zfa, in every format unless noted:
+//
+//     fli                                     one of 32 constants, rs1 picks it
+//     fminm fmaxm                             a NaN operand gives NaN
+//     fround froundnx                         to an integer, froundnx flags inexact
+//     fcvtmod.w.d                             truncate and wrap to 32 bits, rtz only
+//     fleq fltq                               quiet compares
+//
+// fmvh.x.d and fmvp.d.x, the move pair, are RV32 only, and the .q forms need
+// the Q extension, so rv64 without Q has none of them.
+//
+// The registers are 64 bits wide. Single and half-precision values are
+// NaN-boxed, kept in the low bits with all the bits above them ones; read
+// as the narrower format, a register that is not properly boxed holds the
+// canonical NaN.
+//
+// With Zfinx (--isa ..._zfinx, Zdinx and Zhinx adding the other formats)
+// there are no f registers: the operands and results of the same
+// instructions are x registers, narrower results sign-extended and the bits
+// above a narrower operand ignored. The FP loads, stores and moves have
+// nothing to move between and are illegal. Disassembly does not know the
+// ISA and keeps the f names, fa0 standing for a0.
+//
+// LATER: mstatus.FS, which would make these illegal while off and track
+// dirty state. mstatus has no FS field yet.
+
+use super::*;
+use softfloat::{FpEnv, Format, Rounding, F16, F32, F64};
+
+// rm encoding that defers to frm
+const RM_DYN: u32 = 0b111;
+const RM_NAME: [&str; 5] = ["rne", "rtz", "rdn", "rup", "rmm"];
+// fli operands by rs1, as assemblers write them
+const FLI_NAME: [&str; 32] = [
+    "-1.0", "min", "1.52587890625e-05", "3.0517578125e-05", "0.00390625", "0.0078125", "0.0625", "0.125",
+    "0.25", "0.3125", "0.375", "0.4375", "0.5", "0.625", "0.75", "0.875",
+    "1.0", "1.25", "1.5", "1.75", "2.0", "2.5", "3.0", "4.0",
+    "8.0", "16.0", "128.0", "256.0", "32768.0", "65536.0", "inf", "nan",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum FpOp {
+    Load,
+    Store,
+    // Negate the product, negate the addend
+    Fma(bool, bool),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Sqrt,
+    // funct3: 0 fsgnj, 1 fsgnjn, 2 fsgnjx
+    SignInject(u32),
+    // max
+    MinMax(bool),
+    // fcvt to or from an integer: signed, 64 bits
+    ToInt(bool, bool),
+    FromInt(bool, bool),
+    // fcvt between formats, from the one given
+    Convert(&'static Format),
+    MoveToInt,
+    MoveFromInt,
+    // funct3: 0 fle, 1 flt, 2 feq, 4 fleq, 5 fltq
+    Compare(u32),
+    Class,
+    // rs1 is the constant's index
+    Fli,
+    // fminm/fmaxm, max
+    MinMaxNan(bool),
+    // froundnx when flagging inexact
+    Round(bool),
+    // fcvtmod.w.d
+    ConvertModular,
+}
+
+impl FpOp {
+    /// The instruction has an rm field rather than a funct3
+    pub(super) fn rounds(&self) -> bool {
+        matches!(
+            self,
+            FpOp::Fma(..) | FpOp::Add | FpOp::Sub | FpOp::Mul | FpOp::Div | FpOp::Sqrt | FpOp::ToInt(..) | FpOp::FromInt(..) | FpOp::Convert(_) | FpOp::Round(_)
+        )
+    }
+
+    /// rd is an x register
+    pub(super) fn int_dest(&self) -> bool {
+        matches!(self, FpOp::ToInt(..) | FpOp::MoveToInt | FpOp::Compare(_) | FpOp::Class | FpOp::ConvertModular)
+    }
+
+    /// Added by Zfa
+    pub(super) fn zfa(&self) -> bool {
+        matches!(self, FpOp::Fli | FpOp::MinMaxNan(_) | FpOp::Round(_) | FpOp::ConvertModular | FpOp::Compare(0b100 | 0b101))
+    }
+
+    /// rs1 is an x register
+    pub(super) fn int_source(&self) -> bool {
+        matches!(self, FpOp::Load | FpOp::Store | FpOp::FromInt(..) | FpOp::MoveFromInt)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct FpInst {
+    pub op: FpOp,
+    pub fmt: &'static Format,
+}
+
+/// The format in an fmt field
+fn format(fmt: u32) -> Option<&'static Format> {
+    match fmt {
+        0b00 => Some(&F32),
+        0b01 => Some(&F64),
+        0b10 => Some(&F16),
+        _ => None,
+    }
+}
+
+/// The format whose width a load or store funct3 gives
+fn mem_format(funct3: u32) -> Option<&'static Format> {
+    match funct3 {
+        0b001 => Some(&F16),
+        0b010 => Some(&F32),
+        0b011 => Some(&F64),
+        _ => None,
+    }
+}
+
+pub(super) fn decode(inst: u32) -> Option<FpInst> {
+    let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let funct7 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+    let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS);
+    let (op, fmt) = match opcode {
+        OPCODE_LOAD_FP => (FpOp::Load, mem_format(funct3)?),
+        OPCODE_STORE_FP => (FpOp::Store, mem_format(funct3)?),
+        // R4-type, fmt in inst[26:25] below rs3
+        OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD => {
+            let op = match opcode {
+                OPCODE_MADD => FpOp::Fma(false, false),
+                OPCODE_MSUB => FpOp::Fma(false, true),
+                OPCODE_NMSUB => FpOp::Fma(true, false),
+                _ => FpOp::Fma(true, true),
+            };
+            (op, format(funct7 & 0b11)?)
+        }
+        OPCODE_OP_FP => {
+            let fmt = format(funct7 & 0b11)?;
+            let op = match (funct7 >> 2, funct3, rs2) {
+                (0b00000, _, _) => FpOp::Add,
+                (0b00001, _, _) => FpOp::Sub,
+                (0b00010, _, _) => FpOp::Mul,
+                (0b00011, _, _) => FpOp::Div,
+                (0b01011, _, 0) => FpOp::Sqrt,
+                (0b00100, 0b000..=0b010, _) => FpOp::SignInject(funct3),
+                (0b00101, 0b000 | 0b001, _) => FpOp::MinMax(funct3 == 1),
+                (0b00101, 0b010 | 0b011, _) => FpOp::MinMaxNan(funct3 == 0b011),
+                (0b11000, _, 0..=3) => FpOp::ToInt(rs2 & 1 == 0, rs2 >> 1 == 1),
+                (0b11000, 0b001, 0b01000) if fmt == &F64 => FpOp::ConvertModular,
+                (0b11010, _, 0..=3) => FpOp::FromInt(rs2 & 1 == 0, rs2 >> 1 == 1),
+                (0b01000, _, 0b00100 | 0b00101) => FpOp::Round(rs2 == 0b00101),
+                (0b01000, _, _) if rs2 != funct7 & 0b11 => FpOp::Convert(format(rs2)?),
+                (0b11100, 0b000, 0) => FpOp::MoveToInt,
+                (0b11100, 0b001, 0) => FpOp::Class,
+                (0b11110, 0b000, 0) => FpOp::MoveFromInt,
+                (0b11110, 0b000, 1) => FpOp::Fli,
+                (0b10100, 0b000..=0b010 | 0b100 | 0b101, _) => FpOp::Compare(funct3),
+                _ => return None,
+            };
+            (op, fmt)
+        }
+        _ => return None,
+    };
+    // 101 and 110 are reserved rounding modes
+    if op.rounds() && matches!(funct3, 0b101 | 0b110) {
+        return None;
+    }
+    Some(FpInst { op, fmt })
+}
+
+/// Assembly text of an FP instruction, None for anything else
+pub(super) fn disasm(inst: u32) -> Option<String> {
+    let FpInst { op, fmt } = decode(inst)?;
+    let field = |pos| getfield32!(inst, 5, pos) as usize;
+    let (rd, rs1, rs2, rs3) = (field(INST_RD_POS), field(INST_RS1_POS), field(INST_RS2_POS), field(27));
+    let (fd, fs1, fs2, fs3) = (FREGNAME[rd], FREGNAME[rs1], FREGNAME[rs2], FREGNAME[rs3]);
+    let (s, width) = (fmt.name, mem_name(fmt));
+    let rm = match getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS) {
+        RM_DYN => String::new(),
+        rm => format!(",{}", RM_NAME[rm as usize]),
+    };
+    let int = |signed: bool, long: bool| match (signed, long) {
+        (true, false) => "w",
+        (false, false) => "wu",
+        (true, true) => "l",
+        (false, true) => "lu",
+    };
+    let text = match op {
+        FpOp::Load => format!("fl{} {},{}({})", width, fd, signext12to64(inst >> 20) as i64, REGNAME[rs1]),
+        FpOp::Store => {
+            let imm = signext12to64(getfield32!(inst, INST_IMM11_5_WID, INST_IMM11_5_POS) << 5 | rd as u32);
+            format!("fs{} {},{}({})", width, fs2, imm as i64, REGNAME[rs1])
+        }
+        FpOp::Fma(neg_product, neg_addend) => {
+            let name = ["fmadd", "fmsub", "fnmsub", "fnmadd"][(neg_product as usize) << 1 | neg_addend as usize];
+            format!("{}.{} {},{},{},{}{}", name, s, fd, fs1, fs2, fs3, rm)
+        }
+        FpOp::Add | FpOp::Sub | FpOp::Mul | FpOp::Div => {
+            let name = match op {
+                FpOp::Add => "fadd",
+                FpOp::Sub => "fsub",
+                FpOp::Mul => "fmul",
+                _ => "fdiv",
+            };
+            format!("{}.{} {},{},{}{}", name, s, fd, fs1, fs2, rm)
+        }
+        FpOp::Sqrt => format!("fsqrt.{} {},{}{}", s, fd, fs1, rm),
+        FpOp::SignInject(funct3) => {
+            format!("{}.{} {},{},{}", ["fsgnj", "fsgnjn", "fsgnjx"][funct3 as usize], s, fd, fs1, fs2)
+        }
+        FpOp::MinMax(max) => format!("{}.{} {},{},{}", if max { "fmax" } else { "fmin" }, s, fd, fs1, fs2),
+        FpOp::MinMaxNan(max) => format!("{}.{} {},{},{}", if max { "fmaxm" } else { "fminm" }, s, fd, fs1, fs2),
+        FpOp::Round(inexact) => format!("{}.{} {},{}{}", if inexact { "froundnx" } else { "fround" }, s, fd, fs1, rm),
+        FpOp::ConvertModular => format!("fcvtmod.w.d {},{}{}", REGNAME[rd], fs1, rm),
+        FpOp::Fli => format!("fli.{} {},{}", s, fd, FLI_NAME[rs1]),
+        FpOp::ToInt(signed, long) => format!("fcvt.{}.{} {},{}{}", int(signed, long), s, REGNAME[rd], fs1, rm),
+        FpOp::FromInt(signed, long) => format!("fcvt.{}.{} {},{}{}", s, int(signed, long), fd, REGNAME[rs1], rm),
+        // Widening is exact, the rm is not written
+        FpOp::Convert(from) if from.width() < fmt.width() => format!("fcvt.{}.{} {},{}", s, from.name, fd, fs1),
+        FpOp::Convert(from) => format!("fcvt.{}.{} {},{}{}", s, from.name, fd, fs1, rm),
+        FpOp::MoveToInt => format!("fmv.x.{} {},{}", width, REGNAME[rd], fs1),
+        FpOp::MoveFromInt => format!("fmv.{}.x {},{}", width, fd, REGNAME[rs1]),
+        FpOp::Compare(funct3) => {
+            let name = ["fle", "flt", "feq", "", "fleq", "fltq"][funct3 as usize];
+            format!("{}.{} {},{},{}", name, s, REGNAME[rd], fs1, fs2)
+        }
+        FpOp::Class => format!("fclass.{} {},{}", s, REGNAME[rd], fs1),
+    };
+    Some(text)
+}
+
+/// The width letter loads, stores and moves use for `fmt`
+fn mem_name(fmt: &Format) -> &'static str {
+    match fmt.width() {
+        16 => "h",
+        32 => "w",
+        _ => "d",
+    }
+}
+
+impl RiscvCpu {
+    /// Value of f`reg` as `fmt`, the canonical NaN if it is not NaN-boxed
+    fn read_fpr(&self, fmt: &Format, reg: usize) -> u64 {
+        if self.isa.zfinx {
+            return self.ixu[reg] & fmt.mask();
+        }
+        let value = self.fpr[reg];
+        match value | fmt.mask() {
+            u64::MAX => value & fmt.mask(),
+            _ => fmt.canonical_nan(),
+        }
+    }
+
+    /// NaN-box `value` of `fmt` into f`reg`
+    fn write_fpr(&mut self, fmt: &Format, reg: usize, value: u64) {
+        match self.isa.zfinx {
+            true => self.ixu[reg] = signext_nto64(value, fmt.width() as u64),
+            false => self.fpr[reg] = value | !fmt.mask(),
+        }
+    }
+
+    /// The formats of `inst` have registers to live in
+    fn fp_enabled(&self, inst: &FpInst) -> bool {
+        if !self.isa.zfinx {
+            return true;
+        }
+        let format_enabled = |fmt: &Format| match fmt.width() {
+            16 => self.isa.zhinx,
+            64 => self.isa.zdinx,
+            _ => true,
+        };
+        match inst.op {
+            FpOp::Load | FpOp::Store | FpOp::MoveToInt | FpOp::MoveFromInt => false,
+            FpOp::Convert(from) => format_enabled(from) && format_enabled(inst.fmt),
+            _ => format_enabled(inst.fmt),
+        }
+    }
+
+    pub(super) fn execute_fp(&mut self, inst: u32) -> Result<(), RiscvCpuError> {
+        let decoded = decode(inst).filter(|fp| self.fp_enabled(fp));
+        let FpInst { op, fmt } = decoded.ok_or(RiscvCpuError::DecodeError)?;
+        let field = |pos| getfield32!(inst, 5, pos) as usize;
+        let (rd, rs1, rs2, rs3) = (field(INST_RD_POS), field(INST_RS1_POS), field(INST_RS2_POS), field(27));
+        // A dynamic rounding mode with frm holding a reserved one is illegal
+        let rm = match getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS) {
+            _ if !op.rounds() => Some(Rounding::NearestEven),
+            RM_DYN => Rounding::from_bits(self.fcsr >> 5 & 0b111),
+            rm => Rounding::from_bits(rm),
+        };
+        let mut env = FpEnv::new(rm.ok_or(RiscvCpuError::DecodeError)?);
+        let (a, b, c) = (self.read_fpr(fmt, rs1), self.read_fpr(fmt, rs2), self.read_fpr(fmt, rs3));
+        let size = fmt.width() as u64 / 8;
+        let result = match op {
+            FpOp::Load => {
+                let addr = self.ixu[rs1].wrapping_add(signext12to64(inst >> 20));
+                self.mem_access(RiscvAccessType::Load, addr, size)?;
+                self.load_data(addr, size).ok_or(RiscvCpuError::AccessFault)?
+            }
+            FpOp::Store => {
+                let imm = getfield32!(inst, INST_IMM11_5_WID, INST_IMM11_5_POS) << 5 | rd as u32;
+                let addr = self.ixu[rs1].wrapping_add(signext12to64(imm));
+                self.mem_access(RiscvAccessType::Store, addr, size)?;
+                self.store_data(addr, size, self.fpr[rs2] & fmt.mask()).ok_or(RiscvCpuError::AccessFault)?;
+                return Ok(());
+            }
+            FpOp::Fma(neg_product, neg_addend) => env.fma(fmt, a, b, c, neg_product, neg_addend),
+            FpOp::Add => env.add(fmt, a, b),
+            FpOp::Sub => env.sub(fmt, a, b),
+            FpOp::Mul => env.mul(fmt, a, b),
+            FpOp::Div => env.div(fmt, a, b),
+            FpOp::Sqrt => env.sqrt(fmt, a),
+            FpOp::SignInject(funct3) => {
+                let sign = match funct3 {
+                    0b000 => b,
+                    0b001 => !b,
+                    _ => a ^ b,
+                } & fmt.sign_bit();
+                a & !fmt.sign_bit() | sign
+            }
+            FpOp::MinMax(max) => env.min_max(fmt, a, b, max),
+            FpOp::MinMaxNan(max) => env.minimum_maximum(fmt, a, b, max),
+            FpOp::Round(inexact) => env.round_to_integral(fmt, a, inexact),
+            FpOp::Fli => fmt.fli(rs1),
+            FpOp::FromInt(signed, long) => {
+                let x = match (signed, long) {
+                    (_, true) => self.ixu[rs1],
+                    (true, false) => self.ixu[rs1] as i32 as u64,
+                    (false, false) => self.ixu[rs1] as u32 as u64,
+                };
+                env.int_to_float(fmt, x, signed)
+            }
+            FpOp::Convert(from) => env.convert(fmt, from, self.read_fpr(from, rs1)),
+            FpOp::MoveFromInt => self.ixu[rs1] & fmt.mask(),
+            FpOp::ToInt(..) | FpOp::MoveToInt | FpOp::Compare(_) | FpOp::Class | FpOp::ConvertModular => {
+                //Results in x registers, 32-bit ones sign extended
+                self.ixu[rd] = match op {
+                    FpOp::ToInt(signed, true) => env.round_to_int(fmt, a, signed, 64),
+                    FpOp::ToInt(signed, false) => env.round_to_int(fmt, a, signed, 32) as i32 as u64,
+                    FpOp::MoveToInt if fmt.width() == 64 => self.fpr[rs1],
+                    FpOp::MoveToInt => signext_nto64(self.fpr[rs1] & fmt.mask(), fmt.width() as u64),
+                    FpOp::Compare(0b000) => env.lt(fmt, a, b, true) as u64,
+                    FpOp::Compare(0b001) => env.lt(fmt, a, b, false) as u64,
+                    FpOp::Compare(0b100) => env.lt_quiet(fmt, a, b, true) as u64,
+                    FpOp::Compare(0b101) => env.lt_quiet(fmt, a, b, false) as u64,
+                    FpOp::ConvertModular => env.round_to_int_modular(fmt, a),
+                    FpOp::Compare(_) => env.eq(fmt, a, b) as u64,
+                    _ => fmt.classify(a),
+                };
+                self.fcsr |= env.flags;
+                return Ok(());
+            }
+        };
+        self.write_fpr(fmt, rd, result);
+        self.fcsr |= env.flags;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fp_decode() {
+        assert_eq!(disasm(0x00c5f553).unwrap(), "fadd.s fa0,fa1,fa2");
+        assert_eq!(disasm(0x00c59553).unwrap(), "fadd.s fa0,fa1,fa2,rtz");
+        assert_eq!(disasm(0x00852507).unwrap(), "flw fa0,8(a0)");
+        assert_eq!(disasm(0xfeb12e27).unwrap(), "fsw fa1,-4(sp)");
+        assert_eq!(disasm(0x68c5f543).unwrap(), "fmadd.s fa0,fa1,fa2,fa3");
+        assert_eq!(disasm(0x1820f04f).unwrap(), "fnmadd.s ft0,ft1,ft2,ft3");
+        assert_eq!(disasm(0xc0051553).unwrap(), "fcvt.w.s a0,fa0,rtz");
+        assert_eq!(disasm(0x00853507).unwrap(), "fld fa0,8(a0)");
+        assert_eq!(disasm(0x02c5f553).unwrap(), "fadd.d fa0,fa1,fa2");
+        assert_eq!(disasm(0xc2251553).unwrap(), "fcvt.l.d a0,fa0,rtz");
+        assert_eq!(disasm(0x4015f553).unwrap(), "fcvt.s.d fa0,fa1");
+        assert_eq!(disasm(0x42058553).unwrap(), "fcvt.d.s fa0,fa1");
+        assert_eq!(disasm(0xe2058553).unwrap(), "fmv.x.d a0,fa1");
+        assert_eq!(disasm(0xf20505d3).unwrap(), "fmv.d.x fa1,a0");
+        assert_eq!(disasm(0xd035f553).unwrap(), "fcvt.s.lu fa0,a1");
+        assert_eq!(disasm(0xe0058553).unwrap(), "fmv.x.w a0,fa1");
+        assert_eq!(disasm(0xf00505d3).unwrap(), "fmv.w.x fa1,a0");
+        assert_eq!(disasm(0xa0b52553).unwrap(), "feq.s a0,fa0,fa1");
+        assert_eq!(disasm(0xe0051553).unwrap(), "fclass.s a0,fa0");
+        assert_eq!(disasm(0x20c5a553).unwrap(), "fsgnjx.s fa0,fa1,fa2");
+        assert_eq!(disasm(0x28c59553).unwrap(), "fmax.s fa0,fa1,fa2");
+        assert_eq!(disasm(0x5805f553).unwrap(), "fsqrt.s fa0,fa1");
+        assert_eq!(disasm(0x00851507).unwrap(), "flh fa0,8(a0)");
+        assert_eq!(disasm(0x04c5f553).unwrap(), "fadd.h fa0,fa1,fa2");
+        assert_eq!(disasm(0x40258553).unwrap(), "fcvt.s.h fa0,fa1");
+        assert_eq!(disasm(0x4405f553).unwrap(), "fcvt.h.s fa0,fa1");
+        assert_eq!(disasm(0xe4058553).unwrap(), "fmv.x.h a0,fa1");
+        assert_eq!(disasm(0xf0140553).unwrap(), "fli.s fa0,0.25");
+        assert_eq!(disasm(0xf2108553).unwrap(), "fli.d fa0,min");
+        assert_eq!(disasm(0x2ac5b553).unwrap(), "fmaxm.d fa0,fa1,fa2");
+        assert_eq!(disasm(0x4455f553).unwrap(), "froundnx.h fa0,fa1");
+        assert_eq!(disasm(0xc2859553).unwrap(), "fcvtmod.w.d a0,fa1,rtz");
+        assert_eq!(disasm(0xa0c5c553).unwrap(), "fleq.s a0,fa1,fa2");
+        // Reserved rm, fmt and rs2
+        assert_eq!(disasm(0x00c5d553), None);
+        assert_eq!(disasm(0x06c5f553), None);
+        assert_eq!(disasm(0x5815f553), None);
+        assert_eq!(disasm(0x00854507), None);
+        assert_eq!(disasm(0x4215f553), None);
+        // fcvtmod is .d with rtz only
+        assert_eq!(disasm(0xc0859553), None);
+        assert_eq!(disasm(0xc285f553), None);
+    }
+}
@@ -0,0 +1,101 @@
+// Macro-op fusion of common instruction idioms.
+//
+// The pre-decoder looks at a pair of adjacent instructions and, when they
+// form one of the idioms below, hands back a single fused operation which
+// the cpu executes in one dispatch. Fusion is only done when the second
+// instruction overwrites the intermediate result of the first, so the
+// architectural state after the fused op is identical to executing both.
+
+use super::*;
+
+#[derive(Debug, PartialEq)]
+pub(super) enum FusedOp {
+    // auipc rd, imm20 ; addi rd, rd, imm12
+    AuipcAddi { rd: usize, upper: u64, offset: u64 },
+    // lui rd, imm20 ; addi rd, rd, imm12
+    LuiAddi { rd: usize, upper: u64, value: u64 },
+    // slli rd, rs1, n ; srli rd, rd, n
+    SlliSrli { rd: usize, rs1: usize, shamt: u32 },
+}
+
+/// fuse(first, second) returns the fused operation for a fusable pair
+pub(super) fn fuse(first: u32, second: u32) -> Option<FusedOp> {
+    let op1 = getfield32!(first, INST_OPCODE_WID, INST_OPCODE_POS);
+    let op2 = getfield32!(second, INST_OPCODE_WID, INST_OPCODE_POS);
+    let rd1 = getfield32!(first, INST_RD_WID, INST_RD_POS) as usize;
+    let rd2 = getfield32!(second, INST_RD_WID, INST_RD_POS) as usize;
+    let rs1_2 = getfield32!(second, INST_RS1_WID, INST_RS1_POS) as usize;
+    let funct3_2 = getfield32!(second, INST_FUNCT3_WID, INST_FUNCT3_POS);
+
+    // Second instruction must consume and overwrite the first's result.
+    // Writes to zero are left to the regular execute path.
+    if op2 != OPCODE_OP_IMM || rd1 == REG_ZERO || rd1 != rd2 || rs1_2 != rd1 {
+        return None;
+    }
+
+    match (op1, funct3_2) {
+        (OPCODE_AUIPC, 0b000) | (OPCODE_LUI, 0b000) => {
+            let imm20 = getfield32!(first, INST_IMM31_12_WID, INST_IMM31_12_POS);
+            let imm12 = getfield32!(second, INST_IMM11_0_WID, INST_IMM11_0_POS);
+            let upper = signext20to64(imm20) << 12;
+            let value = upper.wrapping_add(signext12to64(imm12));
+            if op1 == OPCODE_AUIPC {
+                Some(FusedOp::AuipcAddi { rd: rd1, upper, offset: value })
+            } else {
+                Some(FusedOp::LuiAddi { rd: rd1, upper, value })
+            }
+        }
+        (OPCODE_OP_IMM, 0b101) => {
+            let funct3_1 = getfield32!(first, INST_FUNCT3_WID, INST_FUNCT3_POS);
+            let funct7_2 = getfield32!(second, INST_FUNCT7_WID, INST_FUNCT7_POS);
+            let shamt1 = getfield32!(first, INST_SHAMT_WID, INST_SHAMT_POS);
+            let shamt2 = getfield32!(second, INST_SHAMT_WID, INST_SHAMT_POS);
+            // slli has imm[11:6] == 0, srli has funct7 == 0
+            let imm11_6 = getfield32!(first, 6, 26);
+            if funct3_1 != 0b001 || imm11_6 != 0 || (funct7_2 >> 1) != 0 || shamt1 != shamt2 {
+                return None;
+            }
+            let rs1 = getfield32!(first, INST_RS1_WID, INST_RS1_POS) as usize;
+            Some(FusedOp::SlliSrli { rd: rd1, rs1, shamt: shamt1 })
+        }
+        _ => None,
+    }
+}
+
+impl FusedOp {
+    /// Assembly text of the pair, e.g. "lui+addi a0,74565"
+    pub(super) fn disasm(&self) -> String {
+        match *self {
+            FusedOp::AuipcAddi { rd, offset, .. } => format!("auipc+addi {},{}", REGNAME[rd], offset as i64),
+            FusedOp::LuiAddi { rd, value, .. } => format!("lui+addi {},{}", REGNAME[rd], value as i64),
+            FusedOp::SlliSrli { rd, rs1, shamt } => {
+                format!("slli+srli {},{},{}", REGNAME[rd], REGNAME[rs1], shamt)
+            }
+        }
+    }
+}
+
+impl RiscvCpu {
+    /// Execute a fused pair. pc points to the first instruction of the pair.
+    /// Returns the registers as the first instruction alone leaves them, so
+    /// the commit log still shows both.
+    pub(super) fn execute_fused(&mut self, op: FusedOp) -> [u64; 32] {
+        let mut first = self.ixu;
+        match op {
+            FusedOp::AuipcAddi { rd, upper, offset } => {
+                first[rd] = self.pc.wrapping_add(upper);
+                self.ixu[rd] = self.pc.wrapping_add(offset);
+            }
+            FusedOp::LuiAddi { rd, upper, value } => {
+                first[rd] = upper;
+                self.ixu[rd] = value;
+            }
+            FusedOp::SlliSrli { rd, rs1, shamt } => {
+                first[rd] = self.ixu[rs1] << shamt;
+                // Zero-extension of the low (64 - shamt) bits of rs1
+                self.ixu[rd] = self.ixu[rs1] & (u64::MAX >> shamt);
+            }
+        }
+        first
+    }
+}
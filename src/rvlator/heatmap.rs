@@ -0,0 +1,91 @@
+// Memory access heatmap.
+//
+// Loads and stores are counted per block of --heatmap bytes (4k pages by
+// default) and listed at exit, hottest first, with a bar scaled to the
+// hottest block:
+//
+//     [heatmap] 4096 byte blocks, 3 touched
+//         0x0000000080003000 loads = 1200 stores = 800   ################################
+//         0x0000000080001000 loads = 40   stores = 0     #
+//
+// Fetches aren't counted, the listing is about data.
+
+use super::*;
+
+const HEATMAP_BAR: u64 = 32;
+
+pub(super) struct Heatmap {
+    granule: u64,
+    // Block address -> (loads, stores)
+    blocks: BTreeMap<u64, (u64, u64)>,
+}
+
+impl Heatmap {
+    pub(super) fn new(granule: u64) -> Heatmap {
+        Heatmap {
+            granule,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    /// Memory access hook
+    pub(super) fn access(&mut self, kind: RiscvAccessType, addr: u64, size: u64) {
+        if let RiscvAccessType::Fetch = kind {
+            return;
+        }
+        let last = addr.saturating_add(size.max(1) - 1);
+        for block in (addr / self.granule)..=(last / self.granule) {
+            let counts = self.blocks.entry(block * self.granule).or_default();
+            match kind {
+                RiscvAccessType::Load => counts.0 += 1,
+                _ => counts.1 += 1,
+            }
+        }
+    }
+
+    /// The report, hottest block first, ties in address order
+    pub(super) fn report(&self) -> String {
+        let mut blocks: Vec<_> = self.blocks.iter().collect();
+        blocks.sort_by_key(|(&addr, &(loads, stores))| (std::cmp::Reverse(loads + stores), addr));
+        let hottest = blocks.first().map_or(1, |(_, &(loads, stores))| loads + stores);
+        let mut text = format!(
+            "{COLOR_AQUA}[heatmap]{COLOR_RESET} {} byte blocks, {} touched\n",
+            self.granule,
+            blocks.len()
+        );
+        for (addr, (loads, stores)) in blocks {
+            let bar = ((loads + stores) * HEATMAP_BAR).div_ceil(hottest);
+            text.push_str(&format!(
+                "    {:#018x} loads = {:<8} stores = {:<8} {}\n",
+                addr,
+                loads,
+                stores,
+                "#".repeat(bar as usize)
+            ));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heatmap() {
+        let mut heatmap = Heatmap::new(0x1000);
+        heatmap.access(RiscvAccessType::Load, 0x1008, 8);
+        heatmap.access(RiscvAccessType::Fetch, 0x5000, 4);
+        for _ in 0..3 {
+            heatmap.access(RiscvAccessType::Store, 0x2ffc, 8);
+        }
+        let report = heatmap.report();
+        let lines: Vec<_> = report.lines().skip(1).map(|line| line.split_whitespace().collect::<Vec<_>>()).collect();
+        // The straddling store counts in both blocks, the fetch nowhere
+        assert!(report.contains("4096 byte blocks, 3 touched"), "{}", report);
+        assert_eq!(lines[0][..7], ["0x0000000000002000", "loads", "=", "0", "stores", "=", "3"]);
+        assert_eq!(lines[1][0], "0x0000000000003000");
+        assert_eq!(lines[2][..4], ["0x0000000000001000", "loads", "=", "1"]);
+        assert_eq!(lines[2][7].len(), 11);
+    }
+}
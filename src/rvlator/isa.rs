@@ -0,0 +1,102 @@
+// ISA configuration, the optional extensions a run decodes.
+//
+// --isa takes a -march style string such as rv64gc_zbc. The single-letter
+// base must be one rvlator implements in full (rv64 with i, m, a, f, d,
+// c, g standing for imafd); the multi-letter extensions after it turn on
+// the optional ones:
+//
+//     zbc     carry-less multiply
+//     zkne    AES encryption
+//     zknd    AES decryption
+//     zknh    SHA-2 hash functions
+//     zbkb zbkc zbkx   the bit-manipulation the crypto code needs
+//     zkn     all of zbkb zbkc zbkx zkne zknd zknh
+//     zkr     the seed CSR entropy source
+//     zfinx   F on the x registers, no f registers, so no f in the base
+//     zdinx zhinx   D and Zfh the same way, each implying zfinx
+//     zicfiss shadow stacks, on as though menvcfg.SSE enabled them
+//
+// Extensions that are always decoded (Zicsr, Zba, Zbb, Zbs, ...) are accepted
+// and change nothing, so the -march string of a build works as is.
+
+// Always on, named only so a -march string can list them
+const ALWAYS: [&str; 13] = ["zicsr", "zifencei", "zba", "zbb", "zbs", "zicond", "zicbom", "zicboz", "zacas", "zawrs", "zfh", "zfhmin", "zfa"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(super) struct Isa {
+    pub zbc: bool,
+    pub zkne: bool,
+    pub zknd: bool,
+    pub zknh: bool,
+    pub zkr: bool,
+    pub zbkb: bool,
+    pub zbkc: bool,
+    pub zbkx: bool,
+    pub zfinx: bool,
+    pub zdinx: bool,
+    pub zhinx: bool,
+    pub zicfiss: bool,
+}
+
+impl Isa {
+    pub(super) fn parse(text: &str) -> Result<Isa, String> {
+        let text = text.to_lowercase();
+        let mut parts = text.split('_');
+        let base = parts.next().unwrap_or_default();
+        let letters = base.strip_prefix("rv64").ok_or(format!("ISA must start with rv64: {}", text))?;
+        if let Some(letter) = letters.chars().find(|&c| !"imafdcg".contains(c)) {
+            return Err(format!("unsupported base extension {}", letter));
+        }
+        let mut isa = Isa::default();
+        for ext in parts {
+            match ext {
+                "zbc" => isa.zbc = true,
+                "zkne" => isa.zkne = true,
+                "zknd" => isa.zknd = true,
+                "zknh" => isa.zknh = true,
+                "zkr" => isa.zkr = true,
+                "zbkb" => isa.zbkb = true,
+                "zbkc" => isa.zbkc = true,
+                "zbkx" => isa.zbkx = true,
+                "zfinx" => isa.zfinx = true,
+                "zdinx" => (isa.zfinx, isa.zdinx) = (true, true),
+                "zhinx" | "zhinxmin" => (isa.zfinx, isa.zhinx) = (true, true),
+                "zicfiss" => isa.zicfiss = true,
+                "zkn" => {
+                    (isa.zbkb, isa.zbkc, isa.zbkx) = (true, true, true);
+                    (isa.zkne, isa.zknd, isa.zknh) = (true, true, true);
+                }
+                _ if ALWAYS.contains(&ext) => {}
+                _ => return Err(format!("unsupported extension {}", ext)),
+            }
+        }
+        if isa.zfinx && letters.contains(['f', 'd', 'g']) {
+            return Err(String::from("zfinx excludes the f registers of F and D"));
+        }
+        Ok(isa)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_isa_parse() {
+        assert_eq!(Isa::parse("rv64gc"), Ok(Isa::default()));
+        assert_eq!(Isa::parse("rv64imac_zicsr_zba_zbb"), Ok(Isa::default()));
+        assert!(Isa::parse("RV64GC_Zbc").unwrap().zbc);
+        let isa = Isa::parse("rv64gc_zkne_zknh").unwrap();
+        assert!(isa.zkne && !isa.zknd && isa.zknh);
+        let isa = Isa::parse("rv64gc_zkn").unwrap();
+        assert!(isa.zbkb && isa.zbkx && isa.zknd && !isa.zbc && !isa.zkr);
+        assert!(Isa::parse("rv64gc_zkr").unwrap().zkr);
+        let isa = Isa::parse("rv64imac_zdinx").unwrap();
+        assert!(isa.zfinx && isa.zdinx && !isa.zhinx);
+        assert!(Isa::parse("rv64gc_zicfiss").unwrap().zicfiss);
+        assert_eq!(Isa::parse("rv64gc_zfinx").unwrap_err(), "zfinx excludes the f registers of F and D");
+        assert_eq!(Isa::parse("rv32gc").unwrap_err(), "ISA must start with rv64: rv32gc");
+        assert_eq!(Isa::parse("rv64gcv").unwrap_err(), "unsupported base extension v");
+        assert_eq!(Isa::parse("rv64gc_zvbb").unwrap_err(), "unsupported extension zvbb");
+    }
+}
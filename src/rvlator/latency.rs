@@ -0,0 +1,87 @@
+// Per instruction class latency table.
+//
+// A cheap alternative to the pipeline model: every retired instruction
+// advances the cycle counter by the latency of its class instead of 1.
+
+use super::pipeline::PipeClass;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct LatencyTable {
+    pub alu: u64,
+    pub mul: u64,
+    pub div: u64,
+    pub load: u64,
+    pub store: u64,
+    pub branch: u64,
+    pub jump: u64,
+    pub system: u64,
+}
+
+impl Default for LatencyTable {
+    fn default() -> LatencyTable {
+        LatencyTable {
+            alu: 1,
+            mul: 3,
+            div: 20,
+            load: 2,
+            store: 1,
+            branch: 1,
+            jump: 2,
+            system: 1,
+        }
+    }
+}
+
+impl LatencyTable {
+    /// Parse "CLASS=N,..." on top of the defaults, e.g. "mul=4,div=32".
+    /// An empty spec keeps the defaults.
+    pub(super) fn parse(spec: &str) -> Option<LatencyTable> {
+        let mut table = LatencyTable::default();
+        for entry in spec.split(',').filter(|e| !e.is_empty()) {
+            let (class, cycles) = entry.split_once('=')?;
+            let cycles: u64 = cycles.parse().ok()?;
+            let slot = match class {
+                "alu" => &mut table.alu,
+                "mul" => &mut table.mul,
+                "div" => &mut table.div,
+                "load" => &mut table.load,
+                "store" => &mut table.store,
+                "branch" => &mut table.branch,
+                "jump" => &mut table.jump,
+                "system" => &mut table.system,
+                _ => return None,
+            };
+            *slot = cycles;
+        }
+        Some(table)
+    }
+
+    pub(super) fn latency(&self, class: PipeClass) -> u64 {
+        match class {
+            PipeClass::Alu => self.alu,
+            PipeClass::Mul => self.mul,
+            PipeClass::Div => self.div,
+            PipeClass::Load => self.load,
+            PipeClass::Store => self.store,
+            PipeClass::Branch => self.branch,
+            PipeClass::Jump => self.jump,
+            PipeClass::System => self.system,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_parse() {
+        let table = LatencyTable::parse("mul=4,div=32").unwrap();
+        assert_eq!(table.latency(PipeClass::Mul), 4);
+        assert_eq!(table.latency(PipeClass::Div), 32);
+        assert_eq!(table.latency(PipeClass::Load), 2);
+        assert_eq!(LatencyTable::parse("").unwrap(), LatencyTable::default());
+        assert_eq!(None, LatencyTable::parse("fpu=4"));
+        assert_eq!(None, LatencyTable::parse("mul"));
+    }
+}
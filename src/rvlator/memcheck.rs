@@ -0,0 +1,118 @@
+// Uninitialized read detection.
+//
+// A validity shadow over guest memory: bytes loaded from the image are
+// valid, the rest of memory (--mem-size padding) only becomes valid once
+// stored to. A load touching an invalid byte is reported with its pc and
+// address. Optionally the registers are shadowed too: all but x0 start
+// invalid and become valid when written, so reading a register nothing has
+// set is reported as well.
+//
+// Each pc is reported once, a loop over uninitialized data doesn't flood
+// the output.
+//
+// LATER: Heap tracing on top of this shadow. Break on the malloc, free and
+// realloc entry points and returns (found by symbol) to keep the live
+// allocations, report the ones still live at exit and loads/stores into
+// freed blocks. Needs ELF images with symbols and JAL/JALR.
+
+use super::*;
+use std::collections::BTreeSet;
+
+pub(super) struct MemCheck {
+    mem_base: u64,
+    valid: Vec<bool>,
+    // None when registers aren't checked
+    regs: Option<[bool; 32]>,
+    reported: BTreeSet<u64>,
+    pub reports: Vec<String>,
+}
+
+impl MemCheck {
+    /// Shadow for `mem_len` bytes at mem_base of which the first
+    /// `image_len` hold the loaded image
+    pub(super) fn new(mem_base: u64, mem_len: usize, image_len: usize, check_regs: bool) -> MemCheck {
+        let mut valid = vec![false; mem_len];
+        valid[..image_len.min(mem_len)].fill(true);
+        let mut regs = [false; 32];
+        regs[REG_ZERO] = true;
+        MemCheck {
+            mem_base,
+            valid,
+            regs: check_regs.then_some(regs),
+            reported: BTreeSet::new(),
+            reports: Vec::new(),
+        }
+    }
+
+    fn report(&mut self, pc: u64, what: String) {
+        if self.reported.insert(pc) {
+            self.reports.push(format!("uninitialized read of {} at pc {:#018x}", what, pc));
+        }
+    }
+
+    /// Memory access hook for the instruction at pc
+    pub(super) fn access(&mut self, pc: u64, kind: RiscvAccessType, addr: u64, size: u64) {
+        let start = addr.wrapping_sub(self.mem_base) as usize;
+        let Some(bytes) = self.valid.get_mut(start..start.saturating_add(size as usize)) else { return };
+        match kind {
+            RiscvAccessType::Fetch => {}
+            RiscvAccessType::Load => {
+                if bytes.iter().any(|&valid| !valid) {
+                    self.report(pc, format!("memory {:#018x}", addr));
+                }
+            }
+            RiscvAccessType::Store => bytes.fill(true),
+        }
+    }
+
+    /// Check the source registers of the instruction retired at pc and
+    /// mark its destination valid
+    pub(super) fn retire(&mut self, inst: u32, pc: u64) {
+        let Some(regs) = &mut self.regs else { return };
+        let pi = PipeInst::decode(inst);
+        let unset = pi.rs.iter().flatten().find(|&&reg| !regs[reg]).copied();
+        if let Some(rd) = pi.rd {
+            regs[rd] = true;
+        }
+        if let Some(reg) = unset {
+            self.report(pc, format!("register {}", REGNAME[reg]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_memcheck_memory() {
+        let mut check = MemCheck::new(0x1000, 64, 16, false);
+        check.access(0, RiscvAccessType::Load, 0x1008, 8);
+        assert!(check.reports.is_empty());
+        check.access(4, RiscvAccessType::Load, 0x100c, 8);
+        check.access(4, RiscvAccessType::Load, 0x1020, 8);
+        assert_eq!(check.reports, ["uninitialized read of memory 0x000000000000100c at pc 0x0000000000000004"]);
+        check.access(8, RiscvAccessType::Store, 0x1020, 8);
+        check.access(12, RiscvAccessType::Load, 0x1020, 8);
+        assert_eq!(check.reports.len(), 1);
+        // Outside memory is left to the access itself to fault
+        check.access(16, RiscvAccessType::Load, 0x0, 8);
+        assert_eq!(check.reports.len(), 1);
+    }
+
+    #[test]
+    fn test_memcheck_registers() {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 1))
+            .inst(encode!(addi a1, a0, 1))
+            .inst(encode!(addi a2, a3, 1))
+            .cpu();
+        cpu.memcheck = Some(MemCheck::new(0, cpu.mem.len(), cpu.mem.len(), true));
+        while cpu.fetch().is_ok() {
+            cpu.step().unwrap();
+        }
+        let reports = cpu.memcheck.unwrap().reports;
+        assert_eq!(reports, ["uninitialized read of register a3 at pc 0x0000000000000008"]);
+    }
+}
@@ -0,0 +1,244 @@
+// Draft P extension packed-SIMD instructions in the OP-P opcode.
+//
+// Executed when built with `--features packed`. The encodings are the
+// 0.9 draft's and may still change, so they aren't part of the default
+// build; without the feature they disassemble as .word and `analyze`
+// reports them. The 64-bit registers are split into 4 halfword or 8 byte
+// lanes:
+//
+//     add16 add8 sub16 sub8               wrapping
+//     radd* rsub* uradd* ursub*           halving, signed and unsigned
+//     kadd* ksub* ukadd* uksub*           saturating, signed and unsigned
+//     cmpeq* scmplt* scmple* ucmplt* ucmple*   all ones in true lanes
+//
+// A saturating lane that clamps sets vxsat.OV, which stays set until the
+// guest clears it with a CSR write.
+// LATER: The 32-bit lane, multiply and shift groups aren't decoded.
+
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Lane {
+    Add,
+    Sub,
+    HalvingAdd(bool),
+    HalvingSub(bool),
+    SatAdd(bool),
+    SatSub(bool),
+    CmpEq,
+    CmpLt(bool),
+    CmpLe(bool),
+}
+
+// (lane op, lane width in bits)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct PackedOp(pub Lane, pub u32);
+
+pub(super) fn decode(inst: u32) -> Option<PackedOp> {
+    if getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS) != OPCODE_OP_P
+        || getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS) != 0
+    {
+        return None;
+    }
+    let funct7 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+    // funct7[6:3] picks the group, funct7[2:0] the operation and width
+    let (group, low) = (funct7 >> 3, funct7 & 0b111);
+    let width = match low {
+        0b000 | 0b001 | 0b110 => 16,
+        0b100 | 0b101 | 0b111 => 8,
+        _ => return None,
+    };
+    let lane = match (group, low) {
+        (_, 0b110 | 0b111) => match group {
+            0b0100 => Lane::CmpEq,
+            0b0000 => Lane::CmpLt(true),
+            0b0001 => Lane::CmpLe(true),
+            0b0010 => Lane::CmpLt(false),
+            0b0011 => Lane::CmpLe(false),
+            _ => return None,
+        },
+        (_, _) => {
+            let sub = low & 1 == 1;
+            match (group, sub) {
+                (0b0100, false) => Lane::Add,
+                (0b0100, true) => Lane::Sub,
+                (0b0000, false) => Lane::HalvingAdd(true),
+                (0b0000, true) => Lane::HalvingSub(true),
+                (0b0010, false) => Lane::HalvingAdd(false),
+                (0b0010, true) => Lane::HalvingSub(false),
+                (0b0001, false) => Lane::SatAdd(true),
+                (0b0001, true) => Lane::SatSub(true),
+                (0b0011, false) => Lane::SatAdd(false),
+                (0b0011, true) => Lane::SatSub(false),
+                _ => return None,
+            }
+        }
+    };
+    Some(PackedOp(lane, width))
+}
+
+/// Mnemonic without the lane width
+pub(super) fn mnemonic(lane: Lane) -> &'static str {
+    match lane {
+        Lane::Add => "add",
+        Lane::Sub => "sub",
+        Lane::HalvingAdd(true) => "radd",
+        Lane::HalvingSub(true) => "rsub",
+        Lane::HalvingAdd(false) => "uradd",
+        Lane::HalvingSub(false) => "ursub",
+        Lane::SatAdd(true) => "kadd",
+        Lane::SatSub(true) => "ksub",
+        Lane::SatAdd(false) => "ukadd",
+        Lane::SatSub(false) => "uksub",
+        Lane::CmpEq => "cmpeq",
+        Lane::CmpLt(true) => "scmplt",
+        Lane::CmpLe(true) => "scmple",
+        Lane::CmpLt(false) => "ucmplt",
+        Lane::CmpLe(false) => "ucmple",
+    }
+}
+
+/// Assembly text of a P instruction, None for anything else
+pub(super) fn disasm(inst: u32) -> Option<String> {
+    let PackedOp(lane, width) = decode(inst)?;
+    let rd = REGNAME[getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize];
+    let rs1 = REGNAME[getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize];
+    let rs2 = REGNAME[getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize];
+    Some(format!("{}{} {},{},{}", mnemonic(lane), width, rd, rs1, rs2))
+}
+
+/// One lane of `lane` on `a` and `b`, both `width` bits wide and zero
+/// extended. The result may have bits set above the lane.
+fn lane_op(lane: Lane, width: u32, a: u64, b: u64) -> u64 {
+    let signed = |v: u64| signext_nto64(v, width as u64) as i64;
+    let (smin, smax) = (-(1i64 << (width - 1)), (1i64 << (width - 1)) - 1);
+    let umax = (1u64 << width) - 1;
+    let all_ones = |cond: bool| if cond { umax } else { 0 };
+    match lane {
+        Lane::Add => a.wrapping_add(b),
+        Lane::Sub => a.wrapping_sub(b),
+        Lane::HalvingAdd(true) => ((signed(a) + signed(b)) >> 1) as u64,
+        Lane::HalvingSub(true) => ((signed(a) - signed(b)) >> 1) as u64,
+        Lane::HalvingAdd(false) => (a + b) >> 1,
+        // The borrow is the top bit of the (width + 1)-bit difference
+        Lane::HalvingSub(false) => ((a as i64 - b as i64) >> 1) as u64,
+        Lane::SatAdd(true) => (signed(a) + signed(b)).clamp(smin, smax) as u64,
+        Lane::SatSub(true) => (signed(a) - signed(b)).clamp(smin, smax) as u64,
+        Lane::SatAdd(false) => (a + b).min(umax),
+        Lane::SatSub(false) => a.saturating_sub(b),
+        Lane::CmpEq => all_ones(a == b),
+        Lane::CmpLt(true) => all_ones(signed(a) < signed(b)),
+        Lane::CmpLe(true) => all_ones(signed(a) <= signed(b)),
+        Lane::CmpLt(false) => all_ones(a < b),
+        Lane::CmpLe(false) => all_ones(a <= b),
+    }
+}
+
+/// Whether a saturating `lane` clamped its result
+fn saturates(lane: Lane, width: u32, a: u64, b: u64) -> bool {
+    let signed = |v: u64| signext_nto64(v, width as u64) as i64;
+    let (smin, smax) = (-(1i64 << (width - 1)), (1i64 << (width - 1)) - 1);
+    match lane {
+        Lane::SatAdd(true) => !(smin..=smax).contains(&(signed(a) + signed(b))),
+        Lane::SatSub(true) => !(smin..=smax).contains(&(signed(a) - signed(b))),
+        Lane::SatAdd(false) => a + b > (1u64 << width) - 1,
+        Lane::SatSub(false) => a < b,
+        _ => false,
+    }
+}
+
+impl RiscvCpu {
+    pub(super) fn execute_packed(&mut self, inst: u32) -> Result<(), RiscvCpuError> {
+        let PackedOp(lane, width) = decode(inst).ok_or(RiscvCpuError::DecodeError)?;
+        let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize;
+        let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize;
+        let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+        let mask = (1u64 << width) - 1;
+        let (x, y) = (self.ixu[rs1], self.ixu[rs2]);
+        let lanes = (0..64).step_by(width as usize).map(|shift| (shift, (x >> shift) & mask, (y >> shift) & mask));
+        if lanes.clone().any(|(_, a, b)| saturates(lane, width, a, b)) {
+            self.csrs.insert(csr::CSR_VXSAT, 1);
+        }
+        self.ixu[rd] = lanes.fold(0, |acc, (shift, a, b)| acc | (lane_op(lane, width, a, b) & mask) << shift);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // OP-P R-type, funct3 000
+    fn enc(funct7: u32, rd: usize, rs1: usize, rs2: usize) -> u32 {
+        funct7 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | (rd as u32) << 7 | OPCODE_OP_P
+    }
+
+    #[test]
+    fn test_packed_lanes() {
+        let (x, y) = (0x7fff_8000_ffff_0001u64, 0x0001_ffff_0001_0002u64);
+        let run = |lane, width| {
+            (0..64).step_by(width as usize).fold(0u64, |acc, shift| {
+                let mask = (1u64 << width) - 1;
+                acc | (lane_op(lane, width, (x >> shift) & mask, (y >> shift) & mask) & mask) << shift
+            })
+        };
+        assert_eq!(run(Lane::Add, 16), 0x8000_7fff_0000_0003);
+        assert_eq!(run(Lane::Sub, 16), 0x7ffe_8001_fffe_ffff);
+        assert_eq!(run(Lane::SatAdd(true), 16), 0x7fff_8000_0000_0003);
+        assert_eq!(run(Lane::SatSub(true), 16), 0x7ffe_8001_fffe_ffff);
+        assert_eq!(run(Lane::SatAdd(false), 16), 0x8000_ffff_ffff_0003);
+        assert_eq!(run(Lane::SatSub(false), 16), 0x7ffe_0000_fffe_0000);
+        assert_eq!(run(Lane::HalvingAdd(true), 16), 0x4000_bfff_0000_0001);
+        assert_eq!(run(Lane::HalvingAdd(false), 16), 0x4000_bfff_8000_0001);
+        assert_eq!(run(Lane::HalvingSub(false), 16), 0x3fff_c000_7fff_ffff);
+        assert_eq!(run(Lane::CmpLt(true), 16), 0x0000_ffff_ffff_ffff);
+        assert_eq!(run(Lane::CmpLt(false), 16), 0x0000_ffff_0000_ffff);
+        assert_eq!(run(Lane::CmpEq, 8), 0x0000_0000_0000_ff00);
+        assert_eq!(run(Lane::SatAdd(true), 8), 0x7f00_80ff_ff00_0003);
+    }
+
+    #[test]
+    fn test_packed_decode() {
+        assert_eq!(disasm(enc(0b0100000, REG_A0, REG_A1, REG_A2)).unwrap(), "add16 a0,a1,a2");
+        assert_eq!(disasm(enc(0b0011101, REG_A0, REG_A1, REG_A2)).unwrap(), "uksub8 a0,a1,a2");
+        assert_eq!(disasm(enc(0b0001110, REG_A0, REG_A1, REG_A2)).unwrap(), "scmple16 a0,a1,a2");
+        assert_eq!(disasm(enc(0b0100111, REG_A0, REG_A1, REG_A2)).unwrap(), "cmpeq8 a0,a1,a2");
+        assert_eq!(disasm(enc(0b0101000, REG_A0, REG_A1, REG_A2)), None);
+        assert_eq!(disasm(enc(0b0100000, REG_A0, REG_A1, REG_A2) | 1 << 12), None);
+
+        let mut cpu = RiscvCpu::new(vec![0; 16]);
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0x0102_0304_0506_07f0, 0x0101_0101_0101_0120);
+        // ukadd8 a0,a1,a2
+        cpu.execute_packed(enc(0b0011100, REG_A0, REG_A1, REG_A2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0203_0405_0607_08ff);
+        #[cfg(feature = "packed")]
+        {
+            cpu.execute(enc(0b0100100, REG_A0, REG_A1, REG_A2)).unwrap();
+            assert_eq!(cpu.ixu[REG_A0], 0x0203_0405_0607_0810);
+        }
+        #[cfg(not(feature = "packed"))]
+        assert!(cpu.execute(enc(0b0100100, REG_A0, REG_A1, REG_A2)).is_err());
+    }
+
+    #[test]
+    fn test_packed_vxsat() {
+        let mut cpu = RiscvCpu::new(vec![0; 16]);
+        // kadd16 a0,a1,a2 without and then with a lane that clamps
+        (cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (0x0001_0002_0003_7ff0, 0x0001_0001_0001_000f);
+        cpu.execute_packed(enc(0b0001000, REG_A0, REG_A1, REG_A2)).unwrap();
+        assert_eq!(cpu.csrs[&csr::CSR_VXSAT], 0);
+        cpu.ixu[REG_A2] += 1;
+        cpu.execute_packed(enc(0b0001000, REG_A0, REG_A1, REG_A2)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0002_0003_0004_7fff);
+        assert_eq!(cpu.csrs[&csr::CSR_VXSAT], 1);
+        // It sticks until written
+        cpu.execute_packed(enc(0b0100000, REG_A0, REG_A1, REG_A2)).unwrap();
+        assert_eq!(cpu.csrs[&csr::CSR_VXSAT], 1);
+        #[cfg(feature = "packed")]
+        {
+            // csrrw a3, vxsat, zero
+            cpu.execute(progbuilder::encode_csr(0b001, REG_A3, "vxsat", REG_ZERO)).unwrap();
+            assert_eq!((cpu.ixu[REG_A3], cpu.csrs[&csr::CSR_VXSAT]), (1, 0));
+        }
+    }
+}
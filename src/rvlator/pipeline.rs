@@ -0,0 +1,229 @@
+// 5-stage in-order pipeline timing model (IF ID EX MEM WB).
+//
+// Trace driven: the functional interpreter retires an instruction and the
+// model works out the cycle in which it reaches EX given
+//  - one instruction entering EX per cycle,
+//  - RAW hazards, resolved by EX->EX and MEM->EX forwarding or, without
+//    forwarding, by waiting for the producer's WB (register file is written
+//    in the first half of the cycle and read in the second),
+//  - control hazards, taken branches and jumps resolve in EX and flush the
+//    two younger instructions in IF and ID (predict not taken).
+
+use super::*;
+
+const PIPE_FLUSH_PENALTY: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum PipeClass {
+    Alu,
+    Mul,
+    Div,
+    Load,
+    Store,
+    Branch,
+    Jump,
+    System,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct PipeInst {
+    pub class: PipeClass,
+    pub rd: Option<usize>,
+    pub rs: [Option<usize>; 2],
+}
+
+impl PipeInst {
+    /// Decode the register usage of a 32-bit instruction
+    pub(super) fn decode(inst: u32) -> PipeInst {
+        let opcode = getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS);
+        let rd = Some(getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize);
+        let rs1 = Some(getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize);
+        let rs2 = Some(getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize);
+        let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+        let funct7 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+        let (class, rd, rs) = match opcode {
+            // M extension: mul* in funct3 0-3, div*/rem* in 4-7
+            OPCODE_OP | OPCODE_OP_32 if funct7 == 0b0000001 => {
+                let class = if funct3 < 0b100 { PipeClass::Mul } else { PipeClass::Div };
+                (class, rd, [rs1, rs2])
+            }
+            OPCODE_LUI | OPCODE_AUIPC => (PipeClass::Alu, rd, [None, None]), // lui, auipc
+            OPCODE_OP_IMM | OPCODE_OP_IMM_32 => (PipeClass::Alu, rd, [rs1, None]), // op-imm(-32)
+            OPCODE_OP | OPCODE_OP_32 => (PipeClass::Alu, rd, [rs1, rs2]), // op(-32)
+            OPCODE_OP_P => (PipeClass::Alu, rd, [rs1, rs2]), // op-p
+            OPCODE_LOAD => (PipeClass::Load, rd, [rs1, None]),
+            OPCODE_STORE => (PipeClass::Store, None, [rs1, rs2]),
+            OPCODE_MISC_MEM if funct3 == 0b010 => (PipeClass::Store, None, [rs1, None]), // cbo.*
+            OPCODE_AMO => (PipeClass::Load, rd, [rs1, rs2]), // lr, sc, amo*
+            OPCODE_BRANCH => (PipeClass::Branch, None, [rs1, rs2]),
+            // F: only the x register side of FP instructions is tracked
+            OPCODE_LOAD_FP => (PipeClass::Load, None, [rs1, None]), // flw
+            OPCODE_STORE_FP => (PipeClass::Store, None, [rs1, None]), // fsw
+            OPCODE_MADD | OPCODE_MSUB | OPCODE_NMSUB | OPCODE_NMADD | OPCODE_OP_FP => match fpu::decode(inst) {
+                Some(fp) => {
+                    let class = match fp.op {
+                        fpu::FpOp::Div | fpu::FpOp::Sqrt => PipeClass::Div,
+                        fpu::FpOp::Mul | fpu::FpOp::Fma(..) => PipeClass::Mul,
+                        _ => PipeClass::Alu,
+                    };
+                    let rd = rd.filter(|_| fp.op.int_dest());
+                    (class, rd, [rs1.filter(|_| fp.op.int_source()), None])
+                }
+                None => (PipeClass::System, None, [None, None]),
+            },
+            // csrr*, the immediate forms have no rs1
+            // Zicfiss: sspush stores rs2, sspopchk loads and compares with rs1
+            OPCODE_SYSTEM if shadowstack::decode(inst).is_some() => match shadowstack::decode(inst) {
+                Some(shadowstack::SsOp::Push(_)) => (PipeClass::Store, None, [None, rs2]),
+                Some(shadowstack::SsOp::PopChk(_)) => (PipeClass::Load, None, [rs1, None]),
+                _ => (PipeClass::Alu, rd, [None, None]),
+            },
+            OPCODE_SYSTEM if funct3 & 0b011 != 0 => (PipeClass::System, rd, [rs1.filter(|_| funct3 < 0b100), None]),
+            OPCODE_JAL => (PipeClass::Jump, rd, [None, None]), // jal
+            OPCODE_JALR => (PipeClass::Jump, rd, [rs1, None]), // jalr
+            _ => (PipeClass::System, None, [None, None]),
+        };
+        PipeInst { class, rd, rs }
+    }
+}
+
+pub(super) struct Pipeline {
+    forwarding: bool,
+    // Cycle in which a register value can be consumed by EX
+    ready: [u64; 32],
+    // EX cycle of the previous instruction
+    last_ex: u64,
+    // Previous instruction redirected the fetch
+    redirect: bool,
+    pub insts: u64,
+    pub stalls: u64,
+    pub flushes: u64,
+}
+
+impl Pipeline {
+    pub(super) fn new(forwarding: bool) -> Pipeline {
+        Pipeline {
+            forwarding,
+            ready: [0; 32],
+            // First instruction: IF at 0, ID at 1, EX at 2
+            last_ex: 1,
+            redirect: false,
+            insts: 0,
+            stalls: 0,
+            flushes: 0,
+        }
+    }
+
+    /// Issue one retired instruction. `taken` is set when it changed the
+    /// sequential flow of the pc.
+    pub(super) fn issue(&mut self, pi: PipeInst, taken: bool) {
+        let mut ex = self.last_ex + 1;
+        if self.redirect {
+            ex += PIPE_FLUSH_PENALTY;
+            self.flushes += 1;
+        }
+
+        let mut operands = ex;
+        for rs in pi.rs.iter().flatten() {
+            if *rs != REG_ZERO {
+                operands = operands.max(self.ready[*rs]);
+            }
+        }
+        self.stalls += operands - ex;
+        ex = operands;
+
+        if let Some(rd) = pi.rd {
+            self.ready[rd] = match (pi.class, self.forwarding) {
+                // Load data leaves MEM a cycle after EX
+                (PipeClass::Load, true) => ex + 2,
+                (_, true) => ex + 1,
+                // Written in WB at ex + 2, readable by ID then, so EX at ex + 3
+                (_, false) => ex + 3,
+            };
+        }
+
+        self.redirect = taken;
+        self.last_ex = ex;
+        self.insts += 1;
+    }
+
+    /// Total cycles until the last issued instruction leaves WB
+    pub(super) fn cycles(&self) -> u64 {
+        if self.insts == 0 {
+            0
+        } else {
+            self.last_ex + 3
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alu(rd: usize, rs1: usize) -> PipeInst {
+        PipeInst { class: PipeClass::Alu, rd: Some(rd), rs: [Some(rs1), None] }
+    }
+
+    #[test]
+    fn test_pipeline_independent() {
+        let mut pipe = Pipeline::new(true);
+        for i in 1..=4 {
+            pipe.issue(alu(i, 0), false);
+        }
+        // 4 instructions, 5 stages: 4 + 5 - 1
+        assert_eq!(pipe.cycles(), 8);
+        assert_eq!(pipe.stalls, 0);
+    }
+
+    #[test]
+    fn test_pipeline_forwarding() {
+        let mut fwd = Pipeline::new(true);
+        let mut nofwd = Pipeline::new(false);
+        for pipe in [&mut fwd, &mut nofwd] {
+            pipe.issue(alu(REG_A0, 0), false);
+            pipe.issue(alu(REG_A1, REG_A0), false);
+        }
+        assert_eq!(fwd.stalls, 0);
+        assert_eq!(nofwd.stalls, 2);
+    }
+
+    #[test]
+    fn test_pipeline_load_use() {
+        let mut pipe = Pipeline::new(true);
+        // ld a0, 0(sp) (00013503) ; addi a1, a0, 1
+        pipe.issue(PipeInst::decode(0x00013503), false);
+        pipe.issue(alu(REG_A1, REG_A0), false);
+        assert_eq!(pipe.stalls, 1);
+    }
+
+    #[test]
+    fn test_pipeline_fp() {
+        // fcvt.w.s a0,fa0 writes an x register, fadd.s fa0,fa1,fa2 none
+        let pi = PipeInst::decode(0xc0051553);
+        assert_eq!((pi.class, pi.rd, pi.rs), (PipeClass::Alu, Some(REG_A0), [None, None]));
+        let pi = PipeInst::decode(0x00c5f553);
+        assert_eq!((pi.rd, pi.rs), (None, [None, None]));
+        // fdiv.s fa0,fa1,fa2
+        assert_eq!(PipeInst::decode(0x18c5f553).class, PipeClass::Div);
+    }
+
+    #[test]
+    fn test_pipeline_csr() {
+        // csrrw a0,mscratch,a1 reads a1, csrrci a0,mstatus,5 no register
+        let pi = PipeInst::decode(0x34059573);
+        assert_eq!((pi.class, pi.rd, pi.rs), (PipeClass::System, Some(REG_A0), [Some(REG_A1), None]));
+        assert_eq!(PipeInst::decode(0x3002f573).rs, [None, None]);
+    }
+
+    #[test]
+    fn test_pipeline_taken_branch() {
+        let mut pipe = Pipeline::new(true);
+        // beq zero, zero, 8 (00000463)
+        pipe.issue(PipeInst::decode(0x00000463), true);
+        pipe.issue(alu(REG_A0, 0), false);
+        assert_eq!(pipe.flushes, 1);
+        // 2 instructions, 5 stages, plus the flushed slots
+        assert_eq!(pipe.cycles(), 2 + 4 + PIPE_FLUSH_PENALTY);
+    }
+}
@@ -0,0 +1,921 @@
+// In-test program builder.
+//
+// Assembles instruction sequences straight into a memory image so tests
+// don't depend on binaries built by test/Makefile:
+//
+//     let mut cpu = ProgramBuilder::new()
+//         .inst(encode!(addi a0, zero, -4))
+//         .inst(encode!(slli a2, a0, 60))
+//         .cpu();
+
+use super::*;
+
+/// Register number for an x-name (x10) or ABI name (a0, fp, s10, ...)
+pub(super) fn reg(name: &str) -> usize {
+    regnum(name).unwrap_or_else(|| panic!("unknown register {}", name))
+}
+
+/// FP register number for an f-name (f10) or ABI name (fa0, ft8, ...)
+pub(super) fn freg(name: &str) -> usize {
+    fregnum(name).unwrap_or_else(|| panic!("unknown FP register {}", name))
+}
+
+pub(super) fn encode_i(opcode: u32, funct3: u32, rd: usize, rs1: usize, imm: i64) -> u32 {
+    assert!((-2048..2048).contains(&imm), "imm12 out of range: {}", imm);
+    ((imm as u32) & 0xfff) << INST_IMM11_0_POS
+        | (rs1 as u32) << INST_RS1_POS
+        | funct3 << INST_FUNCT3_POS
+        | (rd as u32) << INST_RD_POS
+        | opcode
+}
+
+pub(super) fn encode_r(opcode: u32, funct7: u32, funct3: u32, rd: usize, rs1: usize, rs2: usize) -> u32 {
+    funct7 << INST_FUNCT7_POS
+        | (rs2 as u32) << INST_RS2_POS
+        | (rs1 as u32) << INST_RS1_POS
+        | funct3 << INST_FUNCT3_POS
+        | (rd as u32) << INST_RD_POS
+        | opcode
+}
+
+/// Shift immediates carry funct6 in imm[11:6] and shamt in imm[5:0]
+pub(super) fn encode_shift(funct3: u32, funct6: u32, rd: usize, rs1: usize, shamt: i64) -> u32 {
+    assert!((0..64).contains(&shamt), "shamt out of range: {}", shamt);
+    funct6 << INST_FUNCT6_POS | encode_i(OPCODE_OP_IMM, funct3, rd, rs1, shamt)
+}
+
+/// S-type, the offset split in imm[11:5] and imm[4:0]
+pub(super) fn encode_s(funct3: u32, rs2: usize, rs1: usize, imm: i64) -> u32 {
+    assert!((-2048..2048).contains(&imm), "imm12 out of range: {}", imm);
+    let imm = (imm as u32) & 0xfff;
+    (imm >> 5) << INST_IMM11_5_POS
+        | (rs2 as u32) << INST_RS2_POS
+        | (rs1 as u32) << INST_RS1_POS
+        | funct3 << INST_FUNCT3_POS
+        | (imm & 0x1f) << INST_IMM4_0_POS
+        | OPCODE_STORE
+}
+
+/// B-type, offset is the even byte offset from the branch
+pub(super) fn encode_b(funct3: u32, rs1: usize, rs2: usize, offset: i64) -> u32 {
+    assert!((-4096..4096).contains(&offset) && offset % 2 == 0, "branch offset out of range: {}", offset);
+    let imm = (offset as u32) & 0x1fff;
+    (imm >> 12) << 31
+        | ((imm >> 5) & 0x3f) << 25
+        | (rs2 as u32) << INST_RS2_POS
+        | (rs1 as u32) << INST_RS1_POS
+        | funct3 << INST_FUNCT3_POS
+        | ((imm >> 1) & 0xf) << 8
+        | ((imm >> 11) & 1) << 7
+        | OPCODE_BRANCH
+}
+
+/// J-type, offset is the even byte offset from the jump
+pub(super) fn encode_j(rd: usize, offset: i64) -> u32 {
+    assert!((-0x100000..0x100000).contains(&offset) && offset % 2 == 0, "jump offset out of range: {}", offset);
+    let imm = (offset as u32) & 0x1fffff;
+    (imm >> 20) << 31
+        | ((imm >> 1) & 0x3ff) << 21
+        | ((imm >> 11) & 1) << 20
+        | ((imm >> 12) & 0xff) << 12
+        | (rd as u32) << INST_RD_POS
+        | OPCODE_JAL
+}
+
+/// Word shift immediates carry funct7 in imm[11:5] and shamt in imm[4:0]
+pub(super) fn encode_shiftw(funct3: u32, funct7: u32, rd: usize, rs1: usize, shamt: i64) -> u32 {
+    assert!((0..32).contains(&shamt), "shamt out of range: {}", shamt);
+    funct7 << INST_FUNCT7_POS | encode_i(OPCODE_OP_IMM_32, funct3, rd, rs1, shamt)
+}
+
+/// LR, SC and AMOs, width is the w or d suffix. aq and rl are left clear.
+pub(super) fn encode_amo(funct5: u32, width: &str, rd: usize, rs1: usize, rs2: usize) -> u32 {
+    let funct3 = match width {
+        "w" => 0b010,
+        "d" => 0b011,
+        "q" => 0b100,
+        _ => panic!("unknown AMO width {}", width),
+    };
+    encode_r(OPCODE_AMO, funct5 << 2, funct3, rd, rs1, rs2)
+}
+
+/// fmt field of an FP format suffix
+fn encode_fmt(fmt: &str) -> u32 {
+    match fmt {
+        "s" => 0b00,
+        "d" => 0b01,
+        "h" => 0b10,
+        _ => panic!("unknown FP format {}", fmt),
+    }
+}
+
+/// OP-FP, fmt is the s suffix and funct3 the rm (111 dyn) or the selector
+pub(super) fn encode_fp(funct5: u32, fmt: &str, funct3: u32, rd: usize, rs1: usize, rs2: usize) -> u32 {
+    encode_r(OPCODE_OP_FP, funct5 << 2 | encode_fmt(fmt), funct3, rd, rs1, rs2)
+}
+
+/// R4-type fused multiply-add, rs3 in inst[31:27], dynamic rounding
+pub(super) fn encode_fma(opcode: u32, fmt: &str, rd: usize, rs1: usize, rs2: usize, rs3: usize) -> u32 {
+    (rs3 as u32) << 27 | encode_r(opcode, encode_fmt(fmt), 0b111, rd, rs1, rs2)
+}
+
+/// SYSTEM csrr*, `csr` by name and `rs1` a register or, in the immediate
+/// forms (funct3[2] set), a 5-bit value
+pub(super) fn encode_csr(funct3: u32, rd: usize, csr: &str, rs1: usize) -> u32 {
+    let csr = csr::number(csr).unwrap_or_else(|| panic!("unknown CSR {}", csr));
+    assert!(rs1 < 32, "uimm5 out of range: {}", rs1);
+    csr << INST_IMM11_0_POS | (rs1 as u32) << INST_RS1_POS | funct3 << INST_FUNCT3_POS | (rd as u32) << INST_RD_POS | OPCODE_SYSTEM
+}
+
+/// imm is the 20-bit upper immediate as written in assembly (lui a0, 0xdead)
+pub(super) fn encode_u(opcode: u32, rd: usize, imm: i64) -> u32 {
+    assert!((-0x80000..0x100000).contains(&imm), "imm20 out of range: {}", imm);
+    ((imm as u32) & 0xfffff) << INST_IMM31_12_POS | (rd as u32) << INST_RD_POS | opcode
+}
+
+/// encode!(mnemonic operands...) assembles one instruction into its u32 encoding
+macro_rules! encode {
+    (lui $rd:ident, $imm:expr) => {
+        encode_u(OPCODE_LUI, reg(stringify!($rd)), $imm)
+    };
+    (auipc $rd:ident, $imm:expr) => {
+        encode_u(OPCODE_AUIPC, reg(stringify!($rd)), $imm)
+    };
+    (addi $rd:ident, $rs1:ident, $imm:expr) => {
+        encode_i(OPCODE_OP_IMM, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (slti $rd:ident, $rs1:ident, $imm:expr) => {
+        encode_i(OPCODE_OP_IMM, 0b010, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (sltiu $rd:ident, $rs1:ident, $imm:expr) => {
+        encode_i(OPCODE_OP_IMM, 0b011, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (xori $rd:ident, $rs1:ident, $imm:expr) => {
+        encode_i(OPCODE_OP_IMM, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (ori $rd:ident, $rs1:ident, $imm:expr) => {
+        encode_i(OPCODE_OP_IMM, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (andi $rd:ident, $rs1:ident, $imm:expr) => {
+        encode_i(OPCODE_OP_IMM, 0b111, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (slli $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shift(0b001, 0b000000, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (srli $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shift(0b101, 0b000000, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (srai $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shift(0b101, 0b010000, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (lb $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (lh $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (lw $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD, 0b010, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (ld $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD, 0b011, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (lbu $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (lhu $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (lwu $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (sb $rs2:ident, $imm:literal($rs1:ident)) => {
+        encode_s(0b000, reg(stringify!($rs2)), reg(stringify!($rs1)), $imm)
+    };
+    (sh $rs2:ident, $imm:literal($rs1:ident)) => {
+        encode_s(0b001, reg(stringify!($rs2)), reg(stringify!($rs1)), $imm)
+    };
+    (sw $rs2:ident, $imm:literal($rs1:ident)) => {
+        encode_s(0b010, reg(stringify!($rs2)), reg(stringify!($rs1)), $imm)
+    };
+    (sd $rs2:ident, $imm:literal($rs1:ident)) => {
+        encode_s(0b011, reg(stringify!($rs2)), reg(stringify!($rs1)), $imm)
+    };
+    (beq $rs1:ident, $rs2:ident, $offset:expr) => {
+        encode_b(0b000, reg(stringify!($rs1)), reg(stringify!($rs2)), $offset)
+    };
+    (bne $rs1:ident, $rs2:ident, $offset:expr) => {
+        encode_b(0b001, reg(stringify!($rs1)), reg(stringify!($rs2)), $offset)
+    };
+    (blt $rs1:ident, $rs2:ident, $offset:expr) => {
+        encode_b(0b100, reg(stringify!($rs1)), reg(stringify!($rs2)), $offset)
+    };
+    (bge $rs1:ident, $rs2:ident, $offset:expr) => {
+        encode_b(0b101, reg(stringify!($rs1)), reg(stringify!($rs2)), $offset)
+    };
+    (bltu $rs1:ident, $rs2:ident, $offset:expr) => {
+        encode_b(0b110, reg(stringify!($rs1)), reg(stringify!($rs2)), $offset)
+    };
+    (bgeu $rs1:ident, $rs2:ident, $offset:expr) => {
+        encode_b(0b111, reg(stringify!($rs1)), reg(stringify!($rs2)), $offset)
+    };
+    (jal $rd:ident, $offset:expr) => {
+        encode_j(reg(stringify!($rd)), $offset)
+    };
+    (jalr $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_JALR, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (add $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000000, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sub $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0100000, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sll $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (slt $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000000, 0b010, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sltu $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000000, 0b011, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (xor $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000000, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (srl $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000000, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sra $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0100000, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (or $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000000, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (and $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000000, 0b111, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (addiw $rd:ident, $rs1:ident, $imm:expr) => {
+        encode_i(OPCODE_OP_IMM_32, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (slliw $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shiftw(0b001, 0b0000000, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (srliw $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shiftw(0b101, 0b0000000, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (sraiw $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shiftw(0b101, 0b0100000, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (add.uw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000100, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sh1add $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0010000, 0b010, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sh2add $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0010000, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sh3add $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0010000, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sh1add.uw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0010000, 0b010, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sh2add.uw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0010000, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sh3add.uw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0010000, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    // OP-IMM-32 with a 6-bit shamt and funct6 in imm[11:6]
+    (slli.uw $rd:ident, $rs1:ident, $shamt:expr) => {
+        0b000010 << INST_FUNCT6_POS | encode_i(OPCODE_OP_IMM_32, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (andn $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0100000, 0b111, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (orn $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0100000, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (xnor $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0100000, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (min $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000101, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (minu $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000101, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (max $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000101, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (maxu $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000101, 0b111, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (rol $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (ror $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0110000, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (rolw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (rorw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0110000, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (rori $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shift(0b101, 0b011000, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (roriw $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shiftw(0b101, 0b0110000, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    // Unary ones pick the operation in rs2 or the whole imm12
+    (clz $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_IMM, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0)
+    };
+    (ctz $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_IMM, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 1)
+    };
+    (cpop $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_IMM, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 2)
+    };
+    (clzw $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_IMM_32, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0)
+    };
+    (ctzw $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_IMM_32, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 1)
+    };
+    (cpopw $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_IMM_32, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 2)
+    };
+    (sext.b $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_IMM, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 4)
+    };
+    (sext.h $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_IMM, 0b0110000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 5)
+    };
+    (zext.h $rd:ident, $rs1:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000100, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), 0)
+    };
+    (orc.b $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), 0x287)
+    };
+    (rev8 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), 0x6b8)
+    };
+    (bclr $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0100100, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (bext $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0100100, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (binv $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0110100, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (bset $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0010100, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (bclri $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shift(0b001, 0b010010, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (bexti $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shift(0b101, 0b010010, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (binvi $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shift(0b001, 0b011010, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (bseti $rd:ident, $rs1:ident, $shamt:expr) => {
+        encode_shift(0b001, 0b001010, reg(stringify!($rd)), reg(stringify!($rs1)), $shamt)
+    };
+    (czero.eqz $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000111, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (czero.nez $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000111, 0b111, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (pack $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000100, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (packh $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000100, 0b111, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (packw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000100, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (xperm4 $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0010100, 0b010, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (xperm8 $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0010100, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (brev8 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), 0x687)
+    };
+    (clmul $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000101, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (clmulr $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000101, 0b010, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (clmulh $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000101, 0b011, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (aes64es $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0011001, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (aes64esm $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0011011, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (aes64ds $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0011101, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (aes64dsm $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0011111, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (aes64ks2 $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0111111, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (aes64ks1i $rd:ident, $rs1:ident, $rnum:expr) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x310 | $rnum)
+    };
+    (aes64im $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x300)
+    };
+    (sha256sum0 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x100)
+    };
+    (sha256sum1 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x101)
+    };
+    (sha256sig0 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x102)
+    };
+    (sha256sig1 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x103)
+    };
+    (sha512sum0 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x104)
+    };
+    (sha512sum1 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x105)
+    };
+    (sha512sig0 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x106)
+    };
+    (sha512sig1 $rd:ident, $rs1:ident) => {
+        encode_i(OPCODE_OP_IMM, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), 0x107)
+    };
+    (addw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000000, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (subw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0100000, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sllw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000000, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (srlw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000000, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (sraw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0100000, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (mul $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000001, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (mulh $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000001, 0b001, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (mulhsu $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000001, 0b010, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (mulhu $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000001, 0b011, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (div $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000001, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (divu $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000001, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (rem $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000001, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (remu $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP, 0b0000001, 0b111, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (mulw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000001, 0b000, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (divw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000001, 0b100, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (divuw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000001, 0b101, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (remw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000001, 0b110, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (remuw $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_r(OPCODE_OP_32, 0b0000001, 0b111, reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (lr.$w:ident $rd:ident, ($rs1:ident)) => {
+        encode_amo(0b00010, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), 0)
+    };
+    (sc.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b00011, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amoswap.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b00001, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amoadd.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b00000, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amoxor.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b00100, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amoand.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b01100, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amoor.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b01000, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amomin.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b10000, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amomax.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b10100, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amominu.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b11000, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amomaxu.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b11100, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (amocas.$w:ident $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        encode_amo(0b00101, stringify!($w), reg(stringify!($rd)), reg(stringify!($rs1)), reg(stringify!($rs2)))
+    };
+    (flh $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD_FP, 0b001, freg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (flw $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD_FP, 0b010, freg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    (fld $rd:ident, $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_LOAD_FP, 0b011, freg(stringify!($rd)), reg(stringify!($rs1)), $imm)
+    };
+    // STORE-FP is STORE with inst[2] set
+    (fsh $rs2:ident, $imm:literal($rs1:ident)) => {
+        encode_s(0b001, freg(stringify!($rs2)), reg(stringify!($rs1)), $imm) | 0b0000100
+    };
+    (fsw $rs2:ident, $imm:literal($rs1:ident)) => {
+        encode_s(0b010, freg(stringify!($rs2)), reg(stringify!($rs1)), $imm) | 0b0000100
+    };
+    (fsd $rs2:ident, $imm:literal($rs1:ident)) => {
+        encode_s(0b011, freg(stringify!($rs2)), reg(stringify!($rs1)), $imm) | 0b0000100
+    };
+    (fmadd.$f:ident $rd:ident, $rs1:ident, $rs2:ident, $rs3:ident) => {
+        encode_fma(OPCODE_MADD, stringify!($f), freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)), freg(stringify!($rs3)))
+    };
+    (fmsub.$f:ident $rd:ident, $rs1:ident, $rs2:ident, $rs3:ident) => {
+        encode_fma(OPCODE_MSUB, stringify!($f), freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)), freg(stringify!($rs3)))
+    };
+    (fnmsub.$f:ident $rd:ident, $rs1:ident, $rs2:ident, $rs3:ident) => {
+        encode_fma(OPCODE_NMSUB, stringify!($f), freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)), freg(stringify!($rs3)))
+    };
+    (fnmadd.$f:ident $rd:ident, $rs1:ident, $rs2:ident, $rs3:ident) => {
+        encode_fma(OPCODE_NMADD, stringify!($f), freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)), freg(stringify!($rs3)))
+    };
+    (fadd.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00000, stringify!($f), 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fsub.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00001, stringify!($f), 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fmul.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00010, stringify!($f), 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fdiv.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00011, stringify!($f), 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fsqrt.$f:ident $rd:ident, $rs1:ident) => {
+        encode_fp(0b01011, stringify!($f), 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), 0)
+    };
+    (fsgnj.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00100, stringify!($f), 0b000, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fsgnjn.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00100, stringify!($f), 0b001, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fsgnjx.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00100, stringify!($f), 0b010, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fmin.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00101, stringify!($f), 0b000, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fmax.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00101, stringify!($f), 0b001, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fcvt.w.$f:ident $rd:ident, $rs1:ident) => {
+        encode_fp(0b11000, stringify!($f), 0b111, reg(stringify!($rd)), freg(stringify!($rs1)), 0)
+    };
+    (fcvt.wu.$f:ident $rd:ident, $rs1:ident) => {
+        encode_fp(0b11000, stringify!($f), 0b111, reg(stringify!($rd)), freg(stringify!($rs1)), 1)
+    };
+    (fcvt.l.$f:ident $rd:ident, $rs1:ident) => {
+        encode_fp(0b11000, stringify!($f), 0b111, reg(stringify!($rd)), freg(stringify!($rs1)), 2)
+    };
+    (fcvt.lu.$f:ident $rd:ident, $rs1:ident) => {
+        encode_fp(0b11000, stringify!($f), 0b111, reg(stringify!($rd)), freg(stringify!($rs1)), 3)
+    };
+    (fcvt.$f:ident.w $rd:ident, $rs1:ident) => {
+        encode_fp(0b11010, stringify!($f), 0b111, freg(stringify!($rd)), reg(stringify!($rs1)), 0)
+    };
+    (fcvt.$f:ident.wu $rd:ident, $rs1:ident) => {
+        encode_fp(0b11010, stringify!($f), 0b111, freg(stringify!($rd)), reg(stringify!($rs1)), 1)
+    };
+    (fcvt.$f:ident.l $rd:ident, $rs1:ident) => {
+        encode_fp(0b11010, stringify!($f), 0b111, freg(stringify!($rd)), reg(stringify!($rs1)), 2)
+    };
+    (fcvt.$f:ident.lu $rd:ident, $rs1:ident) => {
+        encode_fp(0b11010, stringify!($f), 0b111, freg(stringify!($rd)), reg(stringify!($rs1)), 3)
+    };
+    // rs2 is the source format, widening is exact and written with rm rne
+    (fcvt.s.d $rd:ident, $rs1:ident) => {
+        encode_fp(0b01000, "s", 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), 0b01)
+    };
+    (fcvt.d.s $rd:ident, $rs1:ident) => {
+        encode_fp(0b01000, "d", 0b000, freg(stringify!($rd)), freg(stringify!($rs1)), 0b00)
+    };
+    (fcvt.h.s $rd:ident, $rs1:ident) => {
+        encode_fp(0b01000, "h", 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), 0b00)
+    };
+    (fcvt.h.d $rd:ident, $rs1:ident) => {
+        encode_fp(0b01000, "h", 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), 0b01)
+    };
+    (fcvt.s.h $rd:ident, $rs1:ident) => {
+        encode_fp(0b01000, "s", 0b000, freg(stringify!($rd)), freg(stringify!($rs1)), 0b10)
+    };
+    (fcvt.d.h $rd:ident, $rs1:ident) => {
+        encode_fp(0b01000, "d", 0b000, freg(stringify!($rd)), freg(stringify!($rs1)), 0b10)
+    };
+    (fmv.x.w $rd:ident, $rs1:ident) => {
+        encode_fp(0b11100, "s", 0b000, reg(stringify!($rd)), freg(stringify!($rs1)), 0)
+    };
+    (fmv.w.x $rd:ident, $rs1:ident) => {
+        encode_fp(0b11110, "s", 0b000, freg(stringify!($rd)), reg(stringify!($rs1)), 0)
+    };
+    (fmv.x.d $rd:ident, $rs1:ident) => {
+        encode_fp(0b11100, "d", 0b000, reg(stringify!($rd)), freg(stringify!($rs1)), 0)
+    };
+    (fmv.d.x $rd:ident, $rs1:ident) => {
+        encode_fp(0b11110, "d", 0b000, freg(stringify!($rd)), reg(stringify!($rs1)), 0)
+    };
+    (fmv.x.h $rd:ident, $rs1:ident) => {
+        encode_fp(0b11100, "h", 0b000, reg(stringify!($rd)), freg(stringify!($rs1)), 0)
+    };
+    (fmv.h.x $rd:ident, $rs1:ident) => {
+        encode_fp(0b11110, "h", 0b000, freg(stringify!($rd)), reg(stringify!($rs1)), 0)
+    };
+    (feq.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b10100, stringify!($f), 0b010, reg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (flt.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b10100, stringify!($f), 0b001, reg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fle.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b10100, stringify!($f), 0b000, reg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fclass.$f:ident $rd:ident, $rs1:ident) => {
+        encode_fp(0b11100, stringify!($f), 0b001, reg(stringify!($rd)), freg(stringify!($rs1)), 0)
+    };
+    // rs1 is the constant's index in the table, not its value
+    (fli.$f:ident $rd:ident, $index:literal) => {
+        encode_fp(0b11110, stringify!($f), 0b000, freg(stringify!($rd)), $index, 1)
+    };
+    (fminm.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00101, stringify!($f), 0b010, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fmaxm.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b00101, stringify!($f), 0b011, freg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fround.$f:ident $rd:ident, $rs1:ident) => {
+        encode_fp(0b01000, stringify!($f), 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), 0b00100)
+    };
+    (froundnx.$f:ident $rd:ident, $rs1:ident) => {
+        encode_fp(0b01000, stringify!($f), 0b111, freg(stringify!($rd)), freg(stringify!($rs1)), 0b00101)
+    };
+    (fcvtmod.w.d $rd:ident, $rs1:ident) => {
+        encode_fp(0b11000, "d", 0b001, reg(stringify!($rd)), freg(stringify!($rs1)), 0b01000)
+    };
+    (fleq.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b10100, stringify!($f), 0b100, reg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fltq.$f:ident $rd:ident, $rs1:ident, $rs2:ident) => {
+        encode_fp(0b10100, stringify!($f), 0b101, reg(stringify!($rd)), freg(stringify!($rs1)), freg(stringify!($rs2)))
+    };
+    (fence) => {
+        0x0ff0000fu32
+    };
+    (fence.i) => {
+        0x0000100fu32
+    };
+    (cbo.inval ($rs1:ident)) => {
+        encode_i(OPCODE_MISC_MEM, 0b010, 0, reg(stringify!($rs1)), 0)
+    };
+    (cbo.clean ($rs1:ident)) => {
+        encode_i(OPCODE_MISC_MEM, 0b010, 0, reg(stringify!($rs1)), 1)
+    };
+    (cbo.flush ($rs1:ident)) => {
+        encode_i(OPCODE_MISC_MEM, 0b010, 0, reg(stringify!($rs1)), 2)
+    };
+    (cbo.zero ($rs1:ident)) => {
+        encode_i(OPCODE_MISC_MEM, 0b010, 0, reg(stringify!($rs1)), 4)
+    };
+    // Zicfiss, ra or t0 only
+    (sspush $rs2:ident) => {
+        encode_r(OPCODE_SYSTEM, 0b1100111, 0b100, 0, 0, reg(stringify!($rs2)))
+    };
+    (sspopchk $rs1:ident) => {
+        encode_r(OPCODE_SYSTEM, 0b1100110, 0b100, 0, reg(stringify!($rs1)), 0b11100)
+    };
+    (ssrdp $rd:ident) => {
+        encode_r(OPCODE_SYSTEM, 0b1100110, 0b100, reg(stringify!($rd)), 0, 0b11100)
+    };
+    (csrrw $rd:ident, $csr:ident, $rs1:ident) => {
+        encode_csr(0b001, reg(stringify!($rd)), stringify!($csr), reg(stringify!($rs1)))
+    };
+    (csrrs $rd:ident, $csr:ident, $rs1:ident) => {
+        encode_csr(0b010, reg(stringify!($rd)), stringify!($csr), reg(stringify!($rs1)))
+    };
+    (csrrc $rd:ident, $csr:ident, $rs1:ident) => {
+        encode_csr(0b011, reg(stringify!($rd)), stringify!($csr), reg(stringify!($rs1)))
+    };
+    (csrrwi $rd:ident, $csr:ident, $uimm:literal) => {
+        encode_csr(0b101, reg(stringify!($rd)), stringify!($csr), $uimm)
+    };
+    (csrrsi $rd:ident, $csr:ident, $uimm:literal) => {
+        encode_csr(0b110, reg(stringify!($rd)), stringify!($csr), $uimm)
+    };
+    (csrrci $rd:ident, $csr:ident, $uimm:literal) => {
+        encode_csr(0b111, reg(stringify!($rd)), stringify!($csr), $uimm)
+    };
+    (ecall) => {
+        0x00000073u32
+    };
+    (ebreak) => {
+        0x00100073u32
+    };
+    (pause) => {
+        0x0100000fu32
+    };
+    // ori x0, the offset's low 5 bits are zero and imm[4:0] picks the kind
+    (prefetch.i $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_OP_IMM, 0b110, 0, reg(stringify!($rs1)), $imm)
+    };
+    (prefetch.r $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_OP_IMM, 0b110, 0, reg(stringify!($rs1)), $imm | 1)
+    };
+    (prefetch.w $imm:literal($rs1:ident)) => {
+        encode_i(OPCODE_OP_IMM, 0b110, 0, reg(stringify!($rs1)), $imm | 3)
+    };
+    (wrs.nto) => {
+        0x00d00073u32
+    };
+    (wrs.sto) => {
+        0x01d00073u32
+    };
+}
+
+pub(super) struct ProgramBuilder {
+    code: Vec<u8>,
+}
+
+impl ProgramBuilder {
+    pub(super) fn new() -> ProgramBuilder {
+        ProgramBuilder { code: Vec::new() }
+    }
+
+    /// Append one 32-bit instruction, little-endian like the fetch expects
+    pub(super) fn inst(mut self, inst: u32) -> ProgramBuilder {
+        self.code.extend(inst.to_le_bytes());
+        self
+    }
+
+    pub(super) fn insts(self, insts: &[u32]) -> ProgramBuilder {
+        insts.iter().fold(self, |builder, &inst| builder.inst(inst))
+    }
+
+    pub(super) fn build(self) -> Vec<u8> {
+        self.code
+    }
+
+    /// A fresh cpu with the program loaded at the reset vector
+    pub(super) fn cpu(self) -> RiscvCpu {
+        RiscvCpu::new(self.build())
+    }
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        // Encodings from the assembler
+        assert_eq!(encode!(addi a0, zero, -4), 0xffc00513);
+        assert_eq!(encode!(slti a2, a1, -4), 0xffc5a613);
+        assert_eq!(encode!(srai a4, a1, 1), 0x4015d713);
+        assert_eq!(encode!(srli a1, a0, 32), 0x02055593);
+        assert_eq!(encode!(auipc s3, 0xdead), 0x0dead997);
+        assert_eq!(encode!(lui x20, 0xdead), 0x0deada37);
+        assert_eq!(encode!(addi s11, fp, 1), 0x00140d93);
+        assert_eq!(encode!(ld a0, 8(sp)), 0x00813503);
+        assert_eq!(encode!(lbu a1, -1(a0)), 0xfff54583);
+        assert_eq!(encode!(sd ra, -8(sp)), 0xfe113c23);
+        assert_eq!(encode!(sb a1, 2047(a0)), 0x7eb50fa3);
+        assert_eq!(encode!(bne a0, a1, -4), 0xfeb51ee3);
+        assert_eq!(encode!(bgeu a1, a2, 8), 0x00c5f463);
+        assert_eq!(encode!(beq zero, zero, -4096), 0x80000063);
+        assert_eq!(encode!(jal ra, 2048), 0x001000ef);
+        assert_eq!(encode!(jal zero, -4), 0xffdff06f);
+        assert_eq!(encode!(jalr zero, 0(ra)), 0x00008067);
+        assert_eq!(encode!(add a0, a1, a2), 0x00c58533);
+        assert_eq!(encode!(sra t0, t1, t2), 0x407352b3);
+        assert_eq!(encode!(addiw a0, a0, 0), 0x0005051b);
+        assert_eq!(encode!(sraiw a0, a1, 31), 0x41f5d51b);
+        assert_eq!(encode!(subw a0, a1, a2), 0x40c5853b);
+        assert_eq!(encode!(mulhu a0, a1, a2), 0x02c5b533);
+        assert_eq!(encode!(remuw a0, a1, a2), 0x02c5f53b);
+        assert_eq!(encode!(amoadd.w a0, a1, (a2)), 0x00b6252f);
+        assert_eq!(encode!(amocas.q a2, a4, (a1)), 0x28e5c62f);
+        assert_eq!(encode!(lr.d a0, (a1)), 0x1005b52f);
+        assert_eq!(encode!(sc.d a0, a2, (a1)), 0x18c5b52f);
+        assert_eq!(encode!(flw fa0, 8(a0)), 0x00852507);
+        assert_eq!(encode!(fsw fa1, -4(sp)), 0xfeb12e27);
+        assert_eq!(encode!(fadd.s fa0, fa1, fa2), 0x00c5f553);
+        assert_eq!(encode!(fnmadd.s ft0, ft1, ft2, ft3), 0x1820f04f);
+        assert_eq!(encode!(fsqrt.s fa0, fa1), 0x5805f553);
+        assert_eq!(encode!(fsgnjx.s fa0, fa1, fa2), 0x20c5a553);
+        assert_eq!(encode!(fcvt.s.lu fa0, a1), 0xd035f553);
+        assert_eq!(encode!(fmv.w.x fa1, a0), 0xf00505d3);
+        assert_eq!(encode!(feq.s a0, fa0, fa1), 0xa0b52553);
+        assert_eq!(encode!(fclass.s a0, fa0), 0xe0051553);
+        assert_eq!(encode!(fld fa0, 8(a0)), 0x00853507);
+        assert_eq!(encode!(fsd fa1, -4(sp)), 0xfeb13e27);
+        assert_eq!(encode!(fmadd.d fa0, fa1, fa2, fa3), 0x6ac5f543);
+        assert_eq!(encode!(fcvt.s.d fa0, fa1), 0x4015f553);
+        assert_eq!(encode!(fcvt.d.s fa0, fa1), 0x42058553);
+        assert_eq!(encode!(fmv.x.d a0, fa1), 0xe2058553);
+        assert_eq!(encode!(fmv.d.x fa1, a0), 0xf20505d3);
+        assert_eq!(encode!(flh fa0, 8(a0)), 0x00851507);
+        assert_eq!(encode!(fsh fa1, -4(sp)), 0xfeb11e27);
+        assert_eq!(encode!(fcvt.h.s fa0, fa1), 0x4405f553);
+        assert_eq!(encode!(fcvt.d.h fa0, fa1), 0x42258553);
+        assert_eq!(encode!(fmv.x.h a0, fa1), 0xe4058553);
+        assert_eq!(encode!(fli.s fa0, 8), 0xf0140553);
+        assert_eq!(encode!(fround.s fa0, fa1), 0x4045f553);
+        assert_eq!(encode!(fcvtmod.w.d a0, fa1), 0xc2859553);
+        assert_eq!(encode!(fltq.d a0, fa1, fa2), 0xa2c5d553);
+        assert_eq!(encode!(csrrs a0, mcycle, zero), 0xb0002573);
+        assert_eq!(encode!(sspush t0), 0xce504073);
+        assert_eq!(encode!(sspopchk ra), 0xcdc0c073);
+        assert_eq!(encode!(ssrdp a0), 0xcdc04573);
+        assert_eq!(encode!(csrrw zero, mscratch, a1), 0x34059073);
+        assert_eq!(encode!(csrrci a0, mstatus, 5), 0x3002f573);
+        assert_eq!(encode!(sh1add a0, a1, a2), 0x20c5a533);
+        assert_eq!(encode!(sh3add.uw a0, a1, a2), 0x20c5e53b);
+        assert_eq!(encode!(add.uw a0, a1, a2), 0x08c5853b);
+        assert_eq!(encode!(slli.uw a0, a1, 3), 0x0835951b);
+        assert_eq!(encode!(andn a0, a1, a2), 0x40c5f533);
+        assert_eq!(encode!(rori a0, a1, 3), 0x6035d513);
+        assert_eq!(encode!(roriw a0, a1, 12), 0x60c5d51b);
+        assert_eq!(encode!(clz a0, a2), 0x60061513);
+        assert_eq!(encode!(zext.h a0, a1), 0x0805c53b);
+        assert_eq!(encode!(rev8 a0, a1), 0x6b85d513);
+        assert_eq!(encode!(clmulh a0, a1, a2), 0x0ac5b533);
+        assert_eq!(encode!(pack a0, a1, a2), 0x08c5c533);
+        assert_eq!(encode!(xperm8 a0, a1, a2), 0x28c5c533);
+        assert_eq!(encode!(aes64esm a0, a1, a2), 0x36c58533);
+        assert_eq!(encode!(aes64ks1i a0, a1, 10), 0x31a59513);
+        assert_eq!(encode!(sha256sig1 a0, a1), 0x10359513);
+        assert_eq!(encode!(bexti a0, a1, 3), 0x4835d513);
+        assert_eq!(encode!(binvi a0, a1, 32), 0x6a059513);
+        assert_eq!(encode!(czero.eqz a0, a1, a2), 0x0ec5d533);
+        assert_eq!(encode!(fence), 0x0ff0000f);
+        assert_eq!(encode!(fence.i), 0x0000100f);
+        assert_eq!(encode!(cbo.flush (a0)), 0x0025200f);
+        assert_eq!(encode!(pause), 0x0100000f);
+        assert_eq!(encode!(prefetch.r 64(a0)), 0x04156013);
+    }
+
+    #[test]
+    fn test_builder() {
+        let code = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, -4))
+            .insts(&[encode!(addi a1, zero, -5)])
+            .build();
+        assert_eq!(code, vec![0x13, 0x05, 0xc0, 0xff, 0x93, 0x05, 0xb0, 0xff]);
+    }
+}
@@ -0,0 +1,202 @@
+// Memory protection independent of PMP/MMU.
+//
+// Regions of the address space carry read/write/execute permissions, a
+// fetch, load or store the region doesn't allow faults. Memory outside
+// every region is unrestricted, where regions overlap the one declared
+// last wins, byte by byte, so an access straddling two regions needs
+// both to allow it:
+//
+//     --protect 0x80000000:64k:rx      .text, no writes
+//     --protect 0x80100000:1M:rw       stack and heap, no execution
+//
+// Stack guards are ranges no access may touch at all, typically the page
+// below the initial stack. Touching one is reported as a stack overflow
+// rather than a plain permission fault.
+//
+// The s permission marks Zicfiss shadow stack memory. Ordinary stores may
+// not write it whatever else the region allows, and the shadow stack
+// instructions may only write memory like it, or memory outside every
+// region:
+//
+//     --protect 0x80200000:4k:rs       shadow stack
+
+use super::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct Region {
+    pub start: u64,
+    pub len: u64,
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+    pub shadow: bool,
+}
+
+impl Region {
+    fn allows(&self, kind: RiscvAccessType) -> bool {
+        match kind {
+            RiscvAccessType::Fetch => self.exec,
+            RiscvAccessType::Load => self.read,
+            RiscvAccessType::Store => self.write && !self.shadow,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Protect {
+    regions: Vec<Region>,
+    // (start, len)
+    guards: Vec<(u64, u64)>,
+}
+
+impl Protect {
+    pub(super) fn add_region(&mut self, region: Region) {
+        self.regions.push(region);
+    }
+
+    // LATER: Per-thread guards placed from symbols (a guard below each
+    // `*_stack_bottom`), once images can be ELF files with a symbol table
+    pub(super) fn add_guard(&mut self, start: u64, len: u64) {
+        self.guards.push((start, len));
+    }
+
+    /// Check an access, the fault and the reason for it if it faults
+    pub(super) fn check(&self, kind: RiscvAccessType, addr: u64, size: u64) -> Result<(), (RiscvCpuError, String)> {
+        let what = match kind {
+            RiscvAccessType::Fetch => "fetch from",
+            RiscvAccessType::Load => "load from",
+            RiscvAccessType::Store => "store to",
+        };
+        self.check_guards(what, addr, size)?;
+        match self.regions(addr, size).find(|region| !region.allows(kind)) {
+            Some(region) => Err((
+                RiscvCpuError::AccessFault,
+                format!("{} {:#018x} not permitted by region {:#x}:{:#x}", what, addr, region.start, region.len),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a shadow stack instruction's access, which needs a region
+    /// with the s permission if any region covers it at all
+    pub(super) fn check_shadow(&self, addr: u64, size: u64) -> Result<(), (RiscvCpuError, String)> {
+        self.check_guards("shadow stack access to", addr, size)?;
+        match self.regions(addr, size).find(|region| !region.shadow) {
+            Some(region) => Err((
+                RiscvCpuError::AccessFault,
+                format!("shadow stack access to {:#018x} outside a shadow stack, in region {:#x}:{:#x}", addr, region.start, region.len),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The regions in charge of an access, for each byte of it the last
+    /// one given that covers the byte
+    fn regions(&self, addr: u64, size: u64) -> impl Iterator<Item = &Region> {
+        (addr..addr.saturating_add(size))
+            .filter_map(|byte| self.regions.iter().rev().find(|region| overlaps(region.start, region.len, byte, 1)))
+    }
+
+    fn check_guards(&self, what: &str, addr: u64, size: u64) -> Result<(), (RiscvCpuError, String)> {
+        match self.guards.iter().find(|&&(start, len)| overlaps(start, len, addr, size)) {
+            Some((start, len)) => Err((
+                RiscvCpuError::StackOverflow,
+                format!("stack overflow: {} {:#018x} hits the guard {:#x}:{:#x}", what, addr, start, len),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn overlaps(start: u64, len: u64, addr: u64, size: u64) -> bool {
+    addr < start.saturating_add(len) && start < addr.saturating_add(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_protect_regions() {
+        let region = |start, len, perms: &str| Region {
+            start,
+            len,
+            read: perms.contains('r'),
+            write: perms.contains('w'),
+            exec: perms.contains('x'),
+            shadow: perms.contains('s'),
+        };
+        let mut protect = Protect::default();
+        protect.add_region(region(0x1000, 0x100, "rx"));
+        protect.add_region(region(0x1080, 0x10, ""));
+        assert!(protect.check(RiscvAccessType::Fetch, 0x1000, 4).is_ok());
+        assert!(protect.check(RiscvAccessType::Store, 0x2000, 8).is_ok());
+        assert_eq!(
+            protect.check(RiscvAccessType::Store, 0x10fc, 8),
+            Err((
+                RiscvCpuError::AccessFault,
+                String::from("store to 0x00000000000010fc not permitted by region 0x1000:0x100")
+            ))
+        );
+        // The later region wins where they overlap
+        assert!(protect.check(RiscvAccessType::Load, 0x1084, 4).is_err());
+        assert!(protect.check(RiscvAccessType::Load, 0x1090, 4).is_ok());
+        // Straddling two regions needs both to allow the access
+        protect.add_region(region(0x2000, 0x10, "r"));
+        protect.add_region(region(0x2010, 0x10, "rw"));
+        assert!(protect.check(RiscvAccessType::Store, 0x2014, 8).is_ok());
+        assert_eq!(
+            protect.check(RiscvAccessType::Store, 0x200c, 8),
+            Err((
+                RiscvCpuError::AccessFault,
+                String::from("store to 0x000000000000200c not permitted by region 0x2000:0x10")
+            ))
+        );
+
+        // Only shadow stack instructions write a shadow stack, and only there
+        protect.add_region(region(0x3000, 0x1000, "rws"));
+        assert!(protect.check(RiscvAccessType::Load, 0x3ff8, 8).is_ok());
+        assert!(protect.check(RiscvAccessType::Store, 0x3ff8, 8).is_err());
+        assert!(protect.check_shadow(0x3ff8, 8).is_ok());
+        assert!(protect.check_shadow(0x5000, 8).is_ok());
+        assert_eq!(
+            protect.check_shadow(0x1000, 8),
+            Err((
+                RiscvCpuError::AccessFault,
+                String::from("shadow stack access to 0x0000000000001000 outside a shadow stack, in region 0x1000:0x100")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_protect_stack_guard() {
+        let mut protect = Protect::default();
+        protect.add_guard(0x7000, 0x1000);
+        assert!(protect.check(RiscvAccessType::Store, 0x8000, 8).is_ok());
+        assert_eq!(
+            protect.check(RiscvAccessType::Store, 0x7ffc, 8),
+            Err((
+                RiscvCpuError::StackOverflow,
+                String::from("stack overflow: store to 0x0000000000007ffc hits the guard 0x7000:0x1000")
+            ))
+        );
+        assert!(protect.check(RiscvAccessType::Load, 0x6ff8, 8).is_ok());
+    }
+
+    #[test]
+    fn test_protect_fetch_fault() {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 1))
+            .inst(encode!(addi a1, zero, 2))
+            .cpu();
+        cpu.fusion = false;
+        let mut protect = Protect::default();
+        protect.add_region(Region { start: 4, len: 4, read: true, write: false, exec: false, shadow: false });
+        cpu.protect = Some(protect);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(RiscvCpuError::AccessFault));
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.ixu[REG_A1], 0);
+    }
+}
@@ -0,0 +1,27 @@
+// Emulator-internal randomness.
+//
+// Everything random rvlator does (torture programs, random reset state)
+// draws from an Rng seeded from the command line, --seed for a
+// machine and SEED for torture, so a run repeats bit for bit given the
+// same seed.
+
+/// xorshift64* generator, small and reproducible from a seed
+pub(super) struct Rng(u64);
+
+impl Rng {
+    pub(super) fn new(seed: u64) -> Rng {
+        // xorshift state must never be zero
+        Rng(seed ^ 0x9e3779b97f4a7c15)
+    }
+
+    pub(super) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    pub(super) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
@@ -0,0 +1,274 @@
+// Rhai scripting, built with the `script` cargo feature.
+//
+// --script FILE runs the file's top level once, then calls whichever of
+// these functions it defines while the guest runs:
+//
+//     on_start(m)           before the first instruction
+//     on_break(m, pc)       the pc reached a breakpoint set with m.break_at(ADDR)
+//     on_output(m, text)    the guest wrote text to stdout/stderr
+//     on_trap(m, cause)     a step failed (AccessFault, DecodeError, ...)
+//     on_exit(m, code)      the run is over, code is -1 unless the guest exited
+//
+// `m` is the machine: m.reg("a0"), m.set_reg("a0", 1), m.pc, m.pc = ADDR,
+// m.mem(ADDR, SIZE), m.set_mem(ADDR, SIZE, VALUE), m.instret, m.step(),
+// m.break_at(ADDR) (again to clear) and m.stop() to end the run. A script
+// error, `throw` included, fails the run like an assertion does:
+//
+//     fn on_output(m, text) { if text.contains("ready") { m.break_at(0x80000200); } }
+//     fn on_break(m, pc) { if m.reg("a0") != 0 { throw "bad status"; } m.stop(); }
+//
+// LATER: on_mmio and m.interrupt(N) once there is MMIO dispatch and
+// interrupt delivery to hook into.
+
+use super::*;
+use std::path::Path;
+
+#[cfg(feature = "script")]
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+#[cfg(feature = "script")]
+use std::{cell::RefCell, rc::Rc};
+
+/// The stop request, shared between the script and the run. Breakpoints
+/// live on the machine, where step() sees them.
+#[cfg(feature = "script")]
+#[derive(Default)]
+struct Control {
+    stop: bool,
+}
+
+/// The machine as scripts see it. The cpu is moved in for the duration of
+/// a callback and taken back afterwards.
+#[cfg(feature = "script")]
+#[derive(Clone)]
+struct Machine {
+    cpu: Rc<RefCell<RiscvCpu>>,
+    control: Rc<RefCell<Control>>,
+}
+
+#[cfg(feature = "script")]
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+#[cfg(feature = "script")]
+impl Machine {
+    fn reg_num(name: &str) -> ScriptResult<usize> {
+        regnum(name).ok_or_else(|| format!("unknown register {}", name).into())
+    }
+
+    fn reg(&mut self, name: &str) -> ScriptResult<i64> {
+        Ok(self.cpu.borrow().ixu[Machine::reg_num(name)?] as i64)
+    }
+
+    fn set_reg(&mut self, name: &str, value: i64) -> ScriptResult<()> {
+        let reg = Machine::reg_num(name)?;
+        if reg != REG_ZERO {
+            self.cpu.borrow_mut().ixu[reg] = value as u64;
+        }
+        Ok(())
+    }
+
+    fn mem(&mut self, addr: i64, size: i64) -> ScriptResult<i64> {
+        match self.cpu.borrow().read_mem(addr as u64, size as u64) {
+            Some(value) if matches!(size, 1 | 2 | 4 | 8) => Ok(value as i64),
+            _ => Err(format!("unable to read {} bytes at {:#x}", size, addr).into()),
+        }
+    }
+
+    fn set_mem(&mut self, addr: i64, size: i64, value: i64) -> ScriptResult<()> {
+        match matches!(size, 1 | 2 | 4 | 8) {
+            true => self.cpu.borrow_mut().write_mem(addr as u64, size as u64, value as u64),
+            false => None,
+        }
+        .ok_or_else(|| format!("unable to write {} bytes at {:#x}", size, addr).into())
+    }
+
+    fn step(&mut self) -> ScriptResult<()> {
+        self.cpu.borrow_mut().step().map_err(|e| format!("{:?}", e).into())
+    }
+
+    fn break_at(&mut self, addr: i64) {
+        let breaks = &mut self.cpu.borrow_mut().script_breaks;
+        if !breaks.remove(&(addr as u64)) {
+            breaks.insert(addr as u64);
+        }
+    }
+}
+
+#[cfg(feature = "script")]
+pub(super) struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    control: Rc<RefCell<Control>>,
+}
+
+#[cfg(feature = "script")]
+impl Script {
+    pub(super) fn load(path: &Path) -> Result<Script, String> {
+        let mut engine = Engine::new();
+        engine
+            .register_type_with_name::<Machine>("Machine")
+            .register_fn("reg", Machine::reg)
+            .register_fn("set_reg", Machine::set_reg)
+            .register_fn("mem", Machine::mem)
+            .register_fn("set_mem", Machine::set_mem)
+            .register_fn("step", Machine::step)
+            .register_fn("break_at", Machine::break_at)
+            .register_fn("stop", |m: &mut Machine| m.control.borrow_mut().stop = true)
+            .register_get("pc", |m: &mut Machine| m.cpu.borrow().pc as i64)
+            .register_set("pc", |m: &mut Machine, pc: i64| m.cpu.borrow_mut().pc = pc as u64)
+            .register_get("instret", |m: &mut Machine| m.cpu.borrow().instret as i64);
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast).map_err(|e| e.to_string())?;
+        Ok(Script {
+            engine,
+            ast,
+            scope,
+            control: Rc::default(),
+        })
+    }
+
+    /// Call `name` with the machine and `args` if the script defines it
+    fn call(&mut self, cpu: &mut RiscvCpu, name: &str, args: Vec<Dynamic>) -> Result<(), String> {
+        if !self.ast.iter_functions().any(|f| f.name == name && f.params.len() == args.len() + 1) {
+            return Ok(());
+        }
+        let machine = Machine {
+            cpu: Rc::new(RefCell::new(std::mem::replace(cpu, RiscvCpu::new(Vec::new())))),
+            control: self.control.clone(),
+        };
+        let options = CallFnOptions::new().eval_ast(false).rewind_scope(true);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut self.scope,
+            &self.ast,
+            name,
+            [vec![Dynamic::from(machine.clone())], args].concat(),
+        );
+        *cpu = machine.cpu.replace(RiscvCpu::new(Vec::new()));
+        result.map(|_| ()).map_err(|e| format!("{}: {}", name, e))
+    }
+
+    pub(super) fn start(&mut self, cpu: &mut RiscvCpu) -> Result<(), String> {
+        cpu.output = Some(Vec::new());
+        self.call(cpu, "on_start", Vec::new())
+    }
+
+    fn flush_output(&mut self, cpu: &mut RiscvCpu) -> Result<(), String> {
+        match cpu.output.as_mut().filter(|output| !output.is_empty()) {
+            Some(output) => {
+                let text = String::from_utf8_lossy(&std::mem::take(output)).into_owned();
+                self.call(cpu, "on_output", vec![text.into()])
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Before each step: breakpoints and output. True to end the run.
+    pub(super) fn at_pc(&mut self, cpu: &mut RiscvCpu) -> Result<bool, String> {
+        self.flush_output(cpu)?;
+        if cpu.script_breaks.contains(&cpu.pc) {
+            self.call(cpu, "on_break", vec![(cpu.pc as i64).into()])?;
+        }
+        Ok(self.control.borrow().stop)
+    }
+
+    pub(super) fn trap(&mut self, cpu: &mut RiscvCpu, cause: &RiscvCpuError) -> Result<(), String> {
+        self.call(cpu, "on_trap", vec![format!("{:?}", cause).into()])
+    }
+
+    pub(super) fn exit(&mut self, cpu: &mut RiscvCpu) -> Result<(), String> {
+        self.flush_output(cpu)?;
+        let code = cpu.exit_code.map_or(-1, |code| code as i64);
+        self.call(cpu, "on_exit", vec![code.into()])
+    }
+}
+
+/// Without the `script` feature --script is refused up front
+#[cfg(not(feature = "script"))]
+pub(super) struct Script;
+
+#[cfg(not(feature = "script"))]
+impl Script {
+    pub(super) fn load(_path: &Path) -> Result<Script, String> {
+        Err(String::from("--script needs rvlator built with --features script"))
+    }
+
+    pub(super) fn start(&mut self, _cpu: &mut RiscvCpu) -> Result<(), String> {
+        Ok(())
+    }
+
+    pub(super) fn at_pc(&mut self, _cpu: &mut RiscvCpu) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    pub(super) fn trap(&mut self, _cpu: &mut RiscvCpu, _cause: &RiscvCpuError) -> Result<(), String> {
+        Ok(())
+    }
+
+    pub(super) fn exit(&mut self, _cpu: &mut RiscvCpu) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "script"))]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_script_callbacks() {
+        let path = std::env::temp_dir().join(format!("rvlator-script-{}.rhai", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+            fn on_start(m) { m.break_at(8); m.set_reg("a5", 7); }
+            fn on_output(m, text) { if text != "hi\n" { throw "bad output"; } m.set_reg("a4", 1); }
+            fn on_break(m, pc) { m.set_mem(0x18, 8, m.reg("a5") + pc); m.stop(); }
+            "#,
+        )
+        .unwrap();
+        let mut script = Script::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // write(1, "hi\n", 3), then stop before the instruction at 8
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a7, zero, 64))
+            .inst(encode!(ecall))
+            .inst(encode!(addi a0, zero, 1))
+            .insts(&[0x000a6968, 0, 0, 0, 0, 0])
+            .cpu();
+        cpu.fusion = false;
+        (cpu.ixu[REG_A0], cpu.ixu[REG_A1], cpu.ixu[REG_A2]) = (1, 12, 3);
+        script.start(&mut cpu).unwrap();
+        while !script.at_pc(&mut cpu).unwrap() {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, 8);
+        assert_eq!(cpu.ixu[REG_A4], 1);
+        assert_eq!(cpu.read_mem(0x18, 8), Some(15));
+    }
+
+    #[test]
+    fn test_script_break_fused_pair() {
+        let path = std::env::temp_dir().join(format!("rvlator-script-fused-{}.rhai", std::process::id()));
+        fs::write(&path, "fn on_start(m) { m.break_at(4); }\nfn on_break(m, pc) { m.stop(); }\n").unwrap();
+        let mut script = Script::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // Fusion stays on, but the break on the second half of the pair stops there
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(lui a0, 0x12345))
+            .inst(encode!(addi a0, a0, 0x678))
+            .inst(encode!(addi a1, zero, 1))
+            .cpu();
+        script.start(&mut cpu).unwrap();
+        while !script.at_pc(&mut cpu).unwrap() {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.pc, cpu.ixu[REG_A0]), (4, 0x12345000));
+        assert!(cpu.fusion);
+    }
+}
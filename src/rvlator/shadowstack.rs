@@ -0,0 +1,163 @@
+// Zicfiss, shadow stacks for control-flow integrity.
+//
+// A second stack at the ssp CSR that only these instructions write.
+// Prologues push the return address on it, epilogues pop it and compare it
+// with the one they are about to return to:
+//
+//     sspush x1|x5       ssp -= 8, then store x[rs2] at ssp
+//     sspopchk x1|x5     load at ssp, compare with x[rs1], ssp += 8
+//     ssrdp rd           rd = ssp
+//     c.sspush x1  c.sspopchk x5
+//
+// A sspopchk that doesn't match raises a software-check exception, with
+// nothing to handle it the run stops there. The accesses are checked
+// against the --protect shadow stack regions rather than the ordinary
+// permissions.
+//
+// They take encodings of Zimop's may-be-operations (mop.rr.7, mop.r.28)
+// and only decode with --isa ..._zicfiss, which runs the hart as though
+// menvcfg.SSE had shadow stacks on for it.
+//
+// LATER: Zimop and Zcmop, so the same binaries run without Zicfiss with
+// these as no-ops writing 0 to rd, and ssamoswap.w/.d.
+
+use super::*;
+
+// funct7, funct3 and opcode of mop.rr.7 (sspush) and mop.r.28 (sspopchk,
+// ssrdp, rs2 selecting the 28)
+const MOP_MASK: u32 = 0xfe00707f;
+const MOP_RR_7: u32 = 0xce004073;
+const MOP_R_28: u32 = 0xcc004073;
+const MOP_R_28_RS2: usize = 0b11100;
+// Bytes per shadow stack entry on RV64
+const SS_ENTRY: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum SsOp {
+    Push(usize),
+    PopChk(usize),
+    Rdp(usize),
+}
+
+impl SsOp {
+    pub(super) fn names(&self) -> (&'static str, &'static str) {
+        match self {
+            SsOp::Push(_) => ("Zicfiss", "sspush"),
+            SsOp::PopChk(_) => ("Zicfiss", "sspopchk"),
+            SsOp::Rdp(_) => ("Zicfiss", "ssrdp"),
+        }
+    }
+}
+
+pub(super) fn decode(inst: u32) -> Option<SsOp> {
+    let field = |pos| getfield32!(inst, 5, pos) as usize;
+    let (rd, rs1, rs2) = (field(INST_RD_POS), field(INST_RS1_POS), field(INST_RS2_POS));
+    match (inst & MOP_MASK, rd, rs1, rs2) {
+        (MOP_RR_7, 0, 0, REG_RA | REG_T0) => Some(SsOp::Push(rs2)),
+        (MOP_R_28, 0, REG_RA | REG_T0, MOP_R_28_RS2) => Some(SsOp::PopChk(rs1)),
+        (MOP_R_28, 1.., 0, MOP_R_28_RS2) => Some(SsOp::Rdp(rd)),
+        _ => None,
+    }
+}
+
+/// Assembly text of a Zicfiss instruction, None for anything else
+pub(super) fn disasm(inst: u32) -> Option<String> {
+    let text = match decode(inst)? {
+        SsOp::Push(rs2) => format!("sspush {}", REGNAME[rs2]),
+        SsOp::PopChk(rs1) => format!("sspopchk {}", REGNAME[rs1]),
+        SsOp::Rdp(rd) => format!("ssrdp {}", REGNAME[rd]),
+    };
+    Some(text)
+}
+
+impl RiscvCpu {
+    pub(super) fn execute_shadow_stack(&mut self, inst: u32) -> Result<(), RiscvCpuError> {
+        let op = decode(inst).filter(|_| self.isa.zicfiss).ok_or(RiscvCpuError::DecodeError)?;
+        let ssp = self.csr_read(csr::CSR_SSP).unwrap_or_default();
+        match op {
+            SsOp::Push(rs2) => {
+                let addr = ssp.wrapping_sub(SS_ENTRY);
+                self.shadow_access(RiscvAccessType::Store, addr, SS_ENTRY)?;
+                self.store_data(addr, SS_ENTRY, self.ixu[rs2]).ok_or(RiscvCpuError::AccessFault)?;
+                self.csr_write(csr::CSR_SSP, addr);
+            }
+            SsOp::PopChk(rs1) => {
+                self.shadow_access(RiscvAccessType::Load, ssp, SS_ENTRY)?;
+                let link = self.load_data(ssp, SS_ENTRY).ok_or(RiscvCpuError::AccessFault)?;
+                if link != self.ixu[rs1] {
+                    println!(
+                        "{COLOR_RED}shadow stack mismatch: returning to {:#018x}, pushed {:#018x}{COLOR_RESET}",
+                        self.ixu[rs1], link
+                    );
+                    return Err(RiscvCpuError::Exception(RiscvException::SoftwareCheck));
+                }
+                self.csr_write(csr::CSR_SSP, ssp.wrapping_add(SS_ENTRY));
+            }
+            SsOp::Rdp(rd) => self.ixu[rd] = ssp,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_zicfiss_decode() {
+        assert_eq!(disasm(0xce104073).unwrap(), "sspush ra");
+        assert_eq!(disasm(0xce504073).unwrap(), "sspush t0");
+        assert_eq!(disasm(0xcdc0c073).unwrap(), "sspopchk ra");
+        assert_eq!(disasm(0xcdc04573).unwrap(), "ssrdp a0");
+        // Other registers are plain may-be-operations
+        assert_eq!(disasm(0xce204073), None);
+        assert_eq!(disasm(0xcdc54073), None);
+        assert_eq!(disasm(0xcdc04073), None);
+    }
+
+    #[test]
+    fn test_zicfiss() {
+        let mut cpu = ProgramBuilder::new().cpu();
+        cpu.mem.resize(0x100, 0);
+        assert_eq!(cpu.execute(encode!(sspush ra)), Err(RiscvCpuError::DecodeError));
+        assert_eq!(cpu.csr_read(csr::CSR_SSP), None);
+        cpu.isa = Isa::parse("rv64gc_zicfiss").unwrap();
+        cpu.csr_write(csr::CSR_SSP, 0x100);
+        cpu.ixu[REG_RA] = 0x1234;
+        cpu.execute(encode!(sspush ra)).unwrap();
+        cpu.execute(encode!(ssrdp a0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xf8);
+        assert_eq!(cpu.read_mem(0xf8, 8), Some(0x1234));
+        cpu.ixu[REG_T0] = 0x1234;
+        cpu.execute(encode!(sspopchk t0)).unwrap();
+        assert_eq!(cpu.csr_read(csr::CSR_SSP), Some(0x100));
+
+        // A return address overwritten on the regular stack
+        cpu.execute(encode!(sspush ra)).unwrap();
+        cpu.ixu[REG_RA] = 0x4321;
+        assert_eq!(
+            cpu.execute(encode!(sspopchk ra)),
+            Err(RiscvCpuError::Exception(RiscvException::SoftwareCheck))
+        );
+        assert_eq!(cpu.csr_read(csr::CSR_SSP), Some(0xf8));
+
+        // Shadow stack memory is off limits to sd, and sspush is held to it
+        let mut protect = Protect::default();
+        protect.add_region(Region { start: 0xc0, len: 0x40, read: true, write: true, exec: false, shadow: true });
+        protect.add_region(Region { start: 0x80, len: 0x40, read: true, write: true, exec: false, shadow: false });
+        cpu.protect = Some(protect);
+        cpu.execute(encode!(sspush ra)).unwrap();
+        cpu.ixu[REG_A1] = 0xf0;
+        assert_eq!(cpu.execute(encode!(sd ra, 0(a1))), Err(RiscvCpuError::AccessFault));
+        cpu.csr_write(csr::CSR_SSP, 0xc0);
+        assert_eq!(cpu.execute(encode!(sspush ra)), Err(RiscvCpuError::AccessFault));
+
+        // The compressed forms
+        cpu.csr_write(csr::CSR_SSP, 0x100);
+        cpu.execute(0x6081).unwrap();
+        cpu.ixu[REG_T0] = cpu.ixu[REG_RA];
+        cpu.execute(0x6281).unwrap();
+        assert_eq!(cpu.csr_read(csr::CSR_SSP), Some(0x100));
+    }
+}
@@ -0,0 +1,805 @@
+// IEEE 754 binary floating point on bit patterns.
+//
+// The host's floats only round to nearest-even and don't report exception
+// flags, so the FP instructions are computed here instead. Operands are
+// unpacked into a sign, an exponent and an integer significand, the exact
+// result (or one whose lowest bit is a sticky bit standing for everything
+// shifted out below it) is formed in a u128 and rounded once, in
+// `round_pack`, with the instruction's rounding mode. Tininess is detected
+// after rounding, as RISC-V does.
+//
+// Every NaN result is the canonical NaN, payloads aren't propagated.
+
+use std::cmp::Ordering;
+
+pub(super) const FLAG_NV: u32 = 0x10;
+pub(super) const FLAG_DZ: u32 = 0x08;
+pub(super) const FLAG_OF: u32 = 0x04;
+pub(super) const FLAG_UF: u32 = 0x02;
+pub(super) const FLAG_NX: u32 = 0x01;
+
+// Interchange format, the sign bit above `exp` exponent bits above `man`
+// stored significand bits
+#[derive(Debug, PartialEq)]
+pub(super) struct Format {
+    pub name: &'static str,
+    exp: u32,
+    man: u32,
+}
+
+pub(super) const F16: Format = Format { name: "h", exp: 5, man: 10 };
+pub(super) const F32: Format = Format { name: "s", exp: 8, man: 23 };
+pub(super) const F64: Format = Format { name: "d", exp: 11, man: 52 };
+
+// Zfa fli constants by rs1, entry 1 is the format's smallest normal
+const FLI: [f64; 32] = [
+    -1.0, 0.0, 1.52587890625e-05, 3.0517578125e-05, 0.00390625, 0.0078125, 0.0625, 0.125,
+    0.25, 0.3125, 0.375, 0.4375, 0.5, 0.625, 0.75, 0.875,
+    1.0, 1.25, 1.5, 1.75, 2.0, 2.5, 3.0, 4.0,
+    8.0, 16.0, 128.0, 256.0, 32768.0, 65536.0, f64::INFINITY, f64::NAN,
+];
+
+impl Format {
+    pub(super) fn width(&self) -> u32 {
+        1 + self.exp + self.man
+    }
+
+    pub(super) fn canonical_nan(&self) -> u64 {
+        self.exp_max() << self.man | 1 << (self.man - 1)
+    }
+
+    pub(super) fn sign_bit(&self) -> u64 {
+        1 << (self.exp + self.man)
+    }
+
+    fn bias(&self) -> i32 {
+        (1 << (self.exp - 1)) - 1
+    }
+
+    fn exp_max(&self) -> u64 {
+        (1 << self.exp) - 1
+    }
+
+    fn man_mask(&self) -> u64 {
+        (1 << self.man) - 1
+    }
+
+    // Exponent of the significand's lowest bit for subnormals and the
+    // smallest normal binade
+    fn exp_lsb_min(&self) -> i32 {
+        1 - self.bias() - self.man as i32
+    }
+
+    /// All bits of a value, the sign included
+    pub(super) fn mask(&self) -> u64 {
+        u64::MAX >> (64 - self.width())
+    }
+
+    fn sign(&self, a: u64) -> bool {
+        a & self.sign_bit() != 0
+    }
+
+    fn biased_exp(&self, a: u64) -> u64 {
+        (a >> self.man) & self.exp_max()
+    }
+
+    pub(super) fn is_nan(&self, a: u64) -> bool {
+        self.biased_exp(a) == self.exp_max() && a & self.man_mask() != 0
+    }
+
+    pub(super) fn is_snan(&self, a: u64) -> bool {
+        self.is_nan(a) && a & 1 << (self.man - 1) == 0
+    }
+
+    fn is_inf(&self, a: u64) -> bool {
+        self.biased_exp(a) == self.exp_max() && a & self.man_mask() == 0
+    }
+
+    fn is_zero(&self, a: u64) -> bool {
+        a & !self.sign_bit() == 0
+    }
+
+    fn inf(&self, sign: bool) -> u64 {
+        (sign as u64) << (self.exp + self.man) | self.exp_max() << self.man
+    }
+
+    fn zero(&self, sign: bool) -> u64 {
+        (sign as u64) << (self.exp + self.man)
+    }
+
+    // Largest finite magnitude
+    fn max_finite(&self, sign: bool) -> u64 {
+        self.inf(sign) - 1
+    }
+
+    /// A finite `a` as (sign, significand, exponent of its lowest bit)
+    fn unpack(&self, a: u64) -> (bool, u128, i32) {
+        let (e, f) = (self.biased_exp(a), a & self.man_mask());
+        match e {
+            0 => (self.sign(a), f as u128, self.exp_lsb_min()),
+            _ => (self.sign(a), (f | 1 << self.man) as u128, self.exp_lsb_min() + e as i32 - 1),
+        }
+    }
+
+    /// fli: entry `index` of the constant table in this format. 2^16
+    /// overflows a half to infinity.
+    pub(super) fn fli(&self, index: usize) -> u64 {
+        match index {
+            1 => 1 << self.man,
+            _ => FpEnv::new(Rounding::NearestEven).convert(self, &F64, FLI[index].to_bits()),
+        }
+    }
+
+    /// fclass: one bit set for -inf, -normal, -subnormal, -0, +0,
+    /// +subnormal, +normal, +inf, sNaN, qNaN in that order
+    pub(super) fn classify(&self, a: u64) -> u64 {
+        let sign = self.sign(a);
+        let bit = match self.biased_exp(a) {
+            _ if self.is_snan(a) => 8,
+            _ if self.is_nan(a) => 9,
+            _ if self.is_inf(a) => 0,
+            0 if self.is_zero(a) => 3,
+            0 => 2,
+            _ => 1,
+        };
+        match (bit, sign) {
+            (8 | 9, _) | (_, true) => 1 << bit,
+            _ => 1 << (7 - bit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Rounding {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl Rounding {
+    /// The rm field or frm encoding, None for the reserved ones and dyn
+    pub(super) fn from_bits(rm: u32) -> Option<Rounding> {
+        match rm {
+            0b000 => Some(Rounding::NearestEven),
+            0b001 => Some(Rounding::TowardZero),
+            0b010 => Some(Rounding::Down),
+            0b011 => Some(Rounding::Up),
+            0b100 => Some(Rounding::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+/// `sig` >> `shift` rounded with `rm` for a value of sign `sign`, and
+/// whether any bits were lost
+fn round_shift(sig: u128, shift: u32, sign: bool, rm: Rounding) -> (u128, bool) {
+    let (q, rest) = match shift {
+        0 => return (sig, false),
+        _ if shift >= 128 => (0, sig),
+        _ => (sig >> shift, sig & ((1 << shift) - 1)),
+    };
+    // How the bits shifted out compare to half of the lowest bit kept
+    let half = match shift {
+        _ if shift > 128 => Ordering::Less,
+        _ => rest.cmp(&(1 << (shift - 1))),
+    };
+    let inexact = rest != 0;
+    let up = match rm {
+        Rounding::NearestEven => half == Ordering::Greater || (half == Ordering::Equal && q & 1 == 1),
+        Rounding::NearestMaxMagnitude => half != Ordering::Less,
+        Rounding::TowardZero => false,
+        Rounding::Down => inexact && sign,
+        Rounding::Up => inexact && !sign,
+    };
+    (q + up as u128, inexact)
+}
+
+/// Shift right, OR-ing whatever falls off into the lowest bit
+fn shift_right_jam(sig: u128, shift: u32) -> u128 {
+    match shift {
+        0 => sig,
+        _ if shift >= 128 => (sig != 0) as u128,
+        _ => sig >> shift | (sig & ((1 << shift) - 1) != 0) as u128,
+    }
+}
+
+// Rounding mode and the exception flags raised so far, what an
+// instruction's view of fcsr is
+pub(super) struct FpEnv {
+    pub rm: Rounding,
+    pub flags: u32,
+}
+
+impl FpEnv {
+    pub(super) fn new(rm: Rounding) -> FpEnv {
+        FpEnv { rm, flags: 0 }
+    }
+
+    /// Round (-1)^sign * sig * 2^exp to `fmt`. The lowest bit of `sig` may
+    /// be sticky as long as it is below the rounding point.
+    fn round_pack(&mut self, fmt: &Format, sign: bool, sig: u128, exp: i32) -> u64 {
+        if sig == 0 {
+            return fmt.zero(sign);
+        }
+        let precision = fmt.man + 1;
+        let bits = 128 - sig.leading_zeros();
+        // Exponent of the result's lowest bit with unbounded range, then
+        // clamped to the subnormal one
+        let exp_lsb = exp + bits as i32 - precision as i32;
+        let exp_res = exp_lsb.max(fmt.exp_lsb_min());
+        let (mut q, inexact) = match exp_res - exp {
+            shift if shift <= 0 => (sig << -shift, false),
+            shift => round_shift(sig, shift as u32, sign, self.rm),
+        };
+        let mut exp_res = exp_res;
+        if q >> precision != 0 {
+            q >>= 1;
+            exp_res += 1;
+        }
+        if exp_lsb < fmt.exp_lsb_min() && inexact {
+            // Tiny unless rounding at full precision carries it into the
+            // smallest normal binade
+            let carries = exp_lsb == fmt.exp_lsb_min() - 1
+                && exp_lsb > exp
+                && round_shift(sig, (exp_lsb - exp) as u32, sign, self.rm).0 >> precision != 0;
+            if !carries {
+                self.flags |= FLAG_UF;
+            }
+        }
+        if inexact {
+            self.flags |= FLAG_NX;
+        }
+        let biased = match q >> fmt.man {
+            0 => 0,
+            _ => (exp_res - fmt.exp_lsb_min() + 1) as u64,
+        };
+        if biased >= fmt.exp_max() {
+            self.flags |= FLAG_OF | FLAG_NX;
+            let to_inf = match self.rm {
+                Rounding::NearestEven | Rounding::NearestMaxMagnitude => true,
+                Rounding::TowardZero => false,
+                Rounding::Down => sign,
+                Rounding::Up => !sign,
+            };
+            return if to_inf { fmt.inf(sign) } else { fmt.max_finite(sign) };
+        }
+        fmt.zero(sign) | biased << fmt.man | (q as u64 & fmt.man_mask())
+    }
+
+    /// Canonical NaN for an operation with a NaN operand, invalid if any
+    /// of them is signaling
+    fn propagate_nan(&mut self, fmt: &Format, operands: &[u64]) -> u64 {
+        if operands.iter().any(|&a| fmt.is_snan(a)) {
+            self.flags |= FLAG_NV;
+        }
+        fmt.canonical_nan()
+    }
+
+    fn invalid(&mut self, fmt: &Format) -> u64 {
+        self.flags |= FLAG_NV;
+        fmt.canonical_nan()
+    }
+
+    /// Sum of two exact values, each (sign, significand, exponent)
+    fn add_exact(&mut self, fmt: &Format, a: (bool, u128, i32), b: (bool, u128, i32)) -> u64 {
+        let exact_zero = |rm| fmt.zero(rm == Rounding::Down);
+        match (a.1, b.1) {
+            (0, 0) if a.0 == b.0 => return fmt.zero(a.0),
+            (0, 0) => return exact_zero(self.rm),
+            (0, _) => return self.round_pack(fmt, b.0, b.1, b.2),
+            (_, 0) => return self.round_pack(fmt, a.0, a.1, a.2),
+            _ => {}
+        }
+        // Top bit at 125 leaves room for the carry and a guard bit below
+        // any bit that survives rounding
+        let normalize = |(sign, sig, exp): (bool, u128, i32)| {
+            let shift = sig.leading_zeros() as i32 - 2;
+            (sign, sig << shift, exp - shift)
+        };
+        let (a, b) = (normalize(a), normalize(b));
+        let (big, small) = if a.2 >= b.2 { (a, b) } else { (b, a) };
+        let small_sig = shift_right_jam(small.1, (big.2 - small.2) as u32);
+        let (sign, sig) = match big.0 == small.0 {
+            true => (big.0, big.1 + small_sig),
+            false if big.1 >= small_sig => (big.0, big.1 - small_sig),
+            false => (small.0, small_sig - big.1),
+        };
+        match sig {
+            0 => exact_zero(self.rm),
+            _ => self.round_pack(fmt, sign, sig, big.2),
+        }
+    }
+
+    pub(super) fn add(&mut self, fmt: &Format, a: u64, b: u64) -> u64 {
+        if fmt.is_nan(a) || fmt.is_nan(b) {
+            return self.propagate_nan(fmt, &[a, b]);
+        }
+        match (fmt.is_inf(a), fmt.is_inf(b)) {
+            (true, true) if fmt.sign(a) != fmt.sign(b) => return self.invalid(fmt),
+            (true, _) => return a,
+            (_, true) => return b,
+            _ => {}
+        }
+        self.add_exact(fmt, fmt.unpack(a), fmt.unpack(b))
+    }
+
+    pub(super) fn sub(&mut self, fmt: &Format, a: u64, b: u64) -> u64 {
+        match fmt.is_nan(b) {
+            true => self.add(fmt, a, b),
+            false => self.add(fmt, a, b ^ fmt.sign_bit()),
+        }
+    }
+
+    pub(super) fn mul(&mut self, fmt: &Format, a: u64, b: u64) -> u64 {
+        if fmt.is_nan(a) || fmt.is_nan(b) {
+            return self.propagate_nan(fmt, &[a, b]);
+        }
+        let sign = fmt.sign(a) != fmt.sign(b);
+        match (fmt.is_inf(a) || fmt.is_inf(b), fmt.is_zero(a) || fmt.is_zero(b)) {
+            (true, true) => return self.invalid(fmt),
+            (true, false) => return fmt.inf(sign),
+            _ => {}
+        }
+        let ((_, sa, ea), (_, sb, eb)) = (fmt.unpack(a), fmt.unpack(b));
+        self.round_pack(fmt, sign, sa * sb, ea + eb)
+    }
+
+    pub(super) fn div(&mut self, fmt: &Format, a: u64, b: u64) -> u64 {
+        if fmt.is_nan(a) || fmt.is_nan(b) {
+            return self.propagate_nan(fmt, &[a, b]);
+        }
+        let sign = fmt.sign(a) != fmt.sign(b);
+        match (fmt.is_inf(a), fmt.is_inf(b), fmt.is_zero(a), fmt.is_zero(b)) {
+            (true, true, _, _) | (_, _, true, true) => return self.invalid(fmt),
+            (true, _, _, _) => return fmt.inf(sign),
+            (_, true, _, _) | (_, _, true, _) => return fmt.zero(sign),
+            (_, _, _, true) => {
+                self.flags |= FLAG_DZ;
+                return fmt.inf(sign);
+            }
+            _ => {}
+        }
+        let ((_, sa, ea), (_, sb, eb)) = (fmt.unpack(a), fmt.unpack(b));
+        // The dividend's top bit at 126 leaves a quotient of 70 bits or more
+        let shift = sa.leading_zeros() as i32 - 1;
+        let dividend = sa << shift;
+        let q = (dividend / sb) | !dividend.is_multiple_of(sb) as u128;
+        self.round_pack(fmt, sign, q, ea - shift - eb)
+    }
+
+    pub(super) fn sqrt(&mut self, fmt: &Format, a: u64) -> u64 {
+        if fmt.is_nan(a) {
+            return self.propagate_nan(fmt, &[a]);
+        }
+        if fmt.is_zero(a) {
+            return a;
+        }
+        if fmt.sign(a) {
+            return self.invalid(fmt);
+        }
+        if fmt.is_inf(a) {
+            return a;
+        }
+        let (_, mut sig, mut exp) = fmt.unpack(a);
+        if exp % 2 != 0 {
+            sig <<= 1;
+            exp -= 1;
+        }
+        // An even shift keeps the exponent halvable, the root has 63 bits
+        // or more
+        let shift = (sig.leading_zeros() & !1) as i32;
+        let radicand = sig << shift;
+        let root = radicand.isqrt();
+        let sig = root | (root * root != radicand) as u128;
+        self.round_pack(fmt, false, sig, (exp - shift) / 2)
+    }
+
+    /// (a * b) + c with a single rounding, the product and the addend
+    /// negated first when asked for the fmsub/fnmsub/fnmadd forms
+    pub(super) fn fma(&mut self, fmt: &Format, a: u64, b: u64, c: u64, neg_product: bool, neg_addend: bool) -> u64 {
+        let inf_times_zero = (fmt.is_inf(a) && fmt.is_zero(b)) || (fmt.is_zero(a) && fmt.is_inf(b));
+        // The invalid product wins even over a quiet NaN addend
+        if inf_times_zero {
+            return self.invalid(fmt);
+        }
+        if fmt.is_nan(a) || fmt.is_nan(b) || fmt.is_nan(c) {
+            return self.propagate_nan(fmt, &[a, b, c]);
+        }
+        let product_sign = (fmt.sign(a) != fmt.sign(b)) != neg_product;
+        let addend_sign = fmt.sign(c) != neg_addend;
+        match (fmt.is_inf(a) || fmt.is_inf(b), fmt.is_inf(c)) {
+            (true, true) if product_sign != addend_sign => return self.invalid(fmt),
+            (true, _) => return fmt.inf(product_sign),
+            (_, true) => return fmt.inf(addend_sign),
+            _ => {}
+        }
+        let ((_, sa, ea), (_, sb, eb), (_, sc, ec)) = (fmt.unpack(a), fmt.unpack(b), fmt.unpack(c));
+        self.add_exact(fmt, (product_sign, sa * sb, ea + eb), (addend_sign, sc, ec))
+    }
+
+    /// Round `a` to an integer and convert it to a signed or unsigned
+    /// integer of `bits` bits, saturating and invalid when out of range
+    pub(super) fn round_to_int(&mut self, fmt: &Format, a: u64, signed: bool, bits: u32) -> u64 {
+        let (min, max): (i128, i128) = match signed {
+            true => (-(1 << (bits - 1)), (1 << (bits - 1)) - 1),
+            false => (0, (1 << bits) - 1),
+        };
+        let saturated = |flags: &mut u32, value: i128| {
+            *flags |= FLAG_NV;
+            value as u64
+        };
+        if fmt.is_nan(a) {
+            return saturated(&mut self.flags, max);
+        }
+        if fmt.is_inf(a) {
+            return saturated(&mut self.flags, if fmt.sign(a) { min } else { max });
+        }
+        let (sign, sig, exp) = fmt.unpack(a);
+        // Anything shifted past bit 64 is out of range of every format
+        let (magnitude, inexact) = match exp {
+            _ if exp > 64 => return saturated(&mut self.flags, if sign { min } else { max }),
+            0.. => (sig << exp, false),
+            _ => round_shift(sig, (-exp) as u32, sign, self.rm),
+        };
+        let value = if sign { -(magnitude as i128) } else { magnitude as i128 };
+        if value < min || value > max {
+            return saturated(&mut self.flags, if sign { min } else { max });
+        }
+        if inexact {
+            self.flags |= FLAG_NX;
+        }
+        value as u64
+    }
+
+    /// fcvtmod.w.d: `a` truncated and wrapped to a signed 32-bit integer,
+    /// 0 for NaN and infinity, invalid whenever the wrap loses bits
+    pub(super) fn round_to_int_modular(&mut self, fmt: &Format, a: u64) -> u64 {
+        if fmt.is_nan(a) || fmt.is_inf(a) {
+            self.flags |= FLAG_NV;
+            return 0;
+        }
+        let (sign, sig, exp) = fmt.unpack(a);
+        // Every bit from 2^64 up wraps away
+        let (magnitude, inexact) = match exp {
+            64.. => (1 << 64, false),
+            0.. => (sig << exp, false),
+            _ => round_shift(sig, (-exp) as u32, sign, Rounding::TowardZero),
+        };
+        let value = if sign { -(magnitude as i128) } else { magnitude as i128 };
+        if value < i32::MIN as i128 || value > i32::MAX as i128 {
+            self.flags |= FLAG_NV;
+        } else if inexact {
+            self.flags |= FLAG_NX;
+        }
+        value as i32 as u64
+    }
+
+    /// fround (`inexact` false) and froundnx: `a` rounded to an integer,
+    /// staying in its format. Only froundnx raises the inexact flag.
+    pub(super) fn round_to_integral(&mut self, fmt: &Format, a: u64, inexact: bool) -> u64 {
+        if fmt.is_nan(a) {
+            return self.propagate_nan(fmt, &[a]);
+        }
+        let (sign, sig, exp) = fmt.unpack(a);
+        if fmt.is_inf(a) || exp >= 0 {
+            return a;
+        }
+        let (q, rounded) = round_shift(sig, (-exp) as u32, sign, self.rm);
+        if rounded && inexact {
+            self.flags |= FLAG_NX;
+        }
+        // The integer fits the significand, packing it is exact
+        self.round_pack(fmt, sign, q, 0)
+    }
+
+    /// Convert the integer `value` (signed or not) to `fmt`
+    pub(super) fn int_to_float(&mut self, fmt: &Format, value: u64, signed: bool) -> u64 {
+        let sign = signed && (value as i64) < 0;
+        let magnitude = if sign { (value as i64).unsigned_abs() } else { value };
+        self.round_pack(fmt, sign, magnitude as u128, 0)
+    }
+
+    /// Convert `a` from the format `from` to `fmt`, exact when widening
+    pub(super) fn convert(&mut self, fmt: &Format, from: &Format, a: u64) -> u64 {
+        if from.is_nan(a) {
+            if from.is_snan(a) {
+                self.flags |= FLAG_NV;
+            }
+            return fmt.canonical_nan();
+        }
+        if from.is_inf(a) {
+            return fmt.inf(from.sign(a));
+        }
+        let (sign, sig, exp) = from.unpack(a);
+        self.round_pack(fmt, sign, sig, exp)
+    }
+
+    /// Order of two non-NaN values, -0 and +0 being equal
+    fn compare(&self, fmt: &Format, a: u64, b: u64) -> Ordering {
+        if fmt.is_zero(a) && fmt.is_zero(b) {
+            return Ordering::Equal;
+        }
+        let magnitude = |x: u64| x & !fmt.sign_bit();
+        match (fmt.sign(a), fmt.sign(b)) {
+            (false, false) => magnitude(a).cmp(&magnitude(b)),
+            (true, true) => magnitude(b).cmp(&magnitude(a)),
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+        }
+    }
+
+    /// feq: quiet, only a signaling NaN is invalid
+    pub(super) fn eq(&mut self, fmt: &Format, a: u64, b: u64) -> bool {
+        if fmt.is_nan(a) || fmt.is_nan(b) {
+            self.propagate_nan(fmt, &[a, b]);
+            return false;
+        }
+        self.compare(fmt, a, b) == Ordering::Equal
+    }
+
+    /// flt (`or_equal` false) and fle: any NaN is invalid
+    pub(super) fn lt(&mut self, fmt: &Format, a: u64, b: u64, or_equal: bool) -> bool {
+        if fmt.is_nan(a) || fmt.is_nan(b) {
+            self.flags |= FLAG_NV;
+            return false;
+        }
+        match self.compare(fmt, a, b) {
+            Ordering::Less => true,
+            Ordering::Equal => or_equal,
+            Ordering::Greater => false,
+        }
+    }
+
+    /// fleq (`or_equal`) and fltq: quiet like feq
+    pub(super) fn lt_quiet(&mut self, fmt: &Format, a: u64, b: u64, or_equal: bool) -> bool {
+        if fmt.is_nan(a) || fmt.is_nan(b) {
+            self.propagate_nan(fmt, &[a, b]);
+            return false;
+        }
+        self.lt(fmt, a, b, or_equal)
+    }
+
+    /// fmin/fmax, IEEE 754-2019 minimumNumber/maximumNumber: a NaN gives
+    /// way to the other operand and -0 is below +0
+    pub(super) fn min_max(&mut self, fmt: &Format, a: u64, b: u64, max: bool) -> u64 {
+        if fmt.is_snan(a) || fmt.is_snan(b) {
+            self.flags |= FLAG_NV;
+        }
+        match (fmt.is_nan(a), fmt.is_nan(b)) {
+            (true, true) => return fmt.canonical_nan(),
+            (true, false) => return b,
+            (false, true) => return a,
+            _ => {}
+        }
+        let order = match self.compare(fmt, a, b) {
+            Ordering::Equal => fmt.sign(b).cmp(&fmt.sign(a)),
+            order => order,
+        };
+        match (order == Ordering::Less) != max {
+            true => a,
+            false => b,
+        }
+    }
+
+    /// fminm/fmaxm, IEEE 754-2019 minimum/maximum: like fmin/fmax except
+    /// that a NaN operand makes the result NaN
+    pub(super) fn minimum_maximum(&mut self, fmt: &Format, a: u64, b: u64, max: bool) -> u64 {
+        if fmt.is_nan(a) || fmt.is_nan(b) {
+            return self.propagate_nan(fmt, &[a, b]);
+        }
+        self.min_max(fmt, a, b, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(rm: Rounding) -> FpEnv {
+        FpEnv::new(rm)
+    }
+
+    fn f(x: f32) -> u64 {
+        x.to_bits() as u64
+    }
+
+    #[test]
+    fn test_softfloat_arith() {
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.add(&F32, f(1.5), f(2.25)), f(3.75));
+        assert_eq!(e.sub(&F32, f(1.0), f(1.0)), f(0.0));
+        assert_eq!(e.mul(&F32, f(-3.0), f(0.5)), f(-1.5));
+        assert_eq!(e.div(&F32, f(1.0), f(4.0)), f(0.25));
+        assert_eq!(e.sqrt(&F32, f(2.0)), f(2.0f32.sqrt()));
+        assert_eq!(e.flags, FLAG_NX);
+
+        // 1 + 2^-24 is a tie, rounding to even goes down and max magnitude up
+        assert_eq!(env(Rounding::NearestEven).add(&F32, f(1.0), f(f32::EPSILON / 2.0)), f(1.0));
+        assert_eq!(env(Rounding::NearestMaxMagnitude).add(&F32, f(1.0), f(f32::EPSILON / 2.0)), f(1.0 + f32::EPSILON));
+        // 1/3 to nearest rounds up
+        assert_eq!(env(Rounding::Up).div(&F32, f(1.0), f(3.0)), f(1.0 / 3.0));
+        assert_eq!(env(Rounding::TowardZero).div(&F32, f(1.0), f(3.0)), f(1.0 / 3.0) - 1);
+        assert_eq!(env(Rounding::Down).div(&F32, f(-1.0), f(3.0)), f(-1.0 / 3.0));
+        assert_eq!(env(Rounding::Up).div(&F32, f(-1.0), f(3.0)), f(-1.0 / 3.0) - 1);
+        assert_eq!(env(Rounding::Down).sub(&F32, f(2.0), f(2.0)), f(-0.0));
+
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.mul(&F32, f(f32::MAX), f(2.0)), f(f32::INFINITY));
+        assert_eq!(e.flags, FLAG_OF | FLAG_NX);
+        let mut e = env(Rounding::TowardZero);
+        assert_eq!(e.mul(&F32, f(f32::MAX), f(2.0)), f(f32::MAX));
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.mul(&F32, f(f32::MIN_POSITIVE), f(0.75)), f(f32::MIN_POSITIVE * 0.75));
+        assert_eq!(e.flags, 0);
+        assert_eq!(e.mul(&F32, f(f32::MIN_POSITIVE), f(1.0 / 3.0)), f(f32::MIN_POSITIVE / 3.0));
+        assert_eq!(e.flags, FLAG_UF | FLAG_NX);
+        assert_eq!(e.div(&F32, f(1.0), f(0.0)), f(f32::INFINITY));
+        assert_eq!(e.flags & FLAG_DZ, FLAG_DZ);
+
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.sqrt(&F32, f(-1.0)), F32.canonical_nan());
+        assert_eq!(e.mul(&F32, f(f32::INFINITY), f(0.0)), F32.canonical_nan());
+        assert_eq!(e.flags, FLAG_NV);
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.add(&F32, 0x7fc0_1234, f(1.0)), F32.canonical_nan());
+        assert_eq!(e.flags, 0);
+        assert_eq!(e.add(&F32, 0x7f80_0001, f(1.0)), F32.canonical_nan());
+        assert_eq!(e.flags, FLAG_NV);
+    }
+
+    #[test]
+    fn test_softfloat_double() {
+        let d = |x: f64| x.to_bits();
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.add(&F64, d(0.1), d(0.2)), d(0.1 + 0.2));
+        assert_eq!(e.div(&F64, d(1.0), d(3.0)), d(1.0 / 3.0));
+        assert_eq!(e.sqrt(&F64, d(2.0)), d(2.0f64.sqrt()));
+        assert_eq!(e.mul(&F64, d(f64::MIN_POSITIVE), d(0.5)), d(f64::MIN_POSITIVE / 2.0));
+        assert_eq!(e.round_to_int(&F64, d(-9.007199254740993e15), true, 64), -9007199254740992i64 as u64);
+        assert_eq!(e.int_to_float(&F64, u64::MAX, false), d(u64::MAX as f64));
+        assert_eq!(F64.canonical_nan(), 0x7ff8_0000_0000_0000);
+        assert_eq!(F64.classify(d(-f64::MIN_POSITIVE / 2.0)), 1 << 2);
+
+        // Narrowing rounds, widening is exact, NaNs come out canonical
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.convert(&F32, &F64, d(1.0 / 3.0)), f(1.0 / 3.0));
+        assert_eq!(e.flags, FLAG_NX);
+        assert_eq!(env(Rounding::TowardZero).convert(&F32, &F64, d(1e300)), f(f32::MAX));
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.convert(&F64, &F32, f(1.0 / 3.0)), d((1.0f32 / 3.0) as f64));
+        assert_eq!(e.convert(&F64, &F32, 1), d(f32::from_bits(1) as f64));
+        assert_eq!(e.flags, 0);
+        assert_eq!(e.convert(&F64, &F32, 0x7f80_0001), F64.canonical_nan());
+        assert_eq!(e.flags, FLAG_NV);
+    }
+
+    #[test]
+    fn test_softfloat_half() {
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.add(&F16, 0x3c00, 0x3c00), 0x4000);
+        assert_eq!(e.div(&F16, 0x3c00, 0x4200), 0x3555);
+        assert_eq!(e.flags, FLAG_NX);
+        assert_eq!(F16.canonical_nan(), 0x7e00);
+        assert_eq!(F16.classify(0x0001), 1 << 5);
+        // 65504 is the largest half, 65520 rounds up past it
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.convert(&F16, &F32, f(65504.0)), 0x7bff);
+        assert_eq!(e.convert(&F16, &F32, f(65520.0)), 0x7c00);
+        assert_eq!(e.flags, FLAG_OF | FLAG_NX);
+        assert_eq!(env(Rounding::TowardZero).convert(&F16, &F32, f(65520.0)), 0x7bff);
+        // Subnormal halves widen exactly
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.convert(&F32, &F16, 0x8001), f(-(2.0f32).powi(-24)));
+        assert_eq!(e.round_to_int(&F16, 0xc500, true, 32), -5i64 as u64);
+        assert_eq!(e.int_to_float(&F16, 70000, false), 0x7c00);
+        assert_eq!(e.flags, FLAG_OF | FLAG_NX);
+    }
+
+    #[test]
+    fn test_softfloat_zfa() {
+        let d = |x: f64| x.to_bits();
+        assert_eq!(F32.fli(0), f(-1.0));
+        assert_eq!(F32.fli(1), f(f32::MIN_POSITIVE));
+        assert_eq!(F64.fli(1), d(f64::MIN_POSITIVE));
+        assert_eq!(F64.fli(9), d(0.3125));
+        assert_eq!(F16.fli(2), 0x0100);
+        assert_eq!(F16.fli(29), 0x7c00);
+        assert_eq!(F32.fli(31), F32.canonical_nan());
+
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.minimum_maximum(&F32, f(-0.0), f(0.0), false), f(-0.0));
+        assert_eq!(e.minimum_maximum(&F32, F32.canonical_nan(), f(2.0), true), F32.canonical_nan());
+        assert_eq!(e.flags, 0);
+        assert!(!e.lt_quiet(&F32, F32.canonical_nan(), f(1.0), true));
+        assert!(e.lt_quiet(&F32, f(1.0), f(1.0), true));
+        assert_eq!(e.flags, 0);
+
+        // Ties to even, fround stays quiet where froundnx flags
+        assert_eq!(e.round_to_integral(&F32, f(2.5), false), f(2.0));
+        assert_eq!(e.round_to_integral(&F32, f(-0.25), false), f(-0.0));
+        assert_eq!(e.round_to_integral(&F64, d(1e300), true), d(1e300));
+        assert_eq!(e.flags, 0);
+        assert_eq!(e.round_to_integral(&F32, f(3.5), true), f(4.0));
+        assert_eq!(e.flags, FLAG_NX);
+        assert_eq!(env(Rounding::Down).round_to_integral(&F32, f(-0.5), false), f(-1.0));
+
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.round_to_int_modular(&F64, d(-2.75)), -2i64 as u64);
+        assert_eq!(e.flags, FLAG_NX);
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.round_to_int_modular(&F64, d(4294967297.0)), 1);
+        assert_eq!(e.round_to_int_modular(&F64, d(2147483648.0)), i32::MIN as u64);
+        assert_eq!(e.round_to_int_modular(&F64, d(1e300)), 0);
+        assert_eq!(e.flags, FLAG_NV);
+        assert_eq!(e.round_to_int_modular(&F64, d(f64::INFINITY)), 0);
+    }
+
+    #[test]
+    fn test_softfloat_fma() {
+        let mut e = env(Rounding::NearestEven);
+        // Single rounding: (1 + 2^-12)^2 - 1 keeps the 2^-24 term a
+        // separate multiply would round away
+        let x = f(1.0 + 1.0 / 4096.0);
+        assert_eq!(e.fma(&F32, x, x, f(-1.0), false, false), f(1.0 / 2048.0 + 1.0 / 16777216.0));
+        assert_eq!(e.fma(&F32, f(2.0), f(3.0), f(1.0), true, true), f(-7.0));
+        assert_eq!(e.fma(&F32, f(2.0), f(3.0), f(1.0), false, true), f(5.0));
+        assert_eq!(e.fma(&F32, f(0.0), f(f32::INFINITY), F32.canonical_nan(), false, false), F32.canonical_nan());
+        assert_eq!(e.flags & FLAG_NV, FLAG_NV);
+    }
+
+    #[test]
+    fn test_softfloat_convert_compare() {
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.round_to_int(&F32, f(-2.5), true, 32) as i64, -2);
+        assert_eq!(e.flags, FLAG_NX);
+        assert_eq!(env(Rounding::NearestMaxMagnitude).round_to_int(&F32, f(-2.5), true, 32) as i64, -3);
+        assert_eq!(env(Rounding::Down).round_to_int(&F32, f(2.5), true, 64), 2);
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.round_to_int(&F32, f(3e9), true, 32), i32::MAX as u64);
+        assert_eq!(e.flags, FLAG_NV);
+        assert_eq!(e.round_to_int(&F32, f(3e9), false, 32), 3_000_000_000);
+        assert_eq!(e.round_to_int(&F32, f(-1.0), false, 64), 0);
+        assert_eq!(e.round_to_int(&F32, F32.canonical_nan(), true, 64), i64::MAX as u64);
+        assert_eq!(e.round_to_int(&F32, f(f32::NEG_INFINITY), true, 64), i64::MIN as u64);
+        let mut e = env(Rounding::TowardZero);
+        assert_eq!(e.round_to_int(&F32, f(-0.5), false, 32), 0);
+        assert_eq!(e.flags, FLAG_NX);
+
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.int_to_float(&F32, -7i64 as u64, true), f(-7.0));
+        assert_eq!(e.int_to_float(&F32, u64::MAX, false), f(u64::MAX as f32));
+        assert_eq!(e.flags, FLAG_NX);
+        assert_eq!(env(Rounding::TowardZero).int_to_float(&F32, 0x0100_0001, false), f(16777216.0));
+        assert_eq!(e.int_to_float(&F32, 0, true), f(0.0));
+
+        let mut e = env(Rounding::NearestEven);
+        assert!(e.eq(&F32, f(0.0), f(-0.0)));
+        assert!(e.lt(&F32, f(-1.0), f(0.0), false));
+        assert!(e.lt(&F32, f(-0.0), f(0.0), true));
+        assert!(!e.lt(&F32, f(-0.0), f(0.0), false));
+        assert_eq!(e.flags, 0);
+        assert!(!e.eq(&F32, F32.canonical_nan(), f(1.0)));
+        assert_eq!(e.flags, 0);
+        assert!(!e.lt(&F32, F32.canonical_nan(), f(1.0), true));
+        assert_eq!(e.flags, FLAG_NV);
+
+        let mut e = env(Rounding::NearestEven);
+        assert_eq!(e.min_max(&F32, f(-0.0), f(0.0), false), f(-0.0));
+        assert_eq!(e.min_max(&F32, f(-0.0), f(0.0), true), f(0.0));
+        assert_eq!(e.min_max(&F32, F32.canonical_nan(), f(2.0), false), f(2.0));
+        assert_eq!(e.min_max(&F32, 0x7f80_0001, 0x7fc0_0001, true), F32.canonical_nan());
+        assert_eq!(e.flags, FLAG_NV);
+
+        assert_eq!(F32.classify(f(f32::NEG_INFINITY)), 1 << 0);
+        assert_eq!(F32.classify(f(-1.0)), 1 << 1);
+        assert_eq!(F32.classify(f(-0.0)), 1 << 3);
+        assert_eq!(F32.classify(f(0.0)), 1 << 4);
+        assert_eq!(F32.classify(1), 1 << 5);
+        assert_eq!(F32.classify(f(1.0)), 1 << 6);
+        assert_eq!(F32.classify(f(f32::INFINITY)), 1 << 7);
+        assert_eq!(F32.classify(0x7f80_0001), 1 << 8);
+        assert_eq!(F32.classify(F32.canonical_nan()), 1 << 9);
+    }
+}
@@ -0,0 +1,83 @@
+// Polling loop detection.
+//
+// A hart waiting on a lock word or flag without WRS or PAUSE spins in a
+// short load, compare, branch loop that gets nothing done: each time its
+// backward branch is taken again the registers are as they were the time
+// before and nothing has been stored in between. Such an iteration flags
+// the hart idle, so the run loop gives the host CPU away as it does after
+// wrs.*. A loop that counts, even only towards a timeout, makes progress
+// and runs at full speed, as does one polling the time CSR, whose value
+// moves on every read.
+
+use super::*;
+
+#[derive(Default)]
+pub(super) struct SpinDetector {
+    // The backward branch taken last and the registers when it was
+    branch: Option<u64>,
+    regs: [u64; 32],
+    // Loads and stores retired since
+    loaded: bool,
+    stored: bool,
+}
+
+impl SpinDetector {
+    /// Track the (expanded) instruction retired at pc, which went to
+    /// next_pc and left `regs`. True when it ended an iteration of a
+    /// polling loop.
+    pub(super) fn retire(&mut self, inst: u32, pc: u64, next_pc: u64, regs: &[u64; 32]) -> bool {
+        match getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS) {
+            OPCODE_LOAD | OPCODE_LOAD_FP => self.loaded = true,
+            // LR only reads, but an LR/SC loop stores on the way out
+            OPCODE_STORE | OPCODE_STORE_FP | OPCODE_AMO => self.stored = true,
+            OPCODE_BRANCH if next_pc < pc => {
+                let spinning = self.branch == Some(pc) && self.regs == *regs && self.loaded && !self.stored;
+                *self = SpinDetector {
+                    branch: Some(pc),
+                    regs: *regs,
+                    ..SpinDetector::default()
+                };
+                return spinning;
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_spin_detect() {
+        // Polling the first word of the image, which never changes
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a1, zero, 1))
+            .inst(encode!(lw a0, 0(zero)))
+            .inst(encode!(bne a0, zero, -4))
+            .cpu();
+        cpu.spin = Some(SpinDetector::default());
+        let mut idle = Vec::new();
+        for _ in 0..7 {
+            cpu.step().unwrap();
+            idle.push(std::mem::take(&mut cpu.idle));
+        }
+        // The first iteration has nothing to compare with
+        assert_eq!(idle, [false, false, false, false, true, false, true]);
+
+        // Counting down to a timeout makes progress on every iteration
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a1, zero, 3))
+            .inst(encode!(lw a0, 0(zero)))
+            .inst(encode!(addi a1, a1, -1))
+            .inst(encode!(bne a1, zero, -8))
+            .cpu();
+        cpu.spin = Some(SpinDetector::default());
+        while cpu.fetch().is_ok() {
+            cpu.step().unwrap();
+            assert!(!cpu.idle);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+// Proxy kernel (pk) style syscalls for bare-metal newlib binaries.
+//
+// An ecall passes the syscall number in a7 and arguments in a0-a5, the
+// result comes back in a0 with errors as -errno. Enough is serviced for
+// `riscv64-unknown-elf-gcc hello.c` output to print and exit.
+//
+// --strace (or `t` in the debugger) logs every call strace style:
+//
+//     write(1, 0x0000000080001234, 13) = 13
+//
+// --console-log FILE keeps a copy of everything written to stdout/stderr,
+// each line prefixed with the retired instruction count and the seconds
+// since the run started:
+//
+//     [         1042] [    0.000213] hello world
+//
+// LATER: Trace SBI calls the same way (extension and function IDs from a7
+// and a6) once there is S-mode and an SBI layer to call into.
+
+use super::*;
+use std::time::Instant;
+
+const SYS_CLOSE: u64 = 57;
+const SYS_WRITE: u64 = 64;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_BRK: u64 = 214;
+
+pub(super) struct ConsoleLog {
+    file: Box<dyn Write>,
+    start: Instant,
+    // The next byte starts a line and needs the prefix
+    line_start: bool,
+}
+
+impl ConsoleLog {
+    pub(super) fn new(file: Box<dyn Write>) -> ConsoleLog {
+        ConsoleLog {
+            file,
+            start: Instant::now(),
+            line_start: true,
+        }
+    }
+
+    fn log(&mut self, instret: u64, bytes: &[u8]) -> std::io::Result<()> {
+        let secs = self.start.elapsed().as_secs_f64();
+        for line in bytes.split_inclusive(|&b| b == b'\n') {
+            if self.line_start {
+                write!(self.file, "[{:13}] [{:12.6}] ", instret, secs)?;
+            }
+            self.file.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+        self.file.flush()
+    }
+}
+
+/// Name and argument count of a syscall number
+fn syscall_name(num: u64) -> Option<(&'static str, usize)> {
+    match num {
+        SYS_CLOSE => Some(("close", 1)),
+        SYS_WRITE => Some(("write", 3)),
+        SYS_FSTAT => Some(("fstat", 2)),
+        SYS_EXIT => Some(("exit", 1)),
+        SYS_EXIT_GROUP => Some(("exit_group", 1)),
+        SYS_GETTIMEOFDAY => Some(("gettimeofday", 2)),
+        SYS_BRK => Some(("brk", 1)),
+        _ => None,
+    }
+}
+
+/// strace style line for syscall `num` with arguments a0-a5 and its
+/// result, None when it doesn't return
+fn strace_line(num: u64, args: &[u64], result: Option<i64>) -> String {
+    let (name, count) = match syscall_name(num) {
+        Some((name, count)) => (String::from(name), count),
+        None => (format!("syscall_{}", num), args.len()),
+    };
+    let args: Vec<_> = args[..count]
+        .iter()
+        .map(|&arg| match arg < 0x10000 {
+            true => format!("{}", arg),
+            false => format!("{:#018x}", arg),
+        })
+        .collect();
+    let result = result.map_or(String::from("?"), |result| result.to_string());
+    format!("{}({}) = {}", name, args.join(", "), result)
+}
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+
+impl RiscvCpu {
+    /// Service the ecall at pc. Unknown syscall numbers are an error, as
+    /// they are in pk.
+    pub(super) fn syscall(&mut self) -> Result<(), RiscvCpuError> {
+        let [a0, a1, a2] = [self.ixu[REG_A0], self.ixu[REG_A1], self.ixu[REG_A2]];
+        let num = self.ixu[REG_A7];
+        *self.syscall_counts.entry(num).or_default() += 1;
+        let args: [u64; 6] = std::array::from_fn(|i| self.ixu[REG_A0 + i]);
+        let result = match num {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.strace(num, &args, None);
+                // The ecall retires and the run loop stops at exit_code
+                self.exit_code = Some(a0);
+                return Ok(());
+            }
+            SYS_WRITE => self.sys_write(a0, a1, a2),
+            // stdio is all there is, closing it is harmless
+            SYS_CLOSE => 0,
+            // newlib only uses it to ask whether the fd is a terminal
+            SYS_FSTAT => -EBADF,
+            SYS_GETTIMEOFDAY => self.sys_gettimeofday(a0),
+            SYS_BRK => self.sys_brk(a0),
+            _ => {
+                self.strace(num, &args, None);
+                println!("{COLOR_RED}unimplemented syscall {} at pc {:#018x}{COLOR_RESET}", num, self.pc);
+                return Err(RiscvCpuError::ExecuteError);
+            }
+        };
+        self.strace(num, &args, Some(result));
+        self.ixu[REG_A0] = result as u64;
+        Ok(())
+    }
+
+    /// End of run trap statistics, ecalls by syscall, and CSR accesses by CSR
+    /// LATER: Count every trap cause and interrupt source and SFENCE.VMAs
+    /// here too. Any trap but an ecall ends the run, as nothing delivers
+    /// one to mtvec, and there are no interrupts or S mode yet.
+    pub(super) fn print_trap_stats(&self) {
+        if !self.syscall_counts.is_empty() {
+            let calls: Vec<_> = self
+                .syscall_counts
+                .iter()
+                .map(|(&num, count)| match syscall_name(num) {
+                    Some((name, _)) => format!("{} = {}", name, count),
+                    None => format!("syscall_{} = {}", num, count),
+                })
+                .collect();
+            let total: u64 = self.syscall_counts.values().sum();
+            println!("{COLOR_AQUA}[traps]{COLOR_RESET} ecall = {} ({})", total, calls.join(", "));
+        }
+        if !self.csr_counts.is_empty() {
+            let csrs: Vec<_> = self
+                .csr_counts
+                .iter()
+                .map(|(&csr, count)| match csr::name(csr) {
+                    Some(name) => format!("{} = {}", name, count),
+                    None => format!("{:#x} = {}", csr, count),
+                })
+                .collect();
+            println!("{COLOR_AQUA}[csrs]{COLOR_RESET} {}", csrs.join(", "));
+        }
+    }
+
+    fn strace(&self, num: u64, args: &[u64], result: Option<i64>) {
+        if self.strace {
+            println!("{COLOR_GREY}{}{COLOR_RESET}", strace_line(num, args, result));
+        }
+    }
+
+    fn sys_write(&mut self, fd: u64, buf: u64, len: u64) -> i64 {
+        let Some(idx) = self.mem_index(buf, len) else { return -EFAULT };
+        let bytes = &self.mem[idx..idx + len as usize];
+        let written = match fd {
+            1 => std::io::stdout().write_all(bytes).and_then(|_| std::io::stdout().flush()),
+            2 => std::io::stderr().write_all(bytes),
+            _ => return -EBADF,
+        };
+        if let Some(output) = &mut self.output {
+            output.extend_from_slice(bytes);
+        }
+        if let Some(log) = &mut self.console_log {
+            if let Err(e) = log.log(self.instret, bytes) {
+                println!("{COLOR_RED}console log: {}, logging stopped{COLOR_RESET}", e);
+                self.console_log = None;
+            }
+        }
+        match written {
+            Ok(()) => len as i64,
+            Err(_) => -EBADF,
+        }
+    }
+
+    /// struct timeval { i64 tv_sec; i64 tv_usec; }, the timezone is ignored
+    fn sys_gettimeofday(&mut self, tv: u64) -> i64 {
+        let now = self.timebase.time_of_day(self.instret);
+        match self
+            .store_data(tv, 8, now.as_secs())
+            .and_then(|_| self.store_data(tv + 8, 8, now.subsec_micros() as u64))
+        {
+            Some(()) => 0,
+            None => -EFAULT,
+        }
+    }
+
+    /// brk(0) returns the current break, anything inside memory moves it.
+    /// Like Linux the result is the break after the call, so a failed
+    /// request returns the old one.
+    fn sys_brk(&mut self, addr: u64) -> i64 {
+        if addr > self.mem_base && self.mem_index(addr - 1, 1).is_some() {
+            self.brk = addr;
+        }
+        self.brk as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    fn ecall_cpu(num: i64) -> RiscvCpu {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a7, zero, num))
+            .inst(encode!(ecall))
+            // "hi\n" and room for a timeval
+            .insts(&[0x000a6968, 0, 0, 0, 0])
+            .cpu();
+        cpu.fusion = false;
+        cpu
+    }
+
+    #[test]
+    fn test_syscall_write() {
+        let mut cpu = ecall_cpu(64);
+        cpu.ixu[REG_A0] = 1;
+        cpu.ixu[REG_A1] = 8;
+        cpu.ixu[REG_A2] = 3;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 3);
+
+        let mut cpu = ecall_cpu(64);
+        cpu.ixu[REG_A0] = 5;
+        cpu.ixu[REG_A2] = 3;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.ixu[REG_A0] as i64, -EBADF);
+    }
+
+    #[test]
+    fn test_syscall_brk() {
+        let mut cpu = ecall_cpu(214);
+        cpu.brk = 16;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 16);
+        assert_eq!(cpu.sys_brk(24), 24);
+        // Past the end of memory, the break stays put
+        assert_eq!(cpu.sys_brk(0x1000), 24);
+    }
+
+    #[test]
+    fn test_syscall_gettimeofday() {
+        let mut cpu = ecall_cpu(169);
+        cpu.ixu[REG_A0] = 12;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0);
+        assert!(cpu.read_mem(12, 8).unwrap() > 1_600_000_000);
+        assert!(cpu.read_mem(20, 8).unwrap() < 1_000_000);
+        assert_eq!(cpu.sys_gettimeofday(0x1000), -EFAULT);
+    }
+
+    #[test]
+    fn test_syscall_gettimeofday_instret() {
+        let mut cpu = ecall_cpu(169);
+        cpu.timebase = Timebase::new(TimebaseMode::Instret, 1000);
+        cpu.ixu[REG_A0] = 12;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // The addi before the ecall is the only tick, 1 ms
+        assert_eq!(cpu.read_mem(12, 8), Some(0));
+        assert_eq!(cpu.read_mem(20, 8), Some(1000));
+    }
+
+    #[test]
+    fn test_syscall_counts() {
+        let mut cpu = ecall_cpu(169);
+        cpu.ixu[REG_A0] = 12;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.pc = 4;
+        cpu.step().unwrap();
+        assert_eq!(cpu.syscall_counts.get(&SYS_GETTIMEOFDAY), Some(&2));
+        assert_eq!(cpu.syscall_counts.len(), 1);
+    }
+
+    #[test]
+    fn test_console_log() {
+        let path = std::env::temp_dir().join(format!("rvlator-console-{}.log", std::process::id()));
+        let mut log = ConsoleLog::new(Box::new(fs::File::create(&path).unwrap()));
+        log.log(7, b"hello\nwor").unwrap();
+        log.log(9, b"ld\n").unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = text.lines().map(|line| (&line[..15], &line[31..])).collect();
+        assert_eq!(lines, [("[            7]", "hello"), ("[            7]", "world")]);
+    }
+
+    #[test]
+    fn test_strace_line() {
+        let args = [1, 0x80001234, 13, 0, 0, 0];
+        assert_eq!(strace_line(SYS_WRITE, &args, Some(13)), "write(1, 0x0000000080001234, 13) = 13");
+        assert_eq!(strace_line(SYS_EXIT, &[0; 6], None), "exit(0) = ?");
+        assert_eq!(strace_line(SYS_FSTAT, &args, Some(-EBADF)), "fstat(1, 0x0000000080001234) = -9");
+        assert_eq!(strace_line(1000, &[0; 6], None), "syscall_1000(0, 0, 0, 0, 0, 0) = ?");
+    }
+}
@@ -0,0 +1,142 @@
+// Dynamic taint tracking.
+//
+// Sources are memory regions and registers marked tainted before the run.
+// Taint follows the register dataflow of every retired instruction (rd is
+// tainted if any source register is), loads taint rd with the taint of the
+// bytes read and stores copy the taint of the data register to the bytes
+// written. Reports are made when
+//
+//     - a jump takes its target from a tainted register (data reached the pc)
+//     - the instruction at a sink pc reads a tainted register
+
+use super::*;
+use std::collections::BTreeSet;
+
+#[derive(Default)]
+pub(super) struct Taint {
+    regs: [bool; 32],
+    // Tainted byte addresses
+    mem: BTreeSet<u64>,
+    sinks: BTreeSet<u64>,
+    // Memory touched by the instruction being executed, from mem_access
+    loaded: bool,
+    stored: Vec<(u64, u64)>,
+    pub reports: Vec<String>,
+}
+
+impl Taint {
+    pub(super) fn taint_mem(&mut self, addr: u64, len: u64) {
+        self.mem.extend(addr..addr.saturating_add(len));
+    }
+
+    pub(super) fn taint_reg(&mut self, reg: usize) {
+        // x0 can't hold data
+        self.regs[reg] = reg != REG_ZERO;
+    }
+
+    pub(super) fn add_sink(&mut self, pc: u64) {
+        self.sinks.insert(pc);
+    }
+
+    pub(super) fn is_reg_tainted(&self, reg: usize) -> bool {
+        self.regs[reg]
+    }
+
+    pub(super) fn is_mem_tainted(&self, addr: u64, size: u64) -> bool {
+        self.mem.range(addr..addr.saturating_add(size)).next().is_some()
+    }
+
+    /// Memory access hook, remembers what the current instruction touched
+    pub(super) fn access(&mut self, kind: RiscvAccessType, addr: u64, size: u64) {
+        match kind {
+            RiscvAccessType::Fetch => {}
+            RiscvAccessType::Load => self.loaded |= self.is_mem_tainted(addr, size),
+            RiscvAccessType::Store => self.stored.push((addr, size)),
+        }
+    }
+
+    /// Propagate taint through the instruction retired at pc
+    pub(super) fn retire(&mut self, inst: u32, pc: u64) {
+        let pi = PipeInst::decode(inst);
+        let sources = pi.rs.iter().flatten().filter(|&&reg| self.regs[reg]).count();
+        if sources > 0 && self.sinks.contains(&pc) {
+            self.reports.push(format!("tainted data reaches sink at pc {:#018x}", pc));
+        }
+        match pi.class {
+            PipeClass::Jump if pi.rs[0].is_some_and(|rs1| self.regs[rs1]) => {
+                self.reports.push(format!("jump to a tainted target at pc {:#018x}", pc));
+            }
+            PipeClass::Store => {
+                let data = pi.rs[1].is_some_and(|rs2| self.regs[rs2]);
+                for (addr, size) in std::mem::take(&mut self.stored) {
+                    for byte in addr..addr.saturating_add(size) {
+                        match data {
+                            true => self.mem.insert(byte),
+                            false => self.mem.remove(&byte),
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+        if let Some(rd) = pi.rd.filter(|&rd| rd != REG_ZERO) {
+            self.regs[rd] = match pi.class {
+                PipeClass::Load => self.loaded,
+                // Return addresses and upper immediates carry no data
+                PipeClass::Jump => false,
+                _ => sources > 0,
+            };
+        }
+        self.loaded = false;
+        self.stored.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_taint_registers() {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a1, a0, 1))
+            .inst(encode!(xori a2, a1, -1))
+            .inst(encode!(lui a1, 1))
+            .inst(encode!(addi a3, a2, 0))
+            .cpu();
+        let mut taint = Taint::default();
+        taint.taint_reg(REG_A0);
+        taint.add_sink(12);
+        cpu.taint = Some(taint);
+        while cpu.fetch().is_ok() {
+            cpu.step().unwrap();
+        }
+        let taint = cpu.taint.unwrap();
+        assert!(taint.is_reg_tainted(REG_A2));
+        assert!(taint.is_reg_tainted(REG_A3));
+        // Overwritten by lui
+        assert!(!taint.is_reg_tainted(REG_A1));
+        assert_eq!(taint.reports, ["tainted data reaches sink at pc 0x000000000000000c"]);
+    }
+
+    #[test]
+    fn test_taint_memory() {
+        let mut taint = Taint::default();
+        taint.taint_mem(0x100, 4);
+        assert!(taint.is_mem_tainted(0x103, 2));
+        assert!(!taint.is_mem_tainted(0x104, 4));
+
+        // ld a0,0x100(zero) then sd a0,0x200(zero) copies the taint
+        taint.access(RiscvAccessType::Load, 0x100, 8);
+        taint.retire(0x10003503, 0);
+        assert!(taint.is_reg_tainted(REG_A0));
+        taint.access(RiscvAccessType::Store, 0x200, 8);
+        taint.retire(0x20a03023, 4);
+        assert!(taint.is_mem_tainted(0x207, 1));
+
+        // jalr zero,0(a0)
+        taint.retire(0x00050067, 8);
+        assert_eq!(taint.reports, ["jump to a tainted target at pc 0x0000000000000008"]);
+    }
+}
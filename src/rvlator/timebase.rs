@@ -0,0 +1,82 @@
+// Guest timebase.
+//
+// Guest time is counted in ticks of a fixed frequency (--timebase-freq,
+// 10 MHz by default) derived either from the retired instruction count,
+// which makes runs reproducible, or from the host clock. In instret mode
+// the guest's time of day starts at the epoch when the run starts, and
+// --timebase-ratio sets how many instructions make a tick: 4 slows guest
+// time down to a tick every 4 instructions, 1/4 speeds it up to 4 ticks
+// per instruction.
+//
+// The time CSR and gettimeofday read ticks().
+// LATER: Back the CLINT mtime with ticks() too and put the frequency in
+// the DTB's timebase-frequency, once there are devices and a DTB.
+// LATER: In host mode, raise the machine timer interrupt when the host
+// clock passes mtimecmp rather than when the guest next looks, and sleep
+// the host thread in WFI until then. Needs the CLINT, WFI and interrupts.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub(super) enum TimebaseMode {
+    Instret,
+    Host,
+}
+
+pub(super) struct Timebase {
+    pub mode: TimebaseMode,
+    // Ticks per second
+    pub freq: u64,
+    // Instret mode: (instructions, ticks) they advance the timebase by
+    pub ratio: (u64, u64),
+    start: Instant,
+}
+
+impl Timebase {
+    pub(super) fn new(mode: TimebaseMode, freq: u64) -> Timebase {
+        Timebase {
+            mode,
+            freq,
+            ratio: (1, 1),
+            start: Instant::now(),
+        }
+    }
+
+    /// Ticks since the start of the run after `instret` instructions
+    pub(super) fn ticks(&self, instret: u64) -> u64 {
+        match self.mode {
+            TimebaseMode::Instret => (instret as u128 * self.ratio.1 as u128 / self.ratio.0 as u128) as u64,
+            TimebaseMode::Host => (self.start.elapsed().as_nanos() * self.freq as u128 / 1_000_000_000) as u64,
+        }
+    }
+
+    /// Guest time of day as a duration since the Unix epoch
+    pub(super) fn time_of_day(&self, instret: u64) -> Duration {
+        match self.mode {
+            TimebaseMode::Instret => {
+                let ticks = self.ticks(instret);
+                Duration::from_secs(ticks / self.freq) + Duration::from_nanos(ticks % self.freq * 1_000_000_000 / self.freq)
+            }
+            TimebaseMode::Host => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timebase_instret() {
+        let timebase = Timebase::new(TimebaseMode::Instret, 1000);
+        assert_eq!(timebase.ticks(2500), 2500);
+        assert_eq!(timebase.time_of_day(2500), Duration::from_millis(2500));
+        let mut timebase = Timebase::new(TimebaseMode::Instret, 1000);
+        timebase.ratio = (4, 1);
+        assert_eq!(timebase.ticks(10), 2);
+        timebase.ratio = (1, 4);
+        assert_eq!(timebase.time_of_day(250), Duration::from_secs(1));
+        let timebase = Timebase::new(TimebaseMode::Host, 1000);
+        assert!(timebase.time_of_day(0).as_secs() > 1_600_000_000);
+    }
+}
@@ -0,0 +1,196 @@
+// Constrained-random instruction stream (torture) generator.
+//
+// Programs are sequences of legal instructions from the implemented
+// subset, ending in an ecall. Register choice is biased towards a few
+// registers so results feed each other, and fusable pairs are emitted on
+// purpose so the fused and unfused paths both get exercised, next to
+// loads, stores and forward branches, some of which land between the two
+// halves of a pair. Loads and stores go through gp, which the first
+// instruction points at a scratch area past the code and nothing else
+// writes.
+//
+// Each program runs on two harts in lockstep, one with macro-op fusion and
+// one without, and their architectural state is compared every time the
+// pcs line up. Any divergence is a decode/execute bug.
+
+use super::*;
+
+// Immediates worth hitting more often than uniform sampling would
+const TORTURE_IMM12: [u32; 6] = [0x000, 0x001, 0x7ff, 0x800, 0xfff, 0xffe];
+// Bytes of scratch memory the loads and stores use
+const TORTURE_DATA: u64 = 256;
+
+fn reg(rng: &mut Rng) -> u32 {
+    // Mostly a0-a5 to build dependency chains, zero now and then, and
+    // never gp, which holds the scratch area
+    match rng.below(8) {
+        0 => match rng.below(32) as usize {
+            REG_GP => REG_ZERO as u32,
+            reg => reg as u32,
+        },
+        _ => (REG_A0 as u64 + rng.below(6)) as u32,
+    }
+}
+
+fn imm12(rng: &mut Rng) -> u32 {
+    match rng.below(4) {
+        0 => TORTURE_IMM12[rng.below(TORTURE_IMM12.len() as u64) as usize],
+        _ => rng.below(0x1000) as u32,
+    }
+}
+
+fn enc_i(funct3: u32, rd: u32, rs1: u32, imm12: u32) -> u32 {
+    imm12 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | OPCODE_OP_IMM
+}
+
+fn enc_u(opcode: u32, rd: u32, imm20: u32) -> u32 {
+    imm20 << 12 | rd << 7 | opcode
+}
+
+fn enc_load(funct3: u32, rd: u32, offset: u32) -> u32 {
+    offset << 20 | (REG_GP as u32) << 15 | funct3 << 12 | rd << 7 | OPCODE_LOAD
+}
+
+fn enc_store(funct3: u32, rs2: u32, offset: u32) -> u32 {
+    (offset >> 5) << 25 | rs2 << 20 | (REG_GP as u32) << 15 | funct3 << 12 | (offset & 0x1f) << 7 | OPCODE_STORE
+}
+
+/// A forward branch over `skip` instructions
+fn enc_branch(funct3: u32, rs1: u32, rs2: u32, skip: u32) -> u32 {
+    // offset[4:1] in inst[11:8], the rest of the offset is zero
+    let offset = (skip + 1) * 4;
+    rs2 << 20 | rs1 << 15 | funct3 << 12 | (offset & 0x1e) << 7 | OPCODE_BRANCH
+}
+
+/// Where the scratch area starts for a program of `len` instructions, on
+/// the first page boundary past the code
+fn data_base(len: usize) -> u64 {
+    (len as u64 * 4).next_multiple_of(0x1000)
+}
+
+/// Generate `len` random instructions followed by an ecall
+pub(super) fn generate(seed: u64, len: usize) -> Vec<u32> {
+    let mut rng = Rng::new(seed);
+    let mut prog = Vec::with_capacity(len + 1);
+    prog.push(enc_u(OPCODE_LUI, REG_GP as u32, (data_base(len + 1) >> 12) as u32));
+    while prog.len() < len {
+        let rd = reg(&mut rng);
+        let rs1 = reg(&mut rng);
+        match rng.below(14) {
+            0 => prog.push(enc_u(OPCODE_LUI, rd, rng.below(0x100000) as u32)),
+            1 => prog.push(enc_u(OPCODE_AUIPC, rd, rng.below(0x100000) as u32)),
+            2 => {
+                // Fusable lui/auipc + addi
+                let opcode = if rng.below(2) == 0 { OPCODE_LUI } else { OPCODE_AUIPC };
+                prog.push(enc_u(opcode, rd, rng.below(0x100000) as u32));
+                prog.push(enc_i(0b000, rd, rd, imm12(&mut rng)));
+            }
+            3 => {
+                // Fusable slli + srli zero-extension
+                let shamt = rng.below(64) as u32;
+                prog.push(enc_i(0b001, rd, rs1, shamt));
+                prog.push(enc_i(0b101, rd, rd, shamt));
+            }
+            4 => {
+                // Shifts: slli, srli, srai
+                let shamt = rng.below(64) as u32;
+                match rng.below(3) {
+                    0 => prog.push(enc_i(0b001, rd, rs1, shamt)),
+                    1 => prog.push(enc_i(0b101, rd, rs1, shamt)),
+                    _ => prog.push(enc_i(0b101, rd, rs1, 0x400 | shamt)),
+                }
+            }
+            5 => {
+                // lb, lh, lw, ld, lbu, lhu, lwu, naturally aligned
+                let funct3 = rng.below(7) as u32;
+                let size = 1 << (funct3 & 0b11);
+                prog.push(enc_load(funct3, rd, (rng.below(TORTURE_DATA / size) * size) as u32));
+            }
+            6 => {
+                // sb, sh, sw, sd, naturally aligned
+                let funct3 = rng.below(4) as u32;
+                let size = 1 << funct3;
+                prog.push(enc_store(funct3, rs1, (rng.below(TORTURE_DATA / size) * size) as u32));
+            }
+            7 => {
+                // beq, bne, blt, bge, bltu, bgeu over one or two instructions
+                let funct3 = [0b000, 0b001, 0b100, 0b101, 0b110, 0b111][rng.below(6) as usize];
+                prog.push(enc_branch(funct3, rd, rs1, 1 + rng.below(2) as u32));
+            }
+            8 => {
+                // A branch that, taken, lands on the second half of a fusable pair
+                let funct3 = [0b000, 0b001][rng.below(2) as usize];
+                prog.push(enc_branch(funct3, rs1, reg(&mut rng), 1));
+                prog.push(enc_u(OPCODE_LUI, rd, rng.below(0x100000) as u32));
+                prog.push(enc_i(0b000, rd, rd, imm12(&mut rng)));
+            }
+            _ => {
+                // addi, slti, sltiu, xori, ori, andi
+                let funct3 = [0b000, 0b010, 0b011, 0b100, 0b110, 0b111][rng.below(6) as usize];
+                prog.push(enc_i(funct3, rd, rs1, imm12(&mut rng)));
+            }
+        }
+    }
+    prog.truncate(len);
+    prog.push(INST_ECALL);
+    prog
+}
+
+/// The image of a program: its code, then zeroed memory up to the end of
+/// the scratch area
+pub(super) fn assemble(prog: &[u32]) -> Vec<u8> {
+    let mut image: Vec<u8> = prog.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    image.resize((data_base(prog.len()) + TORTURE_DATA) as usize, 0);
+    image
+}
+
+/// Run the program on a fused and an unfused hart in lockstep. Returns the
+/// pc at which their state first differs.
+pub(super) fn check(prog: &[u32]) -> Result<(), u64> {
+    let code = assemble(prog);
+    // The terminating ecall
+    let end = (prog.len() as u64 - 1) * 4;
+    let mut fused = RiscvCpu::new(code.clone());
+    let mut plain = RiscvCpu::new(code);
+    plain.fusion = false;
+
+    while fused.pc < end {
+        fused.step().map_err(|_| fused.pc)?;
+        while plain.pc < fused.pc {
+            plain.step().map_err(|_| plain.pc)?;
+        }
+        if plain.pc != fused.pc || plain.ixu != fused.ixu {
+            return Err(fused.pc);
+        }
+    }
+    if plain.mem != fused.mem {
+        return Err(fused.pc);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_torture_reproducible() {
+        assert_eq!(generate(42, 64), generate(42, 64));
+        assert_ne!(generate(42, 64), generate(43, 64));
+        assert_eq!(generate(7, 100).len(), 101);
+        assert_eq!(*generate(7, 100).last().unwrap(), INST_ECALL);
+        // Every kind of instruction shows up in a program of this size
+        let opcodes: Vec<u32> = generate(7, 100).iter().map(|inst| inst & 0x7f).collect();
+        for opcode in [OPCODE_OP_IMM, OPCODE_LUI, OPCODE_AUIPC, OPCODE_LOAD, OPCODE_STORE, OPCODE_BRANCH] {
+            assert!(opcodes.contains(&opcode), "opcode {:#09b}", opcode);
+        }
+        assert_eq!(assemble(&generate(7, 100)).len(), 0x1000 + TORTURE_DATA as usize);
+    }
+
+    #[test]
+    fn test_torture_lockstep() {
+        for seed in 0..32 {
+            assert_eq!(Ok(()), check(&generate(seed, 200)), "seed {}", seed);
+        }
+    }
+}
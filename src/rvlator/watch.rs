@@ -0,0 +1,142 @@
+// Watch expressions.
+//
+//     a0==0                    stop when the condition becomes true
+//     mem64[0x80004000] changes    stop when the value changes
+//
+// Operands are the ones assertions take (registers, pc, memN[ADDR]). A
+// watch is evaluated after every step against the value it saw last, so
+// a condition that already holds when the watch is set only fires once it
+// has turned false and true again. `rvlator run --watch` ends the run
+// there, the debugger's `w` stops a step or continue.
+
+use super::*;
+use assertion::Operand;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Watch {
+    pub text: String,
+    lhs: Operand,
+    // (equal, value) of a condition, None to watch for any change
+    cond: Option<(bool, u64)>,
+    // Value at the previous check, None when unreadable
+    last: Option<u64>,
+}
+
+impl Watch {
+    pub(super) fn parse(text: &str) -> Result<Watch, String> {
+        if let Some(lhs) = text.trim().strip_suffix("changes") {
+            let lhs = Operand::parse(lhs).ok_or(format!("bad operand in watch: {}", text))?;
+            return Ok(Watch { text: text.to_string(), lhs, cond: None, last: None });
+        }
+        let cond = Assertion::parse(text).map_err(|e| e.replace("assertion", "watch"))?;
+        if cond.at.is_some() {
+            return Err(format!("a watch is checked at every pc: {}", text));
+        }
+        Ok(Watch {
+            text: text.to_string(),
+            lhs: cond.lhs,
+            cond: Some((cond.equal, cond.value)),
+            last: None,
+        })
+    }
+
+    fn holds(&self, value: Option<u64>) -> bool {
+        match (self.cond, value) {
+            (Some((equal, expected)), Some(value)) => (value == expected) == equal,
+            _ => false,
+        }
+    }
+
+    /// Take the current value as the starting point
+    pub(super) fn arm(&mut self, cpu: &RiscvCpu) {
+        self.last = self.lhs.value(cpu);
+    }
+
+    /// Check against the last value, a description if the watch fired
+    pub(super) fn check(&mut self, cpu: &RiscvCpu) -> Option<String> {
+        let value = self.lhs.value(cpu);
+        let last = std::mem::replace(&mut self.last, value);
+        let fired = match self.cond {
+            Some(_) => self.holds(value) && !self.holds(last),
+            None => value != last,
+        };
+        let show = |value: Option<u64>| value.map_or(String::from("unreadable"), |v| format!("{:#x}", v));
+        fired.then(|| format!("watch {} at pc {:#018x} ({} -> {})", self.text, cpu.pc, show(last), show(value)))
+    }
+}
+
+impl RiscvCpu {
+    pub(super) fn arm_watches(&mut self) {
+        let mut watches = std::mem::take(&mut self.watches);
+        for watch in &mut watches {
+            watch.arm(self);
+        }
+        self.watches = watches;
+    }
+
+    /// Check every watch after a step, print the ones that fired. True
+    /// if execution should stop.
+    pub(super) fn check_watches(&mut self) -> bool {
+        let mut watches = std::mem::take(&mut self.watches);
+        let fired: Vec<_> = watches.iter_mut().filter_map(|watch| watch.check(self)).collect();
+        self.watches = watches;
+        for text in &fired {
+            println!("{COLOR_BROWN}{}{COLOR_RESET}", text);
+        }
+        !fired.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progbuilder::*;
+
+    #[test]
+    fn test_watch_parse() {
+        assert!(Watch::parse("a0 == 0").is_ok());
+        assert!(Watch::parse("mem64[0x80004000] changes").is_ok());
+        assert!(Watch::parse("q0 changes").is_err());
+        assert!(Watch::parse("a0==0@0x40").is_err());
+        assert_eq!(Watch::parse("a0").unwrap_err(), "watch needs == or !=: a0");
+    }
+
+    #[test]
+    fn test_watch_fires() {
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(addi a0, zero, 3))
+            .inst(encode!(addi a1, zero, 1))
+            .inst(encode!(addi a0, a0, -3))
+            .inst(encode!(addi a1, zero, 1))
+            .cpu();
+        cpu.fusion = false;
+        cpu.ixu[REG_A1] = 1;
+        cpu.watches = vec![Watch::parse("a0==0").unwrap(), Watch::parse("a1 changes").unwrap()];
+        cpu.arm_watches();
+        let mut stops = Vec::new();
+        while cpu.fetch().is_ok() {
+            cpu.step().unwrap();
+            if cpu.check_watches() {
+                stops.push(cpu.pc);
+            }
+        }
+        // a0 is 0 at the start, it fires once it has been non-zero. a1 never
+        // changes from the 1 it was armed with.
+        assert_eq!(stops, [12]);
+    }
+
+    #[test]
+    fn test_watch_fused_pair() {
+        // The value between the halves of a lui/addi pair is still seen
+        let mut cpu = ProgramBuilder::new()
+            .inst(encode!(lui a0, 0x12345))
+            .inst(encode!(addi a0, a0, 0x678))
+            .inst(encode!(addi a1, zero, 1))
+            .cpu();
+        cpu.watches = vec![Watch::parse("a0==0x12345000").unwrap()];
+        cpu.arm_watches();
+        cpu.step().unwrap();
+        assert!(cpu.check_watches());
+        assert_eq!(cpu.pc, 4);
+    }
+}
@@ -0,0 +1,264 @@
+// T-Head (XuanTie C906/C910) vendor extensions in the custom-0 opcode.
+//
+// Opt-in with --xthead since the encodings overlap other vendors' custom
+// instructions. Implemented:
+//
+//     XTheadBa        th.addsl
+//     XTheadBb        th.srri th.srriw th.ext th.extu th.ff0 th.ff1 th.rev th.revw th.tstnbz
+//     XTheadBs        th.tst
+//     XTheadCondMov   th.mveqz th.mvnez
+//     XTheadCmo       cache maintenance and th.sync*, no-ops as memory is coherent
+//     XTheadMemPair   th.lwd th.lwud th.ldd th.swd th.sdd
+
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum XTheadOp {
+    Addsl(u32),
+    Srri(u32),
+    Srriw(u32),
+    Ext(u32, u32),
+    Extu(u32, u32),
+    Ff0,
+    Ff1,
+    Rev,
+    Revw,
+    Tstnbz,
+    Tst(u32),
+    Mveqz,
+    Mvnez,
+    Cmo,
+    // Pair loads and stores: access size, sign extend loaded words
+    LoadPair(u64, bool),
+    StorePair(u64),
+}
+
+fn decode(inst: u32) -> Option<XTheadOp> {
+    if getfield32!(inst, INST_OPCODE_WID, INST_OPCODE_POS) != OPCODE_CUSTOM0 {
+        return None;
+    }
+    let funct3 = getfield32!(inst, INST_FUNCT3_WID, INST_FUNCT3_POS);
+    let funct7 = getfield32!(inst, INST_FUNCT7_WID, INST_FUNCT7_POS);
+    let funct6 = getfield32!(inst, INST_FUNCT6_WID, INST_FUNCT6_POS);
+    let funct5 = getfield32!(inst, 5, 27);
+    let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS);
+    let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS);
+    let imm6 = getfield32!(inst, INST_SHAMT_WID, INST_SHAMT_POS);
+
+    let op = match funct3 {
+        0b000 if rd == 0 && funct7 <= 0b0000011 => XTheadOp::Cmo,
+        0b001 => match (funct7, rs2) {
+            (0b0100000, _) => XTheadOp::Mveqz,
+            (0b0100001, _) => XTheadOp::Mvnez,
+            (0b0001010, _) => XTheadOp::Srriw(rs2),
+            (0b1000000, 0) => XTheadOp::Tstnbz,
+            (0b1000001, 0) => XTheadOp::Rev,
+            (0b1000010, 0) => XTheadOp::Ff0,
+            (0b1000011, 0) => XTheadOp::Ff1,
+            (0b1001000, 0) => XTheadOp::Revw,
+            _ if funct5 == 0 => XTheadOp::Addsl(getfield32!(inst, 2, 25)),
+            _ if funct6 == 0b000100 => XTheadOp::Srri(imm6),
+            _ if funct6 == 0b100010 => XTheadOp::Tst(imm6),
+            _ => return None,
+        },
+        0b010 => XTheadOp::Ext(funct6, imm6),
+        0b011 => XTheadOp::Extu(funct6, imm6),
+        0b100 => match funct5 {
+            0b11100 => XTheadOp::LoadPair(4, true),
+            0b11110 => XTheadOp::LoadPair(4, false),
+            0b11111 => XTheadOp::LoadPair(8, false),
+            _ => return None,
+        },
+        0b101 => match funct5 {
+            0b11100 => XTheadOp::StorePair(4),
+            0b11111 => XTheadOp::StorePair(8),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Assembly text of a T-Head instruction, None for anything else
+pub(super) fn disasm(inst: u32) -> Option<String> {
+    let rd = REGNAME[getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize];
+    let rs1 = REGNAME[getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize];
+    let rs2 = REGNAME[getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize];
+    let imm2 = getfield32!(inst, 2, 25);
+    let text = match decode(inst)? {
+        XTheadOp::Addsl(sh) => format!("th.addsl {},{},{},{}", rd, rs1, rs2, sh),
+        XTheadOp::Srri(sh) => format!("th.srri {},{},{}", rd, rs1, sh),
+        XTheadOp::Srriw(sh) => format!("th.srriw {},{},{}", rd, rs1, sh),
+        XTheadOp::Ext(msb, lsb) => format!("th.ext {},{},{},{}", rd, rs1, msb, lsb),
+        XTheadOp::Extu(msb, lsb) => format!("th.extu {},{},{},{}", rd, rs1, msb, lsb),
+        XTheadOp::Ff0 => format!("th.ff0 {},{}", rd, rs1),
+        XTheadOp::Ff1 => format!("th.ff1 {},{}", rd, rs1),
+        XTheadOp::Rev => format!("th.rev {},{}", rd, rs1),
+        XTheadOp::Revw => format!("th.revw {},{}", rd, rs1),
+        XTheadOp::Tstnbz => format!("th.tstnbz {},{}", rd, rs1),
+        XTheadOp::Tst(bit) => format!("th.tst {},{},{}", rd, rs1, bit),
+        XTheadOp::Mveqz => format!("th.mveqz {},{},{}", rd, rs1, rs2),
+        XTheadOp::Mvnez => format!("th.mvnez {},{},{}", rd, rs1, rs2),
+        XTheadOp::Cmo => format!("th.cmo {:#010x}", inst),
+        XTheadOp::LoadPair(size, signed) => {
+            let name = match (size, signed) {
+                (4, true) => "th.lwd",
+                (4, false) => "th.lwud",
+                _ => "th.ldd",
+            };
+            format!("{} {},{},({}),{},{}", name, rd, rs2, rs1, imm2, if size == 4 { 3 } else { 4 })
+        }
+        XTheadOp::StorePair(size) => {
+            let name = if size == 4 { "th.swd" } else { "th.sdd" };
+            format!("{} {},{},({}),{},{}", name, rd, rs2, rs1, imm2, if size == 4 { 3 } else { 4 })
+        }
+    };
+    Some(text)
+}
+
+impl RiscvCpu {
+    pub(super) fn execute_xthead(&mut self, inst: u32) -> Result<(), RiscvCpuError> {
+        let op = decode(inst).ok_or(RiscvCpuError::DecodeError)?;
+        let rd = getfield32!(inst, INST_RD_WID, INST_RD_POS) as usize;
+        let rs1 = getfield32!(inst, INST_RS1_WID, INST_RS1_POS) as usize;
+        let rs2 = getfield32!(inst, INST_RS2_WID, INST_RS2_POS) as usize;
+        let x = self.ixu[rs1];
+        let y = self.ixu[rs2];
+
+        self.ixu[rd] = match op {
+            XTheadOp::Addsl(sh) => x.wrapping_add(y << sh),
+            XTheadOp::Srri(sh) => x.rotate_right(sh),
+            XTheadOp::Srriw(sh) => (x as u32).rotate_right(sh) as i32 as u64,
+            XTheadOp::Ext(msb, lsb) | XTheadOp::Extu(msb, lsb) => {
+                if msb < lsb {
+                    return Err(RiscvCpuError::DecodeError);
+                }
+                let field = (x << (63 - msb)) >> (63 - msb + lsb);
+                match op {
+                    XTheadOp::Ext(..) => ((x << (63 - msb)) as i64 >> (63 - msb + lsb)) as u64,
+                    _ => field,
+                }
+            }
+            XTheadOp::Ff0 => (!x).leading_zeros() as u64,
+            XTheadOp::Ff1 => x.leading_zeros() as u64,
+            XTheadOp::Rev => x.swap_bytes(),
+            XTheadOp::Revw => (x as u32).swap_bytes() as i32 as u64,
+            XTheadOp::Tstnbz => (0..8).fold(0, |acc, byte| match (x >> (8 * byte)) & 0xff {
+                0 => acc | 0xff << (8 * byte),
+                _ => acc,
+            }),
+            XTheadOp::Tst(bit) => (x >> bit) & 1,
+            XTheadOp::Mveqz if y == 0 => x,
+            XTheadOp::Mvnez if y != 0 => x,
+            XTheadOp::Mveqz | XTheadOp::Mvnez => self.ixu[rd],
+            XTheadOp::Cmo => self.ixu[rd],
+            XTheadOp::LoadPair(size, signed) => {
+                // Both destinations and the base must differ
+                if rd == rs2 || rd == rs1 || rs2 == rs1 {
+                    return Err(RiscvCpuError::DecodeError);
+                }
+                let addr = x.wrapping_add(self.pair_offset(inst, size));
+                self.mem_access(RiscvAccessType::Load, addr, 2 * size)?;
+                let read = |cpu: &RiscvCpu, addr| {
+                    let value = cpu.load_data(addr, size).ok_or(RiscvCpuError::ExecuteError)?;
+                    Ok(match signed {
+                        true => value as i32 as u64,
+                        false => value,
+                    })
+                };
+                let first = read(self, addr)?;
+                self.ixu[rs2] = read(self, addr + size)?;
+                first
+            }
+            XTheadOp::StorePair(size) => {
+                let addr = x.wrapping_add(self.pair_offset(inst, size));
+                self.mem_access(RiscvAccessType::Store, addr, 2 * size)?;
+                let first = self.ixu[rd];
+                self.store_data(addr, size, first).ok_or(RiscvCpuError::ExecuteError)?;
+                self.store_data(addr + size, size, y).ok_or(RiscvCpuError::ExecuteError)?;
+                first
+            }
+        };
+        Ok(())
+    }
+
+    /// Pair offset, imm2 scaled by the size of the pair (8 or 16 bytes)
+    fn pair_offset(&self, inst: u32, size: u64) -> u64 {
+        (getfield32!(inst, 2, 25) as u64) * 2 * size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // custom-0 R-type
+    fn enc(funct7: u32, rs2: usize, rs1: usize, funct3: u32, rd: usize) -> u32 {
+        funct7 << 25 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | OPCODE_CUSTOM0
+    }
+
+    fn cpu() -> RiscvCpu {
+        let mut cpu = RiscvCpu::new(vec![0; 64]);
+        cpu.xthead = true;
+        cpu.ixu[REG_A1] = 0x8000_0000_0000_00f0;
+        cpu.ixu[REG_A2] = 3;
+        cpu
+    }
+
+    #[test]
+    fn test_xthead_alu() {
+        let mut cpu = cpu();
+        // th.addsl a0,a1,a2,2
+        cpu.execute(enc(0b0000010, REG_A2, REG_A1, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_00fc);
+        // th.srri a0,a1,4
+        cpu.execute(0b000100 << 26 | enc(0, 4, REG_A1, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x0800_0000_0000_000f);
+        // th.ext a0,a1,7,4 and th.extu
+        cpu.execute(7 << 26 | enc(0, 4, REG_A1, 0b010, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xffff_ffff_ffff_ffff);
+        cpu.execute(7 << 26 | enc(0, 4, REG_A1, 0b011, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xf);
+        // th.ff1, th.ff0
+        cpu.execute(enc(0b1000011, 0, REG_A2, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 62);
+        cpu.execute(enc(0b1000010, 0, REG_A1, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 1);
+        // th.rev, th.tstnbz, th.tst a0,a1,63
+        cpu.execute(enc(0b1000001, 0, REG_A1, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0xf000_0000_0000_0080);
+        cpu.execute(enc(0b1000000, 0, REG_A1, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x00ff_ffff_ffff_ff00);
+        cpu.execute(0b100010 << 26 | enc(0, 63, REG_A1, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 1);
+        // th.mveqz a0,a1,zero moves, th.mvnez a0,a2,zero doesn't
+        cpu.execute(enc(0b0100000, REG_ZERO, REG_A1, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_00f0);
+        cpu.execute(enc(0b0100001, REG_ZERO, REG_A2, 0b001, REG_A0)).unwrap();
+        assert_eq!(cpu.ixu[REG_A0], 0x8000_0000_0000_00f0);
+    }
+
+    #[test]
+    fn test_xthead_mempair() {
+        let mut cpu = cpu();
+        cpu.ixu[REG_A1] = 8;
+        cpu.ixu[REG_A3] = 0xffff_fffe;
+        // th.swd a3,a2,(a1),1,3 stores at 8 + 8
+        cpu.execute(0b11100 << 27 | 1 << 25 | enc(0, REG_A2, REG_A1, 0b101, REG_A3)).unwrap();
+        assert_eq!(cpu.read_mem(16, 8), Some(0x0000_0003_ffff_fffe));
+        // th.lwd a4,a5,(a1),1,3
+        cpu.execute(0b11100 << 27 | 1 << 25 | enc(0, REG_A5, REG_A1, 0b100, REG_A4)).unwrap();
+        assert_eq!(cpu.ixu[REG_A4], 0xffff_ffff_ffff_fffe);
+        assert_eq!(cpu.ixu[REG_A5], 3);
+        // Overlapping destinations are reserved
+        assert!(cpu.execute(0b11111 << 27 | enc(0, REG_A4, REG_A1, 0b100, REG_A4)).is_err());
+    }
+
+    #[test]
+    fn test_xthead_opt_in() {
+        let mut cpu = cpu();
+        cpu.xthead = false;
+        assert!(cpu.execute(enc(0b1000001, 0, REG_A1, 0b001, REG_A0)).is_err());
+        assert_eq!(disasm(enc(0b1000001, 0, REG_A1, 0b001, REG_A0)).unwrap(), "th.rev a0,a1");
+    }
+}